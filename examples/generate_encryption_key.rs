@@ -0,0 +1,15 @@
+use rand::RngCore;
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Prints a new xpub encryption key under the id given as first argument (default "k1"), and a
+/// fingerprint salt, which must never change once records were stored with it.
+fn main() {
+    let key_id = std::env::args().nth(1).unwrap_or_else(|| "k1".into());
+    println!("XPUB_ENCRYPTION_KEYS={}={}", key_id, random_hex(32));
+    println!("XPUB_FINGERPRINT_SALT={}", random_hex(32));
+}
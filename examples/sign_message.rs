@@ -64,10 +64,7 @@ fn random_signer<C: secp256k1::Signing + secp256k1::Verification>(
     let root_test = Xpriv::new_master(NetworkKind::Test, &seed).unwrap();
     let path = "84h/0h/0h".parse::<DerivationPath>().unwrap();
     let priv_child = root_test.derive_priv(secp_ctx, &path).unwrap();
-    
-    // Private
-    let secret_key = priv_child.private_key;
-    let xpub_child = Xpub::from_priv(secp_ctx, &priv_child);
+
 
     // Public
     let xpub = Xpub::from_priv(secp_ctx, &priv_child);
@@ -61,7 +61,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("xpriv: {}", xpriv);
     println!("xpriv key: {}", xpriv);
 
-    let xpub = Xpub::from_priv(&secp, &xpriv);
     let (xpub_child, private_key, public_key) = key_pair_from_xpriv(&secp, &xpriv, &[0,0]);
 
     let mut to_sign = xpub_child.to_string().to_owned();
@@ -0,0 +1,67 @@
+// Local stand-in for a webhook consumer: verifies the signature of every callback and prints it.
+// Usage: cargo run --example webhook_receiver -- [SECRET] [FAIL_FIRST]
+// FAIL_FIRST answers the first N callbacks with 500 to exercise the retries.
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use actix_web::{
+    post,
+    web,
+    App,
+    HttpRequest,
+    HttpResponse,
+    HttpServer,
+};
+use bitcoin_hashes::{
+    hmac,
+    sha256,
+    Hash,
+    HashEngine,
+};
+
+struct Receiver {
+    secret: String,
+    fail_first: usize,
+    received: AtomicUsize,
+}
+
+fn header<'a>(request: &'a HttpRequest, name: &str) -> &'a str {
+    request.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or_default()
+}
+
+#[post("/webhook")]
+async fn webhook(request: HttpRequest, body: web::Bytes, receiver: web::Data<Receiver>) -> HttpResponse {
+    let count = receiver.received.fetch_add(1, Ordering::SeqCst);
+    let timestamp = header(&request, "X-Webhook-Timestamp");
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(receiver.secret.as_bytes());
+    engine.input(timestamp.as_bytes());
+    engine.input(b".");
+    engine.input(&body);
+    let expected = format!("sha256={}", hmac::Hmac::<sha256::Hash>::from_engine(engine));
+    let verified = header(&request, "X-Webhook-Signature") == expected;
+    println!("Delivery {} verified {}", header(&request, "X-Webhook-Delivery"), verified);
+    println!("{}", String::from_utf8_lossy(&body));
+    if !verified {
+        return HttpResponse::Unauthorized().finish()
+    }
+    if count < receiver.fail_first {
+        return HttpResponse::InternalServerError().body("Simulated failure")
+    }
+    HttpResponse::Ok().finish()
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let receiver = web::Data::new(Receiver {
+        secret: args.next().expect("Webhook secret"),
+        fail_first: args.next().and_then(|value| value.parse().ok()).unwrap_or(0),
+        received: AtomicUsize::new(0),
+    });
+    println!("Listening on http://localhost:8081/webhook");
+    HttpServer::new(move || App::new().app_data(receiver.clone()).service(webhook))
+        .bind(("127.0.0.1", 8081))?
+        .run()
+        .await
+}
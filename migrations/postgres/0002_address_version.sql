@@ -0,0 +1,2 @@
+-- Optimistic concurrency for derivation history updates.
+ALTER TABLE users ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Issued at registration, so sessions do not outlive the deletion of their account.
+ALTER TABLE users ADD COLUMN account_id TEXT;
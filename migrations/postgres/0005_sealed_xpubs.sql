@@ -0,0 +1,3 @@
+-- The xpub of each user, sealed. Once it is set, the `xpub` columns of the user's rows hold the
+-- salted fingerprint of the xpub instead of its base58 encoding.
+ALTER TABLE users ADD COLUMN sealed_xpub TEXT;
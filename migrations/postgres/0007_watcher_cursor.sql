@@ -0,0 +1,5 @@
+-- Blocks processed by the deposit watcher, a single row, see model::watcher.
+CREATE TABLE watcher_cursor (
+    id INTEGER PRIMARY KEY,
+    body TEXT NOT NULL
+);
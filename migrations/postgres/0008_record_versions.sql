@@ -0,0 +1,8 @@
+-- Bumped by every update of a record body, for optimistic concurrency, see model::storage::sql.
+ALTER TABLE psbts ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE audit_events ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE invoices ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE broadcasts ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE deposits ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE webhooks ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE deliveries ADD COLUMN row_version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,78 @@
+-- Registered xpubs, keyed by their base58 encoding.
+CREATE TABLE users (
+    xpub TEXT PRIMARY KEY,
+    nonce INTEGER NOT NULL
+);
+
+-- Derivation history of each user, in derivation order.
+CREATE TABLE derived_keys (
+    xpub TEXT NOT NULL REFERENCES users (xpub) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    derived_xpub TEXT NOT NULL,
+    PRIMARY KEY (xpub, seq)
+);
+
+CREATE TABLE psbts (
+    xpub TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    PRIMARY KEY (xpub, txid)
+);
+
+CREATE TABLE audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    xpub TEXT NOT NULL,
+    recorded_at INTEGER NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX audit_events_xpub ON audit_events (xpub, recorded_at);
+
+CREATE TABLE invoices (
+    id TEXT PRIMARY KEY,
+    xpub TEXT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX invoices_xpub ON invoices (xpub);
+
+CREATE TABLE broadcasts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    xpub TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX broadcasts_xpub_txid ON broadcasts (xpub, txid);
+
+CREATE TABLE deposits (
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    xpub TEXT NOT NULL,
+    block_height INTEGER,
+    block_hash TEXT,
+    confirmations INTEGER NOT NULL,
+    first_seen INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    PRIMARY KEY (txid, vout)
+);
+CREATE INDEX deposits_xpub ON deposits (xpub, first_seen);
+CREATE INDEX deposits_block_hash ON deposits (block_hash);
+
+CREATE TABLE webhooks (
+    id TEXT PRIMARY KEY,
+    xpub TEXT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX webhooks_xpub ON webhooks (xpub);
+
+CREATE TABLE deliveries (
+    id TEXT PRIMARY KEY,
+    webhook_id TEXT NOT NULL,
+    xpub TEXT NOT NULL,
+    event_key TEXT NOT NULL,
+    status TEXT NOT NULL,
+    next_attempt_at INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    UNIQUE (webhook_id, event_key)
+);
+CREATE INDEX deliveries_due ON deliveries (status, next_attempt_at);
@@ -0,0 +1,12 @@
+-- Path and listing filters of each derived key. Rows from before have NULL columns until the
+-- record migration recovers their path and fills `body`.
+ALTER TABLE derived_keys ADD COLUMN chain INTEGER;
+ALTER TABLE derived_keys ADD COLUMN idx INTEGER;
+ALTER TABLE derived_keys ADD COLUMN script_type TEXT;
+ALTER TABLE derived_keys ADD COLUMN label TEXT;
+ALTER TABLE derived_keys ADD COLUMN used BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE derived_keys ADD COLUMN body TEXT;
+CREATE UNIQUE INDEX derived_keys_path ON derived_keys (xpub, chain, idx);
+
+-- Derivation quota plan; NULL stands for the default plan.
+ALTER TABLE users ADD COLUMN plan TEXT;
@@ -0,0 +1,4 @@
+-- Wallets linked to a user named after another wallet, see model::user.
+ALTER TABLE users ADD COLUMN user_id TEXT;
+ALTER TABLE users ADD COLUMN label TEXT;
+CREATE INDEX users_user_id ON users (user_id);
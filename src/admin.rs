@@ -0,0 +1,103 @@
+//! Operator endpoints, served on the admin listeners only (`listeners.admin_bind`), where
+//! `tls.client_ca_file` requires client certificates.
+use actix_web::{
+    web,
+    get,
+    post,
+    HttpResponse,
+    Responder,
+    Error,
+    error::{
+        InternalError,
+        ErrorBadGateway,
+        ErrorBadRequest,
+        ErrorNotFound,
+    },
+};
+use serde::Deserialize;
+use std::str::FromStr;
+
+use crate::model::{
+    chain::ChainBackend,
+    storage::Storage,
+    watch_only::{
+        ImportRequest,
+        WatchOnlyMirror,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct DryRunParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[get("/info")]
+pub async fn info() -> Result<impl Responder, Error> {
+    Ok(r#"
+        Admin services:
+        /watch_only/import
+        /watch_only/import/{xpub}
+        /migrate
+        /rotate_keys
+    "#)
+}
+
+/// fn import_watch_only starts mirroring a registered xpub into the node's watch-only descriptor
+/// wallet, rescanning from the given timestamp, and returns 202 with the imported descriptors.
+#[post("/watch_only/import/{xpub}")]
+pub async fn import_watch_only(
+    xpub: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    mirror: web::Data<WatchOnlyMirror>,
+    import_web: web::Json<ImportRequest>,
+) -> Result<impl Responder, Error> {
+    if !mirror.is_enabled() {
+        return Err(ErrorNotFound("Watch-only mirroring is disabled"))
+    }
+    let xpub = bitcoin::bip32::Xpub::from_str(&xpub.into_inner()).map_err(ErrorBadRequest)?;
+    let address = storage.address_lookup(xpub.into()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let timestamp = import_web.into_inner().get_timestamp();
+    let descriptors = mirror.descriptors(&xpub, timestamp);
+    let wallet_id = storage.wallet_id(&address.get_xpubwrapper());
+    mirror.spawn_mirror(chain, xpub, timestamp, wallet_id);
+    Ok(HttpResponse::Accepted().json(descriptors))
+}
+
+/// fn watch_only_status reports whether the watch-only wallet is rescanning after an import.
+#[get("/watch_only/import")]
+pub async fn watch_only_status(
+    chain: web::Data<dyn ChainBackend>,
+    mirror: web::Data<WatchOnlyMirror>,
+) -> Result<impl Responder, Error> {
+    if !mirror.is_enabled() {
+        return Err(ErrorNotFound("Watch-only mirroring is disabled"))
+    }
+    let progress = web::block(move || chain.scan_progress()).await?.map_err(ErrorBadGateway)?;
+    Ok(web::Json(progress))
+}
+
+/// fn migrate upgrades the stored records, as `xpub-session-api migrate`, and returns the report.
+#[post("/migrate")]
+pub async fn migrate(
+    params: web::Query<DryRunParams>,
+    storage: web::Data<dyn Storage>,
+) -> Result<impl Responder, Error> {
+    let report = storage.migrate(params.dry_run).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    Ok(web::Json(report))
+}
+
+/// fn rotate_keys re-wraps the values sealed under retired keys, as `xpub-session-api rotate-keys`,
+/// and returns the report.
+#[post("/rotate_keys")]
+pub async fn rotate_keys(
+    params: web::Query<DryRunParams>,
+    storage: web::Data<dyn Storage>,
+) -> Result<impl Responder, Error> {
+    let report = storage.rotate_keys(params.dry_run).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    Ok(web::Json(report))
+}
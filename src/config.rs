@@ -0,0 +1,597 @@
+use bitcoin::Network;
+use clap::{
+    Parser,
+    Subcommand,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+use crate::model::{
+    fees,
+    quota::{
+        self,
+        QuotaPolicy,
+    },
+    session,
+    storage::sql,
+    watch_only,
+    watcher,
+    webhook,
+};
+use crate::tls;
+
+pub const STORAGE_BACKENDS: [&str; 4] = ["mongo", "memory", "sqlite", "postgres"];
+pub const CHAIN_BACKENDS: [&str; 2] = ["core", "memory"];
+pub const SESSION_STORES: [&str; 3] = ["redis", "memory", "cookie"];
+pub const SAME_SITE: [&str; 3] = ["strict", "lax", "none"];
+
+/// Command line of the server binary. Flags override the environment, which overrides the
+/// configuration file.
+#[derive(Parser)]
+#[command(version, about = "Non-custodial xpub session API")]
+pub struct Cli {
+    /// TOML configuration file.
+    #[arg(long, env = "XPUB_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Validates the configuration, prints it and exits.
+    #[arg(long)]
+    pub check_config: bool,
+    /// Address to listen on, repeatable.
+    #[arg(long)]
+    pub bind: Vec<String>,
+    /// Address of an admin listener, repeatable.
+    #[arg(long)]
+    pub admin_bind: Vec<String>,
+    #[arg(long)]
+    pub workers: Option<usize>,
+    /// Bitcoin network: bitcoin, testnet, signet or regtest.
+    #[arg(long)]
+    pub network: Option<String>,
+    #[arg(long)]
+    pub storage_backend: Option<String>,
+    #[arg(long)]
+    pub session_store: Option<String>,
+    /// PEM certificate chain, serving HTTPS with `--tls-key-file`.
+    #[arg(long)]
+    pub tls_cert_file: Option<PathBuf>,
+    #[arg(long)]
+    pub tls_key_file: Option<PathBuf>,
+    /// Log filter, as `RUST_LOG`.
+    #[arg(long)]
+    pub log_filter: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Upgrades the stored records and exits.
+    Migrate {
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Re-wraps the values sealed under retired keys with the active one and exits.
+    RotateKeys {
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Moves a user to a quota plan, or back to the default one without a plan, and exits.
+    SetPlan {
+        xpub: String,
+        plan: Option<String>,
+    },
+    /// Prints a new key for `SESSION_KEYS` and exits.
+    GenerateSessionKey,
+}
+
+/// Bitcoin network and the node behind the chain backend.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub bitcoin: Network,
+    pub chain_backend: String,
+    pub rpc_url: String,
+    pub rpc_user: String,
+    pub rpc_pass: String,
+    pub watch_only_wallet: String,
+    pub watch_only_mirror: bool,
+    pub watch_only_range: u32,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            bitcoin: Network::Testnet,
+            chain_backend: "core".into(),
+            rpc_url: "http://localhost:18443".into(),
+            rpc_user: "project".into(),
+            rpc_pass: "xpub_manager".into(),
+            watch_only_wallet: "xpub-session-api".into(),
+            watch_only_mirror: false,
+            watch_only_range: watch_only::DEFAULT_RANGE_END,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenersConfig {
+    pub bind: Vec<String>,
+    /// Addresses serving the operator endpoints of `admin` instead of the API, none when empty.
+    pub admin_bind: Vec<String>,
+    /// Worker threads, one per core when unset.
+    pub workers: Option<usize>,
+}
+
+impl Default for ListenersConfig {
+    fn default() -> Self {
+        ListenersConfig {
+            bind: vec!["127.0.0.1:8080".into()],
+            admin_bind: Vec::new(),
+            workers: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: String,
+    pub mongodb_uri: String,
+    pub mongodb_database: String,
+    /// Collection of the address records.
+    pub mongodb_addresses_collection: String,
+    pub database_url: String,
+    pub max_connections: u32,
+    pub migrate_on_startup: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: "mongo".into(),
+            mongodb_uri: "mongodb://localhost:27017".into(),
+            mongodb_database: crate::DB_NAME.into(),
+            mongodb_addresses_collection: crate::COLL_NAME.into(),
+            database_url: sql::DEFAULT_SQLITE_URL.into(),
+            max_connections: sql::DEFAULT_MAX_CONNECTIONS,
+            migrate_on_startup: true,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub store: String,
+    pub redis_uri: String,
+    pub ttl_secs: i64,
+    /// Session keys, one per line, the primary first. `SESSION_KEYS` is read when unset.
+    pub keys_file: Option<PathBuf>,
+    /// Whether the session cookie is sent over HTTPS only, see `Config::cookie_secure`.
+    pub cookie_secure: Option<bool>,
+    pub cookie_http_only: bool,
+    pub cookie_same_site: String,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            store: "redis".into(),
+            redis_uri: "redis://127.0.0.1:6379".into(),
+            ttl_secs: session::DEFAULT_TTL_SECS,
+            keys_file: None,
+            cookie_secure: None,
+            cookie_http_only: false,
+            cookie_same_site: "strict".into(),
+        }
+    }
+}
+
+/// HTTPS for every listener, enabled by a certificate and its key.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key of the certificate.
+    pub key_file: Option<PathBuf>,
+    /// PEM CA certificates: when set, clients of the admin listeners must present a certificate
+    /// they issued.
+    pub client_ca_file: Option<PathBuf>,
+    /// How often the certificate files are checked for changes.
+    pub reload_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert_file: None,
+            key_file: None,
+            client_ca_file: None,
+            reload_interval_secs: tls::DEFAULT_RELOAD_INTERVAL_SECS,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// The certificate and key files, when TLS is enabled.
+    pub fn files(&self) -> Option<(&Path, &Path)> {
+        Some((self.cert_file.as_deref()?, self.key_file.as_deref()?))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub json_limit_bytes: usize,
+    /// Concurrent connections per worker.
+    pub max_connections: usize,
+    pub default_plan: String,
+    /// Derivation quotas as `plan=quota` pairs, see `QuotaPolicy::parse`.
+    pub derivation_quotas: Option<String>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            json_limit_bytes: 2_097_152,
+            max_connections: 25_000,
+            default_plan: quota::DEFAULT_PLAN.into(),
+            derivation_quotas: None,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn quota_policy(&self) -> Result<QuotaPolicy, String> {
+        match &self.derivation_quotas {
+            Some(spec) => QuotaPolicy::parse(spec, &self.default_plan),
+            None => Ok(QuotaPolicy::default()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Filter directives, as `RUST_LOG`.
+    pub filter: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            filter: "info".into(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    pub start_height: Option<u32>,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig {
+            enabled: false,
+            interval_secs: watcher::DEFAULT_INTERVAL_SECS,
+            start_height: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeesConfig {
+    pub floor_sat_vb: u64,
+    pub fallback_sat_vb: u64,
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for FeesConfig {
+    fn default() -> Self {
+        FeesConfig {
+            floor_sat_vb: fees::DEFAULT_FLOOR_SAT_VB,
+            fallback_sat_vb: fees::DEFAULT_FALLBACK_SAT_VB,
+            cache_ttl_secs: fees::DEFAULT_TTL_SECS,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub interval_secs: u64,
+    /// Lets webhooks target loopback and private addresses, for local receivers.
+    pub allow_private_urls: bool,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            interval_secs: webhook::DEFAULT_DISPATCH_INTERVAL_SECS,
+            allow_private_urls: false,
+        }
+    }
+}
+
+/// Server configuration. Secrets (encryption keys, fingerprint salt, session keys) are only read
+/// from the environment or their own files.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub listeners: ListenersConfig,
+    pub storage: StorageConfig,
+    pub session: SessionConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    pub logging: LoggingConfig,
+    pub watcher: WatcherConfig,
+    pub fees: FeesConfig,
+    pub webhooks: WebhooksConfig,
+}
+
+/// Overrides `target` with the environment variable `name` when set.
+fn env<T: FromStr>(name: &str, target: &mut T, errors: &mut Vec<String>)
+where
+    T::Err: Display,
+{
+    if let Ok(value) = std::env::var(name) {
+        match value.parse() {
+            Ok(value) => *target = value,
+            Err(err) => errors.push(format!("{}: {}", name, err)),
+        }
+    }
+}
+
+fn env_opt<T: FromStr>(name: &str, target: &mut Option<T>, errors: &mut Vec<String>)
+where
+    T::Err: Display,
+{
+    if let Ok(value) = std::env::var(name) {
+        match value.parse() {
+            Ok(value) => *target = Some(value),
+            Err(err) => errors.push(format!("{}: {}", name, err)),
+        }
+    }
+}
+
+impl Config {
+    /// The configuration of `cli`: its file, then the environment, then its flags. All errors are
+    /// reported at once.
+    pub fn load(cli: &Cli) -> Result<Self, Vec<String>> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|err| vec![format!("{}: {}", path.display(), err)])?;
+                toml::from_str(&text).map_err(|err| vec![format!("{}: {}", path.display(), err)])?
+            },
+            None => Config::default(),
+        };
+        let mut errors = Vec::new();
+        config.apply_env(&mut errors);
+        config.apply_cli(cli, &mut errors);
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        let network = &mut self.network;
+        env("BITCOIN_NETWORK", &mut network.bitcoin, errors);
+        env("CHAIN_BACKEND", &mut network.chain_backend, errors);
+        env("BITCOIN_RPC_URL", &mut network.rpc_url, errors);
+        env("BITCOIN_RPC_USER", &mut network.rpc_user, errors);
+        env("BITCOIN_RPC_PASS", &mut network.rpc_pass, errors);
+        env("WATCH_ONLY_WALLET", &mut network.watch_only_wallet, errors);
+        env("WATCH_ONLY_MIRROR", &mut network.watch_only_mirror, errors);
+        env("WATCH_ONLY_RANGE", &mut network.watch_only_range, errors);
+        if let Ok(bind) = std::env::var("BIND") {
+            self.listeners.bind = bind.split(',').map(|addr| addr.trim().to_string()).collect();
+        }
+        if let Ok(bind) = std::env::var("ADMIN_BIND") {
+            self.listeners.admin_bind = bind.split(',').map(|addr| addr.trim().to_string()).filter(|addr| !addr.is_empty()).collect();
+        }
+        env_opt("WORKERS", &mut self.listeners.workers, errors);
+        let storage = &mut self.storage;
+        env("STORAGE_BACKEND", &mut storage.backend, errors);
+        env("MONGODB_URI", &mut storage.mongodb_uri, errors);
+        env("MONGODB_DATABASE", &mut storage.mongodb_database, errors);
+        env("MONGODB_ADDRESSES_COLLECTION", &mut storage.mongodb_addresses_collection, errors);
+        env("DATABASE_URL", &mut storage.database_url, errors);
+        env("DATABASE_MAX_CONNECTIONS", &mut storage.max_connections, errors);
+        env("MIGRATE_ON_STARTUP", &mut storage.migrate_on_startup, errors);
+        let session = &mut self.session;
+        env("SESSION_STORE", &mut session.store, errors);
+        env("REDIS_URI", &mut session.redis_uri, errors);
+        env("SESSION_TTL_SECS", &mut session.ttl_secs, errors);
+        env_opt("SESSION_KEYS_FILE", &mut session.keys_file, errors);
+        env_opt("SESSION_COOKIE_SECURE", &mut session.cookie_secure, errors);
+        env("SESSION_COOKIE_HTTP_ONLY", &mut session.cookie_http_only, errors);
+        env("SESSION_COOKIE_SAME_SITE", &mut session.cookie_same_site, errors);
+        env_opt("TLS_CERT_FILE", &mut self.tls.cert_file, errors);
+        env_opt("TLS_KEY_FILE", &mut self.tls.key_file, errors);
+        env_opt("TLS_CLIENT_CA_FILE", &mut self.tls.client_ca_file, errors);
+        env("TLS_RELOAD_INTERVAL_SECS", &mut self.tls.reload_interval_secs, errors);
+        let limits = &mut self.limits;
+        env("JSON_LIMIT_BYTES", &mut limits.json_limit_bytes, errors);
+        env("MAX_CONNECTIONS", &mut limits.max_connections, errors);
+        env("DEFAULT_PLAN", &mut limits.default_plan, errors);
+        env_opt("DERIVATION_QUOTAS", &mut limits.derivation_quotas, errors);
+        env("RUST_LOG", &mut self.logging.filter, errors);
+        env("WATCHER_ENABLED", &mut self.watcher.enabled, errors);
+        env("WATCHER_INTERVAL_SECS", &mut self.watcher.interval_secs, errors);
+        env_opt("WATCHER_START_HEIGHT", &mut self.watcher.start_height, errors);
+        env("FEE_FLOOR_SAT_VB", &mut self.fees.floor_sat_vb, errors);
+        env("FEE_FALLBACK_SAT_VB", &mut self.fees.fallback_sat_vb, errors);
+        env("FEE_CACHE_TTL_SECS", &mut self.fees.cache_ttl_secs, errors);
+        env("WEBHOOK_INTERVAL_SECS", &mut self.webhooks.interval_secs, errors);
+        env("WEBHOOK_ALLOW_PRIVATE_URLS", &mut self.webhooks.allow_private_urls, errors);
+    }
+
+    fn apply_cli(&mut self, cli: &Cli, errors: &mut Vec<String>) {
+        if !cli.bind.is_empty() {
+            self.listeners.bind = cli.bind.clone();
+        }
+        if !cli.admin_bind.is_empty() {
+            self.listeners.admin_bind = cli.admin_bind.clone();
+        }
+        if cli.workers.is_some() {
+            self.listeners.workers = cli.workers;
+        }
+        if let Some(network) = &cli.network {
+            match network.parse() {
+                Ok(network) => self.network.bitcoin = network,
+                Err(err) => errors.push(format!("--network: {}", err)),
+            }
+        }
+        if let Some(backend) = &cli.storage_backend {
+            self.storage.backend = backend.clone();
+        }
+        if let Some(store) = &cli.session_store {
+            self.session.store = store.clone();
+        }
+        if cli.tls_cert_file.is_some() {
+            self.tls.cert_file = cli.tls_cert_file.clone();
+        }
+        if cli.tls_key_file.is_some() {
+            self.tls.key_file = cli.tls_key_file.clone();
+        }
+        if let Some(filter) = &cli.log_filter {
+            self.logging.filter = filter.clone();
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut one_of = |field: &str, value: &str, allowed: &[&str]| {
+            if !allowed.contains(&value) {
+                errors.push(format!("{} must be one of {}, not {:?}", field, allowed.join(", "), value));
+            }
+        };
+        one_of("network.chain_backend", &self.network.chain_backend, &CHAIN_BACKENDS);
+        one_of("storage.backend", &self.storage.backend, &STORAGE_BACKENDS);
+        one_of("session.store", &self.session.store, &SESSION_STORES);
+        one_of("session.cookie_same_site", &self.session.cookie_same_site, &SAME_SITE);
+        if self.listeners.bind.is_empty() {
+            errors.push("listeners.bind is empty".into());
+        }
+        for addr in &self.listeners.bind {
+            if addr.parse::<SocketAddr>().is_err() {
+                errors.push(format!("listeners.bind: invalid address {:?}", addr));
+            }
+        }
+        for addr in &self.listeners.admin_bind {
+            match addr.parse::<SocketAddr>() {
+                Err(_) => errors.push(format!("listeners.admin_bind: invalid address {:?}", addr)),
+                Ok(_) if self.listeners.bind.contains(addr) =>
+                    errors.push(format!("listeners.admin_bind: {} is also an API listener", addr)),
+                // Anyone reaching the address could migrate, rotate keys or trigger rescans.
+                Ok(parsed) if !parsed.ip().is_loopback() && self.tls.client_ca_file.is_none() =>
+                    errors.push(format!("listeners.admin_bind: {} is not a loopback address, which requires tls.client_ca_file", addr)),
+                Ok(_) => (),
+            }
+        }
+        if self.tls.client_ca_file.is_some() && self.listeners.admin_bind.is_empty() {
+            errors.push("tls.client_ca_file applies to the admin listeners, but listeners.admin_bind is empty".into());
+        }
+        if self.listeners.workers == Some(0) {
+            errors.push("listeners.workers must be positive".into());
+        }
+        let scheme = self.storage.database_url.split(':').next().unwrap_or_default();
+        match self.storage.backend.as_str() {
+            "sqlite" | "postgres" if !self.storage.database_url.starts_with(&self.storage.backend) =>
+                errors.push(format!("storage.database_url is a {} URL for the {} backend", scheme, self.storage.backend)),
+            "mongo" if self.storage.mongodb_database.is_empty() || self.storage.mongodb_addresses_collection.is_empty() =>
+                errors.push("storage.mongodb_database and storage.mongodb_addresses_collection must be set".into()),
+            _ => (),
+        }
+        if self.storage.max_connections == 0 {
+            errors.push("storage.max_connections must be positive".into());
+        }
+        if self.session.ttl_secs <= 0 {
+            errors.push("session.ttl_secs must be positive".into());
+        }
+        if self.session.store == "redis" && !self.session.redis_uri.starts_with("redis") {
+            errors.push(format!("session.redis_uri is not a Redis URI: {:?}", self.session.redis_uri));
+        }
+        if self.session.cookie_same_site == "none" && !self.cookie_secure() {
+            errors.push("session.cookie_same_site = \"none\" requires session.cookie_secure".into());
+        }
+        match self.tls.files() {
+            Some((cert_file, key_file)) => {
+                if let Err(err) = tls::check(cert_file, key_file, self.tls.client_ca_file.as_deref()) {
+                    errors.push(format!("tls: {}", err));
+                }
+            },
+            None if self.tls.cert_file.is_some() || self.tls.key_file.is_some() =>
+                errors.push("tls.cert_file and tls.key_file must be set together".into()),
+            None if self.tls.client_ca_file.is_some() =>
+                errors.push("tls.client_ca_file requires tls.cert_file and tls.key_file".into()),
+            None => (),
+        }
+        if self.tls.reload_interval_secs == 0 {
+            errors.push("tls.reload_interval_secs must be positive".into());
+        }
+        if self.limits.json_limit_bytes == 0 || self.limits.max_connections == 0 {
+            errors.push("limits.json_limit_bytes and limits.max_connections must be positive".into());
+        }
+        if let Err(err) = self.limits.quota_policy() {
+            errors.push(format!("limits.derivation_quotas: {}", err));
+        }
+        if let Err(err) = tracing_subscriber::EnvFilter::builder().parse(&self.logging.filter) {
+            errors.push(format!("logging.filter: {}", err));
+        }
+        if self.watcher.interval_secs == 0 || self.webhooks.interval_secs == 0 {
+            errors.push("watcher.interval_secs and webhooks.interval_secs must be positive".into());
+        }
+        errors
+    }
+
+    /// `session.cookie_secure`, by default set when the listeners serve HTTPS: a browser would
+    /// not send a secure cookie back over plain HTTP. Set it when TLS is terminated in front.
+    pub fn cookie_secure(&self) -> bool {
+        self.session.cookie_secure.unwrap_or_else(|| self.tls.files().is_some())
+    }
+
+    /// The configuration as TOML, with credentials masked and `session.cookie_secure` resolved.
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        config.session.cookie_secure = Some(self.cookie_secure());
+        config.network.rpc_pass = "********".into();
+        config.network.rpc_url = redact_userinfo(&config.network.rpc_url);
+        config.storage.database_url = redact_userinfo(&config.storage.database_url);
+        config.storage.mongodb_uri = redact_userinfo(&config.storage.mongodb_uri);
+        config.session.redis_uri = redact_userinfo(&config.session.redis_uri);
+        toml::to_string_pretty(&config).expect("Serializable configuration")
+    }
+}
+
+/// `uri` with the credentials of its userinfo, if any, masked.
+fn redact_userinfo(uri: &str) -> String {
+    let Some((scheme, rest)) = uri.split_once("://") else {
+        return uri.into()
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    match rest[..authority_end].rfind('@') {
+        Some(at) => format!("{}://********{}", scheme, &rest[at..]),
+        None => uri.into(),
+    }
+}
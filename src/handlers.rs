@@ -1,20 +1,52 @@
 use actix_web::{
     web,
+    delete,
     get,
     post,
+    HttpResponse,
     Responder,
+    http::StatusCode,
     Error,
     error::{
         InternalError,
-        ErrorInsufficientStorage,
+        ErrorBadGateway,
+        ErrorBadRequest,
+        ErrorConflict,
+        ErrorForbidden,
+        ErrorNotFound,
+        ErrorServiceUnavailable,
         ErrorUnauthorized,
+        ErrorUnprocessableEntity,
     },
 };
 use actix_session::Session;
+use std::str::FromStr;
 
-use mongodb::{bson::doc, Client};
+use bitcoin::{
+    Network,
+    Txid,
+};
+use mongodb::bson::{
+    doc,
+    oid::ObjectId,
+};
 
-use crate::model;
+use crate::model::{
+    self,
+    audit::{
+        self,
+        AuditAction,
+    },
+    chain::{
+        ChainBackend,
+        ChainError,
+    },
+    quota::QuotaPolicy,
+    storage::{
+        DerivationTarget,
+        Storage,
+    },
+};
 
 #[get("/info")]
 // This will be the general information page for this API.
@@ -22,16 +54,47 @@ pub async fn info() -> Result<impl Responder, Error> {
     Ok(r#"
         Services:
         /login
+        /nonce/{xpub}
         /derive_address/{first_index}/{second_index}
         /get_address
+        /addresses
+        /quota
         /create_psbt
+        /create_sweep_psbt
+        /fees
+        /payment_uri/{first_index}/{second_index}
+        /parse_payment_uri
+        /invoice
+        /invoices
+        /invoice/{id}
+        /balance
+        /utxos
+        /transactions
+        /broadcast
+        /broadcast/{txid}
+        /deposits
+        /psbt/signed
+        /psbts
+        /audit
+        /webhooks
+        /webhooks/{id}
+        /webhooks/{id}/deliveries
+        /account/export
+        /account/delete/challenge
+        /account/delete
+        /wallets
+        /wallets/link
+        /wallets/{wallet_id}
+        /wallets/{wallet_id}/select
     "#)
 }
 
 #[post("/login")]
 /// Login handler
 pub async fn login(
-    client: web::Data<Client>,
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    mirror: web::Data<model::watch_only::WatchOnlyMirror>,
     credentials: web::Json<model::Credentials<model::XpubWrapper>>,
     session: Session,
 ) -> Result<impl Responder, Error> {
@@ -39,49 +102,108 @@ pub async fn login(
     match model::UserAddress::authenticate(credentials.clone()).await {
         Ok(false) => Err(ErrorUnauthorized("Unauthorized")),
         Ok(true) => {
-            session.insert("credentials", credentials.clone())?;
+            let xpub = credentials.clone().get_xpub();
+            let nonce = credentials.clone().get_nonce();
+            let registered = model::storage::register_address(storage.get_ref(), credentials.clone()).await
+                .map_err(|err| InternalError::from_response("", err))?;
+            // Each signed nonce logs in once.
+            match storage.advance_nonce(xpub.clone(), nonce).await {
+                Ok(Some(_)) => (),
+                Ok(None) => return Err(ErrorUnauthorized("Stale nonce")),
+                Err(err) => return Err(InternalError::from_response("", err).into()),
+            }
+            let address = storage.address_lookup(xpub.clone()).await
+                .map_err(|err| InternalError::from_response("", err))?;
+            session.insert("credentials", credentials)?;
+            session.remove("wallet");
+            if let Some(account_id) = address.get_account_id() {
+                session.insert("account_id", account_id.to_hex())?;
+            }
+            let action = if registered.is_some() { AuditAction::Register } else { AuditAction::Login };
+            audit::record(storage.get_ref(), xpub, action, None).await;
+            if let (Some(address), true) = (registered, mirror.is_enabled()) {
+                let wallet_id = storage.wallet_id(&address.clone().get_xpubwrapper());
+                // The account is usable without the mirror; it can be retried on the admin listener.
+                mirror.spawn_mirror(chain, address.get_xpub(), None, wallet_id);
+            }
             Ok("Authorized")
         },
         Err(err) => Err(InternalError::from_response("", err).into()),
     }
 }
 
+/// fn get_nonce returns the nonce the next login of `xpub` has to sign, 0 before registration.
+#[get("/nonce/{xpub}")]
+pub async fn get_nonce(
+    xpub: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+) -> Result<impl Responder, Error> {
+    let xpub = bitcoin::bip32::Xpub::from_str(&xpub.into_inner()).map_err(ErrorBadRequest)?;
+    match storage.address_lookup(xpub.into()).await {
+        Ok(address) => Ok(web::Json(address.get_nonce())),
+        Err(err) if err.status() == StatusCode::NOT_FOUND => Ok(web::Json(model::Nonce::default())),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
 
 #[get("/get_address")]
 pub async fn get_address(
-    client: web::Data<Client>,
+    storage: web::Data<dyn Storage>,
     session: Session,
 ) -> Result<impl Responder, Error> {
-    match model::db::lookup_or_update_address(client, session).await {
+    match model::storage::lookup_or_update_address(storage.get_ref(), session).await {
         Ok(address) => Ok(web::Json(address)),
         Err(err) => Err(err)
     }
 }
 
+/// fn derive_address records the key at the given path of the logged-in xpub, optionally labeled,
+/// within the derivation quota of the user's plan.
 #[get("/derive_address/{first_path}/{second_path}")]
 pub async fn derive_address(
     path: web::Path<(u32, u32)>,
-    client: web::Data<Client>,
+    params: web::Query<model::derived::DeriveParams>,
+    storage: web::Data<dyn Storage>,
+    quotas: web::Data<QuotaPolicy>,
     session: Session,
 ) -> Result<impl Responder, Error> {
     let (first, second) = path.into_inner();
     let derivation_path = [first, second];
-    match model::db::lookup_or_update_address(client.clone(), session.clone()).await {
-        Ok(address) => {
-            let size = address.get_xpub_list_ref().len();
-            if size > 255 {
-                return Err(ErrorInsufficientStorage(size))
-            }
-            let mut new_address = address.clone();
-            let derived_xpub = model::derivation::derive_xpub(&new_address.get_xpub(), &derivation_path);
-            new_address.insert_xpub(derived_xpub.into());
-            match model::db::update_address(client, new_address.clone()).await {
-                Ok(updated_address) => Ok(web::Json(updated_address)),
-                Err(err) => Err(InternalError::from_response("", err).into()),
-            }
-            
-        },
-        Err(err) => Err(err)
+    model::derivation::check_path(&derivation_path).map_err(ErrorBadRequest)?;
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let target = DerivationTarget::Path(derivation_path);
+    let (address, key) = model::storage::record_derivation(storage.get_ref(), &quotas, address, target, params.into_inner().get_label()).await?;
+    let detail = format!("{:?}", derivation_path);
+    audit::record(storage.get_ref(), address.get_xpubwrapper(), AuditAction::DeriveAddress, Some(detail)).await;
+    Ok(web::Json(key))
+}
+
+/// fn get_addresses lists the keys derived from the logged-in xpub a page at a time, filtered by
+/// chain, script type, label or whether they received a payment.
+#[get("/addresses")]
+pub async fn get_addresses(
+    filter: web::Query<model::derived::DerivedKeyFilter>,
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.derived_keys_lookup(address.get_xpubwrapper(), &filter).await {
+        Ok(page) => Ok(web::Json(page)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn get_quota returns the plan of the logged-in xpub with its derivation quota and usage.
+#[get("/quota")]
+pub async fn get_quota(
+    storage: web::Data<dyn Storage>,
+    quotas: web::Data<QuotaPolicy>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.derived_keys_count(address.clone().get_xpubwrapper()).await {
+        Ok(derived) => Ok(web::Json(model::quota::QuotaUsage::new(&quotas, address.get_plan(), derived))),
+        Err(err) => Err(InternalError::from_response("", err).into()),
     }
 }
 
@@ -89,22 +211,642 @@ pub async fn derive_address(
 /// the sender's public keys and the output and input amounts for the transation.
 #[post("/create_psbt")]
 pub async fn create_psbt(
-    client: web::Data<Client>,
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    oracle: web::Data<model::fees::FeeOracle>,
+    network: web::Data<Network>,
     psbt_web: web::Json<model::psbt::PsbtSerialized>,
     session: Session,
 ) -> Result<impl Responder, Error> {
-    let credentials = match session.get("credentials")? {
+    let credentials: model::Credentials<model::XpubWrapper> = match session.get("credentials")? {
         Some(credential) => credential,
         None => {
             return Err(ErrorUnauthorized("Unauthorized"));
         }
     };
+    let xpub = credentials.clone().get_xpub();
+    // The PSBT is stored for the account, which has to be live.
+    model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
     match model::UserAddress::authenticate(credentials).await {
         Ok(false) => Err(ErrorUnauthorized("Unauthorized")),
         Ok(true) => {
-            let psbt = psbt_web.into_inner().try_into_psbt()?;
+            let mut psbt_serialized = psbt_web.into_inner();
+            if !psbt_serialized.has_tip_height() {
+                if let Some(tip_height) = tip_height(chain.clone()).await {
+                    psbt_serialized.set_tip_height(tip_height);
+                }
+            }
+            if let Some(target) = psbt_serialized.get_fee_target() {
+                let fee_rate = web::block(move || oracle.fee_rate(chain.get_ref(), target)).await?.map_err(ErrorBadGateway)?;
+                psbt_serialized.set_fee_rate(fee_rate);
+            }
+            let psbt = psbt_serialized.try_into_psbt(**network).map_err(|err| ErrorBadRequest(err.to_string()))?;
+            store_psbt(storage.get_ref(), xpub, &psbt, AuditAction::CreatePsbt).await?;
             Ok(web::Json(psbt))
         },
         Err(err) => Err(InternalError::from_response("", err).into()),
     }
-}
\ No newline at end of file
+}
+
+/// The chain tip for the anti-fee-sniping locktime, `None` when the node is unreachable, in
+/// which case the transaction is built with locktime 0 instead of failing.
+async fn tip_height(chain: web::Data<dyn ChainBackend>) -> Option<u32> {
+    match web::block(move || chain.tip_height()).await {
+        Ok(Ok(tip_height)) => Some(tip_height),
+        Ok(Err(err)) => {
+            tracing::warn!("No tip height for the locktime: {}", err);
+            None
+        },
+        Err(err) => {
+            tracing::warn!("No tip height for the locktime: {}", err);
+            None
+        },
+    }
+}
+
+/// Keeps the latest version of a PSBT for `/psbts` and records `action` in the audit log.
+async fn store_psbt(
+    storage: &dyn Storage,
+    xpub: model::XpubWrapper,
+    psbt: &bitcoin::Psbt,
+    action: AuditAction,
+) -> Result<(), Error> {
+    let record = model::psbt::PsbtRecord::new(xpub.clone(), psbt);
+    let detail = record.get_txid().to_string();
+    storage.upsert_psbt(record).await.map_err(|err| InternalError::from_response("", err))?;
+    audit::record(storage, xpub, action, Some(detail)).await;
+    Ok(())
+}
+
+/// fn create_sweep_psbt builds a psbt spending the given UTXOs of the logged-in xpub into a
+/// single output, either a fresh internal address (consolidation) or an external address (sweep).
+#[post("/create_sweep_psbt")]
+pub async fn create_sweep_psbt(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    oracle: web::Data<model::fees::FeeOracle>,
+    quotas: web::Data<QuotaPolicy>,
+    network: web::Data<Network>,
+    sweep_web: web::Json<model::psbt::SweepSerialized>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let xpub = address.clone().get_xpubwrapper();
+    let mut sweep = sweep_web.into_inner();
+    sweep.check_inputs().map_err(ErrorBadRequest)?;
+    if let Some(target) = sweep.get_fee_target() {
+        let chain = chain.clone();
+        let fee_rate = web::block(move || oracle.fee_rate(chain.get_ref(), target)).await?.map_err(ErrorBadGateway)?;
+        sweep.set_fee_rate(fee_rate);
+    }
+    let mut paths = Vec::new();
+    for utxo in sweep.get_utxos() {
+        let path = utxo.get_derivation_path();
+        let derived = storage.derived_key_lookup(xpub.clone(), path).await
+            .map_err(|err| InternalError::from_response("", err))?;
+        if derived.is_none() {
+            return Err(ErrorForbidden(format!("{:?} not derived", path)))
+        }
+        paths.push(path);
+    }
+    let account_xpub = address.get_xpub();
+    let utxo_chain = chain.clone();
+    let unspent = web::block(move || model::balance::account_utxos(utxo_chain.get_ref(), &account_xpub, &paths))
+        .await?
+        .map_err(ErrorBadGateway)?;
+    sweep.check_utxos(&unspent).map_err(ErrorUnprocessableEntity)?;
+    if let model::psbt::SweepDestination::Internal = sweep.get_destination() {
+        let chain = model::balance::CHANGE_CHAIN;
+        let last = storage.last_derived_index(xpub.clone(), chain).await
+            .map_err(|err| InternalError::from_response("", err))?;
+        sweep.set_change_path([chain, last.map_or(0, |index| index + 1)]);
+    }
+    if !sweep.has_tip_height() {
+        if let Some(tip_height) = tip_height(chain).await {
+            sweep.set_tip_height(tip_height);
+        }
+    }
+    let change_path = sweep.get_change_path();
+    let psbt = sweep.try_into_psbt(&address.get_xpub(), **network).map_err(|err| ErrorBadRequest(err.to_string()))?;
+    // A concurrent derivation of the same change path fails with 409, and the client retries.
+    if let Some(path) = change_path {
+        let target = DerivationTarget::FreshPath(path);
+        model::storage::record_derivation(storage.get_ref(), &quotas, address, target, None).await?;
+    }
+    store_psbt(storage.get_ref(), xpub, &psbt, AuditAction::CreatePsbt).await?;
+    Ok(web::Json(psbt))
+}
+
+/// fn fees returns sat/vB fee rates for the supported confirmation targets.
+#[get("/fees")]
+pub async fn fees(
+    chain: web::Data<dyn ChainBackend>,
+    oracle: web::Data<model::fees::FeeOracle>,
+) -> Result<impl Responder, Error> {
+    let estimates = web::block(move || oracle.estimates(chain.get_ref())).await?.map_err(ErrorBadGateway)?;
+    Ok(web::Json(estimates))
+}
+
+/// fn payment_uri returns a BIP21 `bitcoin:` URI for the address derived from the logged-in
+/// xpub at the given path, recording the derivation if it is new.
+#[post("/payment_uri/{first_path}/{second_path}")]
+pub async fn payment_uri(
+    path: web::Path<(u32, u32)>,
+    params: web::Json<model::bip21::PaymentParams>,
+    storage: web::Data<dyn Storage>,
+    quotas: web::Data<QuotaPolicy>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let (first, second) = path.into_inner();
+    let derivation_path = [first, second];
+    model::derivation::check_path(&derivation_path).map_err(ErrorBadRequest)?;
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let target = DerivationTarget::Path(derivation_path);
+    let (address, _) = model::storage::record_derivation(storage.get_ref(), &quotas, address, target, None).await?;
+    let btc_address = model::derivation::derive_network_address(&address.get_xpub(), &derivation_path);
+    Ok(web::Json(model::bip21::PaymentUri::new(&btc_address, &params.into_inner())))
+}
+
+/// fn parse_payment_uri parses a BIP21 `bitcoin:` URI into the recipient fields of a `/create_psbt` request.
+#[post("/parse_payment_uri")]
+pub async fn parse_payment_uri(
+    network: web::Data<Network>,
+    uri: web::Json<model::bip21::PaymentUri>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    if session.get::<model::Credentials<model::XpubWrapper>>("credentials")?.is_none() {
+        return Err(ErrorUnauthorized("Unauthorized"))
+    }
+    match uri.into_inner().to_payment_request(**network) {
+        Ok(payment_request) => Ok(web::Json(payment_request)),
+        Err(err) => Err(ErrorBadRequest(err.to_string())),
+    }
+}
+
+/// Matches the open invoices against the node and stores the ones whose payments changed.
+async fn refresh_invoices(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    invoices: Vec<model::invoice::Invoice>,
+) -> Result<Vec<model::invoice::Invoice>, Error> {
+    let mut refreshed = Vec::with_capacity(invoices.len());
+    let now = model::invoice::unix_now();
+    for invoice in invoices {
+        if !invoice.is_open(now) {
+            refreshed.push(invoice);
+            continue
+        }
+        let chain = chain.clone();
+        let mut observed = invoice.clone();
+        observed = web::block(move || {
+            model::invoice::observe_payments(chain.get_ref(), &mut observed, model::invoice::DEFAULT_MIN_CONF)
+                .map(|_| observed)
+        }).await?.map_err(ErrorBadGateway)?;
+        let observed = model::invoice::store_observed(storage.get_ref(), &invoice, observed).await
+            .map_err(|err| InternalError::from_response("", err))?;
+        refreshed.push(observed);
+    }
+    Ok(refreshed)
+}
+
+/// fn create_invoice creates a payment request for an amount, bound to a fresh address derived
+/// from the logged-in xpub and watched by the node.
+#[post("/invoice")]
+pub async fn create_invoice(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    quotas: web::Data<QuotaPolicy>,
+    invoice_web: web::Json<model::invoice::InvoiceRequest>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let target = DerivationTarget::NextOnChain(0);
+    let (address, key) = model::storage::record_derivation(storage.get_ref(), &quotas, address, target, None).await?;
+    let derivation_path = key.get_derivation_path();
+    let btc_address = model::derivation::derive_network_address(&address.get_xpub(), &derivation_path);
+    let invoice = invoice_web.into_inner().to_invoice(address.get_xpubwrapper(), derivation_path, &btc_address);
+    let watched = invoice.clone();
+    web::block(move || model::invoice::watch_invoice_address(chain.get_ref(), &watched))
+        .await?
+        .map_err(ErrorBadGateway)?;
+    match storage.insert_invoice(invoice).await {
+        Ok(invoice) => {
+            let detail = invoice.get_id().to_hex();
+            audit::record(storage.get_ref(), invoice.get_xpub().clone(), AuditAction::CreateInvoice, Some(detail)).await;
+            Ok(web::Json(invoice))
+        },
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+#[get("/invoices")]
+pub async fn get_invoices(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let invoices = match storage.invoices_lookup(address.get_xpubwrapper()).await {
+        Ok(invoices) => invoices,
+        Err(err) => return Err(InternalError::from_response("", err).into()),
+    };
+    Ok(web::Json(refresh_invoices(storage, chain, invoices).await?))
+}
+
+#[get("/invoice/{id}")]
+pub async fn get_invoice(
+    id: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let id = ObjectId::parse_str(id.into_inner()).map_err(ErrorBadRequest)?;
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let invoice = match storage.invoice_lookup(address.get_xpubwrapper(), id).await {
+        Ok(invoice) => invoice,
+        Err(err) => return Err(InternalError::from_response("", err).into()),
+    };
+    let mut refreshed = refresh_invoices(storage, chain, vec![invoice]).await?;
+    Ok(web::Json(refreshed.remove(0)))
+}
+
+async fn scan_account_utxos(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    params: model::balance::ScanParams,
+    session: Session,
+) -> Result<Vec<model::balance::AccountUtxo>, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let paths = model::storage::scan_paths(storage.get_ref(), address.clone().get_xpubwrapper(), params.get_gap_limit()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let xpub = address.get_xpub();
+    web::block(move || model::balance::account_utxos(chain.get_ref(), &xpub, &paths))
+        .await?
+        .map_err(ErrorBadGateway)
+}
+
+/// fn get_balance returns the confirmed, unconfirmed and immature balance of the logged-in xpub,
+/// scanning its derived addresses plus a gap limit lookahead.
+#[get("/balance")]
+pub async fn get_balance(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    params: web::Query<model::balance::ScanParams>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let utxos = scan_account_utxos(storage, chain, params.into_inner(), session).await?;
+    Ok(web::Json(model::balance::Balance::from_utxos(&utxos)))
+}
+
+#[get("/utxos")]
+pub async fn get_utxos(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    params: web::Query<model::balance::ScanParams>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    Ok(web::Json(scan_account_utxos(storage, chain, params.into_inner(), session).await?))
+}
+
+/// fn get_transactions lists, paginated and newest first, the transactions touching any
+/// address derived from the logged-in xpub.
+#[get("/transactions")]
+pub async fn get_transactions(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    params: web::Query<model::history::HistoryParams>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let params = params.into_inner();
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let paths = model::storage::scan_paths(storage.get_ref(), address.clone().get_xpubwrapper(), params.get_gap_limit()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let xpub = address.get_xpub();
+    let page = web::block(move || model::history::account_history(chain.get_ref(), &xpub, &paths, &params))
+        .await?
+        .map_err(|err| match err {
+            ChainError::Unsupported(_) => ErrorServiceUnavailable(err),
+            _ => ErrorBadGateway(err),
+        })?;
+    Ok(web::Json(page))
+}
+
+/// fn broadcast extracts the transaction of a finalized PSBT, checks that the mempool accepts it
+/// and relays it, recording the txid for the logged-in xpub.
+#[post("/broadcast")]
+pub async fn broadcast(
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    broadcast_web: web::Json<model::broadcast::BroadcastRequest>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let transaction = broadcast_web.into_inner().extract_tx().map_err(ErrorBadRequest)?;
+    let txid = web::block(move || {
+        let acceptance = chain.test_mempool_accept(&transaction)?;
+        if !acceptance.allowed {
+            return Err(ChainError::Rejected(acceptance.reject_reason.unwrap_or_default()))
+        }
+        chain.broadcast(&transaction)
+    }).await?.map_err(|err| match err {
+        ChainError::Rejected(_) => ErrorUnprocessableEntity(err),
+        _ => ErrorBadGateway(err),
+    })?;
+    let xpub = address.get_xpubwrapper();
+    let record = model::broadcast::BroadcastRecord::new(xpub.clone(), txid);
+    let record = match storage.insert_broadcast(record).await {
+        Ok(record) => record,
+        Err(err) => return Err(InternalError::from_response("", err).into()),
+    };
+    audit::record(storage.get_ref(), xpub.clone(), AuditAction::Broadcast, Some(txid.to_string())).await;
+    model::webhook::notify_or_log(storage.get_ref(), &xpub, model::webhook::WebhookEvent::TransactionBroadcast(record.clone())).await;
+    Ok(web::Json(record))
+}
+
+#[get("/broadcast/{txid}")]
+pub async fn get_broadcast(
+    txid: web::Path<Txid>,
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let txid = txid.into_inner();
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let record = match storage.broadcast_lookup(address.get_xpubwrapper(), txid).await {
+        Ok(record) => record,
+        Err(err) => return Err(InternalError::from_response("", err).into()),
+    };
+    let chain_tx = web::block(move || chain.transaction(&txid)).await?.map_err(ErrorBadGateway)?;
+    Ok(web::Json(model::broadcast::BroadcastStatus::new(
+        record,
+        chain_tx.as_ref().map(|chain_tx| chain_tx.confirmations),
+        chain_tx.and_then(|chain_tx| chain_tx.block_height),
+    )))
+}
+
+/// fn get_deposits lists the deposits recorded by the background watcher for the logged-in xpub.
+#[get("/deposits")]
+pub async fn get_deposits(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.deposits_lookup(address.get_xpubwrapper()).await {
+        Ok(deposits) => Ok(web::Json(deposits)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn psbt_signed takes a PSBT returned by a cosigner, stores it and notifies the account's webhooks.
+#[post("/psbt/signed")]
+pub async fn psbt_signed(
+    storage: web::Data<dyn Storage>,
+    psbt_web: web::Json<model::psbt::SignedPsbtSerialized>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let xpub = address.get_xpubwrapper();
+    let signed = psbt_web.into_inner();
+    let signatures = signed.to_signatures();
+    if signatures.get_signed_inputs() == 0 {
+        return Err(ErrorBadRequest("PSBT carries no signatures"))
+    }
+    store_psbt(storage.get_ref(), xpub.clone(), signed.get_psbt(), AuditAction::PsbtSigned).await?;
+    model::webhook::notify_or_log(storage.get_ref(), &xpub, model::webhook::WebhookEvent::PsbtSigned(signatures.clone())).await;
+    Ok(web::Json(signatures))
+}
+
+/// fn create_webhook registers a callback url for the logged-in xpub. The signing secret is
+/// only returned here.
+#[post("/webhooks")]
+pub async fn create_webhook(
+    storage: web::Data<dyn Storage>,
+    policy: web::Data<model::webhook::WebhookPolicy>,
+    webhook_web: web::Json<model::webhook::WebhookRequest>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let xpub = address.get_xpubwrapper();
+    let webhook = webhook_web.into_inner().to_webhook(xpub.clone(), policy.get_ref()).await.map_err(ErrorBadRequest)?;
+    match storage.insert_webhook(webhook).await {
+        Ok(webhook) => {
+            let detail = webhook.get_id().to_hex();
+            audit::record(storage.get_ref(), xpub, AuditAction::CreateWebhook, Some(detail)).await;
+            Ok(web::Json(webhook))
+        },
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+#[get("/webhooks")]
+pub async fn get_webhooks(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.webhooks_lookup(address.get_xpubwrapper()).await {
+        Ok(webhooks) => Ok(web::Json(webhooks.iter().map(model::webhook::WebhookInfo::from).collect::<Vec<_>>())),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+#[delete("/webhooks/{id}")]
+pub async fn delete_webhook(
+    id: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let id = ObjectId::parse_str(id.into_inner()).map_err(ErrorBadRequest)?;
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let xpub = address.get_xpubwrapper();
+    match storage.delete_webhook(xpub.clone(), id).await {
+        Ok(()) => {
+            audit::record(storage.get_ref(), xpub, AuditAction::DeleteWebhook, Some(id.to_hex())).await;
+            Ok(HttpResponse::NoContent())
+        },
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn get_deliveries returns the delivery log of a webhook, newest first.
+#[get("/webhooks/{id}/deliveries")]
+pub async fn get_deliveries(
+    id: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let id = ObjectId::parse_str(id.into_inner()).map_err(ErrorBadRequest)?;
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.deliveries_lookup(address.get_xpubwrapper(), id, model::webhook::DISPATCH_BATCH).await {
+        Ok(deliveries) => Ok(web::Json(deliveries)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn get_psbts lists the PSBTs created or signed for the logged-in xpub, latest version first.
+#[get("/psbts")]
+pub async fn get_psbts(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.psbts_lookup(address.get_xpubwrapper()).await {
+        Ok(psbts) => Ok(web::Json(psbts)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn get_audit returns the latest account actions of the logged-in xpub, newest first.
+#[get("/audit")]
+pub async fn get_audit(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    match storage.audit_events_lookup(address.get_xpubwrapper(), audit::AUDIT_PAGE_SIZE).await {
+        Ok(events) => Ok(web::Json(events)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+
+/// fn export_account returns everything stored for the logged-in xpub as one JSON document.
+#[get("/account/export")]
+pub async fn export_account(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let xpub = address.clone().get_xpubwrapper();
+    let export = model::account::export(storage.get_ref(), address).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    audit::record(storage.get_ref(), xpub, AuditAction::ExportAccount, None).await;
+    Ok(web::Json(export))
+}
+
+/// fn deletion_challenge issues the message the logged-in xpub has to sign to delete its account.
+#[post("/account/delete/challenge")]
+pub async fn deletion_challenge(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session.clone()).await?;
+    model::user::check_deletable(storage.get_ref(), &address).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let challenge = model::account::DeletionChallenge::new();
+    session.insert("deletion_challenge", challenge.clone())?;
+    Ok(web::Json(model::account::ChallengeResponse::new(&challenge, &address.get_xpub())))
+}
+
+/// fn delete_account removes the logged-in xpub's account and every record kept for it, once
+/// the pending deletion challenge is signed, and ends the session.
+#[post("/account/delete")]
+pub async fn delete_account(
+    storage: web::Data<dyn Storage>,
+    deletion_web: web::Json<model::account::DeletionRequest>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session.clone()).await?;
+    // Each challenge is good for one attempt.
+    let challenge = match session.remove_as::<model::account::DeletionChallenge>("deletion_challenge") {
+        Some(Ok(challenge)) if !challenge.is_expired() => challenge,
+        _ => return Err(ErrorForbidden("No pending deletion challenge")),
+    };
+    let signed = challenge.verify(&address.get_xpub(), deletion_web.into_inner().get_witness())
+        .map_err(|err| InternalError::from_response("", err))?;
+    if !signed {
+        return Err(ErrorUnauthorized("Unauthorized"))
+    }
+    model::user::check_deletable(storage.get_ref(), &address).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let wallet_id = storage.wallet_id(&address.clone().get_xpubwrapper());
+    let deleted = storage.delete_account(address.get_xpubwrapper()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    session.purge();
+    tracing::info!("Wallet {} deleted with {} records", wallet_id.as_str(), deleted);
+    Ok(web::Json(model::account::DeletionReport::new(deleted)))
+}
+
+/// fn get_wallets lists the wallets of the logged-in user, flagging the one the other endpoints
+/// act on.
+#[get("/wallets")]
+pub async fn get_wallets(
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let user_id = model::user::owner_id(storage.get_ref(), &address);
+    match model::user::User::load(storage.get_ref(), user_id, &address.get_xpubwrapper()).await {
+        Ok(user) => Ok(web::Json(user)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn link_wallet links another wallet to the logged-in user, which proves control of it by
+/// signing its login nonce.
+#[post("/wallets/link")]
+pub async fn link_wallet(
+    storage: web::Data<dyn Storage>,
+    link_web: web::Json<model::user::LinkRequest>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session).await?;
+    let user_id = model::user::owner_id(storage.get_ref(), &address);
+    let linked = model::user::link(storage.get_ref(), &user_id, link_web.into_inner()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    audit::record(storage.get_ref(), linked.get_xpubwrapper(), AuditAction::LinkWallet, Some(user_id.as_str().to_string())).await;
+    match model::user::User::load(storage.get_ref(), user_id, &address.get_xpubwrapper()).await {
+        Ok(user) => Ok(web::Json(user)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn select_wallet makes the other endpoints act on the given wallet of the logged-in user.
+#[post("/wallets/{wallet_id}/select")]
+pub async fn select_wallet(
+    wallet_id: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session.clone()).await?;
+    let user_id = model::user::owner_id(storage.get_ref(), &address);
+    let user = model::user::User::load(storage.get_ref(), user_id.clone(), &address.get_xpubwrapper()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let xpub = match user.wallet(&wallet_id) {
+        Some(wallet) => wallet.get_xpubwrapper(),
+        None => return Err(ErrorNotFound("NotFound")),
+    };
+    session.insert("wallet", xpub.clone())?;
+    match model::user::User::load(storage.get_ref(), user_id, &xpub).await {
+        Ok(user) => Ok(web::Json(user)),
+        Err(err) => Err(InternalError::from_response("", err).into()),
+    }
+}
+
+/// fn unlink_wallet makes a wallet linked to the logged-in user a user of its own again.
+#[delete("/wallets/{wallet_id}")]
+pub async fn unlink_wallet(
+    wallet_id: web::Path<String>,
+    storage: web::Data<dyn Storage>,
+    session: Session,
+) -> Result<impl Responder, Error> {
+    let address = model::storage::lookup_or_update_address(storage.get_ref(), session.clone()).await?;
+    let user_id = model::user::owner_id(storage.get_ref(), &address);
+    let user = model::user::User::load(storage.get_ref(), user_id.clone(), &address.get_xpubwrapper()).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    let wallet = match user.wallet(&wallet_id) {
+        Some(wallet) => wallet,
+        None => return Err(ErrorNotFound("NotFound")),
+    };
+    if wallet.get_wallet_id() == user.get_user_id() {
+        return Err(ErrorConflict("The wallet the user is named after"))
+    }
+    let xpub = wallet.get_xpubwrapper();
+    storage.link_wallet(xpub.clone(), None, None).await
+        .map_err(|err| InternalError::from_response("", err))?;
+    if session.get::<model::XpubWrapper>("wallet")?.as_ref() == Some(&xpub) {
+        session.remove("wallet");
+    }
+    audit::record(storage.get_ref(), xpub, AuditAction::UnlinkWallet, Some(user_id.as_str().to_string())).await;
+    Ok(HttpResponse::NoContent())
+}
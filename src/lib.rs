@@ -1,5 +1,17 @@
 pub mod model;
 pub mod handlers;
+pub mod admin;
+pub mod config;
+pub mod tls;
 
 pub const DB_NAME: &str = "xpub-session-api";
-pub const COLL_NAME: &str = "addresses";
\ No newline at end of file
+pub const COLL_NAME: &str = "addresses";
+pub const DERIVED_KEY_COLL_NAME: &str = "derived_keys";
+pub const INVOICE_COLL_NAME: &str = "invoices";
+pub const BROADCAST_COLL_NAME: &str = "broadcasts";
+pub const DEPOSIT_COLL_NAME: &str = "deposits";
+pub const WEBHOOK_COLL_NAME: &str = "webhooks";
+pub const DELIVERY_COLL_NAME: &str = "deliveries";
+pub const PSBT_COLL_NAME: &str = "psbts";
+pub const AUDIT_COLL_NAME: &str = "audit_events";
+pub const WATCHER_COLL_NAME: &str = "watcher";
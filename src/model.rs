@@ -1,29 +1,43 @@
-use std::hash::{Hash, Hasher};
-use actix_web::{
-    HttpResponse,
-    web,
-};
+use std::hash::Hash;
+use actix_web::HttpResponse;
 use serde::{Deserialize, Serialize};
-use mongodb::{
-    Collection,
-    bson::{
-        doc,
-        Bson,
-        to_document,
-    },
-};
-use crate::{
-    DB_NAME,
-    COLL_NAME,
+use mongodb::bson::{
+    Bson,
+    oid::ObjectId,
+    to_document,
 };
 use bitcoin::{
     bip32,
     sign_message::MessageSignature,
 };
+use bitcoin_hashes::{
+    hmac,
+    sha256,
+    Hash as _,
+    HashEngine,
+};
+use schema::SCHEMA_VERSION;
+pub mod account;
+pub mod audit;
+pub mod balance;
+pub mod bip21;
+pub mod broadcast;
+pub mod chain;
 pub mod derivation;
-pub mod db;
+pub mod derived;
+pub mod fees;
+pub mod history;
+pub mod invoice;
 pub mod psbt;
+pub mod quota;
+pub mod schema;
+pub mod session;
+pub mod storage;
 pub mod user;
+pub mod vault;
+pub mod watch_only;
+pub mod watcher;
+pub mod webhook;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CredentialWitness(
@@ -37,18 +51,51 @@ impl CredentialWitness {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Keyed hash standing for an xpub in stored records, logs and webhooks, see `model::vault`.
+#[derive(Clone, Hash, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(transparent)]
 pub struct SaltedFingerPrint {
     salted_fingerprint: String,
 }
 
-#[derive(Clone, Hash, Serialize, Deserialize, Debug, PartialEq)]
+impl SaltedFingerPrint {
+    /// HMAC-SHA256 of `xpub` keyed with `salt`.
+    pub fn new(salt: &[u8], xpub: &XpubWrapper) -> Self {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(salt);
+        engine.input(&xpub.clone().to_bytes());
+        SaltedFingerPrint {
+            salted_fingerprint: hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string(),
+        }
+    }
+    /// A fingerprint read back from where it was stored.
+    pub fn from_stored(salted_fingerprint: String) -> Self {
+        SaltedFingerPrint {
+            salted_fingerprint,
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        &self.salted_fingerprint
+    }
+}
+
+impl From<SaltedFingerPrint> for Bson {
+    fn from(val: SaltedFingerPrint) -> Self {
+        Bson::String(val.salted_fingerprint)
+    }
+}
+
+#[derive(Clone, Default, Hash, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Nonce(u32);
 
 impl Nonce {
-    fn to_str(self) -> String {
+    fn to_str(&self) -> String {
         self.0.to_string()
     }
+    /// The nonce after this one. The last one cannot be used, as its successor would wrap
+    /// around to nonces signed already.
+    fn next(&self) -> Result<Nonce, HttpResponse> {
+        self.0.checked_add(1).map(Nonce).ok_or_else(|| HttpResponse::Unauthorized().json("Nonce exhausted"))
+    }
 }
 
 #[derive(Clone, Hash, Serialize, Deserialize, PartialEq)]
@@ -97,7 +144,26 @@ impl From<XpubWrapper> for Bson {
 pub struct UserAddress<T: Hash> {
     xpub: T,
     nonce: Nonce,
-    xpub_list: Vec<XpubWrapper>,
+    /// Bumped on every derivation, for optimistic concurrency. The derived keys themselves
+    /// are stored apart, see `model::derived`.
+    #[serde(default)]
+    version: u64,
+    /// Derivation quota plan, see `model::quota`.
+    #[serde(default)]
+    plan: Option<String>,
+    /// New on every registration, so sessions of a deleted account are not valid for the
+    /// xpub registered again. Accounts from before have none.
+    #[serde(default)]
+    account_id: Option<ObjectId>,
+    /// User the wallet was linked to, see `model::user`. Wallets not linked are users of their own.
+    #[serde(default)]
+    user_id: Option<SaltedFingerPrint>,
+    /// Name given to the wallet when it was linked.
+    #[serde(default)]
+    label: Option<String>,
+    /// Shape of the stored record, see `model::schema`.
+    #[serde(default)]
+    schema_version: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -111,6 +177,9 @@ impl<T: Hash> Credentials<T> {
     pub fn get_nonce(self) -> Nonce {
         self.nonce.clone()
     }
+    pub fn get_xpub(self) -> T {
+        self.xpub
+    }
 }
 
 impl UserAddress<XpubWrapper> {
@@ -120,31 +189,35 @@ impl UserAddress<XpubWrapper> {
     pub fn get_xpub(&self) -> bip32::Xpub {
         self.xpub.clone().to_xpub()
     }
-    pub fn insert_xpub(&mut self, xpub: XpubWrapper) {
-        self.xpub_list.push(xpub);
-    }
-    pub fn get_xpub_list(self) -> Vec<XpubWrapper> {
-        self.xpub_list
-    }
-    pub fn get_xpub_list_ref(&self) -> &Vec<XpubWrapper> {
-        &self.xpub_list
-    }
-    pub fn update_xpub_list(mut self, list: Vec<XpubWrapper>) {
-        self.xpub_list = list;
-    }
     pub fn from_credentials(credentials: Credentials<XpubWrapper>) -> Self {
         UserAddress {
             xpub: credentials.xpub,
             nonce: credentials.nonce,
-            xpub_list: Vec::new(),
+            version: 0,
+            plan: None,
+            account_id: Some(ObjectId::new()),
+            user_id: None,
+            label: None,
+            schema_version: SCHEMA_VERSION,
         }
     }
     pub fn get_nonce(&self) -> Nonce {
         self.nonce.clone()
     }
-    pub fn update_nonce(mut self) -> Self {
-        self.nonce = Nonce(self.nonce.0 + 1);
-        self
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+    pub fn get_plan(&self) -> Option<&str> {
+        self.plan.as_deref()
+    }
+    pub fn get_account_id(&self) -> Option<ObjectId> {
+        self.account_id
+    }
+    pub fn get_user_id(&self) -> Option<&SaltedFingerPrint> {
+        self.user_id.as_ref()
+    }
+    pub fn get_label(&self) -> Option<&str> {
+        self.label.as_deref()
     }
     pub async fn authenticate(credentials: Credentials<XpubWrapper>) -> Result<bool, HttpResponse> {
         let credential_xpub: bip32::Xpub = credentials.xpub.clone().to_xpub();
@@ -0,0 +1,154 @@
+use actix_web::HttpResponse;
+use bitcoin::{
+    bip32,
+    sign_message::MessageSignature,
+};
+use rand::RngCore;
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    derivation,
+    CredentialWitness,
+    SaltedFingerPrint,
+    UserAddress,
+    XpubWrapper,
+    audit::AuditEvent,
+    broadcast::BroadcastRecord,
+    derived::DerivedKey,
+    invoice::{
+        unix_now,
+        Invoice,
+    },
+    psbt::PsbtRecord,
+    storage::Storage,
+    user,
+    watcher::Deposit,
+    webhook::{
+        Delivery,
+        WebhookInfo,
+    },
+};
+
+/// Seconds a deletion challenge can be signed in.
+pub const CHALLENGE_TTL_SECS: u64 = 300;
+
+/// Random challenge the owner signs to confirm the deletion of their account. It is only kept
+/// in the server side session, and used once.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeletionChallenge {
+    challenge: String,
+    expires_at: u64,
+}
+
+impl DeletionChallenge {
+    pub fn new() -> Self {
+        let mut challenge = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        DeletionChallenge {
+            challenge: challenge.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            expires_at: unix_now() + CHALLENGE_TTL_SECS,
+        }
+    }
+    /// The message to sign with the key of `xpub`, distinct from the login message.
+    pub fn message(&self, xpub: &bip32::Xpub) -> String {
+        format!("Delete account {} {}", xpub, self.challenge)
+    }
+    pub fn is_expired(&self) -> bool {
+        unix_now() > self.expires_at
+    }
+    /// Whether `witness` signs the challenge message with the public key of `xpub`.
+    pub fn verify(&self, xpub: &bip32::Xpub, witness: CredentialWitness) -> Result<bool, HttpResponse> {
+        let signature = MessageSignature::from_slice(&witness.get_slice())
+            .map_err(|err| HttpResponse::BadRequest().body(err.to_string()))?;
+        derivation::verify(xpub.public_key, &self.message(xpub), signature)
+            .map_err(|err| HttpResponse::InternalServerError().body(err.to_string()))
+    }
+}
+
+impl Default for DeletionChallenge {
+    fn default() -> Self {
+        DeletionChallenge::new()
+    }
+}
+
+/// What `/account/delete/challenge` returns: the message to sign and until when.
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    message: String,
+    expires_at: u64,
+}
+
+impl ChallengeResponse {
+    pub fn new(challenge: &DeletionChallenge, xpub: &bip32::Xpub) -> Self {
+        ChallengeResponse {
+            message: challenge.message(xpub),
+            expires_at: challenge.expires_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeletionRequest {
+    witness: CredentialWitness,
+}
+
+impl DeletionRequest {
+    pub fn get_witness(self) -> CredentialWitness {
+        self.witness
+    }
+}
+
+/// Records removed by an account deletion.
+#[derive(Serialize, Deserialize)]
+pub struct DeletionReport {
+    deleted_records: u64,
+}
+
+impl DeletionReport {
+    pub fn new(deleted_records: u64) -> Self {
+        DeletionReport {
+            deleted_records,
+        }
+    }
+}
+
+/// Everything stored for one xpub. Webhook secrets are left out, as everywhere but creation.
+#[derive(Serialize)]
+pub struct AccountExport {
+    exported_at: u64,
+    user_id: SaltedFingerPrint,
+    address: UserAddress<XpubWrapper>,
+    derived_keys: Vec<DerivedKey>,
+    psbts: Vec<PsbtRecord>,
+    audit_events: Vec<AuditEvent>,
+    invoices: Vec<Invoice>,
+    broadcasts: Vec<BroadcastRecord>,
+    deposits: Vec<Deposit>,
+    webhooks: Vec<WebhookInfo>,
+    deliveries: Vec<Delivery>,
+}
+
+/// Collects everything stored for the owner of `address`.
+pub async fn export(storage: &dyn Storage, address: UserAddress<XpubWrapper>) -> Result<AccountExport, HttpResponse> {
+    let xpub = address.clone().get_xpubwrapper();
+    let webhooks = storage.webhooks_lookup(xpub.clone()).await?;
+    let mut deliveries = Vec::new();
+    for webhook in webhooks.iter() {
+        deliveries.extend(storage.deliveries_lookup(xpub.clone(), webhook.get_id(), i64::MAX).await?);
+    }
+    Ok(AccountExport {
+        exported_at: unix_now(),
+        user_id: user::owner_id(storage, &address),
+        derived_keys: storage.all_derived_keys(xpub.clone()).await?,
+        psbts: storage.psbts_lookup(xpub.clone()).await?,
+        audit_events: storage.audit_events_lookup(xpub.clone(), i64::MAX).await?,
+        invoices: storage.invoices_lookup(xpub.clone()).await?,
+        broadcasts: storage.broadcasts_lookup(xpub.clone()).await?,
+        deposits: storage.deposits_lookup(xpub).await?,
+        webhooks: webhooks.iter().map(WebhookInfo::from).collect(),
+        deliveries,
+        address,
+    })
+}
@@ -0,0 +1,66 @@
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    XpubWrapper,
+    invoice::unix_now,
+    schema::SCHEMA_VERSION,
+    storage::Storage,
+};
+
+/// Events kept per user for `/audit`.
+pub const AUDIT_PAGE_SIZE: i64 = 100;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Register,
+    Login,
+    DeriveAddress,
+    CreatePsbt,
+    PsbtSigned,
+    Broadcast,
+    CreateInvoice,
+    CreateWebhook,
+    DeleteWebhook,
+    ExportAccount,
+    LinkWallet,
+    UnlinkWallet,
+}
+
+/// An account action, recorded for later review by its owner.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    xpub: XpubWrapper,
+    action: AuditAction,
+    detail: Option<String>,
+    at: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl AuditEvent {
+    pub fn new(xpub: XpubWrapper, action: AuditAction, detail: Option<String>) -> Self {
+        AuditEvent {
+            xpub,
+            action,
+            detail,
+            at: unix_now(),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_at(&self) -> u64 {
+        self.at
+    }
+}
+
+/// Records an event, logging instead of failing the caller: the action already happened.
+pub async fn record(storage: &dyn Storage, xpub: XpubWrapper, action: AuditAction, detail: Option<String>) {
+    if let Err(err) = storage.insert_audit_event(AuditEvent::new(xpub, action, detail)).await {
+        tracing::warn!("Could not record {:?} audit event: {}", action, err.status());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use bitcoin::{
+    bip32,
+    CompressedPublicKey,
+    OutPoint,
+    Script,
+    ScriptBuf,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    derivation,
+    chain::{
+        ChainBackend,
+        ChainError,
+    },
+};
+
+/// Unused addresses scanned past the last derived index of each chain.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+pub const MAX_GAP_LIMIT: u32 = 1000;
+/// Receive (0) and change (1) chains, scanned for the account even before any derivation on them.
+pub const SCAN_CHAINS: [u32; 2] = [0, 1];
+pub const CHANGE_CHAIN: u32 = 1;
+
+#[derive(Deserialize)]
+pub struct ScanParams {
+    #[serde(default)]
+    gap_limit: Option<u32>,
+}
+
+impl ScanParams {
+    pub fn get_gap_limit(&self) -> u32 {
+        self.gap_limit.unwrap_or(DEFAULT_GAP_LIMIT).min(MAX_GAP_LIMIT)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Other,
+}
+
+impl From<&Script> for ScriptType {
+    fn from(script: &Script) -> Self {
+        if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccountUtxo {
+    outpoint: OutPoint,
+    value_sat: u64,
+    script_type: ScriptType,
+    derivation_path: [u32; 2],
+    confirmations: u32,
+    coinbase: bool,
+    immature: bool,
+}
+
+impl AccountUtxo {
+    pub fn get_outpoint(&self) -> OutPoint {
+        self.outpoint
+    }
+    pub fn get_value_sat(&self) -> u64 {
+        self.value_sat
+    }
+    pub fn get_derivation_path(&self) -> [u32; 2] {
+        self.derivation_path
+    }
+    pub fn get_confirmations(&self) -> u32 {
+        self.confirmations
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Balance {
+    confirmed_sat: u64,
+    unconfirmed_sat: u64,
+    immature_sat: u64,
+}
+
+impl Balance {
+    pub fn from_utxos(utxos: &[AccountUtxo]) -> Self {
+        utxos.iter().fold(Balance::default(), |mut balance, utxo| {
+            if utxo.immature {
+                balance.immature_sat += utxo.value_sat;
+            } else if utxo.confirmations > 0 {
+                balance.confirmed_sat += utxo.value_sat;
+            } else {
+                balance.unconfirmed_sat += utxo.value_sat;
+            }
+            balance
+        })
+    }
+}
+
+pub fn p2wpkh_script(xpub: &bip32::Xpub, path: &[u32; 2]) -> ScriptBuf {
+    let public_key = derivation::derive_xpub(xpub, path).public_key;
+    ScriptBuf::new_p2wpkh(&CompressedPublicKey(public_key).wpubkey_hash())
+}
+
+/// Paths to scan for the account: every index below the next index of each chain, given as
+/// `(chain, next index)`, plus `gap_limit` unused paths past it, up to the last normal index.
+pub fn scan_paths(next_indexes: &[(u32, u32)], gap_limit: u32) -> Vec<[u32; 2]> {
+    next_indexes
+        .iter()
+        .flat_map(|(chain, next)| (0..next.saturating_add(gap_limit).min(1 << 31)).map(move |index| [*chain, index]))
+        .collect()
+}
+
+/// UTXOs held by the account on `paths`, with their derivation path.
+pub fn account_utxos(
+    chain: &dyn ChainBackend,
+    xpub: &bip32::Xpub,
+    paths: &[[u32; 2]],
+) -> Result<Vec<AccountUtxo>, ChainError> {
+    let scripts: HashMap<ScriptBuf, [u32; 2]> = paths
+        .iter()
+        .map(|path| (p2wpkh_script(xpub, path), *path))
+        .collect();
+    let script_list: Vec<ScriptBuf> = scripts.keys().cloned().collect();
+    let utxos = chain.utxos_for_scripts(&script_list)?
+        .into_iter()
+        .filter_map(|utxo| {
+            scripts.get(&utxo.txout.script_pubkey).map(|path| AccountUtxo {
+                outpoint: utxo.outpoint,
+                value_sat: utxo.txout.value.to_sat(),
+                script_type: ScriptType::from(utxo.txout.script_pubkey.as_script()),
+                derivation_path: *path,
+                confirmations: utxo.confirmations,
+                coinbase: utxo.coinbase,
+                immature: utxo.is_immature(),
+            })
+        })
+        .collect();
+    Ok(utxos)
+}
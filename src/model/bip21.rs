@@ -0,0 +1,181 @@
+// BIP21 payment URIs: https://github.com/bitcoin/bips/blob/master/bip-0021.mediawiki
+
+use std::str::FromStr;
+use bitcoin::{
+    Address,
+    Amount,
+    Denomination,
+    Network,
+    address::NetworkChecked,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::psbt::AddressSerialized;
+
+const SCHEME: &str = "bitcoin:";
+
+#[derive(Debug)]
+pub enum Bip21Error {
+    Scheme,
+    Address(String),
+    Amount(String),
+    Encoding,
+    RequiredParameter(String),
+}
+
+impl std::fmt::Display for Bip21Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bip21Error::Scheme => write!(f, "URI scheme is not bitcoin:"),
+            Bip21Error::Address(err) => write!(f, "Invalid address: {}", err),
+            Bip21Error::Amount(err) => write!(f, "Invalid amount: {}", err),
+            Bip21Error::Encoding => write!(f, "Invalid percent encoding"),
+            Bip21Error::RequiredParameter(name) => write!(f, "Unsupported required parameter: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for Bip21Error {}
+
+/// Optional parameters for a payment URI. Amounts are expressed in satoshis.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PaymentParams {
+    #[serde(default)]
+    amount_sat: Option<u64>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    lightning: Option<String>,
+    #[serde(default)]
+    pj: Option<String>,
+}
+
+/// A parsed payment URI, named after the recipient fields of `PsbtSerialized` so that it can
+/// be merged into a `/create_psbt` request.
+#[derive(Serialize, Deserialize)]
+pub struct PaymentRequest {
+    out_address_serialized: AddressSerialized,
+    #[serde(default)]
+    spend_amount_sat: Option<u64>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    lightning: Option<String>,
+    #[serde(default)]
+    pj: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentUri {
+    uri: String,
+}
+
+impl PaymentUri {
+    pub fn new(address: &Address<NetworkChecked>, params: &PaymentParams) -> Self {
+        PaymentUri {
+            uri: to_uri(address, params),
+        }
+    }
+    pub fn to_payment_request(&self, network: Network) -> Result<PaymentRequest, Bip21Error> {
+        let (address, params) = from_uri(&self.uri, network)?;
+        Ok(PaymentRequest {
+            out_address_serialized: AddressSerialized::from(address),
+            spend_amount_sat: params.amount_sat,
+            label: params.label,
+            message: params.message,
+            lightning: params.lightning,
+            pj: params.pj,
+        })
+    }
+}
+
+pub fn to_uri(address: &Address<NetworkChecked>, params: &PaymentParams) -> String {
+    let mut query: Vec<String> = Vec::new();
+    if let Some(amount) = params.amount_sat {
+        query.push(format!("amount={}", Amount::from_sat(amount).display_in(Denomination::Bitcoin)));
+    }
+    let text_params = [
+        ("label", &params.label),
+        ("message", &params.message),
+        ("lightning", &params.lightning),
+        ("pj", &params.pj),
+    ];
+    for (name, value) in text_params {
+        if let Some(value) = value {
+            query.push(format!("{}={}", name, percent_encode(value)));
+        }
+    }
+    let mut uri = format!("{}{}", SCHEME, address);
+    if !query.is_empty() {
+        uri.push('?');
+        uri.push_str(&query.join("&"));
+    }
+    uri
+}
+
+pub fn from_uri(uri: &str, network: Network) -> Result<(Address<NetworkChecked>, PaymentParams), Bip21Error> {
+    if !uri.get(..SCHEME.len()).is_some_and(|scheme| scheme.eq_ignore_ascii_case(SCHEME)) {
+        return Err(Bip21Error::Scheme)
+    }
+    let (address_str, query) = match uri[SCHEME.len()..].split_once('?') {
+        Some((address_str, query)) => (address_str, query),
+        None => (&uri[SCHEME.len()..], ""),
+    };
+    let address = Address::from_str(address_str)
+        .map_err(|err| Bip21Error::Address(err.to_string()))?
+        .require_network(network)
+        .map_err(|err| Bip21Error::Address(err.to_string()))?;
+
+    let mut params = PaymentParams::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value)?;
+        match name {
+            "amount" => {
+                let amount = Amount::from_str_in(&value, Denomination::Bitcoin)
+                    .map_err(|err| Bip21Error::Amount(err.to_string()))?;
+                params.amount_sat = Some(amount.to_sat());
+            },
+            "label" => params.label = Some(value),
+            "message" => params.message = Some(value),
+            "lightning" => params.lightning = Some(value),
+            "pj" => params.pj = Some(value),
+            name if name.starts_with("req-") => return Err(Bip21Error::RequiredParameter(name.to_string())),
+            _ => {},
+        }
+    }
+    Ok((address, params))
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> Result<String, Bip21Error> {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = value.get(index + 1..index + 3).ok_or(Bip21Error::Encoding)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Bip21Error::Encoding)?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| Bip21Error::Encoding)
+}
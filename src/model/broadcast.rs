@@ -0,0 +1,98 @@
+use bitcoin::{
+    Psbt,
+    Transaction,
+    Txid,
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    XpubWrapper,
+    invoice::unix_now,
+    schema::SCHEMA_VERSION,
+};
+
+#[derive(Debug)]
+pub enum BroadcastError {
+    NotFinalized(usize),
+    Extract(String),
+}
+
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastError::NotFinalized(index) => write!(f, "Input {} is not finalized", index),
+            BroadcastError::Extract(err) => write!(f, "Cannot extract transaction: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+#[derive(Serialize, Deserialize)]
+pub struct BroadcastRequest {
+    psbt: Psbt,
+}
+
+impl BroadcastRequest {
+    /// Extracts the network transaction of a finalized PSBT, refusing absurd fee rates.
+    pub fn extract_tx(self) -> Result<Transaction, BroadcastError> {
+        if let Some(index) = self.psbt.inputs
+            .iter()
+            .position(|input| input.final_script_witness.is_none() && input.final_script_sig.is_none())
+        {
+            return Err(BroadcastError::NotFinalized(index))
+        }
+        self.psbt.extract_tx().map_err(|err| BroadcastError::Extract(err.to_string()))
+    }
+}
+
+/// A transaction broadcast on behalf of a user, kept for later lookup.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BroadcastRecord {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    xpub: XpubWrapper,
+    txid: Txid,
+    broadcast_at: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl BroadcastRecord {
+    pub fn new(xpub: XpubWrapper, txid: Txid) -> Self {
+        BroadcastRecord {
+            id: ObjectId::new(),
+            xpub,
+            txid,
+            broadcast_at: unix_now(),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BroadcastStatus {
+    #[serde(flatten)]
+    record: BroadcastRecord,
+    confirmations: Option<u32>,
+    block_height: Option<u32>,
+}
+
+impl BroadcastStatus {
+    pub fn new(record: BroadcastRecord, confirmations: Option<u32>, block_height: Option<u32>) -> Self {
+        BroadcastStatus {
+            record,
+            confirmations,
+            block_height,
+        }
+    }
+}
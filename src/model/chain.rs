@@ -0,0 +1,145 @@
+use bitcoin::{
+    Address,
+    Amount,
+    BlockHash,
+    FeeRate,
+    OutPoint,
+    ScriptBuf,
+    Transaction,
+    TxOut,
+    Txid,
+    Weight,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+pub mod core_rpc;
+pub mod memory;
+
+/// Coinbase outputs can only be spent after this many confirmations.
+pub const COINBASE_MATURITY: u32 = 100;
+
+#[derive(Debug)]
+pub enum ChainError {
+    /// The backend could not be reached or answered with an error.
+    Backend(String),
+    /// The node refused the transaction.
+    Rejected(String),
+    /// The backend is not set up to answer this call.
+    Unsupported(String),
+}
+
+impl std::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Backend(err) => write!(f, "Chain backend error: {}", err),
+            ChainError::Rejected(reason) => write!(f, "Transaction rejected: {}", reason),
+            ChainError::Unsupported(reason) => write!(f, "Unsupported by the chain backend: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// An unspent output as seen by the chain backend.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChainUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    /// Zero while the output is only in the mempool.
+    pub confirmations: u32,
+    pub coinbase: bool,
+}
+
+impl ChainUtxo {
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmations > 0
+    }
+    pub fn is_immature(&self) -> bool {
+        self.coinbase && self.confirmations < COINBASE_MATURITY
+    }
+}
+
+/// A transaction with its position in the chain, if any.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChainTransaction {
+    pub transaction: Transaction,
+    pub confirmations: u32,
+    pub block_height: Option<u32>,
+    pub block_time: Option<u64>,
+}
+
+/// Result of a mempool acceptance test.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MempoolAcceptance {
+    pub txid: Txid,
+    pub allowed: bool,
+    pub reject_reason: Option<String>,
+}
+
+/// Fee and weight of a mempool transaction.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MempoolEntry {
+    pub txid: Txid,
+    pub fee: Amount,
+    pub weight: Weight,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> FeeRate {
+        self.fee / self.weight
+    }
+}
+
+/// A ranged descriptor to be tracked by the backend, rescanning from `timestamp` (unix
+/// seconds) or from now when `None`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct WatchDescriptor {
+    pub descriptor: String,
+    pub range: (u32, u32),
+    pub internal: bool,
+    pub timestamp: Option<u64>,
+}
+
+/// Rescan state of the wallet tracking watched descriptors.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ScanProgress {
+    pub scanning: bool,
+    /// Share of the blocks rescanned so far, from 0 to 1.
+    pub progress: Option<f32>,
+    pub duration_secs: Option<u64>,
+}
+
+/// Source of chain data and transaction relay used by the handlers.
+///
+/// Calls are blocking; handlers run them through `web::block`.
+pub trait ChainBackend: Send + Sync {
+    /// Confirmed UTXOs locked to any of `scripts`, and the mempool ones the backend tracks.
+    fn utxos_for_scripts(&self, scripts: &[ScriptBuf]) -> Result<Vec<ChainUtxo>, ChainError>;
+    fn transaction(&self, txid: &Txid) -> Result<Option<ChainTransaction>, ChainError>;
+    /// Transactions paying to or spending from any of `scripts`, newest first with unconfirmed
+    /// ones on top, skipping the first `skip` and returning at most `limit`.
+    fn transactions_for_scripts(&self, scripts: &[ScriptBuf], skip: usize, limit: usize) -> Result<Vec<ChainTransaction>, ChainError>;
+    fn tip_height(&self) -> Result<u32, ChainError>;
+    /// Hash of the active chain block at `height`, `None` above the tip.
+    fn block_hash(&self, height: u32) -> Result<Option<BlockHash>, ChainError>;
+    fn block_transactions(&self, hash: &BlockHash) -> Result<Vec<Transaction>, ChainError>;
+    /// Mempool transactions the backend tracks, which may be fewer than the whole mempool.
+    fn mempool_transactions(&self) -> Result<Vec<Transaction>, ChainError>;
+    /// Fee and weight of every mempool transaction whose fee is known.
+    fn mempool_entries(&self) -> Result<Vec<MempoolEntry>, ChainError>;
+    /// Fee rate expected to confirm within `conf_target` blocks, if the backend has an estimate.
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<FeeRate>, ChainError>;
+    /// Checks whether the node would accept `transaction` without relaying it.
+    fn test_mempool_accept(&self, transaction: &Transaction) -> Result<MempoolAcceptance, ChainError>;
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, ChainError>;
+    /// Starts tracking payments to `address`.
+    fn watch_address(&self, address: &Address, label: Option<String>) -> Result<(), ChainError>;
+    /// Starts tracking every script of a ranged descriptor.
+    fn watch_descriptor(&self, descriptor: &WatchDescriptor) -> Result<(), ChainError>;
+    /// Whether the wallet is rescanning after `watch_descriptor`, and how far it got.
+    fn scan_progress(&self) -> Result<ScanProgress, ChainError>;
+    /// Total received by a watched `address` with at least `min_conf` confirmations, and the paying txids.
+    fn received_by_address(&self, address: &Address, min_conf: u32) -> Result<(Amount, Vec<Txid>), ChainError>;
+}
@@ -0,0 +1,380 @@
+use std::collections::HashSet;
+use bitcoin::{
+    Address,
+    Amount,
+    BlockHash,
+    FeeRate,
+    OutPoint,
+    ScriptBuf,
+    Transaction,
+    TxOut,
+    Txid,
+    Weight,
+};
+use bitcoincore_rpc::{
+    json::{
+        ImportDescriptors,
+        ScanningDetails,
+        Timestamp,
+    },
+    jsonrpc,
+    Auth,
+    Client,
+    RpcApi,
+};
+use serde::Deserialize;
+use super::{
+    ChainBackend,
+    ChainError,
+    ChainTransaction,
+    ChainUtxo,
+    MempoolAcceptance,
+    MempoolEntry,
+    ScanProgress,
+    WatchDescriptor,
+};
+
+// Bitcoin Core RPC_INVALID_ADDRESS_OR_KEY, returned for unknown transactions.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+// Bitcoin Core RPC_WALLET_NOT_FOUND and RPC_WALLET_NOT_SPECIFIED, and the JSON-RPC method not
+// found error of nodes without wallet support.
+const RPC_NO_WALLET: [i32; 3] = [-18, -19, -32601];
+// Wallet entries read per `listtransactions` call.
+const WALLET_LISTING_BATCH: usize = 200;
+
+impl From<bitcoincore_rpc::Error> for ChainError {
+    fn from(value: bitcoincore_rpc::Error) -> Self {
+        ChainError::Backend(value.to_string())
+    }
+}
+
+// `scantxoutset` result, keeping the `coinbase` flag reported by recent Core versions.
+#[derive(Deserialize)]
+struct ScanResult {
+    height: u64,
+    unspents: Vec<ScanUtxo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScanUtxo {
+    txid: Txid,
+    vout: u32,
+    script_pub_key: ScriptBuf,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    amount: Amount,
+    height: u64,
+    #[serde(default)]
+    coinbase: bool,
+}
+
+/// Chain backend talking to a Bitcoin Core node over JSON-RPC.
+pub struct CoreRpcBackend {
+    client: Client,
+    mirror: bool,
+}
+
+impl CoreRpcBackend {
+    pub fn new(url: &str, auth: Auth) -> Result<Self, ChainError> {
+        Ok(CoreRpcBackend {
+            client: Client::new(url, auth)?,
+            mirror: false,
+        })
+    }
+    /// Marks the wallet of `url` as the watch-only mirror of every xpub, which transaction
+    /// history is read from.
+    pub fn with_mirror(mut self) -> Self {
+        self.mirror = true;
+        self
+    }
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+    /// Loads the wallet `name`, creating it as a blank watch-only descriptor wallet if missing.
+    pub fn ensure_watch_only_wallet(&self, name: &str) -> Result<(), ChainError> {
+        if self.client.list_wallets()?.iter().any(|wallet| wallet == name) {
+            return Ok(())
+        }
+        if self.client.load_wallet(name).is_ok() {
+            return Ok(())
+        }
+        self.client.create_wallet(name, Some(true), Some(true), None, None)?;
+        Ok(())
+    }
+    fn import_descriptor(&self, request: ImportDescriptors) -> Result<(), ChainError> {
+        match self.client.import_descriptors(request)?.into_iter().next() {
+            Some(result) if result.success => Ok(()),
+            Some(result) => Err(ChainError::Backend(format!("importdescriptors failed: {:?}", result.error))),
+            None => Err(ChainError::Backend("Empty importdescriptors result".to_string())),
+        }
+    }
+    /// Unconfirmed wallet transactions, none when no wallet is loaded.
+    fn wallet_mempool_txids(&self) -> Result<Vec<Txid>, ChainError> {
+        let tip = self.client.get_best_block_hash()?;
+        // Since the tip, the wallet only lists its transactions still in the mempool.
+        let since = match self.client.list_since_block(Some(&tip), None, Some(true), None) {
+            Ok(since) => since,
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) if RPC_NO_WALLET.contains(&err.code) => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut txids: Vec<Txid> = since.transactions
+            .into_iter()
+            .filter(|entry| entry.info.confirmations == 0)
+            .map(|entry| entry.info.txid)
+            .collect();
+        txids.sort();
+        txids.dedup();
+        Ok(txids)
+    }
+    /// Whether `transaction` pays to one of `wanted` or spends a wallet output locked to one.
+    fn touches(&self, transaction: &Transaction, wanted: &HashSet<&ScriptBuf>) -> Result<bool, ChainError> {
+        if transaction.output.iter().any(|txout| wanted.contains(&txout.script_pubkey)) {
+            return Ok(true)
+        }
+        for input in transaction.input.iter() {
+            let previous = self.wallet_transaction(&input.previous_output.txid)?;
+            let spent = previous.as_ref().and_then(|previous| previous.transaction.output.get(input.previous_output.vout as usize));
+            if spent.is_some_and(|txout| wanted.contains(&txout.script_pubkey)) {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+    fn wallet_transaction(&self, txid: &Txid) -> Result<Option<ChainTransaction>, ChainError> {
+        let result = match self.client.get_transaction(txid, Some(true)) {
+            Ok(result) => result,
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) if err.code == RPC_INVALID_ADDRESS_OR_KEY => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(ChainTransaction {
+            transaction: result.transaction().map_err(|err| ChainError::Backend(err.to_string()))?,
+            // Negative confirmations mark transactions conflicting with the chain.
+            confirmations: result.info.confirmations.max(0) as u32,
+            block_height: result.info.blockheight,
+            block_time: result.info.blocktime,
+        }))
+    }
+}
+
+impl ChainBackend for CoreRpcBackend {
+    fn utxos_for_scripts(&self, scripts: &[ScriptBuf]) -> Result<Vec<ChainUtxo>, ChainError> {
+        if scripts.is_empty() {
+            return Ok(Vec::new())
+        }
+        let descriptors: Vec<String> = scripts
+            .iter()
+            .map(|script| format!("raw({})", script.to_hex_string()))
+            .collect();
+        let scan: ScanResult = self.client.call(
+            "scantxoutset",
+            &["start".into(), serde_json::to_value(descriptors).map_err(|err| ChainError::Backend(err.to_string()))?],
+        )?;
+        let mut utxos: Vec<ChainUtxo> = scan.unspents
+            .into_iter()
+            .map(|utxo| ChainUtxo {
+                outpoint: OutPoint { txid: utxo.txid, vout: utxo.vout },
+                txout: TxOut { value: utxo.amount, script_pubkey: utxo.script_pub_key },
+                confirmations: (scan.height + 1).saturating_sub(utxo.height) as u32,
+                coinbase: utxo.coinbase,
+            })
+            .collect();
+
+        // The UTXO set scan does not see the mempool: add the unconfirmed outputs known to the
+        // wallet and drop those it spends.
+        let wanted: HashSet<&ScriptBuf> = scripts.iter().collect();
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        for transaction in self.mempool_transactions()? {
+            let txid = transaction.compute_txid();
+            spent.extend(transaction.input.iter().map(|input| input.previous_output));
+            transaction.output
+                .into_iter()
+                .enumerate()
+                .filter(|(_, txout)| wanted.contains(&txout.script_pubkey))
+                .for_each(|(vout, txout)| utxos.push(ChainUtxo {
+                    outpoint: OutPoint { txid, vout: vout as u32 },
+                    txout,
+                    confirmations: 0,
+                    coinbase: false,
+                }));
+        }
+        utxos.retain(|utxo| !spent.contains(&utxo.outpoint));
+        Ok(utxos)
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Option<ChainTransaction>, ChainError> {
+        let info = match self.client.get_raw_transaction_info(txid, None) {
+            Ok(info) => info,
+            // Without -txindex only mempool transactions are known to the node; try the wallet.
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) if err.code == RPC_INVALID_ADDRESS_OR_KEY => return self.wallet_transaction(txid),
+            Err(err) => return Err(err.into()),
+        };
+        let block_height = match info.blockhash {
+            Some(hash) => Some(self.client.get_block_header_info(&hash)?.height as u32),
+            None => None,
+        };
+        Ok(Some(ChainTransaction {
+            transaction: info.transaction().map_err(|err| ChainError::Backend(err.to_string()))?,
+            confirmations: info.confirmations.unwrap_or(0),
+            block_height,
+            block_time: info.blocktime.map(|time| time as u64),
+        }))
+    }
+
+    /// Reads the watch-only wallet newest first, in batches, until the page is complete.
+    fn transactions_for_scripts(&self, scripts: &[ScriptBuf], skip: usize, limit: usize) -> Result<Vec<ChainTransaction>, ChainError> {
+        if !self.mirror {
+            return Err(ChainError::Unsupported("transaction history needs the watch-only wallet mirror, set WATCH_ONLY_MIRROR=true".to_string()))
+        }
+        let wanted: HashSet<&ScriptBuf> = scripts.iter().collect();
+        let mut seen: HashSet<Txid> = HashSet::new();
+        let mut skipped = 0;
+        let mut transactions: Vec<ChainTransaction> = Vec::new();
+        let mut wallet_skip = 0;
+        while transactions.len() < limit {
+            let entries = self.client.list_transactions(None, Some(WALLET_LISTING_BATCH), Some(wallet_skip), Some(true))?;
+            let count = entries.len();
+            // Each batch is ordered oldest first.
+            for entry in entries.into_iter().rev() {
+                if transactions.len() == limit {
+                    break
+                }
+                if !seen.insert(entry.info.txid) {
+                    continue
+                }
+                let chain_tx = match self.wallet_transaction(&entry.info.txid)? {
+                    Some(chain_tx) if self.touches(&chain_tx.transaction, &wanted)? => chain_tx,
+                    _ => continue,
+                };
+                if skipped < skip {
+                    skipped += 1;
+                    continue
+                }
+                transactions.push(chain_tx);
+            }
+            if count < WALLET_LISTING_BATCH {
+                break
+            }
+            wallet_skip += count;
+        }
+        Ok(transactions)
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        Ok(self.client.get_block_count()? as u32)
+    }
+
+    fn block_hash(&self, height: u32) -> Result<Option<BlockHash>, ChainError> {
+        if height > self.tip_height()? {
+            return Ok(None)
+        }
+        Ok(Some(self.client.get_block_hash(height as u64)?))
+    }
+
+    fn block_transactions(&self, hash: &BlockHash) -> Result<Vec<Transaction>, ChainError> {
+        Ok(self.client.get_block(hash)?.txdata)
+    }
+
+    /// Only the mempool transactions of the node wallet, see `watch_address` and `watch_descriptor`.
+    fn mempool_transactions(&self) -> Result<Vec<Transaction>, ChainError> {
+        let mut transactions = Vec::new();
+        for txid in self.wallet_mempool_txids()? {
+            if let Some(chain_tx) = self.wallet_transaction(&txid)? {
+                transactions.push(chain_tx.transaction);
+            }
+        }
+        Ok(transactions)
+    }
+
+    fn mempool_entries(&self) -> Result<Vec<MempoolEntry>, ChainError> {
+        Ok(self.client
+            .get_raw_mempool_verbose()?
+            .into_iter()
+            .map(|(txid, entry)| MempoolEntry {
+                txid,
+                fee: entry.fees.base,
+                weight: Weight::from_wu(entry.weight.unwrap_or(entry.vsize * 4)),
+            })
+            .collect())
+    }
+
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<FeeRate>, ChainError> {
+        let estimate = self.client.estimate_smart_fee(conf_target, None)?;
+        // Core reports BTC per kvB; 1 kvB is 4 kwu.
+        Ok(estimate.fee_rate.map(|per_kvb| FeeRate::from_sat_per_kwu(per_kvb.to_sat() / 4)))
+    }
+
+    fn test_mempool_accept(&self, transaction: &Transaction) -> Result<MempoolAcceptance, ChainError> {
+        match self.client.test_mempool_accept(&[transaction])?.into_iter().next() {
+            Some(result) => Ok(MempoolAcceptance {
+                txid: result.txid,
+                allowed: result.allowed,
+                reject_reason: result.reject_reason,
+            }),
+            None => Err(ChainError::Backend("Empty testmempoolaccept result".to_string())),
+        }
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, ChainError> {
+        match self.client.send_raw_transaction(transaction) {
+            Ok(txid) => Ok(txid),
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) => Err(ChainError::Rejected(err.message)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn watch_address(&self, address: &Address, label: Option<String>) -> Result<(), ChainError> {
+        let descriptor = self.client.get_descriptor_info(&format!("addr({})", address))?.descriptor;
+        self.import_descriptor(ImportDescriptors {
+            descriptor,
+            timestamp: Timestamp::Now,
+            active: None,
+            range: None,
+            next_index: None,
+            internal: None,
+            label,
+        })
+    }
+
+    fn watch_descriptor(&self, descriptor: &WatchDescriptor) -> Result<(), ChainError> {
+        let checksummed = self.client.get_descriptor_info(&descriptor.descriptor)?.descriptor;
+        self.import_descriptor(ImportDescriptors {
+            descriptor: checksummed,
+            timestamp: match descriptor.timestamp {
+                Some(time) => Timestamp::Time(time),
+                None => Timestamp::Now,
+            },
+            // Several xpubs share the wallet, so none of them is the active one.
+            active: Some(false),
+            range: Some((descriptor.range.0 as usize, descriptor.range.1 as usize)),
+            next_index: None,
+            internal: Some(descriptor.internal),
+            label: None,
+        })
+    }
+
+    fn scan_progress(&self) -> Result<ScanProgress, ChainError> {
+        Ok(match self.client.get_wallet_info()?.scanning {
+            Some(ScanningDetails::Scanning { duration, progress }) => ScanProgress {
+                scanning: true,
+                progress: Some(progress),
+                duration_secs: Some(duration as u64),
+            },
+            _ => ScanProgress {
+                scanning: false,
+                progress: None,
+                duration_secs: None,
+            },
+        })
+    }
+
+    fn received_by_address(&self, address: &Address, min_conf: u32) -> Result<(Amount, Vec<Txid>), ChainError> {
+        match self.client
+            .list_received_by_address(Some(address), Some(min_conf), Some(true), Some(true))?
+            .into_iter()
+            .next()
+        {
+            Some(result) => Ok((result.amount, result.txids)),
+            None => Ok((Amount::ZERO, Vec::new())),
+        }
+    }
+}
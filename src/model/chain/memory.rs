@@ -0,0 +1,332 @@
+use std::collections::{
+    BTreeMap,
+    HashMap,
+    HashSet,
+};
+use std::sync::Mutex;
+use bitcoin::{
+    hashes::{
+        sha256d,
+        Hash,
+        HashEngine,
+    },
+    Address,
+    Amount,
+    BlockHash,
+    FeeRate,
+    OutPoint,
+    ScriptBuf,
+    Transaction,
+    Txid,
+};
+use super::{
+    ChainBackend,
+    ChainError,
+    ChainTransaction,
+    ChainUtxo,
+    MempoolAcceptance,
+    MempoolEntry,
+    ScanProgress,
+    WatchDescriptor,
+};
+
+#[derive(Default)]
+struct MemoryState {
+    tip_height: u32,
+    // Transactions by txid; `block_height` is `None` while in the mempool.
+    transactions: HashMap<Txid, (Transaction, Option<u32>, Option<u64>)>,
+    coinbase: HashSet<Txid>,
+    fee_rates: BTreeMap<u16, FeeRate>,
+    watched: HashSet<ScriptBuf>,
+    descriptors: Vec<WatchDescriptor>,
+    rejections: HashMap<Txid, String>,
+    // Bumped when the block at a height is disconnected, so its replacement gets a new hash.
+    generations: HashMap<u32, u32>,
+}
+
+impl MemoryState {
+    fn block_txids(&self, height: u32) -> Vec<Txid> {
+        let mut txids: Vec<Txid> = self.transactions
+            .iter()
+            .filter(|(_, (_, block_height, _))| *block_height == Some(height))
+            .map(|(txid, _)| *txid)
+            .collect();
+        txids.sort();
+        txids
+    }
+    fn block_hash(&self, height: u32) -> BlockHash {
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&height.to_le_bytes());
+        engine.input(&self.generations.get(&height).copied().unwrap_or(0).to_le_bytes());
+        for txid in self.block_txids(height) {
+            engine.input(txid.as_byte_array());
+        }
+        BlockHash::from_raw_hash(sha256d::Hash::from_engine(engine))
+    }
+}
+
+/// In-memory chain for tests and local development: transactions are added,
+/// mined and rejected by hand instead of coming from a node.
+#[derive(Default)]
+pub struct MemoryChain {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_tip_height(&self, height: u32) {
+        self.state.lock().expect("Memory chain lock").tip_height = height;
+    }
+    pub fn set_fee_rate(&self, conf_target: u16, fee_rate: FeeRate) {
+        self.state.lock().expect("Memory chain lock").fee_rates.insert(conf_target, fee_rate);
+    }
+    /// Adds `transaction` to the mempool, or to the block at `block_height`.
+    pub fn add_transaction(&self, transaction: Transaction, block_height: Option<u32>, block_time: Option<u64>) {
+        let mut state = self.state.lock().expect("Memory chain lock");
+        if transaction.is_coinbase() {
+            state.coinbase.insert(transaction.compute_txid());
+        }
+        state.transactions.insert(transaction.compute_txid(), (transaction, block_height, block_time));
+    }
+    /// Confirms a mempool transaction at the current tip.
+    pub fn mine(&self, txid: &Txid, block_time: u64) {
+        let mut state = self.state.lock().expect("Memory chain lock");
+        let tip_height = state.tip_height;
+        if let Some(entry) = state.transactions.get_mut(txid) {
+            entry.1 = Some(tip_height);
+            entry.2 = Some(block_time);
+        }
+    }
+    pub fn watched_descriptors(&self) -> Vec<WatchDescriptor> {
+        self.state.lock().expect("Memory chain lock").descriptors.clone()
+    }
+    /// Disconnects the blocks from `height` up, moving their transactions back to the mempool.
+    pub fn disconnect_blocks(&self, height: u32) {
+        let mut state = self.state.lock().expect("Memory chain lock");
+        let tip_height = state.tip_height;
+        for disconnected in height..=tip_height {
+            *state.generations.entry(disconnected).or_insert(0) += 1;
+        }
+        state.transactions
+            .values_mut()
+            .filter(|(_, block_height, _)| block_height.is_some_and(|block_height| block_height >= height))
+            .for_each(|entry| {
+                entry.1 = None;
+                entry.2 = None;
+            });
+        state.tip_height = height.saturating_sub(1);
+    }
+    /// Makes the mempool acceptance test and the broadcast of `txid` fail with `reason`.
+    pub fn reject(&self, txid: Txid, reason: &str) {
+        self.state.lock().expect("Memory chain lock").rejections.insert(txid, reason.to_string());
+    }
+}
+
+fn confirmations(tip_height: u32, block_height: Option<u32>) -> u32 {
+    match block_height {
+        Some(height) if height <= tip_height => tip_height - height + 1,
+        _ => 0,
+    }
+}
+
+impl ChainBackend for MemoryChain {
+    fn utxos_for_scripts(&self, scripts: &[ScriptBuf]) -> Result<Vec<ChainUtxo>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        let spent: HashSet<OutPoint> = state.transactions
+            .values()
+            .flat_map(|(transaction, _, _)| transaction.input.iter().map(|input| input.previous_output))
+            .collect();
+        let utxos = state.transactions
+            .iter()
+            .flat_map(|(txid, (transaction, block_height, _))| {
+                transaction.output
+                    .iter()
+                    .enumerate()
+                    .map(move |(vout, txout)| (OutPoint { txid: *txid, vout: vout as u32 }, txout, block_height))
+            })
+            .filter(|(outpoint, txout, _)| scripts.contains(&txout.script_pubkey) && !spent.contains(outpoint))
+            .map(|(outpoint, txout, block_height)| ChainUtxo {
+                outpoint,
+                txout: txout.clone(),
+                confirmations: confirmations(state.tip_height, *block_height),
+                coinbase: state.coinbase.contains(&outpoint.txid),
+            })
+            .collect();
+        Ok(utxos)
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Option<ChainTransaction>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        Ok(state.transactions.get(txid).map(|(transaction, block_height, block_time)| ChainTransaction {
+            transaction: transaction.clone(),
+            confirmations: confirmations(state.tip_height, *block_height),
+            block_height: *block_height,
+            block_time: *block_time,
+        }))
+    }
+
+    fn transactions_for_scripts(&self, scripts: &[ScriptBuf], skip: usize, limit: usize) -> Result<Vec<ChainTransaction>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        let owned: HashSet<OutPoint> = state.transactions
+            .iter()
+            .flat_map(|(txid, (transaction, _, _))| {
+                transaction.output
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, txout)| scripts.contains(&txout.script_pubkey))
+                    .map(move |(vout, _)| OutPoint { txid: *txid, vout: vout as u32 })
+            })
+            .collect();
+        let mut transactions: Vec<ChainTransaction> = state.transactions
+            .values()
+            .filter(|(transaction, _, _)| {
+                transaction.output.iter().any(|txout| scripts.contains(&txout.script_pubkey))
+                    || transaction.input.iter().any(|input| owned.contains(&input.previous_output))
+            })
+            .map(|(transaction, block_height, block_time)| ChainTransaction {
+                transaction: transaction.clone(),
+                confirmations: confirmations(state.tip_height, *block_height),
+                block_height: *block_height,
+                block_time: *block_time,
+            })
+            .collect();
+        transactions.sort_by_key(|chain_tx| (std::cmp::Reverse(chain_tx.block_height.unwrap_or(u32::MAX)), chain_tx.transaction.compute_txid()));
+        Ok(transactions.into_iter().skip(skip).take(limit).collect())
+    }
+
+    fn tip_height(&self) -> Result<u32, ChainError> {
+        Ok(self.state.lock().expect("Memory chain lock").tip_height)
+    }
+
+    fn block_hash(&self, height: u32) -> Result<Option<BlockHash>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        if height > state.tip_height {
+            return Ok(None)
+        }
+        Ok(Some(state.block_hash(height)))
+    }
+
+    fn block_transactions(&self, hash: &BlockHash) -> Result<Vec<Transaction>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        match (0..=state.tip_height).find(|height| state.block_hash(*height) == *hash) {
+            Some(height) => Ok(state.block_txids(height)
+                .iter()
+                .map(|txid| state.transactions[txid].0.clone())
+                .collect()),
+            None => Err(ChainError::Backend(format!("Unknown block {}", hash))),
+        }
+    }
+
+    fn mempool_transactions(&self) -> Result<Vec<Transaction>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        Ok(state.transactions
+            .values()
+            .filter(|(_, block_height, _)| block_height.is_none())
+            .map(|(transaction, _, _)| transaction.clone())
+            .collect())
+    }
+
+    fn mempool_entries(&self) -> Result<Vec<MempoolEntry>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        let value = |outpoint: &OutPoint| state.transactions
+            .get(&outpoint.txid)
+            .and_then(|(transaction, _, _)| transaction.output.get(outpoint.vout as usize))
+            .map(|txout| txout.value);
+        Ok(state.transactions
+            .iter()
+            .filter(|(_, (_, block_height, _))| block_height.is_none())
+            .filter_map(|(txid, (transaction, _, _))| {
+                // Transactions spending outputs unknown to the memory chain have no known fee.
+                let inputs = transaction.input
+                    .iter()
+                    .map(|input| value(&input.previous_output))
+                    .sum::<Option<Amount>>()?;
+                let outputs: Amount = transaction.output.iter().map(|txout| txout.value).sum();
+                Some(MempoolEntry {
+                    txid: *txid,
+                    fee: inputs.checked_sub(outputs)?,
+                    weight: transaction.weight(),
+                })
+            })
+            .collect())
+    }
+
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<FeeRate>, ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        // Like Core, fall back to the closest estimate for a longer horizon.
+        Ok(state.fee_rates.range(conf_target..).next().map(|(_, fee_rate)| *fee_rate))
+    }
+
+    fn test_mempool_accept(&self, transaction: &Transaction) -> Result<MempoolAcceptance, ChainError> {
+        let txid = transaction.compute_txid();
+        let state = self.state.lock().expect("Memory chain lock");
+        let reject_reason = match state.rejections.get(&txid) {
+            Some(reason) => Some(reason.clone()),
+            None if state.transactions.contains_key(&txid) => Some("txn-already-known".to_string()),
+            None => None,
+        };
+        Ok(MempoolAcceptance {
+            txid,
+            allowed: reject_reason.is_none(),
+            reject_reason,
+        })
+    }
+
+    fn broadcast(&self, transaction: &Transaction) -> Result<Txid, ChainError> {
+        let txid = transaction.compute_txid();
+        if let Some(reason) = self.state.lock().expect("Memory chain lock").rejections.get(&txid) {
+            return Err(ChainError::Rejected(reason.clone()))
+        }
+        self.add_transaction(transaction.clone(), None, None);
+        Ok(txid)
+    }
+
+    fn watch_address(&self, address: &Address, _label: Option<String>) -> Result<(), ChainError> {
+        self.state.lock().expect("Memory chain lock").watched.insert(address.script_pubkey());
+        Ok(())
+    }
+
+    fn watch_descriptor(&self, descriptor: &WatchDescriptor) -> Result<(), ChainError> {
+        let mut state = self.state.lock().expect("Memory chain lock");
+        if !state.descriptors.contains(descriptor) {
+            state.descriptors.push(descriptor.clone());
+        }
+        Ok(())
+    }
+
+    fn scan_progress(&self) -> Result<ScanProgress, ChainError> {
+        // Descriptors are matched as transactions are added, there is nothing to rescan.
+        Ok(ScanProgress {
+            scanning: false,
+            progress: None,
+            duration_secs: None,
+        })
+    }
+
+    fn received_by_address(&self, address: &Address, min_conf: u32) -> Result<(Amount, Vec<Txid>), ChainError> {
+        let state = self.state.lock().expect("Memory chain lock");
+        let script_pubkey = address.script_pubkey();
+        if !state.watched.contains(&script_pubkey) {
+            return Ok((Amount::ZERO, Vec::new()))
+        }
+        let mut received = Amount::ZERO;
+        let mut txids = Vec::new();
+        for (txid, (transaction, block_height, _)) in state.transactions.iter() {
+            if confirmations(state.tip_height, *block_height) < min_conf {
+                continue
+            }
+            let paid: Amount = transaction.output
+                .iter()
+                .filter(|txout| txout.script_pubkey == script_pubkey)
+                .map(|txout| txout.value)
+                .sum();
+            if paid > Amount::ZERO {
+                received += paid;
+                txids.push(*txid);
+            }
+        }
+        Ok((received, txids))
+    }
+}
@@ -23,6 +23,12 @@ use bitcoin_hashes::{
     sha256d::Hash as Sha256dHash,
 };
 
+/// Checks that `path` only holds normal (non-hardened) indices, below 2^31, which the
+/// derivations from an xpub require.
+pub fn check_path(path: &[u32; 2]) -> Result<(), bip32::Error> {
+    path.iter().try_for_each(|index| ChildNumber::from_normal_idx(*index).map(|_| ()))
+}
+
 pub fn derive_xpub(init: &bip32::Xpub, path: &[u32; 2]) -> bip32::Xpub {
     let mut buf: Vec<AlignedType> = Vec::new();
     buf.resize(Secp256k1::preallocate_size(), AlignedType::zeroed());
@@ -38,6 +44,16 @@ pub fn derive_address(init: &bip32::Xpub, path: &[u32; 2]) -> Address {
     Address::p2wpkh(&CompressedPublicKey(public_key), KnownHrp::Mainnet)
 }
 
+/// P2WPKH address at `path` for the network the xpub was encoded for.
+pub fn derive_network_address(init: &bip32::Xpub, path: &[u32; 2]) -> Address {
+    let public_key = derive_xpub(init, path).public_key;
+    let hrp = match init.network {
+        NetworkKind::Main => KnownHrp::Mainnet,
+        NetworkKind::Test => KnownHrp::Testnets,
+    };
+    Address::p2wpkh(&CompressedPublicKey(public_key), hrp)
+}
+
 pub fn xpub_from_xpriv<C: secp256k1::Signing + secp256k1::Verification>(
     secp_ctx: &secp256k1::Secp256k1<C>, 
     xpriv: &Xpriv,
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use bitcoin::{
+    bip32::{
+        self,
+        ChildNumber,
+        Fingerprint,
+    },
+    secp256k1::Secp256k1,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    derivation,
+    XpubWrapper,
+    balance::ScriptType,
+    invoice::unix_now,
+    schema::SCHEMA_VERSION,
+};
+
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+pub const MAX_PAGE_SIZE: u64 = 1000;
+/// Chains searched when recovering the path of a key recorded before paths were stored.
+pub const LEGACY_CHAINS: u32 = 256;
+
+/// A key derived from a user's xpub, stored apart from the user so histories can grow large.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DerivedKey {
+    xpub: XpubWrapper,
+    chain: u32,
+    index: u32,
+    derived_xpub: XpubWrapper,
+    address: String,
+    script_type: ScriptType,
+    label: Option<String>,
+    /// Set once the watcher sees a payment to the address.
+    #[serde(default)]
+    used: bool,
+    created_at: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl DerivedKey {
+    pub fn new(xpub: &bip32::Xpub, path: [u32; 2], label: Option<String>) -> Self {
+        DerivedKey {
+            xpub: (*xpub).into(),
+            chain: path[0],
+            index: path[1],
+            derived_xpub: derivation::derive_xpub(xpub, &path).into(),
+            address: derivation::derive_network_address(xpub, &path).to_string(),
+            script_type: ScriptType::P2wpkh,
+            label,
+            used: false,
+            created_at: unix_now(),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_derivation_path(&self) -> [u32; 2] {
+        [self.chain, self.index]
+    }
+    pub fn get_derived_xpub(&self) -> &XpubWrapper {
+        &self.derived_xpub
+    }
+    pub fn get_chain(&self) -> u32 {
+        self.chain
+    }
+    pub fn get_index(&self) -> u32 {
+        self.index
+    }
+    pub fn get_script_type(&self) -> ScriptType {
+        self.script_type
+    }
+    pub fn get_label(&self) -> Option<&String> {
+        self.label.as_ref()
+    }
+    pub fn is_used(&self) -> bool {
+        self.used
+    }
+    pub fn mark_used(&mut self) {
+        self.used = true;
+    }
+    pub fn matches(&self, filter: &DerivedKeyFilter) -> bool {
+        filter.chain.is_none_or(|chain| chain == self.chain)
+            && filter.script_type.is_none_or(|script_type| script_type == self.script_type)
+            && filter.label.as_ref().is_none_or(|label| Some(label) == self.label.as_ref())
+            && filter.used.is_none_or(|used| used == self.used)
+    }
+}
+
+#[derive(Default, Deserialize)]
+pub struct DeriveParams {
+    #[serde(default)]
+    label: Option<String>,
+}
+
+impl DeriveParams {
+    pub fn get_label(self) -> Option<String> {
+        self.label
+    }
+}
+
+/// Query of `/addresses`. Unset fields match every key.
+#[derive(Default, Deserialize)]
+pub struct DerivedKeyFilter {
+    #[serde(default)]
+    chain: Option<u32>,
+    #[serde(default)]
+    script_type: Option<ScriptType>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    used: Option<bool>,
+    #[serde(default)]
+    page: u64,
+    #[serde(default)]
+    per_page: Option<u64>,
+}
+
+impl DerivedKeyFilter {
+    pub fn get_chain(&self) -> Option<u32> {
+        self.chain
+    }
+    pub fn get_script_type(&self) -> Option<ScriptType> {
+        self.script_type
+    }
+    pub fn get_label(&self) -> Option<&String> {
+        self.label.as_ref()
+    }
+    pub fn get_used(&self) -> Option<bool> {
+        self.used
+    }
+    pub fn get_page(&self) -> u64 {
+        self.page
+    }
+    pub fn get_per_page(&self) -> u64 {
+        self.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+    /// Keys skipped before the requested page.
+    pub fn get_offset(&self) -> u64 {
+        self.page.saturating_mul(self.get_per_page())
+    }
+}
+
+/// One page of derived keys, ordered by chain then index.
+#[derive(Serialize, Deserialize)]
+pub struct DerivedKeyPage {
+    page: u64,
+    per_page: u64,
+    total: u64,
+    keys: Vec<DerivedKey>,
+}
+
+impl DerivedKeyPage {
+    pub fn new(filter: &DerivedKeyFilter, total: u64, keys: Vec<DerivedKey>) -> Self {
+        DerivedKeyPage {
+            page: filter.get_page(),
+            per_page: filter.get_per_page(),
+            total,
+            keys,
+        }
+    }
+}
+
+/// Paths of keys recorded as bare derived xpubs, matched on their parent fingerprint. Keys
+/// derived on a chain past `LEGACY_CHAINS` or at a hardened index come back as `None`.
+pub fn legacy_paths(xpub: &bip32::Xpub, derived: &[XpubWrapper]) -> Vec<Option<[u32; 2]>> {
+    let secp = Secp256k1::verification_only();
+    let chains: HashMap<Fingerprint, u32> = (0..LEGACY_CHAINS)
+        .filter_map(|chain| {
+            let child = ChildNumber::from_normal_idx(chain).ok()?;
+            xpub.ckd_pub(&secp, child).ok().map(|chain_xpub| (chain_xpub.fingerprint(), chain))
+        })
+        .collect();
+    derived
+        .iter()
+        .map(|derived| {
+            let key = derived.clone().to_xpub();
+            let index = match key.child_number {
+                ChildNumber::Normal { index } => index,
+                ChildNumber::Hardened { .. } => return None,
+            };
+            let path = [*chains.get(&key.parent_fingerprint)?, index];
+            (XpubWrapper::from(derivation::derive_xpub(xpub, &path)) == *derived).then_some(path)
+        })
+        .collect()
+}
@@ -0,0 +1,178 @@
+use std::sync::Mutex;
+use std::time::{
+    Duration,
+    Instant,
+};
+use bitcoin::{
+    FeeRate,
+    Weight,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    chain::{
+        ChainBackend,
+        ChainError,
+        MempoolEntry,
+    },
+    invoice::unix_now,
+};
+
+pub const DEFAULT_TTL_SECS: u64 = 30;
+/// Bitcoin Core's default minimum relay fee.
+pub const DEFAULT_FLOOR_SAT_VB: u64 = 1;
+pub const DEFAULT_FALLBACK_SAT_VB: u64 = 10;
+
+/// Confirmation horizon a fee rate is requested for.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeTarget {
+    #[serde(alias = "next block")]
+    NextBlock,
+    #[serde(alias = "half hour")]
+    HalfHour,
+    Hour,
+    Economy,
+}
+
+impl FeeTarget {
+    pub const ALL: [FeeTarget; 4] = [FeeTarget::NextBlock, FeeTarget::HalfHour, FeeTarget::Hour, FeeTarget::Economy];
+
+    pub fn conf_target(&self) -> u16 {
+        match self {
+            FeeTarget::NextBlock => 1,
+            FeeTarget::HalfHour => 3,
+            FeeTarget::Hour => 6,
+            FeeTarget::Economy => 144,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeSource {
+    /// Bitcoin Core `estimatesmartfee`.
+    Node,
+    /// Projected blocks built from the current mempool.
+    Mempool,
+    /// Configured fallback, no estimate was available.
+    Fallback,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FeeEstimate {
+    target: FeeTarget,
+    conf_target: u16,
+    sat_per_vb: u64,
+    source: FeeSource,
+}
+
+impl FeeEstimate {
+    pub fn get_target(&self) -> FeeTarget {
+        self.target
+    }
+    pub fn get_fee_rate(&self) -> FeeRate {
+        FeeRate::from_sat_per_vb_unchecked(self.sat_per_vb)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FeeEstimates {
+    estimates: Vec<FeeEstimate>,
+    floor_sat_per_vb: u64,
+    updated_at: u64,
+}
+
+impl FeeEstimates {
+    pub fn get(&self, target: FeeTarget) -> Option<&FeeEstimate> {
+        self.estimates.iter().find(|estimate| estimate.target == target)
+    }
+}
+
+/// Fee rate paid at the bottom of the `blocks`-th block projected from `entries`, filling
+/// blocks with the highest fee rates first; `None` when the mempool would clear before that.
+pub fn mempool_fee_rate(entries: &[MempoolEntry], blocks: u16) -> Option<FeeRate> {
+    let mut entries: Vec<&MempoolEntry> = entries.iter().collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.fee_rate()));
+    let capacity = Weight::MAX_BLOCK.to_wu().saturating_mul(blocks as u64);
+    let mut filled: u64 = 0;
+    for entry in entries {
+        filled += entry.weight.to_wu();
+        if filled >= capacity {
+            return Some(entry.fee_rate())
+        }
+    }
+    None
+}
+
+/// Fee rates for every `FeeTarget`, from the node estimator, then the mempool, then the
+/// fallback, never below the floor. Results are cached for `ttl`.
+pub struct FeeOracle {
+    floor: FeeRate,
+    fallback: FeeRate,
+    ttl: Duration,
+    cache: Mutex<Option<(Instant, FeeEstimates)>>,
+}
+
+impl Default for FeeOracle {
+    fn default() -> Self {
+        FeeOracle::new(DEFAULT_FLOOR_SAT_VB, DEFAULT_FALLBACK_SAT_VB, Duration::from_secs(DEFAULT_TTL_SECS))
+    }
+}
+
+impl FeeOracle {
+    pub fn new(floor_sat_per_vb: u64, fallback_sat_per_vb: u64, ttl: Duration) -> Self {
+        FeeOracle {
+            floor: FeeRate::from_sat_per_vb_unchecked(floor_sat_per_vb),
+            fallback: FeeRate::from_sat_per_vb_unchecked(fallback_sat_per_vb),
+            ttl,
+            cache: Mutex::new(None),
+        }
+    }
+
+    fn estimate(&self, chain: &dyn ChainBackend, target: FeeTarget, mempool: &[MempoolEntry]) -> Result<FeeEstimate, ChainError> {
+        let conf_target = target.conf_target();
+        let (fee_rate, source) = match chain.estimate_fee_rate(conf_target)? {
+            Some(fee_rate) => (fee_rate, FeeSource::Node),
+            None => match mempool_fee_rate(mempool, conf_target) {
+                Some(fee_rate) => (fee_rate, FeeSource::Mempool),
+                // A mempool clearing within the horizon only needs the floor.
+                None if !mempool.is_empty() => (self.floor, FeeSource::Mempool),
+                None => (self.fallback, FeeSource::Fallback),
+            },
+        };
+        Ok(FeeEstimate {
+            target,
+            conf_target,
+            sat_per_vb: fee_rate.max(self.floor).to_sat_per_vb_ceil(),
+            source,
+        })
+    }
+
+    /// Current estimates, refreshed from `chain` once the cached ones are older than the TTL.
+    pub fn estimates(&self, chain: &dyn ChainBackend) -> Result<FeeEstimates, ChainError> {
+        if let Some((fetched_at, estimates)) = self.cache.lock().expect("Fee cache lock").as_ref() {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(estimates.clone())
+            }
+        }
+        let mempool = chain.mempool_entries()?;
+        let estimates = FeeEstimates {
+            estimates: FeeTarget::ALL
+                .iter()
+                .map(|target| self.estimate(chain, *target, &mempool))
+                .collect::<Result<Vec<FeeEstimate>, ChainError>>()?,
+            floor_sat_per_vb: self.floor.to_sat_per_vb_ceil(),
+            updated_at: unix_now(),
+        };
+        *self.cache.lock().expect("Fee cache lock") = Some((Instant::now(), estimates.clone()));
+        Ok(estimates)
+    }
+
+    pub fn fee_rate(&self, chain: &dyn ChainBackend, target: FeeTarget) -> Result<FeeRate, ChainError> {
+        let estimates = self.estimates(chain)?;
+        Ok(estimates.get(target).map(|estimate| estimate.get_fee_rate()).unwrap_or(self.fallback))
+    }
+}
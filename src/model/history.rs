@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use bitcoin::{
+    bip32,
+    ScriptBuf,
+    Txid,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    balance::{
+        p2wpkh_script,
+        DEFAULT_GAP_LIMIT,
+        MAX_GAP_LIMIT,
+    },
+    chain::{
+        ChainBackend,
+        ChainError,
+        ChainTransaction,
+    },
+};
+
+pub const DEFAULT_PAGE_SIZE: usize = 25;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    #[serde(default)]
+    page: usize,
+    #[serde(default)]
+    per_page: Option<usize>,
+    #[serde(default)]
+    gap_limit: Option<u32>,
+}
+
+impl HistoryParams {
+    pub fn get_per_page(&self) -> usize {
+        self.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+    pub fn get_gap_limit(&self) -> u32 {
+        self.gap_limit.unwrap_or(DEFAULT_GAP_LIMIT).min(MAX_GAP_LIMIT)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccountTransaction {
+    txid: Txid,
+    direction: Direction,
+    /// Received minus spent by the account, in satoshis.
+    net_sat: i64,
+    /// Only known for outgoing transactions whose inputs can all be resolved.
+    fee_sat: Option<u64>,
+    confirmations: u32,
+    block_height: Option<u32>,
+    block_time: Option<u64>,
+    derivation_paths: Vec<[u32; 2]>,
+}
+
+impl AccountTransaction {
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryPage {
+    page: usize,
+    per_page: usize,
+    /// Whether older transactions follow this page.
+    has_more: bool,
+    transactions: Vec<AccountTransaction>,
+}
+
+fn account_transaction(
+    chain: &dyn ChainBackend,
+    chain_tx: &ChainTransaction,
+    scripts: &HashMap<ScriptBuf, [u32; 2]>,
+    known: &HashMap<Txid, &ChainTransaction>,
+) -> Result<AccountTransaction, ChainError> {
+    let transaction = &chain_tx.transaction;
+    let mut paths: Vec<[u32; 2]> = Vec::new();
+    let mut received: u64 = 0;
+    for txout in transaction.output.iter() {
+        if let Some(path) = scripts.get(&txout.script_pubkey) {
+            received += txout.value.to_sat();
+            paths.push(*path);
+        }
+    }
+
+    // Inputs are resolved from the page first, then from the backend, since the outputs
+    // spent by the account may belong to older pages.
+    let mut spent: u64 = 0;
+    let mut inputs_total: Option<u64> = Some(0);
+    for input in transaction.input.iter() {
+        let outpoint = input.previous_output;
+        let previous = match known.get(&outpoint.txid) {
+            Some(previous) => Some(previous.transaction.output.get(outpoint.vout as usize).cloned()),
+            None => chain.transaction(&outpoint.txid)?
+                .map(|previous| previous.transaction.output.get(outpoint.vout as usize).cloned()),
+        };
+        match previous.flatten() {
+            Some(txout) => {
+                if let Some(path) = scripts.get(&txout.script_pubkey) {
+                    spent += txout.value.to_sat();
+                    paths.push(*path);
+                }
+                inputs_total = inputs_total.map(|total| total + txout.value.to_sat());
+            },
+            None => inputs_total = None,
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    let outputs_total: u64 = transaction.output.iter().map(|txout| txout.value.to_sat()).sum();
+    let direction = if spent > 0 { Direction::Outgoing } else { Direction::Incoming };
+    let fee_sat = match direction {
+        Direction::Outgoing => inputs_total.and_then(|total| total.checked_sub(outputs_total)),
+        Direction::Incoming => None,
+    };
+    Ok(AccountTransaction {
+        txid: transaction.compute_txid(),
+        direction,
+        net_sat: received as i64 - spent as i64,
+        fee_sat,
+        confirmations: chain_tx.confirmations,
+        block_height: chain_tx.block_height,
+        block_time: chain_tx.block_time,
+        derivation_paths: paths,
+    })
+}
+
+/// The page of `params` of the transactions touching the account on `paths`, newest first,
+/// unconfirmed ones on top.
+pub fn account_history(
+    chain: &dyn ChainBackend,
+    xpub: &bip32::Xpub,
+    paths: &[[u32; 2]],
+    params: &HistoryParams,
+) -> Result<HistoryPage, ChainError> {
+    let scripts: HashMap<ScriptBuf, [u32; 2]> = paths
+        .iter()
+        .map(|path| (p2wpkh_script(xpub, path), *path))
+        .collect();
+    let script_list: Vec<ScriptBuf> = scripts.keys().cloned().collect();
+    let per_page = params.get_per_page();
+    // One more than the page tells whether another page follows.
+    let mut chain_txs = chain.transactions_for_scripts(&script_list, params.page.saturating_mul(per_page), per_page + 1)?;
+    let has_more = chain_txs.len() > per_page;
+    chain_txs.truncate(per_page);
+    let known: HashMap<Txid, &ChainTransaction> = chain_txs
+        .iter()
+        .map(|chain_tx| (chain_tx.transaction.compute_txid(), chain_tx))
+        .collect();
+    let transactions = chain_txs
+        .iter()
+        .map(|chain_tx| account_transaction(chain, chain_tx, &scripts, &known))
+        .collect::<Result<Vec<AccountTransaction>, ChainError>>()?;
+    Ok(HistoryPage {
+        page: params.page,
+        per_page,
+        has_more,
+        transactions,
+    })
+}
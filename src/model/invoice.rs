@@ -0,0 +1,182 @@
+use std::str::FromStr;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use bitcoin::{
+    Address,
+    Amount,
+    Txid,
+};
+use actix_web::HttpResponse;
+use mongodb::bson::oid::ObjectId;
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    XpubWrapper,
+    chain::{
+        ChainBackend,
+        ChainError,
+    },
+    schema::SCHEMA_VERSION,
+    storage::Storage,
+    webhook::{
+        self,
+        WebhookEvent,
+    },
+};
+
+pub const DEFAULT_EXPIRY_SECS: u64 = 3600;
+pub const DEFAULT_MIN_CONF: u32 = 1;
+/// Expired invoices are still matched this long after their expiry, to record late payments.
+pub const LATE_PAYMENT_SECS: u64 = 7 * 86400;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum InvoiceStatus {
+    Pending,
+    PartiallyPaid,
+    Paid,
+    Overpaid,
+    Expired,
+    /// Paid in full only after it expired.
+    PaidLate,
+}
+
+/// Statuses no longer matched against the chain.
+pub const FINAL_STATUSES: [InvoiceStatus; 3] = [InvoiceStatus::Paid, InvoiceStatus::Overpaid, InvoiceStatus::PaidLate];
+
+impl InvoiceStatus {
+    /// Paid invoices, in time or late, are no longer matched against the chain.
+    pub fn is_final(&self) -> bool {
+        FINAL_STATUSES.contains(self)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InvoiceRequest {
+    amount_sat: u64,
+    #[serde(default)]
+    expiry_secs: Option<u64>,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+impl InvoiceRequest {
+    pub fn to_invoice(self, xpub: XpubWrapper, derivation_path: [u32; 2], address: &Address) -> Invoice {
+        let created_at = unix_now();
+        Invoice {
+            id: ObjectId::new(),
+            xpub,
+            derivation_path,
+            address: address.to_string(),
+            amount_sat: self.amount_sat,
+            received_sat: 0,
+            label: self.label,
+            status: InvoiceStatus::Pending,
+            txids: Vec::new(),
+            created_at,
+            expires_at: created_at + self.expiry_secs.unwrap_or(DEFAULT_EXPIRY_SECS),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+}
+
+/// A payment request for a fixed amount, bound to a dedicated address derived from the owner's xpub.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Invoice {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    xpub: XpubWrapper,
+    derivation_path: [u32; 2],
+    address: String,
+    amount_sat: u64,
+    received_sat: u64,
+    label: Option<String>,
+    status: InvoiceStatus,
+    txids: Vec<Txid>,
+    created_at: u64,
+    expires_at: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl Invoice {
+    pub fn get_id(&self) -> ObjectId {
+        self.id
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_status(&self) -> InvoiceStatus {
+        self.status
+    }
+    pub fn get_received_sat(&self) -> u64 {
+        self.received_sat
+    }
+    pub fn get_txids(&self) -> &Vec<Txid> {
+        &self.txids
+    }
+    pub fn get_expires_at(&self) -> u64 {
+        self.expires_at
+    }
+    /// Whether payments are still matched at `now`: not paid yet, and not expired for longer
+    /// than `LATE_PAYMENT_SECS`.
+    pub fn is_open(&self, now: u64) -> bool {
+        !self.status.is_final() && now <= self.expires_at.saturating_add(LATE_PAYMENT_SECS)
+    }
+    pub fn get_address(&self) -> Address {
+        Address::from_str(&self.address).expect("Valid stored address").assume_checked()
+    }
+    /// Moves the invoice to the status matching `received` at `now`.
+    pub fn update_received(&mut self, received: Amount, txids: Vec<Txid>, now: u64) {
+        self.received_sat = received.to_sat();
+        self.txids = txids;
+        let expired = now > self.expires_at;
+        self.status = match self.received_sat {
+            received if received >= self.amount_sat && expired => InvoiceStatus::PaidLate,
+            received if received > self.amount_sat => InvoiceStatus::Overpaid,
+            received if received == self.amount_sat => InvoiceStatus::Paid,
+            _ if expired => InvoiceStatus::Expired,
+            0 => InvoiceStatus::Pending,
+            _ => InvoiceStatus::PartiallyPaid,
+        };
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time after unix epoch")
+        .as_secs()
+}
+
+/// Registers the invoice address with the chain backend so its payments are tracked.
+pub fn watch_invoice_address(chain: &dyn ChainBackend, invoice: &Invoice) -> Result<(), ChainError> {
+    chain.watch_address(&invoice.get_address(), invoice.label.clone())
+}
+
+/// Matches payments received by the invoice address with at least `min_conf` confirmations.
+pub fn observe_payments(chain: &dyn ChainBackend, invoice: &mut Invoice, min_conf: u32) -> Result<(), ChainError> {
+    if !invoice.is_open(unix_now()) {
+        return Ok(())
+    }
+    let (received, txids) = chain.received_by_address(&invoice.get_address(), min_conf)?;
+    invoice.update_received(received, txids, unix_now());
+    Ok(())
+}
+
+/// Stores `observed`, a new sighting of `invoice`, when its payments changed, and notifies the
+/// `invoice_paid` webhooks once it is paid.
+pub async fn store_observed(storage: &dyn Storage, invoice: &Invoice, observed: Invoice) -> Result<Invoice, HttpResponse> {
+    if observed.status == invoice.status && observed.received_sat == invoice.received_sat {
+        return Ok(observed)
+    }
+    let observed = storage.update_invoice(observed).await?;
+    if observed.status.is_final() {
+        let xpub = observed.xpub.clone();
+        webhook::notify_or_log(storage, &xpub, WebhookEvent::InvoicePaid(observed.clone())).await;
+    }
+    Ok(observed)
+}
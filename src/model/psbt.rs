@@ -2,43 +2,61 @@
 // https://github.com/rust-bitcoin/
 
 use std::str::FromStr;
-use std::collections::BTreeMap;
+use std::collections::{
+    BTreeMap,
+    HashSet,
+};
 use bitcoin::{
-    transaction, Address, Amount, Network, OutPoint, Psbt, ScriptBuf,
-    Sequence, Transaction, TxIn, TxOut, Witness,
+    transaction, Address, Amount, CompressedPublicKey, FeeRate, Network, OutPoint,
+    Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
     bip32::{
         self,
+        ChildNumber,
         Fingerprint,
     },
-    locktime::absolute,
+    transaction::InputWeightPrediction,
+    locktime::{
+        absolute,
+        relative,
+    },
     taproot::TaprootSpendInfo,
     psbt::{
         Input,
         PsbtSighashType
     },
-    secp256k1::Secp256k1,
     key::PublicKey,
     address::{
         error::ParseError,
-        NetworkUnchecked,
         NetworkChecked,
     },
     key::FromSliceError,
-    consensus::{
-        Decodable,
-        Encodable,
-    },
 };
 use serde::{
     Serialize,
     Deserialize,
 };
+use crate::model::{
+    XpubWrapper,
+    balance::AccountUtxo,
+    derivation,
+    fees::FeeTarget,
+    invoice::unix_now,
+    schema::SCHEMA_VERSION,
+};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AddressSerialized {
     address_string: String,
 }
 
+impl From<Address<NetworkChecked>> for AddressSerialized {
+    fn from(value: Address<NetworkChecked>) -> Self {
+        AddressSerialized {
+            address_string: value.to_string(),
+        }
+    }
+}
+
 impl AddressSerialized {
     pub fn to_address(self, network: Network) -> Result<Address<NetworkChecked>, ParseError> {
         Address::from_str(&self.address_string)?
@@ -57,23 +75,391 @@ impl PublicKeySerialized {
     }
 }
 
+/// Absolute locktime as requested by the client, either a block height or a unix timestamp.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum AbsoluteLockSerialized {
+    Height(u32),
+    Time(u32),
+}
+
+impl AbsoluteLockSerialized {
+    pub fn to_lock_time(self) -> Result<absolute::LockTime, absolute::ConversionError> {
+        match self {
+            AbsoluteLockSerialized::Height(height) => absolute::LockTime::from_height(height),
+            AbsoluteLockSerialized::Time(time) => absolute::LockTime::from_time(time),
+        }
+    }
+}
+
+/// BIP68 relative locktime for a single input, in blocks or in 512 seconds intervals.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum RelativeLockSerialized {
+    Blocks(u16),
+    Intervals(u16),
+}
+
+impl RelativeLockSerialized {
+    pub fn to_lock_time(self) -> relative::LockTime {
+        match self {
+            RelativeLockSerialized::Blocks(blocks) => relative::LockTime::from_height(blocks),
+            RelativeLockSerialized::Intervals(intervals) => relative::LockTime::from_512_second_intervals(intervals),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TimelocksSerialized {
+    #[serde(default)]
+    lock_time: Option<AbsoluteLockSerialized>,
+    #[serde(default)]
+    relative_locks: Vec<Option<RelativeLockSerialized>>,
+    #[serde(default)]
+    rbf: bool,
+    #[serde(default)]
+    tip_height: Option<u32>,
+}
+
+impl TimelocksSerialized {
+    /// The timelocks of a transaction spending `inputs` inputs, with at most one relative lock each.
+    pub fn to_timelocks(self, inputs: usize) -> Result<Timelocks, Box<dyn std::error::Error>> {
+        if self.relative_locks.len() > inputs {
+            return Err(format!("{} relative locks for {} inputs", self.relative_locks.len(), inputs).into())
+        }
+        let lock_time = match self.lock_time {
+            Some(lock) => Some(lock.to_lock_time()?),
+            None => None,
+        };
+        let tip_height = match self.tip_height {
+            Some(height) => Some(absolute::Height::from_consensus(height)?),
+            None => None,
+        };
+        Ok(Timelocks {
+            lock_time,
+            relative_locks: self.relative_locks
+                .into_iter()
+                .map(|lock| lock.map(RelativeLockSerialized::to_lock_time))
+                .collect(),
+            rbf: self.rbf,
+            tip_height,
+        })
+    }
+}
+
+/// Locktime and sequence policy applied to an unsigned transaction.
+///
+/// When no absolute locktime is requested and the current tip height is known,
+/// the locktime defaults to the tip height to discourage fee sniping.
+#[derive(Clone, Default)]
+pub struct Timelocks {
+    lock_time: Option<absolute::LockTime>,
+    relative_locks: Vec<Option<relative::LockTime>>,
+    rbf: bool,
+    tip_height: Option<absolute::Height>,
+}
+
+impl Timelocks {
+    pub fn new(lock_time: Option<absolute::LockTime>, rbf: bool) -> Self {
+        Timelocks {
+            lock_time,
+            rbf,
+            ..Default::default()
+        }
+    }
+    pub fn with_relative_locks(mut self, relative_locks: Vec<Option<relative::LockTime>>) -> Self {
+        self.relative_locks = relative_locks;
+        self
+    }
+    pub fn with_tip_height(mut self, tip_height: absolute::Height) -> Self {
+        self.tip_height = Some(tip_height);
+        self
+    }
+    pub fn has_tip_height(&self) -> bool {
+        self.tip_height.is_some()
+    }
+    pub fn lock_time(&self) -> absolute::LockTime {
+        match (self.lock_time, self.tip_height) {
+            (Some(lock_time), _) => lock_time,
+            (None, Some(tip_height)) => absolute::LockTime::Blocks(tip_height),
+            (None, None) => absolute::LockTime::ZERO,
+        }
+    }
+    /// Sequence for the input at `index`, keeping the client provided value
+    /// unless a relative lock, RBF or an absolute locktime requires otherwise.
+    pub fn sequence(&self, index: usize, current: Sequence) -> Sequence {
+        if let Some(Some(relative_lock)) = self.relative_locks.get(index) {
+            return relative_lock.to_sequence()
+        }
+        if self.rbf {
+            return Sequence::ENABLE_RBF_NO_LOCKTIME
+        }
+        if self.lock_time() != absolute::LockTime::ZERO && current == Sequence::MAX {
+            return Sequence::ENABLE_LOCKTIME_NO_RBF
+        }
+        current
+    }
+    pub fn apply(&self, transaction: &mut Transaction) {
+        transaction.lock_time = self.lock_time();
+        transaction.input
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, input)| input.sequence = self.sequence(index, input.sequence));
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PsbtSerialized {
     inputs: Vec<TxIn>,
     out_address_serialized: AddressSerialized,
     pk_change_serialized: PublicKeySerialized,
+    #[serde(default)]
     spend_amount_u64: u64,
+    #[serde(default)]
     change_amount_u64: u64,
+    /// Spend amount in satoshis, taking precedence over the whole bitcoins of `spend_amount_u64`.
+    #[serde(default)]
+    spend_amount_sat: Option<u64>,
+    /// Change amount in satoshis, taking precedence over the whole bitcoins of `change_amount_u64`.
+    #[serde(default)]
+    change_amount_sat: Option<u64>,
+    #[serde(default)]
+    timelocks: TimelocksSerialized,
+    /// When set, the fee is taken from the change output.
+    #[serde(default)]
+    fee_rate_sat_vb: Option<u64>,
+    /// Resolved into `fee_rate_sat_vb` by the fee oracle when no rate is given.
+    #[serde(default)]
+    fee_target: Option<FeeTarget>,
 }
 
 impl PsbtSerialized {
-    pub fn try_into_psbt(self) -> Result<Psbt, Box<dyn std::error::Error>> {
+    pub fn has_tip_height(&self) -> bool {
+        self.timelocks.tip_height.is_some()
+    }
+    pub fn set_tip_height(&mut self, tip_height: u32) {
+        self.timelocks.tip_height = Some(tip_height);
+    }
+    /// The fee target still to be resolved, if no explicit rate was given.
+    pub fn get_fee_target(&self) -> Option<FeeTarget> {
+        self.fee_target.filter(|_| self.fee_rate_sat_vb.is_none())
+    }
+    pub fn set_fee_rate(&mut self, fee_rate: FeeRate) {
+        self.fee_rate_sat_vb = Some(fee_rate.to_sat_per_vb_ceil());
+    }
+    /// The PSBT of this request, its recipient address checked against `network`.
+    pub fn try_into_psbt(self, network: Network) -> Result<Psbt, Box<dyn std::error::Error>> {
         let inputs = self.inputs;
-        let out_address = self.out_address_serialized.to_address(Network::Testnet)?;
+        let out_address = self.out_address_serialized.to_address(network)?;
         let pk_change = self.pk_change_serialized.to_public_key()?;
-        let spend_amount = Amount::from_int_btc(self.spend_amount_u64);
-        let change_amount = Amount::from_int_btc(self.change_amount_u64);
-        Ok(create_ecdsa_psbt(inputs, out_address, pk_change, spend_amount, change_amount)?)
+        let spend_amount = self.spend_amount_sat.map_or(Amount::from_int_btc(self.spend_amount_u64), Amount::from_sat);
+        let change_amount = self.change_amount_sat.map_or(Amount::from_int_btc(self.change_amount_u64), Amount::from_sat);
+        let timelocks = self.timelocks.to_timelocks(inputs.len())?;
+        let fee_rate = match self.fee_rate_sat_vb {
+            Some(fee_rate) => Some(FeeRate::from_sat_per_vb(fee_rate).ok_or("Fee rate overflow")?),
+            None if self.fee_target.is_some() => return Err("Unresolved fee target".into()),
+            None => None,
+        };
+        create_ecdsa_psbt(inputs, out_address, pk_change, spend_amount, change_amount, &timelocks, fee_rate)
+    }
+}
+
+/// An unspent output owned by the user, referenced by the derivation path of its key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UtxoSerialized {
+    outpoint: OutPoint,
+    value_u64: u64,
+    derivation_path: [u32; 2],
+}
+
+impl UtxoSerialized {
+    pub fn get_derivation_path(&self) -> [u32; 2] {
+        self.derivation_path
+    }
+    pub fn get_amount(&self) -> Amount {
+        Amount::from_sat(self.value_u64)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum SweepDestination {
+    /// Consolidate into the next unused address of the user's change chain.
+    Internal,
+    /// Sweep everything to an external address.
+    External(AddressSerialized),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SweepSerialized {
+    utxos: Vec<UtxoSerialized>,
+    destination: SweepDestination,
+    #[serde(default)]
+    fee_rate_sat_vb: Option<u64>,
+    /// Resolved into `fee_rate_sat_vb` by the fee oracle when no rate is given.
+    #[serde(default)]
+    fee_target: Option<FeeTarget>,
+    #[serde(default)]
+    min_value_u64: u64,
+    #[serde(default)]
+    timelocks: TimelocksSerialized,
+    /// Resolved from the derived keys for an `Internal` destination.
+    #[serde(skip)]
+    change_path: Option<[u32; 2]>,
+}
+
+impl SweepSerialized {
+    pub fn has_tip_height(&self) -> bool {
+        self.timelocks.tip_height.is_some()
+    }
+    pub fn set_tip_height(&mut self, tip_height: u32) {
+        self.timelocks.tip_height = Some(tip_height);
+    }
+    pub fn get_change_path(&self) -> Option<[u32; 2]> {
+        self.change_path
+    }
+    pub fn set_change_path(&mut self, path: [u32; 2]) {
+        self.change_path = Some(path);
+    }
+    /// The fee target still to be resolved, if no explicit rate was given.
+    pub fn get_fee_target(&self) -> Option<FeeTarget> {
+        self.fee_target.filter(|_| self.fee_rate_sat_vb.is_none())
+    }
+    pub fn set_fee_rate(&mut self, fee_rate: FeeRate) {
+        self.fee_rate_sat_vb = Some(fee_rate.to_sat_per_vb_ceil());
+    }
+    pub fn get_destination(&self) -> &SweepDestination {
+        &self.destination
+    }
+    /// UTXOs selected for the sweep, dropping those below `min_value_u64`.
+    pub fn get_utxos(&self) -> Vec<UtxoSerialized> {
+        self.utxos
+            .iter()
+            .filter(|utxo| utxo.value_u64 >= self.min_value_u64)
+            .cloned()
+            .collect()
+    }
+    /// Checks that no UTXO is listed twice and that every derivation path is a normal one.
+    pub fn check_inputs(&self) -> Result<(), String> {
+        check_sweep_inputs(&self.utxos)
+    }
+    /// Checks the selected UTXOs against those the chain backend sees unspent on their paths,
+    /// since their values end up in the witness UTXOs the signer trusts.
+    pub fn check_utxos(&self, unspent: &[AccountUtxo]) -> Result<(), String> {
+        self.get_utxos().iter().try_for_each(|utxo| {
+            let found = unspent.iter().find(|unspent| unspent.get_outpoint() == utxo.outpoint);
+            match found {
+                None => Err(format!("{} is not an unspent output", utxo.outpoint)),
+                Some(unspent) if unspent.get_derivation_path() != utxo.derivation_path =>
+                    Err(format!("{} is not locked to {:?}", utxo.outpoint, utxo.derivation_path)),
+                Some(unspent) if unspent.get_value_sat() != utxo.value_u64 =>
+                    Err(format!("{} holds {} sat, not {}", utxo.outpoint, unspent.get_value_sat(), utxo.value_u64)),
+                Some(_) => Ok(()),
+            }
+        })
+    }
+    pub fn try_into_psbt(self, xpub: &bip32::Xpub, network: Network) -> Result<Psbt, Box<dyn std::error::Error>> {
+        let utxos = self.get_utxos();
+        let destination = match self.destination {
+            SweepDestination::Internal => {
+                let path = self.change_path.ok_or("Change path required")?;
+                derivation::check_path(&path)?;
+                let public_key = derivation::derive_xpub(xpub, &path).public_key;
+                ScriptBuf::new_p2wpkh(&CompressedPublicKey(public_key).wpubkey_hash())
+            },
+            SweepDestination::External(address) => address.to_address(network)?.script_pubkey(),
+        };
+        let fee_rate = FeeRate::from_sat_per_vb(self.fee_rate_sat_vb.ok_or("Fee rate or fee target required")?)
+            .ok_or("Fee rate overflow")?;
+        let timelocks = self.timelocks.to_timelocks(utxos.len())?;
+        create_sweep_psbt(xpub, utxos, destination, fee_rate, &timelocks)
+    }
+}
+
+/// A PSBT handed back by a cosigner after signing.
+#[derive(Serialize, Deserialize)]
+pub struct SignedPsbtSerialized {
+    psbt: Psbt,
+}
+
+impl SignedPsbtSerialized {
+    pub fn get_psbt(&self) -> &Psbt {
+        &self.psbt
+    }
+    pub fn to_signatures(&self) -> PsbtSignatures {
+        PsbtSignatures::from(&self.psbt)
+    }
+}
+
+/// Signature progress of a PSBT, identified by its unsigned transaction id.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PsbtSignatures {
+    txid: Txid,
+    inputs: usize,
+    signed_inputs: usize,
+    signatures: usize,
+    finalized: bool,
+}
+
+impl PsbtSignatures {
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+    pub fn get_signed_inputs(&self) -> usize {
+        self.signed_inputs
+    }
+    pub fn get_signatures(&self) -> usize {
+        self.signatures
+    }
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
+}
+
+impl From<&Psbt> for PsbtSignatures {
+    fn from(psbt: &Psbt) -> Self {
+        let is_final = |input: &Input| input.final_script_witness.is_some() || input.final_script_sig.is_some();
+        let count = |input: &Input| input.partial_sigs.len()
+            + input.tap_script_sigs.len()
+            + usize::from(input.tap_key_sig.is_some());
+        PsbtSignatures {
+            txid: psbt.unsigned_tx.compute_txid(),
+            inputs: psbt.inputs.len(),
+            signed_inputs: psbt.inputs.iter().filter(|input| is_final(input) || count(input) > 0).count(),
+            signatures: psbt.inputs.iter().map(count).sum(),
+            finalized: !psbt.inputs.is_empty() && psbt.inputs.iter().all(is_final),
+        }
+    }
+}
+
+/// A PSBT created for, or signed on behalf of, a user; stored hex encoded.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PsbtRecord {
+    xpub: XpubWrapper,
+    txid: Txid,
+    psbt_hex: String,
+    signatures: PsbtSignatures,
+    updated_at: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl PsbtRecord {
+    pub fn new(xpub: XpubWrapper, psbt: &Psbt) -> Self {
+        PsbtRecord {
+            xpub,
+            txid: psbt.unsigned_tx.compute_txid(),
+            psbt_hex: psbt.serialize_hex(),
+            signatures: PsbtSignatures::from(psbt),
+            updated_at: unix_now(),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+    pub fn get_updated_at(&self) -> u64 {
+        self.updated_at
     }
 }
 
@@ -89,25 +475,48 @@ pub fn create_ecdsa_psbt(
     out_address: Address,
     pk_change: PublicKey,
     spend_amount: Amount, 
-    change_amount: Amount
+    change_amount: Amount,
+    timelocks: &Timelocks,
+    fee_rate: Option<FeeRate>,
 ) -> Result<Psbt, Box<dyn std::error::Error>> {
-    let secp = Secp256k1::new();
     // The spend output is locked to a key controlled by the receiver.
     let spend = TxOut { value: spend_amount, script_pubkey: out_address.script_pubkey() };
 
     // The change output is locked to a key controlled by us.
-    let change = TxOut {
+    let mut change = TxOut {
         value: change_amount,
         script_pubkey: ScriptBuf::new_p2wpkh(&pk_change.wpubkey_hash()?), // Change comes back to us.
     };
 
+    // With a fee rate, the fee for P2WPKH inputs is deducted from the change.
+    if let Some(fee_rate) = fee_rate {
+        let weight = transaction::predict_weight(
+            vec![InputWeightPrediction::P2WPKH_MAX; inputs.len()],
+            [spend.script_pubkey.len(), change.script_pubkey.len()],
+        );
+        let fee = fee_rate.fee_wu(weight).ok_or("Fee overflow")?;
+        change.value = change.value.checked_sub(fee).ok_or("Insufficient change for fee")?;
+        if change.value < change.script_pubkey.minimal_non_dust() {
+            return Err("Change below dust limit".into())
+        }
+    }
+
+    create_unsigned_psbt(inputs, vec![spend, change], timelocks)
+}
+
+fn create_unsigned_psbt(
+    inputs: Vec<TxIn>,
+    outputs: Vec<TxOut>,
+    timelocks: &Timelocks,
+) -> Result<Psbt, Box<dyn std::error::Error>> {
     // The transaction we want to sign and broadcast.
-    let unsigned_tx = Transaction {
+    let mut unsigned_tx = Transaction {
         version: transaction::Version::TWO,  // Post BIP 68.
-        lock_time: absolute::LockTime::ZERO, // Ignore the locktime.
+        lock_time: absolute::LockTime::ZERO, // Set by the timelocks below.
         input: inputs,                       // Input is 0-indexed.
-        output: vec![spend, change],         // Outputs, order does not matter.
+        output: outputs,                     // Outputs, order does not matter.
     };
+    timelocks.apply(&mut unsigned_tx);
 
     // Now we'll start the PSBT workflow.
     // Step 1: Creator role; that creates,
@@ -115,10 +524,80 @@ pub fn create_ecdsa_psbt(
     Ok(Psbt::from_unsigned_tx(unsigned_tx)?)
 }
 
+fn check_sweep_inputs(utxos: &[UtxoSerialized]) -> Result<(), String> {
+    let mut outpoints = HashSet::new();
+    utxos.iter().try_for_each(|utxo| {
+        if !outpoints.insert(utxo.outpoint) {
+            return Err(format!("{} is listed twice", utxo.outpoint))
+        }
+        derivation::check_path(&utxo.derivation_path)
+            .map_err(|err| format!("{:?} is not a valid path: {}", utxo.derivation_path, err))
+    })
+}
+
+/// Builds a PSBT spending every given P2WPKH UTXO of `xpub` into a single output
+/// locked to `destination`, with the fee taken from the swept amount and no change.
+pub fn create_sweep_psbt(
+    xpub: &bip32::Xpub,
+    utxos: Vec<UtxoSerialized>,
+    destination: ScriptBuf,
+    fee_rate: FeeRate,
+    timelocks: &Timelocks,
+) -> Result<Psbt, Box<dyn std::error::Error>> {
+    if utxos.is_empty() {
+        return Err("No UTXOs to sweep".into())
+    }
+    check_sweep_inputs(&utxos)?;
+    let total = utxos
+        .iter()
+        .try_fold(Amount::ZERO, |total, utxo| total.checked_add(utxo.get_amount()))
+        .ok_or("Amount overflow")?;
+    let weight = transaction::predict_weight(
+        vec![InputWeightPrediction::P2WPKH_MAX; utxos.len()],
+        [destination.len()],
+    );
+    let fee = fee_rate.fee_wu(weight).ok_or("Fee overflow")?;
+    let value = total.checked_sub(fee).ok_or("Insufficient funds for fee")?;
+    let output = TxOut { value, script_pubkey: destination };
+    if value < output.script_pubkey.minimal_non_dust() {
+        return Err("Output below dust limit".into())
+    }
+
+    let inputs = utxos
+        .iter()
+        .map(|utxo| TxIn {
+            previous_output: utxo.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        })
+        .collect();
+    let mut psbt = create_unsigned_psbt(inputs, vec![output], timelocks)?;
+
+    // Step 2: Updater role; attach the spent outputs and key origins so that
+    // the signer can produce the witnesses.
+    let fingerprint = xpub.fingerprint();
+    for (input, utxo) in psbt.inputs.iter_mut().zip(utxos.iter()) {
+        let path: bip32::DerivationPath = utxo.derivation_path
+            .iter()
+            .map(|index| ChildNumber::from_normal_idx(*index))
+            .collect::<Result<Vec<ChildNumber>, bip32::Error>>()?
+            .into();
+        let public_key = derivation::derive_xpub(xpub, &utxo.derivation_path).public_key;
+        input.witness_utxo = Some(TxOut {
+            value: utxo.get_amount(),
+            script_pubkey: ScriptBuf::new_p2wpkh(&CompressedPublicKey(public_key).wpubkey_hash()),
+        });
+        input.bip32_derivation.insert(public_key, (fingerprint, path));
+    }
+    Ok(psbt)
+}
+
 pub fn create_psbt_for_taproot_key_path_spend(
     from_address: Address,
     to_address: Address,
     tree: TaprootSpendInfo,
+    timelocks: &Timelocks,
 ) -> Psbt {
     let send_value = 6400;
     let out_puts = vec![TxOut {
@@ -127,17 +606,18 @@ pub fn create_psbt_for_taproot_key_path_spend(
     }];
     let prev_tx_id = "06980ca116f74c7845a897461dd0e1d15b114130176de5004957da516b4dee3a";
 
-    let transaction = Transaction {
+    let mut transaction = Transaction {
         version: transaction::Version(2),
         lock_time: absolute::LockTime::ZERO,
         input: vec![TxIn {
             previous_output: OutPoint { txid: prev_tx_id.parse().unwrap(), vout: 0 },
             script_sig: ScriptBuf::new(),
-            sequence: Sequence(0xFFFFFFFF), // Ignore nSequence.
+            sequence: Sequence::MAX, // Overridden by the timelocks if required.
             witness: Witness::default(),
         }],
         output: out_puts,
     };
+    timelocks.apply(&mut transaction);
 
     let mut psbt = Psbt::from_unsigned_tx(transaction).unwrap();
 
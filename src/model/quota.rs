@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use serde::{
+    Serialize,
+    Deserialize,
+};
+
+pub const DEFAULT_PLAN: &str = "default";
+/// Keys a user on the default plan may derive when no quotas are configured.
+pub const DEFAULT_QUOTA: u64 = 256;
+
+/// Number of keys each plan may derive per user. Users without a plan, or with a plan that is
+/// no longer configured, get the quota of the default plan.
+#[derive(Clone)]
+pub struct QuotaPolicy {
+    default_plan: String,
+    quotas: HashMap<String, u64>,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        QuotaPolicy {
+            default_plan: DEFAULT_PLAN.to_string(),
+            quotas: HashMap::from([(DEFAULT_PLAN.to_string(), DEFAULT_QUOTA)]),
+        }
+    }
+}
+
+impl QuotaPolicy {
+    /// Reads comma separated `plan=quota` pairs, e.g. `default=256,merchant=50000`, which must
+    /// include `default_plan`.
+    pub fn parse(spec: &str, default_plan: &str) -> Result<Self, String> {
+        let mut quotas = HashMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (plan, quota) = pair.split_once('=').ok_or_else(|| format!("Expected plan=quota, got {}", pair))?;
+            let quota = quota.trim().parse().map_err(|_| format!("Invalid quota for plan {}: {}", plan, quota))?;
+            quotas.insert(plan.trim().to_string(), quota);
+        }
+        if !quotas.contains_key(default_plan) {
+            return Err(format!("No quota for the default plan {}", default_plan))
+        }
+        Ok(QuotaPolicy {
+            default_plan: default_plan.to_string(),
+            quotas,
+        })
+    }
+    pub fn has_plan(&self, plan: &str) -> bool {
+        self.quotas.contains_key(plan)
+    }
+    /// The plan applying to a user with `plan` set.
+    pub fn effective_plan<'a>(&'a self, plan: Option<&'a str>) -> &'a str {
+        plan.filter(|plan| self.has_plan(plan)).unwrap_or(&self.default_plan)
+    }
+    pub fn quota_for(&self, plan: Option<&str>) -> u64 {
+        self.quotas[self.effective_plan(plan)]
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QuotaUsage {
+    plan: String,
+    quota: u64,
+    derived: u64,
+}
+
+impl QuotaUsage {
+    pub fn new(policy: &QuotaPolicy, plan: Option<&str>, derived: u64) -> Self {
+        QuotaUsage {
+            plan: policy.effective_plan(plan).to_string(),
+            quota: policy.quota_for(plan),
+            derived,
+        }
+    }
+}
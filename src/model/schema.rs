@@ -0,0 +1,77 @@
+use serde::{
+    Serialize,
+    Deserialize,
+};
+
+/// Shape version written into every stored record. Records from before versioning read as 0.
+/// Bumping it requires a migration to the new version in every backend keeping documents,
+/// see `storage::mongo::MIGRATIONS`.
+pub const SCHEMA_VERSION: u32 = 4;
+/// Upgraded records between two progress reports.
+pub const PROGRESS_INTERVAL: u64 = 500;
+
+/// One migration as run, or as it would run in a dry run.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MigrationStep {
+    collection: String,
+    from_version: u32,
+    to_version: u32,
+    description: String,
+    pending: u64,
+    migrated: u64,
+}
+
+impl MigrationStep {
+    pub fn new(collection: &str, from_version: u32, to_version: u32, description: &str, pending: u64) -> Self {
+        MigrationStep {
+            collection: collection.to_string(),
+            from_version,
+            to_version,
+            description: description.to_string(),
+            pending,
+            migrated: 0,
+        }
+    }
+    pub fn get_pending(&self) -> u64 {
+        self.pending
+    }
+    pub fn get_migrated(&self) -> u64 {
+        self.migrated
+    }
+    /// Counts one more upgraded record, logging progress every `PROGRESS_INTERVAL` records.
+    pub fn record_progress(&mut self) {
+        self.migrated += 1;
+        if self.migrated.is_multiple_of(PROGRESS_INTERVAL) || self.migrated == self.pending {
+            tracing::info!(
+                "Migrating {} v{} to v{}: {}/{}",
+                self.collection, self.from_version, self.to_version, self.migrated, self.pending,
+            );
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MigrationReport {
+    dry_run: bool,
+    schema_version: u32,
+    steps: Vec<MigrationStep>,
+}
+
+impl MigrationReport {
+    pub fn new(dry_run: bool) -> Self {
+        MigrationReport {
+            dry_run,
+            schema_version: SCHEMA_VERSION,
+            steps: Vec::new(),
+        }
+    }
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+    pub fn push(&mut self, step: MigrationStep) {
+        self.steps.push(step);
+    }
+    pub fn get_steps(&self) -> &Vec<MigrationStep> {
+        &self.steps
+    }
+}
@@ -0,0 +1,277 @@
+use actix_session::storage::{
+    CookieSessionStore,
+    LoadError,
+    RedisSessionStore,
+    SaveError,
+    SessionKey,
+    SessionStore,
+    UpdateError,
+};
+use actix_web::{
+    body::MessageBody,
+    cookie::{
+        time::Duration,
+        Cookie,
+        CookieJar,
+        Key,
+    },
+    dev::{
+        ServiceRequest,
+        ServiceResponse,
+    },
+    http::header::{
+        HeaderValue,
+        COOKIE,
+    },
+    middleware::Next,
+};
+use rand::distributions::{
+    Alphanumeric,
+    DistString,
+};
+use std::collections::HashMap;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Instant,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+/// Sessions live for a day unless `SESSION_TTL_SECS` says otherwise.
+pub const DEFAULT_TTL_SECS: i64 = 86400;
+
+/// Name of the session cookie.
+pub const COOKIE_NAME: &str = "id";
+
+/// Session entry holding the expiry of a cookie session, since the browser alone enforces it
+/// otherwise.
+const EXPIRES_AT: &str = "_expires_at";
+
+type SessionState = HashMap<String, String>;
+
+/// Sessions held by the process, lost on restart and not shared between instances.
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, (SessionState, Instant)>>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn expiry(ttl: &Duration) -> Instant {
+        Instant::now() + std::time::Duration::from_secs(ttl.whole_seconds().max(0) as u64)
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions.get(session_key.as_ref())
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(state, _)| state.clone()))
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let key = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        sessions.insert(key.clone(), (session_state, Self::expiry(ttl)));
+        SessionKey::try_from(key).map_err(|err| SaveError::Other(err.into()))
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(session_key.as_ref()) {
+            *entry = (session_state, Self::expiry(ttl));
+            return Ok(session_key)
+        }
+        // Expired and pruned in the meantime: start a new session.
+        self.save(session_state, ttl).await.map_err(|err| match err {
+            SaveError::Serialization(err) => UpdateError::Serialization(err),
+            SaveError::Other(err) => UpdateError::Other(err),
+        })
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        if let Some((_, expires_at)) = self.sessions.lock().unwrap().get_mut(session_key.as_ref()) {
+            *expires_at = Self::expiry(ttl);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        self.sessions.lock().unwrap().remove(session_key.as_ref());
+        Ok(())
+    }
+}
+
+/// The session store picked by `SESSION_STORE`: Redis, the process memory, or the cookie itself.
+#[derive(Clone)]
+pub enum SessionBackend {
+    Redis(RedisSessionStore),
+    Memory(MemorySessionStore),
+    /// The state travels encrypted in the cookie; nothing can be revoked before it expires.
+    Cookie,
+}
+
+impl SessionBackend {
+    pub async fn redis(uri: &str) -> Result<Self, String> {
+        RedisSessionStore::new(uri).await
+            .map(SessionBackend::Redis)
+            .map_err(|err| format!("Redis session store at {}: {}", uri, err))
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64)
+}
+
+/// Stamps the expiry of a cookie session into its state.
+fn stamp_expiry(mut session_state: SessionState, ttl: &Duration) -> SessionState {
+    session_state.insert(EXPIRES_AT.into(), (unix_now() + ttl.whole_seconds()).to_string());
+    session_state
+}
+
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            SessionBackend::Redis(store) => store.load(session_key).await,
+            SessionBackend::Memory(store) => store.load(session_key).await,
+            SessionBackend::Cookie => {
+                let session_state = CookieSessionStore::default().load(session_key).await?;
+                Ok(session_state.and_then(|mut session_state| {
+                    let expires_at: i64 = session_state.remove(EXPIRES_AT)?.parse().ok()?;
+                    (expires_at > unix_now()).then_some(session_state)
+                }))
+            },
+        }
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Redis(store) => store.save(session_state, ttl).await,
+            SessionBackend::Memory(store) => store.save(session_state, ttl).await,
+            SessionBackend::Cookie => CookieSessionStore::default().save(stamp_expiry(session_state, ttl), ttl).await,
+        }
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Redis(store) => store.update(session_key, session_state, ttl).await,
+            SessionBackend::Memory(store) => store.update(session_key, session_state, ttl).await,
+            SessionBackend::Cookie => CookieSessionStore::default().update(session_key, stamp_expiry(session_state, ttl), ttl).await,
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Redis(store) => store.update_ttl(session_key, ttl).await,
+            SessionBackend::Memory(store) => store.update_ttl(session_key, ttl).await,
+            SessionBackend::Cookie => Ok(()),
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Redis(store) => store.delete(session_key).await,
+            SessionBackend::Memory(store) => store.delete(session_key).await,
+            SessionBackend::Cookie => Ok(()),
+        }
+    }
+}
+
+/// Keys encrypting the session cookie. New cookies are sealed with the primary key; those sealed
+/// with a previous key keep working while it is listed.
+#[derive(Clone)]
+pub struct SessionKeys {
+    primary: Key,
+    previous: Vec<Key>,
+}
+
+impl SessionKeys {
+    /// Parses hex keys of 64 bytes separated by commas or lines, the primary first. Blank lines
+    /// and `#` comments are skipped.
+    pub fn parse(keys: &str) -> Result<Self, String> {
+        let mut keys = keys.split([',', '\n'])
+            .map(str::trim)
+            .filter(|key| !key.is_empty() && !key.starts_with('#'))
+            .map(|key| {
+                let bytes = hex::decode(key).map_err(|err| format!("Invalid session key: {}", err))?;
+                Key::try_from(bytes.as_slice()).map_err(|_| "Session keys must be at least 64 bytes".to_string())
+            })
+            .collect::<Result<Vec<Key>, String>>()?;
+        if keys.is_empty() {
+            return Err("No session key".into())
+        }
+        let primary = keys.remove(0);
+        Ok(SessionKeys {
+            primary,
+            previous: keys,
+        })
+    }
+    /// A random primary key, lasting as long as the process.
+    pub fn generate() -> Self {
+        SessionKeys {
+            primary: Key::generate(),
+            previous: Vec::new(),
+        }
+    }
+    /// A new key, hex encoded for `SESSION_KEYS`.
+    pub fn generate_hex() -> String {
+        hex::encode(Key::generate().master())
+    }
+    pub fn get_primary(&self) -> &Key {
+        &self.primary
+    }
+    pub fn get_previous(&self) -> &[Key] {
+        &self.previous
+    }
+    /// Re-seals the session cookie of `req` with the primary key when a previous key sealed it.
+    fn reseal(&self, req: &mut ServiceRequest) {
+        if self.previous.is_empty() {
+            return
+        }
+        let header = req.headers().get_all(COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<&str>>()
+            .join("; ");
+        let mut cookies: Vec<Cookie<'static>> = header.split(';')
+            .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_owned()).ok())
+            .collect();
+        let Some(session_cookie) = cookies.iter_mut().find(|cookie| cookie.name() == COOKIE_NAME) else {
+            return
+        };
+        let mut jar = CookieJar::new();
+        jar.add_original(session_cookie.clone());
+        if jar.private(&self.primary).get(COOKIE_NAME).is_some() {
+            return
+        }
+        let Some(plain) = self.previous.iter().find_map(|key| jar.private(key).get(COOKIE_NAME)) else {
+            return
+        };
+        let mut resealed = CookieJar::new();
+        resealed.private_mut(&self.primary).add(plain);
+        let Some(resealed) = resealed.get(COOKIE_NAME) else {
+            return
+        };
+        *session_cookie = resealed.clone();
+        let header = cookies.iter()
+            .map(|cookie| cookie.encoded().stripped().to_string())
+            .collect::<Vec<String>>()
+            .join("; ");
+        if let Ok(header) = HeaderValue::from_str(&header) {
+            req.headers_mut().insert(COOKIE, header);
+        }
+    }
+}
+
+/// Middleware letting the session middleware, which only knows the primary key, read cookies
+/// sealed with a previous key. It must wrap the session middleware.
+pub async fn reseal_cookie(keys: SessionKeys, mut req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    keys.reseal(&mut req);
+    next.call(req).await
+}
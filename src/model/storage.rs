@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use actix_session::Session;
+use actix_web::{
+    Error,
+    HttpResponse,
+    error::{
+        ErrorBadRequest,
+        ErrorConflict,
+        ErrorInsufficientStorage,
+        InternalError,
+    },
+    http::StatusCode,
+};
+use async_trait::async_trait;
+use bitcoin::{
+    BlockHash,
+    Txid,
+};
+use mongodb::bson::oid::ObjectId;
+use crate::model::{
+    self,
+    balance,
+    Credentials,
+    Nonce,
+    SaltedFingerPrint,
+    UserAddress,
+    XpubWrapper,
+    audit::AuditEvent,
+    broadcast::BroadcastRecord,
+    derivation,
+    derived::{
+        DerivedKey,
+        DerivedKeyFilter,
+        DerivedKeyPage,
+    },
+    invoice::Invoice,
+    psbt::PsbtRecord,
+    quota::QuotaPolicy,
+    schema::MigrationReport,
+    user,
+    vault::RotationReport,
+    watcher::{
+        Deposit,
+        WatcherCursor,
+    },
+    webhook::{
+        Delivery,
+        EventKind,
+        Webhook,
+    },
+};
+pub mod memory;
+pub mod mongo;
+pub mod sql;
+
+/// Address updates attempted before giving up on concurrent writers.
+pub const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+/// Persistence used by the handlers and background tasks.
+///
+/// Errors are the HTTP responses to return, as in the rest of the model layer.
+#[async_trait(?Send)]
+pub trait Storage: Send + Sync {
+    /// Creates indexes and whatever else the backend needs before serving.
+    async fn init(&self) -> Result<(), HttpResponse>;
+    /// Upgrades stored records to `schema::SCHEMA_VERSION`. A dry run only counts the records
+    /// each migration would upgrade.
+    async fn migrate(&self, dry_run: bool) -> Result<MigrationReport, HttpResponse>;
+    /// Re-wraps the values sealed under retired keys with the active key of the vault. A dry run
+    /// only counts the records holding them.
+    async fn rotate_keys(&self, dry_run: bool) -> Result<RotationReport, HttpResponse>;
+    /// Stable id of the wallet of `xpub`, naming it wherever the xpub should not appear.
+    fn wallet_id(&self, xpub: &XpubWrapper) -> SaltedFingerPrint;
+
+    async fn insert_address(&self, address: UserAddress<XpubWrapper>) -> Result<UserAddress<XpubWrapper>, HttpResponse>;
+    /// The address registered for `xpub`, 404 when unknown.
+    async fn address_lookup(&self, xpub: XpubWrapper) -> Result<UserAddress<XpubWrapper>, HttpResponse>;
+    /// Stores `derived` and bumps the version, only while the stored version is still `version`.
+    /// `None` when a concurrent update got there first.
+    async fn push_derived(&self, xpub: XpubWrapper, version: u64, derived: Vec<DerivedKey>) -> Result<Option<UserAddress<XpubWrapper>>, HttpResponse>;
+    async fn all_addresses(&self) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse>;
+    /// Increments the nonce only while it is still `nonce`, returning the new one. `None` when
+    /// it already moved on, i.e. the signature was replayed or raced by another login.
+    async fn advance_nonce(&self, xpub: XpubWrapper, nonce: Nonce) -> Result<Option<Nonce>, HttpResponse>;
+    /// Removes the address of `xpub` and every record kept for it, returning how many.
+    async fn delete_account(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse>;
+    /// Moves `xpub` to `plan`, or back to the default plan with `None`.
+    async fn set_plan(&self, xpub: XpubWrapper, plan: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse>;
+    /// Wallets of the user `user_id`: the one it is named after, unless linked elsewhere, and
+    /// those linked to it.
+    async fn wallets_lookup(&self, user_id: &SaltedFingerPrint) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse>;
+    /// Links the wallet of `xpub` to the user `user_id` as `label`, or makes it a user of its own
+    /// with `None`.
+    async fn link_wallet(&self, xpub: XpubWrapper, user_id: Option<SaltedFingerPrint>, label: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse>;
+
+    async fn derived_key_lookup(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<Option<DerivedKey>, HttpResponse>;
+    async fn derived_keys_count(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse>;
+    /// Every key derived from `xpub`, ordered by chain then index.
+    async fn all_derived_keys(&self, xpub: XpubWrapper) -> Result<Vec<DerivedKey>, HttpResponse>;
+    /// Highest derived index on `chain`, `None` before the first derivation on it.
+    async fn last_derived_index(&self, xpub: XpubWrapper, chain: u32) -> Result<Option<u32>, HttpResponse>;
+    /// Highest derived index of every chain with derived keys, as `(chain, index)` ordered by chain.
+    async fn last_derived_indexes(&self, xpub: XpubWrapper) -> Result<Vec<(u32, u32)>, HttpResponse>;
+    /// The page of keys matching `filter`, ordered by chain then index.
+    async fn derived_keys_lookup(&self, xpub: XpubWrapper, filter: &DerivedKeyFilter) -> Result<DerivedKeyPage, HttpResponse>;
+    /// Flags the key at `path` as used. Paths that were never derived are ignored.
+    async fn mark_used(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<(), HttpResponse>;
+
+    /// Stores a PSBT, replacing the previous version of the same unsigned transaction.
+    async fn upsert_psbt(&self, record: PsbtRecord) -> Result<PsbtRecord, HttpResponse>;
+    async fn psbts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<PsbtRecord>, HttpResponse>;
+
+    async fn insert_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, HttpResponse>;
+    /// Latest `limit` events of `xpub`, newest first.
+    async fn audit_events_lookup(&self, xpub: XpubWrapper, limit: i64) -> Result<Vec<AuditEvent>, HttpResponse>;
+
+    async fn insert_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse>;
+    async fn invoice_lookup(&self, xpub: XpubWrapper, id: ObjectId) -> Result<Invoice, HttpResponse>;
+    async fn invoices_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Invoice>, HttpResponse>;
+    /// Invoices of every user still matched against the chain at `now`.
+    async fn open_invoices(&self, now: u64) -> Result<Vec<Invoice>, HttpResponse>;
+    async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse>;
+
+    async fn insert_broadcast(&self, record: BroadcastRecord) -> Result<BroadcastRecord, HttpResponse>;
+    async fn broadcast_lookup(&self, xpub: XpubWrapper, txid: Txid) -> Result<BroadcastRecord, HttpResponse>;
+    async fn broadcasts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<BroadcastRecord>, HttpResponse>;
+
+    /// Records a deposit keyed by its outpoint, returning it only when first seen. Block data is
+    /// only overwritten when `confirmed`, so a mempool sighting never downgrades a confirmed deposit.
+    async fn upsert_deposit(&self, deposit: Deposit, confirmed: bool) -> Result<Option<Deposit>, HttpResponse>;
+    /// Moves the deposits of a disconnected block back to unconfirmed.
+    async fn revert_deposits(&self, block_hash: BlockHash) -> Result<u64, HttpResponse>;
+    /// Recomputes confirmation counts against `tip_height` for deposits below `limit` confirmations.
+    async fn update_deposit_confirmations(&self, tip_height: u32, limit: u32) -> Result<u64, HttpResponse>;
+    /// Deposits of `xpub`, newest first.
+    async fn deposits_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Deposit>, HttpResponse>;
+    /// Deposits of `xpub` confirmed in blocks from `min_height` to `max_height`.
+    async fn confirmed_deposits(&self, xpub: XpubWrapper, min_height: u32, max_height: u32) -> Result<Vec<Deposit>, HttpResponse>;
+    /// Blocks processed by the deposit watcher, `None` before its first round.
+    async fn watcher_cursor(&self) -> Result<Option<WatcherCursor>, HttpResponse>;
+    async fn set_watcher_cursor(&self, cursor: WatcherCursor) -> Result<(), HttpResponse>;
+
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<Webhook, HttpResponse>;
+    async fn webhooks_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Webhook>, HttpResponse>;
+    /// Webhooks of every user subscribed to `kind`.
+    async fn webhooks_for_event(&self, kind: EventKind) -> Result<Vec<Webhook>, HttpResponse>;
+    async fn webhook_by_id(&self, id: ObjectId) -> Result<Option<Webhook>, HttpResponse>;
+    async fn delete_webhook(&self, xpub: XpubWrapper, id: ObjectId) -> Result<(), HttpResponse>;
+
+    /// Queues a delivery, returning `None` when the same event was already queued for the webhook.
+    async fn insert_delivery(&self, delivery: Delivery) -> Result<Option<Delivery>, HttpResponse>;
+    /// Pending deliveries due at `now`, oldest first.
+    async fn due_deliveries(&self, now: u64, limit: i64) -> Result<Vec<Delivery>, HttpResponse>;
+    async fn update_delivery(&self, delivery: Delivery) -> Result<Delivery, HttpResponse>;
+    /// Latest `limit` deliveries of a webhook, newest first.
+    async fn deliveries_lookup(&self, xpub: XpubWrapper, webhook_id: ObjectId, limit: i64) -> Result<Vec<Delivery>, HttpResponse>;
+}
+
+/// Stores the address of first-time logins, returning it only when newly registered.
+pub async fn register_address(
+    storage: &dyn Storage,
+    credentials: Credentials<XpubWrapper>,
+) -> Result<Option<UserAddress<XpubWrapper>>, HttpResponse> {
+    match storage.address_lookup(credentials.xpub.clone()).await {
+        Ok(_) => Ok(None),
+        Err(response) if response.status() == StatusCode::NOT_FOUND => {
+            if !UserAddress::authenticate(credentials.clone()).await? {
+                return Err(HttpResponse::Unauthorized().json("Unauthorized"))
+            }
+            Ok(Some(storage.insert_address(UserAddress::from_credentials(credentials)).await?))
+        },
+        Err(response) => Err(response),
+    }
+}
+
+/// The stored address of the wallet the session acts on: the one selected among the wallets of
+/// its user, else the one it logged in with.
+pub async fn lookup_or_update_address(
+    storage: &dyn Storage,
+    session: Session,
+) -> Result<UserAddress<XpubWrapper>, Error> {
+    match session.get::<model::Credentials<model::XpubWrapper>>("credentials")? {
+        Some(credential) => {
+            let user_address = match storage.address_lookup(credential.xpub).await {
+                Ok(lookup_address) => lookup_address,
+                Err(err) => return Err(InternalError::from_response("", err).into())
+            };
+            // A session opened before the account was deleted does not carry over to the xpub
+            // registered again.
+            if session.get::<String>("account_id")? != user_address.get_account_id().map(|id| id.to_hex()) {
+                session.purge();
+                return Err(InternalError::from_response("", HttpResponse::Unauthorized().json("Unauthorized")).into())
+            }
+            match session.get::<XpubWrapper>("wallet")? {
+                Some(xpub) if xpub != user_address.xpub => {
+                    // The selected wallet may have been unlinked or deleted since.
+                    let user_id = user::owner_id(storage, &user_address);
+                    match storage.address_lookup(xpub).await {
+                        Ok(wallet) if user::owner_id(storage, &wallet) == user_id => Ok(wallet),
+                        _ => {
+                            session.remove("wallet");
+                            Err(InternalError::from_response("", HttpResponse::Forbidden().json("Wallet not linked")).into())
+                        },
+                    }
+                },
+                _ => Ok(user_address),
+            }
+        },
+        None => Err(InternalError::from_response("", HttpResponse::Unauthorized().json("Unauthorized")).into())
+    }
+}
+
+/// Where `record_derivation` derives the next key.
+pub enum DerivationTarget {
+    /// The given path, returning the stored key when it is already derived.
+    Path([u32; 2]),
+    /// The given path, which must not be derived yet.
+    FreshPath([u32; 2]),
+    /// The index after the highest derived one on the chain.
+    NextOnChain(u32),
+}
+
+/// Records the key derived at `target` within the quota of the user's plan. When a concurrent
+/// update bumped the version first, the address is read again and the target resolved again.
+pub async fn record_derivation(
+    storage: &dyn Storage,
+    quotas: &QuotaPolicy,
+    mut address: UserAddress<XpubWrapper>,
+    target: DerivationTarget,
+    label: Option<String>,
+) -> Result<(UserAddress<XpubWrapper>, DerivedKey), Error> {
+    let xpub = address.xpub.clone();
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let path = match target {
+            DerivationTarget::Path(path) | DerivationTarget::FreshPath(path) => path,
+            DerivationTarget::NextOnChain(chain) => {
+                let last = storage.last_derived_index(xpub.clone(), chain).await
+                    .map_err(|err| InternalError::from_response("", err))?;
+                [chain, last.map_or(0, |index| index + 1)]
+            },
+        };
+        // Past the last normal index of a chain, or a path not checked by the handler.
+        derivation::check_path(&path).map_err(ErrorBadRequest)?;
+        let stored = storage.derived_key_lookup(xpub.clone(), path).await
+            .map_err(|err| InternalError::from_response("", err))?;
+        match (stored, &target) {
+            (Some(_), DerivationTarget::FreshPath(_)) => return Err(ErrorConflict(format!("{:?} already derived", path))),
+            (Some(key), _) => return Ok((address, key)),
+            (None, _) => (),
+        }
+        let quota = quotas.quota_for(address.get_plan());
+        let derived = storage.derived_keys_count(xpub.clone()).await
+            .map_err(|err| InternalError::from_response("", err))?;
+        if derived >= quota {
+            return Err(ErrorInsufficientStorage(format!("Derivation quota of {} keys reached", quota)))
+        }
+        let key = DerivedKey::new(&address.get_xpub(), path, label.clone());
+        match storage.push_derived(xpub.clone(), address.version, vec![key.clone()]).await {
+            Ok(Some(updated)) => return Ok((updated, key)),
+            Ok(None) => {
+                address = storage.address_lookup(xpub.clone()).await
+                    .map_err(|err| InternalError::from_response("", err))?;
+            },
+            Err(err) => return Err(InternalError::from_response("", err).into()),
+        }
+    }
+    Err(ErrorConflict("Concurrent address updates, retry"))
+}
+
+/// Derivation paths to scan for `xpub`, see `balance::scan_paths`: the receive and change
+/// chains, and every other chain keys were derived on.
+pub async fn scan_paths(storage: &dyn Storage, xpub: XpubWrapper, gap_limit: u32) -> Result<Vec<[u32; 2]>, HttpResponse> {
+    let mut next_indexes: BTreeMap<u32, u32> = balance::SCAN_CHAINS.iter().map(|chain| (*chain, 0)).collect();
+    for (chain, last) in storage.last_derived_indexes(xpub).await? {
+        next_indexes.insert(chain, last.saturating_add(1));
+    }
+    Ok(balance::scan_paths(&next_indexes.into_iter().collect::<Vec<(u32, u32)>>(), gap_limit))
+}
@@ -0,0 +1,473 @@
+use std::collections::BTreeMap;
+use std::sync::{
+    Mutex,
+    MutexGuard,
+};
+use actix_web::HttpResponse;
+use async_trait::async_trait;
+use bitcoin::{
+    BlockHash,
+    Txid,
+};
+use mongodb::bson::oid::ObjectId;
+use rand::RngCore;
+use crate::model::{
+    Nonce,
+    SaltedFingerPrint,
+    UserAddress,
+    XpubWrapper,
+    audit::AuditEvent,
+    broadcast::BroadcastRecord,
+    derived::{
+        DerivedKey,
+        DerivedKeyFilter,
+        DerivedKeyPage,
+    },
+    invoice::Invoice,
+    psbt::PsbtRecord,
+    schema::MigrationReport,
+    vault::RotationReport,
+    watcher::{
+        Deposit,
+        WatcherCursor,
+    },
+    webhook::{
+        Delivery,
+        DeliveryStatus,
+        EventKind,
+        Webhook,
+    },
+};
+use super::Storage;
+
+#[derive(Default)]
+struct MemoryState {
+    addresses: Vec<UserAddress<XpubWrapper>>,
+    derived_keys: Vec<DerivedKey>,
+    psbts: Vec<PsbtRecord>,
+    audit_events: Vec<AuditEvent>,
+    invoices: Vec<Invoice>,
+    broadcasts: Vec<BroadcastRecord>,
+    deposits: Vec<Deposit>,
+    webhooks: Vec<Webhook>,
+    deliveries: Vec<Delivery>,
+    watcher_cursor: Option<WatcherCursor>,
+}
+
+/// Storage kept in process memory, for tests and local development. Nothing survives a restart,
+/// user ids included: their salt is drawn at startup.
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+    salt: [u8; 32],
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        let mut salt = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut salt);
+        MemoryStorage {
+            state: Mutex::default(),
+            salt,
+        }
+    }
+    fn state(&self) -> Result<MutexGuard<'_, MemoryState>, HttpResponse> {
+        self.state.lock().map_err(|err| HttpResponse::InternalServerError().body(err.to_string()))
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json("NotFound")
+}
+
+/// Removes the items matching `predicate`, returning how many.
+fn remove_where<T>(items: &mut Vec<T>, predicate: impl Fn(&T) -> bool) -> u64 {
+    let before = items.len();
+    items.retain(|item| !predicate(item));
+    (before - items.len()) as u64
+}
+
+fn newest_first<T: Clone>(items: impl Iterator<Item = T>, key: impl Fn(&T) -> u64, limit: Option<i64>) -> Vec<T> {
+    let mut items: Vec<T> = items.collect();
+    // Stable sort on reversed items keeps later insertions first among equal keys.
+    items.reverse();
+    items.sort_by_key(|item| std::cmp::Reverse(key(item)));
+    if let Some(limit) = limit {
+        items.truncate(limit.max(0) as usize);
+    }
+    items
+}
+
+#[async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn init(&self) -> Result<(), HttpResponse> {
+        Ok(())
+    }
+
+    async fn migrate(&self, dry_run: bool) -> Result<MigrationReport, HttpResponse> {
+        // Nothing outlives the process, so every record has the current shape.
+        Ok(MigrationReport::new(dry_run))
+    }
+
+    async fn rotate_keys(&self, dry_run: bool) -> Result<RotationReport, HttpResponse> {
+        // Records stay in process memory, where nothing is sealed.
+        Ok(RotationReport::new(dry_run, None))
+    }
+
+    fn wallet_id(&self, xpub: &XpubWrapper) -> SaltedFingerPrint {
+        SaltedFingerPrint::new(&self.salt, xpub)
+    }
+
+    async fn insert_address(&self, address: UserAddress<XpubWrapper>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let mut state = self.state()?;
+        if state.addresses.iter().any(|stored| stored.xpub == address.xpub) {
+            return Err(HttpResponse::Conflict().json("Conflict"))
+        }
+        state.addresses.push(address.clone());
+        Ok(address)
+    }
+
+    async fn address_lookup(&self, xpub: XpubWrapper) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        self.state()?.addresses.iter().find(|address| address.xpub == xpub).cloned().ok_or_else(not_found)
+    }
+
+    async fn push_derived(&self, xpub: XpubWrapper, version: u64, derived: Vec<DerivedKey>) -> Result<Option<UserAddress<XpubWrapper>>, HttpResponse> {
+        let mut state = self.state()?;
+        let stored = state.addresses.iter_mut().find(|stored| stored.xpub == xpub).ok_or_else(not_found)?;
+        if stored.version != version {
+            return Ok(None)
+        }
+        stored.version += 1;
+        let updated = stored.clone();
+        state.derived_keys.extend(derived);
+        Ok(Some(updated))
+    }
+
+    async fn all_addresses(&self) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse> {
+        Ok(self.state()?.addresses.clone())
+    }
+
+    async fn advance_nonce(&self, xpub: XpubWrapper, nonce: Nonce) -> Result<Option<Nonce>, HttpResponse> {
+        let next = nonce.next()?;
+        let mut state = self.state()?;
+        match state.addresses.iter_mut().find(|stored| stored.xpub == xpub && stored.nonce == nonce) {
+            Some(stored) => {
+                stored.nonce = next;
+                Ok(Some(stored.nonce.clone()))
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_account(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse> {
+        let mut state = self.state()?;
+        let owned = |owner: &XpubWrapper| *owner == xpub;
+        let deleted = remove_where(&mut state.addresses, |address| owned(&address.xpub));
+        if deleted == 0 {
+            return Err(not_found())
+        }
+        Ok(deleted
+            + remove_where(&mut state.derived_keys, |key| owned(key.get_xpub()))
+            + remove_where(&mut state.psbts, |record| owned(record.get_xpub()))
+            + remove_where(&mut state.audit_events, |event| owned(event.get_xpub()))
+            + remove_where(&mut state.invoices, |invoice| owned(invoice.get_xpub()))
+            + remove_where(&mut state.broadcasts, |record| owned(record.get_xpub()))
+            + remove_where(&mut state.deposits, |deposit| owned(deposit.get_xpub()))
+            + remove_where(&mut state.webhooks, |webhook| owned(webhook.get_xpub()))
+            + remove_where(&mut state.deliveries, |delivery| owned(delivery.get_xpub())))
+    }
+
+    async fn set_plan(&self, xpub: XpubWrapper, plan: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let mut state = self.state()?;
+        let stored = state.addresses.iter_mut().find(|stored| stored.xpub == xpub).ok_or_else(not_found)?;
+        stored.plan = plan;
+        Ok(stored.clone())
+    }
+
+    async fn wallets_lookup(&self, user_id: &SaltedFingerPrint) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse> {
+        Ok(self.state()?.addresses
+            .iter()
+            .filter(|address| match &address.user_id {
+                Some(owner) => owner == user_id,
+                None => self.wallet_id(&address.xpub) == *user_id,
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn link_wallet(&self, xpub: XpubWrapper, user_id: Option<SaltedFingerPrint>, label: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let mut state = self.state()?;
+        let stored = state.addresses.iter_mut().find(|stored| stored.xpub == xpub).ok_or_else(not_found)?;
+        stored.user_id = user_id;
+        stored.label = label;
+        Ok(stored.clone())
+    }
+
+    async fn derived_key_lookup(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<Option<DerivedKey>, HttpResponse> {
+        Ok(self.state()?.derived_keys
+            .iter()
+            .find(|key| *key.get_xpub() == xpub && key.get_derivation_path() == path)
+            .cloned())
+    }
+
+    async fn derived_keys_count(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse> {
+        Ok(self.state()?.derived_keys.iter().filter(|key| *key.get_xpub() == xpub).count() as u64)
+    }
+
+    async fn all_derived_keys(&self, xpub: XpubWrapper) -> Result<Vec<DerivedKey>, HttpResponse> {
+        let mut keys: Vec<DerivedKey> = self.state()?.derived_keys.iter().filter(|key| *key.get_xpub() == xpub).cloned().collect();
+        keys.sort_by_key(DerivedKey::get_derivation_path);
+        Ok(keys)
+    }
+
+    async fn last_derived_index(&self, xpub: XpubWrapper, chain: u32) -> Result<Option<u32>, HttpResponse> {
+        Ok(self.state()?.derived_keys
+            .iter()
+            .filter(|key| *key.get_xpub() == xpub && key.get_chain() == chain)
+            .map(DerivedKey::get_index)
+            .max())
+    }
+
+    async fn last_derived_indexes(&self, xpub: XpubWrapper) -> Result<Vec<(u32, u32)>, HttpResponse> {
+        let mut last: BTreeMap<u32, u32> = BTreeMap::new();
+        for key in self.state()?.derived_keys.iter().filter(|key| *key.get_xpub() == xpub) {
+            let index = last.entry(key.get_chain()).or_insert(key.get_index());
+            *index = (*index).max(key.get_index());
+        }
+        Ok(last.into_iter().collect())
+    }
+
+    async fn derived_keys_lookup(&self, xpub: XpubWrapper, filter: &DerivedKeyFilter) -> Result<DerivedKeyPage, HttpResponse> {
+        let state = self.state()?;
+        let mut keys: Vec<DerivedKey> = state.derived_keys
+            .iter()
+            .filter(|key| *key.get_xpub() == xpub && key.matches(filter))
+            .cloned()
+            .collect();
+        keys.sort_by_key(DerivedKey::get_derivation_path);
+        let total = keys.len() as u64;
+        let page = keys
+            .into_iter()
+            .skip(filter.get_offset() as usize)
+            .take(filter.get_per_page() as usize)
+            .collect();
+        Ok(DerivedKeyPage::new(filter, total, page))
+    }
+
+    async fn mark_used(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<(), HttpResponse> {
+        let mut state = self.state()?;
+        if let Some(key) = state.derived_keys.iter_mut().find(|key| *key.get_xpub() == xpub && key.get_derivation_path() == path) {
+            key.mark_used();
+        }
+        Ok(())
+    }
+
+    async fn upsert_psbt(&self, record: PsbtRecord) -> Result<PsbtRecord, HttpResponse> {
+        let mut state = self.state()?;
+        state.psbts.retain(|stored| stored.get_xpub() != record.get_xpub() || stored.get_txid() != record.get_txid());
+        state.psbts.push(record.clone());
+        Ok(record)
+    }
+
+    async fn psbts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<PsbtRecord>, HttpResponse> {
+        let state = self.state()?;
+        let records = state.psbts.iter().filter(|record| *record.get_xpub() == xpub).cloned();
+        Ok(newest_first(records, PsbtRecord::get_updated_at, None))
+    }
+
+    async fn insert_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, HttpResponse> {
+        self.state()?.audit_events.push(event.clone());
+        Ok(event)
+    }
+
+    async fn audit_events_lookup(&self, xpub: XpubWrapper, limit: i64) -> Result<Vec<AuditEvent>, HttpResponse> {
+        let state = self.state()?;
+        let events = state.audit_events.iter().filter(|event| *event.get_xpub() == xpub).cloned();
+        Ok(newest_first(events, AuditEvent::get_at, Some(limit)))
+    }
+
+    async fn insert_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse> {
+        self.state()?.invoices.push(invoice.clone());
+        Ok(invoice)
+    }
+
+    async fn invoice_lookup(&self, xpub: XpubWrapper, id: ObjectId) -> Result<Invoice, HttpResponse> {
+        self.state()?.invoices
+            .iter()
+            .find(|invoice| invoice.get_id() == id && *invoice.get_xpub() == xpub)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn invoices_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Invoice>, HttpResponse> {
+        Ok(self.state()?.invoices.iter().filter(|invoice| *invoice.get_xpub() == xpub).cloned().collect())
+    }
+
+    async fn open_invoices(&self, now: u64) -> Result<Vec<Invoice>, HttpResponse> {
+        Ok(self.state()?.invoices.iter().filter(|invoice| invoice.is_open(now)).cloned().collect())
+    }
+
+    async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse> {
+        let mut state = self.state()?;
+        if let Some(stored) = state.invoices.iter_mut().find(|stored| stored.get_id() == invoice.get_id()) {
+            *stored = invoice.clone();
+        }
+        Ok(invoice)
+    }
+
+    async fn insert_broadcast(&self, record: BroadcastRecord) -> Result<BroadcastRecord, HttpResponse> {
+        self.state()?.broadcasts.push(record.clone());
+        Ok(record)
+    }
+
+    async fn broadcast_lookup(&self, xpub: XpubWrapper, txid: Txid) -> Result<BroadcastRecord, HttpResponse> {
+        self.state()?.broadcasts
+            .iter()
+            .find(|record| record.get_txid() == txid && *record.get_xpub() == xpub)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn broadcasts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<BroadcastRecord>, HttpResponse> {
+        Ok(self.state()?.broadcasts.iter().filter(|record| *record.get_xpub() == xpub).cloned().collect())
+    }
+
+    async fn upsert_deposit(&self, deposit: Deposit, confirmed: bool) -> Result<Option<Deposit>, HttpResponse> {
+        let mut state = self.state()?;
+        let stored = state.deposits
+            .iter_mut()
+            .find(|stored| stored.get_txid() == deposit.get_txid() && stored.get_vout() == deposit.get_vout());
+        match stored {
+            Some(stored) => {
+                if confirmed {
+                    stored.confirm_as(&deposit);
+                }
+                Ok(None)
+            },
+            None => {
+                state.deposits.push(deposit.clone());
+                Ok(Some(deposit))
+            },
+        }
+    }
+
+    async fn revert_deposits(&self, block_hash: BlockHash) -> Result<u64, HttpResponse> {
+        let mut state = self.state()?;
+        let mut reverted = 0;
+        for deposit in state.deposits.iter_mut().filter(|deposit| deposit.get_block_hash() == Some(block_hash)) {
+            deposit.revert();
+            reverted += 1;
+        }
+        Ok(reverted)
+    }
+
+    async fn update_deposit_confirmations(&self, tip_height: u32, limit: u32) -> Result<u64, HttpResponse> {
+        let mut state = self.state()?;
+        let mut updated = 0;
+        for deposit in state.deposits
+            .iter_mut()
+            .filter(|deposit| deposit.get_block_height().is_some() && deposit.get_confirmations() < limit)
+        {
+            deposit.set_tip_height(tip_height);
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    async fn deposits_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Deposit>, HttpResponse> {
+        let state = self.state()?;
+        let deposits = state.deposits.iter().filter(|deposit| *deposit.get_xpub() == xpub).cloned();
+        Ok(newest_first(deposits, Deposit::get_first_seen, None))
+    }
+
+    async fn confirmed_deposits(&self, xpub: XpubWrapper, min_height: u32, max_height: u32) -> Result<Vec<Deposit>, HttpResponse> {
+        Ok(self.state()?.deposits
+            .iter()
+            .filter(|deposit| *deposit.get_xpub() == xpub)
+            .filter(|deposit| deposit.get_block_height().is_some_and(|height| (min_height..=max_height).contains(&height)))
+            .cloned()
+            .collect())
+    }
+
+    async fn watcher_cursor(&self) -> Result<Option<WatcherCursor>, HttpResponse> {
+        Ok(self.state()?.watcher_cursor.clone())
+    }
+
+    async fn set_watcher_cursor(&self, cursor: WatcherCursor) -> Result<(), HttpResponse> {
+        self.state()?.watcher_cursor = Some(cursor);
+        Ok(())
+    }
+
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<Webhook, HttpResponse> {
+        self.state()?.webhooks.push(webhook.clone());
+        Ok(webhook)
+    }
+
+    async fn webhooks_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Webhook>, HttpResponse> {
+        Ok(self.state()?.webhooks.iter().filter(|webhook| *webhook.get_xpub() == xpub).cloned().collect())
+    }
+
+    async fn webhooks_for_event(&self, kind: EventKind) -> Result<Vec<Webhook>, HttpResponse> {
+        Ok(self.state()?.webhooks.iter().filter(|webhook| webhook.subscribes(kind)).cloned().collect())
+    }
+
+    async fn webhook_by_id(&self, id: ObjectId) -> Result<Option<Webhook>, HttpResponse> {
+        Ok(self.state()?.webhooks.iter().find(|webhook| webhook.get_id() == id).cloned())
+    }
+
+    async fn delete_webhook(&self, xpub: XpubWrapper, id: ObjectId) -> Result<(), HttpResponse> {
+        let mut state = self.state()?;
+        let position = state.webhooks
+            .iter()
+            .position(|webhook| webhook.get_id() == id && *webhook.get_xpub() == xpub)
+            .ok_or_else(not_found)?;
+        state.webhooks.remove(position);
+        Ok(())
+    }
+
+    async fn insert_delivery(&self, delivery: Delivery) -> Result<Option<Delivery>, HttpResponse> {
+        let mut state = self.state()?;
+        let duplicate = state.deliveries.iter().any(|stored| {
+            stored.get_webhook_id() == delivery.get_webhook_id() && stored.get_event_key() == delivery.get_event_key()
+        });
+        if duplicate {
+            return Ok(None)
+        }
+        state.deliveries.push(delivery.clone());
+        Ok(Some(delivery))
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: i64) -> Result<Vec<Delivery>, HttpResponse> {
+        let state = self.state()?;
+        let mut due: Vec<Delivery> = state.deliveries
+            .iter()
+            .filter(|delivery| delivery.get_status() == DeliveryStatus::Pending && delivery.get_next_attempt_at() <= now)
+            .cloned()
+            .collect();
+        due.sort_by_key(Delivery::get_next_attempt_at);
+        due.truncate(limit.max(0) as usize);
+        Ok(due)
+    }
+
+    async fn update_delivery(&self, delivery: Delivery) -> Result<Delivery, HttpResponse> {
+        let mut state = self.state()?;
+        if let Some(stored) = state.deliveries.iter_mut().find(|stored| stored.get_id() == delivery.get_id()) {
+            *stored = delivery.clone();
+        }
+        Ok(delivery)
+    }
+
+    async fn deliveries_lookup(&self, xpub: XpubWrapper, webhook_id: ObjectId, limit: i64) -> Result<Vec<Delivery>, HttpResponse> {
+        let state = self.state()?;
+        let deliveries = state.deliveries
+            .iter()
+            .filter(|delivery| delivery.get_webhook_id() == webhook_id && *delivery.get_xpub() == xpub)
+            .cloned();
+        Ok(newest_first(deliveries, Delivery::get_created_at, Some(limit)))
+    }
+}
@@ -0,0 +1,832 @@
+use actix_web::HttpResponse;
+use async_trait::async_trait;
+use bitcoin::{
+    BlockHash,
+    Txid,
+};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Client,
+    Collection,
+    IndexModel,
+    bson::{
+        doc,
+        from_bson,
+        from_document,
+        oid::ObjectId,
+        to_bson,
+        to_document,
+        Bson,
+        Document,
+    },
+    options::{
+        IndexOptions,
+        ReturnDocument,
+    },
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+use crate::{
+    COLL_NAME,
+    DERIVED_KEY_COLL_NAME,
+    INVOICE_COLL_NAME,
+    BROADCAST_COLL_NAME,
+    DEPOSIT_COLL_NAME,
+    WEBHOOK_COLL_NAME,
+    DELIVERY_COLL_NAME,
+    PSBT_COLL_NAME,
+    AUDIT_COLL_NAME,
+    WATCHER_COLL_NAME,
+};
+use crate::model::{
+    Nonce,
+    SaltedFingerPrint,
+    UserAddress,
+    XpubWrapper,
+    audit::AuditEvent,
+    broadcast::BroadcastRecord,
+    derived::{
+        self,
+        DerivedKey,
+        DerivedKeyFilter,
+        DerivedKeyPage,
+    },
+    invoice::{
+        Invoice,
+        FINAL_STATUSES,
+        LATE_PAYMENT_SECS,
+    },
+    psbt::PsbtRecord,
+    schema::{
+        MigrationReport,
+        MigrationStep,
+    },
+    vault::{
+        RotationReport,
+        Vault,
+        FINGERPRINT_FIELD,
+        SEALED_FIELDS,
+    },
+    watcher::{
+        Deposit,
+        WatcherCursor,
+    },
+    webhook::{
+        Delivery,
+        DeliveryStatus,
+        EventKind,
+        EventPayload,
+        Webhook,
+    },
+};
+use super::Storage;
+
+fn internal_error(err: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::InternalServerError().body(err.to_string())
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        mongodb::error::ErrorKind::Write(mongodb::error::WriteFailure::WriteError(write_error)) => write_error.code == 11000,
+        mongodb::error::ErrorKind::InsertMany(insert_error) => insert_error.write_errors
+            .iter()
+            .flatten()
+            .any(|write_error| write_error.code == 11000),
+        _ => false,
+    }
+}
+
+/// Storage backed by a MongoDB database. Xpubs and derived addresses are stored sealed by
+/// `vault`, and records are looked up by the salted fingerprint of their owner's xpub.
+pub struct MongoStorage {
+    client: Client,
+    database: String,
+    addresses_collection: String,
+    vault: Vault,
+}
+
+impl MongoStorage {
+    /// Storage in the database `database`, its address records in `addresses_collection`
+    /// (`DB_NAME` and `COLL_NAME` by default).
+    pub fn new(client: Client, database: &str, addresses_collection: &str, vault: Vault) -> Self {
+        MongoStorage {
+            client,
+            database: database.into(),
+            addresses_collection: addresses_collection.into(),
+            vault,
+        }
+    }
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+    fn collection<T: Send + Sync>(&self, name: &str) -> Collection<T> {
+        let name = if name == COLL_NAME { &self.addresses_collection } else { name };
+        self.client.database(&self.database).collection(name)
+    }
+    /// Filter on the records of `xpub`.
+    fn owned_by(&self, xpub: &XpubWrapper) -> Document {
+        doc! {FINGERPRINT_FIELD: self.vault.fingerprint(xpub)}
+    }
+    fn seal(&self, record: &impl Serialize) -> Result<Document, HttpResponse> {
+        let mut document = to_document(record).map_err(internal_error)?;
+        self.vault.seal_record(&mut document)?;
+        Ok(document)
+    }
+    fn open<T: DeserializeOwned>(&self, mut document: Document) -> Result<T, HttpResponse> {
+        self.vault.open_record(&mut document)?;
+        from_document(document).map_err(internal_error)
+    }
+    /// Takes out the derived keys `push_derived` inserted, if any, when it fails.
+    async fn remove_derived(&self, ids: &[ObjectId]) -> Result<(), HttpResponse> {
+        if ids.is_empty() {
+            return Ok(())
+        }
+        self.collection::<Document>(DERIVED_KEY_COLL_NAME)
+            .delete_many(doc! {"_id": {"$in": ids}})
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+    async fn find_all<T>(&self, name: &str, filter: Document, sort: Document, limit: Option<i64>) -> Result<Vec<T>, HttpResponse>
+    where
+        T: DeserializeOwned + Send + Sync,
+    {
+        let collection: Collection<Document> = self.collection(name);
+        let mut find = collection.find(filter).sort(sort);
+        if let Some(limit) = limit {
+            find = find.limit(limit);
+        }
+        let cursor = find.await.map_err(internal_error)?;
+        let documents: Vec<Document> = cursor.try_collect().await.map_err(internal_error)?;
+        documents.into_iter().map(|document| self.open(document)).collect()
+    }
+    async fn find_one<T>(&self, name: &str, filter: Document) -> Result<Option<T>, HttpResponse>
+    where
+        T: DeserializeOwned + Send + Sync,
+    {
+        let collection: Collection<Document> = self.collection(name);
+        let document = collection.find_one(filter).await.map_err(internal_error)?;
+        document.map(|document| self.open(document)).transpose()
+    }
+    async fn insert<T: Serialize>(&self, name: &str, record: &T) -> Result<(), HttpResponse> {
+        let document = self.seal(record)?;
+        self.collection::<Document>(name).insert_one(document).await.map_err(internal_error)?;
+        Ok(())
+    }
+    async fn create_unique_index<T: Send + Sync>(&self, name: &str, keys: Document) -> Result<(), mongodb::error::Error> {
+        self.create_index::<T>(name, keys, IndexOptions::builder().unique(true).build()).await
+    }
+    /// Unique index over records keyed by fingerprint, leaving out those still awaiting the
+    /// migration that seals them.
+    async fn create_owned_index<T: Send + Sync>(&self, name: &str, keys: Document) -> Result<(), mongodb::error::Error> {
+        let options = IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {FINGERPRINT_FIELD: {"$exists": true}})
+            .build();
+        self.create_index::<T>(name, keys, options).await
+    }
+    async fn create_index<T: Send + Sync>(&self, name: &str, keys: Document, options: IndexOptions) -> Result<(), mongodb::error::Error> {
+        let model = IndexModel::builder()
+            .keys(keys)
+            .options(options)
+            .build();
+        self.collection::<T>(name).create_index(model).await?;
+        Ok(())
+    }
+}
+
+/// Collections of records kept for an xpub, besides its address.
+const OWNED_COLLECTIONS: [&str; 8] = [
+    DERIVED_KEY_COLL_NAME,
+    PSBT_COLL_NAME,
+    AUDIT_COLL_NAME,
+    INVOICE_COLL_NAME,
+    BROADCAST_COLL_NAME,
+    DEPOSIT_COLL_NAME,
+    WEBHOOK_COLL_NAME,
+    DELIVERY_COLL_NAME,
+];
+
+/// `_id` of the single document in the watcher collection.
+const WATCHER_CURSOR_ID: &str = "cursor";
+
+/// Document upgrade to `to_version` from the version before. Records read and written back by
+/// an older build keep their old version, so upgrades must accept documents already upgraded.
+struct Migration {
+    collection: &'static str,
+    to_version: u32,
+    description: &'static str,
+    /// Upgrades the document in place, returning the documents it moves to other collections.
+    upgrade: fn(&mut Document) -> Vec<(&'static str, Document)>,
+}
+
+fn stamp_only(_: &mut Document) -> Vec<(&'static str, Document)> {
+    Vec::new()
+}
+
+/// Moves the derived xpubs kept in `xpub_list` to their own documents, with the path recovered
+/// from the key. Keys whose path is not found stay in `xpub_list`, which nothing reads anymore.
+fn move_derivation_history(document: &mut Document) -> Vec<(&'static str, Document)> {
+    let xpub: XpubWrapper = match document.get("xpub").cloned().map(from_bson) {
+        Some(Ok(xpub)) => xpub,
+        _ => return Vec::new(),
+    };
+    let legacy: Vec<XpubWrapper> = match document.remove("xpub_list").map(from_bson) {
+        Some(Ok(legacy)) => legacy,
+        _ => return Vec::new(),
+    };
+    let parent = xpub.to_xpub();
+    let paths = derived::legacy_paths(&parent, &legacy);
+    let mut moved = Vec::new();
+    let mut unresolved = Vec::new();
+    for (key, path) in legacy.into_iter().zip(paths) {
+        match path.map(|path| to_document(&DerivedKey::new(&parent, path, None))) {
+            Some(Ok(key_doc)) => moved.push((DERIVED_KEY_COLL_NAME, key_doc)),
+            _ => unresolved.push(Bson::from(key)),
+        }
+    }
+    if !unresolved.is_empty() {
+        tracing::warn!("{} keys of address record {:?} have no recoverable path, left in xpub_list", unresolved.len(), document.get("_id"));
+        document.insert("xpub_list", unresolved);
+    }
+    moved
+}
+
+/// Replaces the xpub in the event of a delivery with the user id of its owner, which the
+/// document holds since it was sealed.
+fn name_event_owner(document: &mut Document) -> Vec<(&'static str, Document)> {
+    let user_id: Option<SaltedFingerPrint> = document.get(FINGERPRINT_FIELD).cloned().and_then(|value| from_bson(value).ok());
+    let event: Option<serde_json::Value> = document.get("event").cloned().and_then(|value| from_bson(value).ok());
+    if let (Some(user_id), Some(event)) = (user_id, event) {
+        match EventPayload::redact(event, user_id).map(|payload| to_bson(&payload)) {
+            Ok(Ok(payload)) => {
+                document.insert("event", payload);
+            },
+            _ => tracing::warn!("Delivery {:?} has an unreadable event", document.get("_id")),
+        }
+    }
+    Vec::new()
+}
+
+const SEAL_DESCRIPTION: &str = "Seal xpubs and key records by salted fingerprint";
+
+/// Every document migration, in the order they run.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        collection: COLL_NAME,
+        to_version: 1,
+        description: "Add the derivation history version",
+        upgrade: |document| {
+            if !document.contains_key("version") {
+                document.insert("version", 0_i64);
+            }
+            Vec::new()
+        },
+    },
+    Migration {
+        collection: COLL_NAME,
+        to_version: 2,
+        description: "Move the derivation history to derived_keys",
+        upgrade: move_derivation_history,
+    },
+    Migration { collection: INVOICE_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    Migration { collection: BROADCAST_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    Migration { collection: DEPOSIT_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    Migration { collection: WEBHOOK_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    Migration { collection: DELIVERY_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    Migration { collection: PSBT_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    Migration { collection: AUDIT_COLL_NAME, to_version: 1, description: "Add the schema version", upgrade: stamp_only },
+    // Migrated documents are written back sealed, so these only need the version stamp.
+    Migration { collection: COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: DERIVED_KEY_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: INVOICE_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: BROADCAST_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: DEPOSIT_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: WEBHOOK_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: DELIVERY_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: PSBT_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration { collection: AUDIT_COLL_NAME, to_version: 3, description: SEAL_DESCRIPTION, upgrade: stamp_only },
+    Migration {
+        collection: DELIVERY_COLL_NAME,
+        to_version: 4,
+        description: "Name webhook event owners by user id",
+        upgrade: name_event_owner,
+    },
+];
+
+/// Documents with a value sealed under another key than `key_id`, or not bound to its record.
+fn sealed_under_other_key(key_id: &str) -> Document {
+    let conditions: Vec<Document> = SEALED_FIELDS
+        .iter()
+        .flat_map(|field| [
+            doc! {format!("{}.key_id", field): {"$exists": true, "$ne": key_id}},
+            doc! {format!("{}.key_id", field): {"$exists": true}, format!("{}.bound", field): {"$ne": true}},
+        ])
+        .collect();
+    doc! {"$or": conditions}
+}
+
+/// Documents older than `version`, including those written before versioning.
+fn older_than(version: u32) -> Document {
+    doc! {
+        "$or": [
+            { "schema_version": { "$lt": version as i64 } },
+            { "schema_version": Bson::Null },
+        ]
+    }
+}
+
+fn bson_of(value: &impl Serialize) -> Result<Bson, HttpResponse> {
+    to_bson(value).map_err(internal_error)
+}
+
+#[async_trait(?Send)]
+impl Storage for MongoStorage {
+    async fn init(&self) -> Result<(), HttpResponse> {
+        // Make addresses' persistent references unique.
+        self.create_owned_index::<Document>(COLL_NAME, doc! {FINGERPRINT_FIELD: 1}).await.map_err(internal_error)?;
+        self.create_index::<Document>(COLL_NAME, doc! {"user_id": 1}, IndexOptions::default()).await.map_err(internal_error)?;
+        self.create_owned_index::<Document>(DERIVED_KEY_COLL_NAME, doc! {FINGERPRINT_FIELD: 1, "chain": 1, "index": 1}).await.map_err(internal_error)?;
+        // One delivery per webhook and event.
+        self.create_unique_index::<Delivery>(DELIVERY_COLL_NAME, doc! {"webhook_id": 1, "event_key": 1}).await.map_err(internal_error)?;
+        self.create_owned_index::<Document>(PSBT_COLL_NAME, doc! {FINGERPRINT_FIELD: 1, "txid": 1}).await.map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn migrate(&self, dry_run: bool) -> Result<MigrationReport, HttpResponse> {
+        let mut report = MigrationReport::new(dry_run);
+        for migration in MIGRATIONS {
+            let collection: Collection<Document> = self.collection(migration.collection);
+            let filter_doc = older_than(migration.to_version);
+            let pending = collection.count_documents(filter_doc.clone()).await.map_err(internal_error)?;
+            let mut step = MigrationStep::new(migration.collection, migration.to_version - 1, migration.to_version, migration.description, pending);
+            if !dry_run && pending > 0 {
+                let mut cursor = collection.find(filter_doc).await.map_err(internal_error)?;
+                while let Some(mut document) = cursor.try_next().await.map_err(internal_error)? {
+                    let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+                    // Upgrades see plain values, whichever version sealed the document.
+                    self.vault.open_record(&mut document)?;
+                    for (name, mut moved) in (migration.upgrade)(&mut document) {
+                        self.vault.seal_record(&mut moved)?;
+                        // Already moved by an interrupted run.
+                        match self.collection::<Document>(name).insert_one(moved).await {
+                            Ok(_) => (),
+                            Err(err) if is_duplicate_key(&err) => (),
+                            Err(err) => return Err(internal_error(err)),
+                        }
+                    }
+                    self.vault.seal_record(&mut document)?;
+                    document.insert("schema_version", migration.to_version as i64);
+                    collection.replace_one(doc! {"_id": id}, document).await.map_err(internal_error)?;
+                    step.record_progress();
+                }
+            }
+            report.push(step);
+        }
+        Ok(report)
+    }
+
+    async fn rotate_keys(&self, dry_run: bool) -> Result<RotationReport, HttpResponse> {
+        let key_id = self.vault.get_active_key_id();
+        let mut report = RotationReport::new(dry_run, Some(key_id));
+        for name in [COLL_NAME].into_iter().chain(OWNED_COLLECTIONS) {
+            let collection: Collection<Document> = self.collection(name);
+            let mut cursor = collection.find(sealed_under_other_key(key_id)).await.map_err(internal_error)?;
+            while let Some(mut document) = cursor.try_next().await.map_err(internal_error)? {
+                if !dry_run {
+                    let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+                    self.vault.rewrap_record(&mut document)?;
+                    collection.replace_one(doc! {"_id": id}, document).await.map_err(internal_error)?;
+                }
+                report.record();
+            }
+        }
+        Ok(report)
+    }
+
+    fn wallet_id(&self, xpub: &XpubWrapper) -> SaltedFingerPrint {
+        self.vault.fingerprint(xpub)
+    }
+
+    async fn insert_address(&self, address: UserAddress<XpubWrapper>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        self.insert(COLL_NAME, &address).await?;
+        Ok(address)
+    }
+
+    async fn address_lookup(&self, xpub: XpubWrapper) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        match self.find_one(COLL_NAME, self.owned_by(&xpub)).await? {
+            Some(address) => Ok(address),
+            None => Err(HttpResponse::NotFound().json("NotFound")),
+        }
+    }
+
+    async fn push_derived(&self, xpub: XpubWrapper, version: u64, derived: Vec<DerivedKey>) -> Result<Option<UserAddress<XpubWrapper>>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(COLL_NAME);
+        // Records written before versioning have no `version` field, which stands for 0.
+        let version_filter = match version {
+            0 => doc! {"$in": [0_i64, Bson::Null]},
+            version => doc! {"$eq": version as i64},
+        };
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("version", version_filter);
+        // The keys go in first, with ids of our own to take them out again: a path derived
+        // concurrently fails on the unique index, and a version claimed concurrently removes them,
+        // so that the keys are stored only together with the version bump.
+        let mut keys: Vec<Document> = derived.iter().map(|key| self.seal(key)).collect::<Result<_, _>>()?;
+        let ids: Vec<ObjectId> = keys.iter_mut()
+            .map(|key| {
+                let id = ObjectId::new();
+                key.insert("_id", id);
+                id
+            })
+            .collect();
+        if !keys.is_empty() {
+            match self.collection::<Document>(DERIVED_KEY_COLL_NAME).insert_many(keys).await {
+                Ok(_) => (),
+                Err(err) => {
+                    self.remove_derived(&ids).await?;
+                    return if is_duplicate_key(&err) { Ok(None) } else { Err(internal_error(err)) }
+                },
+            }
+        }
+        let updated = collection.find_one_and_update(filter_doc, doc! {"$inc": { "version": 1_i64 }})
+            .return_document(ReturnDocument::After)
+            .await;
+        match updated {
+            Ok(Some(address)) => self.open(address).map(Some),
+            Ok(None) => {
+                self.remove_derived(&ids).await?;
+                Ok(None)
+            },
+            Err(err) => {
+                self.remove_derived(&ids).await?;
+                Err(internal_error(err))
+            },
+        }
+    }
+
+    async fn all_addresses(&self) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse> {
+        self.find_all(COLL_NAME, doc! {}, doc! {}, None).await
+    }
+
+    async fn advance_nonce(&self, xpub: XpubWrapper, nonce: Nonce) -> Result<Option<Nonce>, HttpResponse> {
+        let next = nonce.next()?;
+        let collection: Collection<Document> = self.collection(COLL_NAME);
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("nonce", bson_of(&nonce)?);
+        match collection.update_one(filter_doc, doc! {"$inc": {"nonce": 1_i64}}).await {
+            Ok(result) if result.matched_count == 0 => Ok(None),
+            Ok(_) => Ok(Some(next)),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn delete_account(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse> {
+        let users: Collection<Document> = self.collection(COLL_NAME);
+        if users.count_documents(self.owned_by(&xpub)).await.map_err(internal_error)? == 0 {
+            return Err(HttpResponse::NotFound().json("NotFound"))
+        }
+        // The user goes last: when a deletion fails half way, the account is still there for
+        // its owner to delete again, rather than its records being left without it.
+        let mut total = 0;
+        for name in OWNED_COLLECTIONS {
+            let collection: Collection<Document> = self.collection(name);
+            total += collection.delete_many(self.owned_by(&xpub)).await.map_err(internal_error)?.deleted_count;
+        }
+        total += users.delete_one(self.owned_by(&xpub)).await.map_err(internal_error)?.deleted_count;
+        Ok(total)
+    }
+
+    async fn set_plan(&self, xpub: XpubWrapper, plan: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(COLL_NAME);
+        let update_doc = match plan {
+            Some(plan) => doc! {"$set": {"plan": plan}},
+            None => doc! {"$unset": {"plan": ""}},
+        };
+        match collection.find_one_and_update(self.owned_by(&xpub), update_doc).return_document(ReturnDocument::After).await {
+            Ok(Some(address)) => self.open(address),
+            Ok(None) => Err(HttpResponse::NotFound().json("NotFound")),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn wallets_lookup(&self, user_id: &SaltedFingerPrint) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse> {
+        let filter_doc = doc! {
+            "$or": [
+                { "user_id": user_id.clone() },
+                { FINGERPRINT_FIELD: user_id.clone(), "user_id": Bson::Null },
+            ]
+        };
+        self.find_all(COLL_NAME, filter_doc, doc! {"_id": 1}, None).await
+    }
+
+    async fn link_wallet(&self, xpub: XpubWrapper, user_id: Option<SaltedFingerPrint>, label: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(COLL_NAME);
+        let update_doc = doc! {"$set": {"user_id": bson_of(&user_id)?, "label": bson_of(&label)?}};
+        match collection.find_one_and_update(self.owned_by(&xpub), update_doc).return_document(ReturnDocument::After).await {
+            Ok(Some(address)) => self.open(address),
+            Ok(None) => Err(HttpResponse::NotFound().json("NotFound")),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn derived_key_lookup(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<Option<DerivedKey>, HttpResponse> {
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.extend(doc! {"chain": path[0], "index": path[1]});
+        self.find_one(DERIVED_KEY_COLL_NAME, filter_doc).await
+    }
+
+    async fn derived_keys_count(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DERIVED_KEY_COLL_NAME);
+        collection.count_documents(self.owned_by(&xpub)).await.map_err(internal_error)
+    }
+
+    async fn all_derived_keys(&self, xpub: XpubWrapper) -> Result<Vec<DerivedKey>, HttpResponse> {
+        self.find_all(DERIVED_KEY_COLL_NAME, self.owned_by(&xpub), doc! {"chain": 1, "index": 1}, None).await
+    }
+
+    async fn last_derived_index(&self, xpub: XpubWrapper, chain: u32) -> Result<Option<u32>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DERIVED_KEY_COLL_NAME);
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("chain", chain);
+        match collection.find_one(filter_doc).sort(doc! {"index": -1}).await {
+            Ok(key) => Ok(key.and_then(|key| key.get("index").cloned()).and_then(|index| from_bson(index).ok())),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn last_derived_indexes(&self, xpub: XpubWrapper) -> Result<Vec<(u32, u32)>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DERIVED_KEY_COLL_NAME);
+        let pipeline = [
+            doc! {"$match": self.owned_by(&xpub)},
+            doc! {"$group": {"_id": "$chain", "index": {"$max": "$index"}}},
+            doc! {"$sort": {"_id": 1}},
+        ];
+        let cursor = collection.aggregate(pipeline).await.map_err(internal_error)?;
+        let groups: Vec<Document> = cursor.try_collect().await.map_err(internal_error)?;
+        groups
+            .into_iter()
+            .map(|group| {
+                let chain = group.get("_id").cloned().and_then(|chain| from_bson(chain).ok());
+                let index = group.get("index").cloned().and_then(|index| from_bson(index).ok());
+                chain.zip(index).ok_or_else(|| internal_error("Invalid derived key index"))
+            })
+            .collect()
+    }
+
+    async fn derived_keys_lookup(&self, xpub: XpubWrapper, filter: &DerivedKeyFilter) -> Result<DerivedKeyPage, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DERIVED_KEY_COLL_NAME);
+        let mut filter_doc = self.owned_by(&xpub);
+        if let Some(chain) = filter.get_chain() {
+            filter_doc.insert("chain", chain);
+        }
+        if let Some(script_type) = filter.get_script_type() {
+            filter_doc.insert("script_type", bson_of(&script_type)?);
+        }
+        if let Some(label) = filter.get_label() {
+            filter_doc.insert("label", label);
+        }
+        if let Some(used) = filter.get_used() {
+            filter_doc.insert("used", used);
+        }
+        let total = collection.count_documents(filter_doc.clone()).await.map_err(internal_error)?;
+        let cursor = collection.find(filter_doc)
+            .sort(doc! {"chain": 1, "index": 1})
+            .skip(filter.get_offset())
+            .limit(filter.get_per_page() as i64)
+            .await
+            .map_err(internal_error)?;
+        let keys: Vec<Document> = cursor.try_collect().await.map_err(internal_error)?;
+        let keys = keys.into_iter().map(|key| self.open(key)).collect::<Result<_, _>>()?;
+        Ok(DerivedKeyPage::new(filter, total, keys))
+    }
+
+    async fn mark_used(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<(), HttpResponse> {
+        let collection: Collection<Document> = self.collection(DERIVED_KEY_COLL_NAME);
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.extend(doc! {"chain": path[0], "index": path[1]});
+        match collection.update_one(filter_doc, doc! {"$set": {"used": true}}).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn upsert_psbt(&self, record: PsbtRecord) -> Result<PsbtRecord, HttpResponse> {
+        let collection: Collection<Document> = self.collection(PSBT_COLL_NAME);
+        let mut filter_doc = self.owned_by(record.get_xpub());
+        filter_doc.insert("txid", record.get_txid().to_string());
+        match collection.replace_one(filter_doc, self.seal(&record)?).upsert(true).await {
+            Ok(_) => Ok(record),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn psbts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<PsbtRecord>, HttpResponse> {
+        self.find_all(PSBT_COLL_NAME, self.owned_by(&xpub), doc! {"updated_at": -1}, None).await
+    }
+
+    async fn insert_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, HttpResponse> {
+        self.insert(AUDIT_COLL_NAME, &event).await?;
+        Ok(event)
+    }
+
+    async fn audit_events_lookup(&self, xpub: XpubWrapper, limit: i64) -> Result<Vec<AuditEvent>, HttpResponse> {
+        self.find_all(AUDIT_COLL_NAME, self.owned_by(&xpub), doc! {"at": -1}, Some(limit)).await
+    }
+
+    async fn insert_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse> {
+        self.insert(INVOICE_COLL_NAME, &invoice).await?;
+        Ok(invoice)
+    }
+
+    async fn invoice_lookup(&self, xpub: XpubWrapper, id: ObjectId) -> Result<Invoice, HttpResponse> {
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("_id", id);
+        match self.find_one(INVOICE_COLL_NAME, filter_doc).await? {
+            Some(invoice) => Ok(invoice),
+            None => Err(HttpResponse::NotFound().json("NotFound")),
+        }
+    }
+
+    async fn invoices_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Invoice>, HttpResponse> {
+        self.find_all(INVOICE_COLL_NAME, self.owned_by(&xpub), doc! {}, None).await
+    }
+
+    async fn open_invoices(&self, now: u64) -> Result<Vec<Invoice>, HttpResponse> {
+        let filter_doc = doc! {
+            "status": {"$nin": bson_of(&FINAL_STATUSES)?},
+            "expires_at": {"$gte": bson_of(&now.saturating_sub(LATE_PAYMENT_SECS))?},
+        };
+        self.find_all(INVOICE_COLL_NAME, filter_doc, doc! {}, None).await
+    }
+
+    async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse> {
+        let collection: Collection<Document> = self.collection(INVOICE_COLL_NAME);
+        match collection.replace_one(doc! {"_id": invoice.get_id()}, self.seal(&invoice)?).await {
+            Ok(_) => Ok(invoice),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn insert_broadcast(&self, record: BroadcastRecord) -> Result<BroadcastRecord, HttpResponse> {
+        self.insert(BROADCAST_COLL_NAME, &record).await?;
+        Ok(record)
+    }
+
+    async fn broadcast_lookup(&self, xpub: XpubWrapper, txid: Txid) -> Result<BroadcastRecord, HttpResponse> {
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("txid", txid.to_string());
+        match self.find_one(BROADCAST_COLL_NAME, filter_doc).await? {
+            Some(record) => Ok(record),
+            None => Err(HttpResponse::NotFound().json("NotFound")),
+        }
+    }
+
+    async fn broadcasts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<BroadcastRecord>, HttpResponse> {
+        self.find_all(BROADCAST_COLL_NAME, self.owned_by(&xpub), doc! {}, None).await
+    }
+
+    async fn upsert_deposit(&self, deposit: Deposit, confirmed: bool) -> Result<Option<Deposit>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DEPOSIT_COLL_NAME);
+        let mut insert_doc = self.seal(&deposit)?;
+        let mut set_doc = doc! {};
+        if confirmed {
+            for key in ["block_height", "block_hash", "confirmations"] {
+                if let Some(value) = insert_doc.remove(key) {
+                    set_doc.insert(key, value);
+                }
+            }
+        }
+        let filter_doc = doc! {
+            "txid": deposit.get_txid().to_string(),
+            "vout": deposit.get_vout(),
+        };
+        let mut update_doc = doc! {
+            "$setOnInsert": insert_doc,
+        };
+        if !set_doc.is_empty() {
+            update_doc.insert("$set", set_doc);
+        }
+        match collection.update_one(filter_doc, update_doc).upsert(true).await {
+            Ok(result) if result.upserted_id.is_some() => Ok(Some(deposit)),
+            Ok(_) => Ok(None),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn revert_deposits(&self, block_hash: BlockHash) -> Result<u64, HttpResponse> {
+        let collection: Collection<Deposit> = self.collection(DEPOSIT_COLL_NAME);
+        let update_doc = doc! {
+            "$set": doc! {
+                "block_height": Bson::Null,
+                "block_hash": Bson::Null,
+                "confirmations": 0,
+            }
+        };
+        match collection.update_many(doc! {"block_hash": block_hash.to_string()}, update_doc).await {
+            Ok(result) => Ok(result.modified_count),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn update_deposit_confirmations(&self, tip_height: u32, limit: u32) -> Result<u64, HttpResponse> {
+        let collection: Collection<Deposit> = self.collection(DEPOSIT_COLL_NAME);
+        let filter_doc = doc! {
+            "block_height": { "$ne": Bson::Null },
+            "confirmations": { "$lt": limit },
+        };
+        let pipeline = vec![doc! {
+            "$set": {
+                "confirmations": { "$add": [{ "$subtract": [tip_height, "$block_height"] }, 1] }
+            }
+        }];
+        match collection.update_many(filter_doc, pipeline).await {
+            Ok(result) => Ok(result.modified_count),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn deposits_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Deposit>, HttpResponse> {
+        self.find_all(DEPOSIT_COLL_NAME, self.owned_by(&xpub), doc! {"first_seen": -1}, None).await
+    }
+
+    async fn confirmed_deposits(&self, xpub: XpubWrapper, min_height: u32, max_height: u32) -> Result<Vec<Deposit>, HttpResponse> {
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("block_height", doc! { "$gte": min_height, "$lte": max_height });
+        self.find_all(DEPOSIT_COLL_NAME, filter_doc, doc! {}, None).await
+    }
+
+    async fn watcher_cursor(&self) -> Result<Option<WatcherCursor>, HttpResponse> {
+        self.find_one(WATCHER_COLL_NAME, doc! {"_id": WATCHER_CURSOR_ID}).await
+    }
+
+    async fn set_watcher_cursor(&self, cursor: WatcherCursor) -> Result<(), HttpResponse> {
+        self.collection::<Document>(WATCHER_COLL_NAME)
+            .replace_one(doc! {"_id": WATCHER_CURSOR_ID}, self.seal(&cursor)?)
+            .upsert(true)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<Webhook, HttpResponse> {
+        self.insert(WEBHOOK_COLL_NAME, &webhook).await?;
+        Ok(webhook)
+    }
+
+    async fn webhooks_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Webhook>, HttpResponse> {
+        self.find_all(WEBHOOK_COLL_NAME, self.owned_by(&xpub), doc! {}, None).await
+    }
+
+    async fn webhooks_for_event(&self, kind: EventKind) -> Result<Vec<Webhook>, HttpResponse> {
+        self.find_all(WEBHOOK_COLL_NAME, doc! {"events": bson_of(&kind)?}, doc! {}, None).await
+    }
+
+    async fn webhook_by_id(&self, id: ObjectId) -> Result<Option<Webhook>, HttpResponse> {
+        self.find_one(WEBHOOK_COLL_NAME, doc! {"_id": id}).await
+    }
+
+    async fn delete_webhook(&self, xpub: XpubWrapper, id: ObjectId) -> Result<(), HttpResponse> {
+        let collection: Collection<Document> = self.collection(WEBHOOK_COLL_NAME);
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("_id", id);
+        match collection.delete_one(filter_doc).await {
+            Ok(result) if result.deleted_count == 0 => Err(HttpResponse::NotFound().json("NotFound")),
+            Ok(_) => Ok(()),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn insert_delivery(&self, delivery: Delivery) -> Result<Option<Delivery>, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DELIVERY_COLL_NAME);
+        match collection.insert_one(self.seal(&delivery)?).await {
+            Ok(_) => Ok(Some(delivery)),
+            Err(err) if is_duplicate_key(&err) => Ok(None),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: i64) -> Result<Vec<Delivery>, HttpResponse> {
+        let filter_doc = doc! {
+            "status": bson_of(&DeliveryStatus::Pending)?,
+            "next_attempt_at": { "$lte": now as i64 },
+        };
+        self.find_all(DELIVERY_COLL_NAME, filter_doc, doc! {"next_attempt_at": 1}, Some(limit)).await
+    }
+
+    async fn update_delivery(&self, delivery: Delivery) -> Result<Delivery, HttpResponse> {
+        let collection: Collection<Document> = self.collection(DELIVERY_COLL_NAME);
+        match collection.replace_one(doc! {"_id": delivery.get_id()}, self.seal(&delivery)?).await {
+            Ok(_) => Ok(delivery),
+            Err(err) => Err(internal_error(err)),
+        }
+    }
+
+    async fn deliveries_lookup(&self, xpub: XpubWrapper, webhook_id: ObjectId, limit: i64) -> Result<Vec<Delivery>, HttpResponse> {
+        let mut filter_doc = self.owned_by(&xpub);
+        filter_doc.insert("webhook_id", webhook_id);
+        self.find_all(DELIVERY_COLL_NAME, filter_doc, doc! {"created_at": -1}, Some(limit)).await
+    }
+}
@@ -0,0 +1,1190 @@
+use std::str::FromStr;
+use actix_web::HttpResponse;
+use async_trait::async_trait;
+use bitcoin::{
+    bip32,
+    BlockHash,
+    Txid,
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+use sqlx::{
+    any::{
+        AnyArguments,
+        AnyPoolOptions,
+        AnyRow,
+    },
+    migrate::Migrator,
+    query::QueryAs,
+    Any,
+    AnyPool,
+    Executor,
+    Row,
+};
+use crate::model::{
+    Nonce,
+    SaltedFingerPrint,
+    UserAddress,
+    XpubWrapper,
+    audit::AuditEvent,
+    balance::ScriptType,
+    broadcast::BroadcastRecord,
+    derived::{
+        self,
+        DerivedKey,
+        DerivedKeyFilter,
+        DerivedKeyPage,
+    },
+    invoice::Invoice,
+    psbt::PsbtRecord,
+    schema::{
+        MigrationReport,
+        MigrationStep,
+        SCHEMA_VERSION,
+    },
+    vault::{
+        RotationReport,
+        Sealed,
+        Vault,
+    },
+    watcher::{
+        Deposit,
+        WatcherCursor,
+    },
+    webhook::{
+        Delivery,
+        DeliveryStatus,
+        EventKind,
+        EventPayload,
+        Webhook,
+    },
+};
+use super::Storage;
+
+pub const DEFAULT_SQLITE_URL: &str = "sqlite://xpub-session-api.db?mode=rwc";
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 5;
+
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/sqlite");
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/postgres");
+
+fn internal_error(err: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::InternalServerError().body(err.to_string())
+}
+
+/// Whether `err` reports a missing table, by PostgreSQL code or SQLite message.
+fn is_missing_table(err: &dyn sqlx::error::DatabaseError) -> bool {
+    err.code().as_deref() == Some("42P01") || err.message().starts_with("no such table")
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json("NotFound")
+}
+
+/// Tables of records kept for a user, besides its derived keys, with their primary key.
+const RECORD_TABLES: [(&str, &[&str]); 7] = [
+    ("psbts", &["xpub", "txid"]),
+    ("audit_events", &["id"]),
+    ("invoices", &["id"]),
+    ("broadcasts", &["id"]),
+    ("deposits", &["txid", "vout"]),
+    ("webhooks", &["id"]),
+    ("deliveries", &["id"]),
+];
+
+/// Primary key column value of a record row, bound back as it was read.
+enum KeyValue {
+    Integer(i64),
+    Text(String),
+}
+
+/// A row of one of the `RECORD_TABLES`. `row_version` is bumped by every update of the body,
+/// so that rewriting a row read earlier does not undo a concurrent update.
+struct RecordRow {
+    key: Vec<KeyValue>,
+    row_version: i64,
+    body: String,
+}
+
+/// Rows of `table`, only those of `owner` when given.
+async fn record_rows<'e, E: Executor<'e, Database = Any>>(
+    executor: E,
+    table: &str,
+    key_columns: &[&str],
+    owner: Option<&str>,
+) -> Result<Vec<RecordRow>, HttpResponse> {
+    let sql = format!(
+        "SELECT {}, row_version, body FROM {}{}",
+        key_columns.join(", "),
+        table,
+        if owner.is_some() { " WHERE xpub = $1" } else { "" },
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(owner) = owner {
+        query = query.bind(owner);
+    }
+    let rows: Vec<AnyRow> = query.fetch_all(executor).await.map_err(internal_error)?;
+    rows.iter()
+        .map(|row| {
+            let key = (0..key_columns.len())
+                .map(|column| row.try_get::<i64, _>(column)
+                    .map(KeyValue::Integer)
+                    .or_else(|_| row.try_get::<String, _>(column).map(KeyValue::Text)))
+                .collect::<Result<Vec<KeyValue>, sqlx::Error>>()
+                .map_err(internal_error)?;
+            Ok(RecordRow {
+                key,
+                row_version: row.try_get(key_columns.len()).map_err(internal_error)?,
+                body: row.try_get(key_columns.len() + 1).map_err(internal_error)?,
+            })
+        })
+        .collect()
+}
+
+/// Sets `values` on `row`, unless it was updated since it was read. Returns whether it was.
+async fn update_record_row<'e, E: Executor<'e, Database = Any>>(
+    executor: E,
+    table: &str,
+    key_columns: &[&str],
+    row: &RecordRow,
+    values: &[(&str, String)],
+) -> Result<bool, HttpResponse> {
+    let assignments: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(position, (column, _))| format!("{} = ${}", column, position + 1))
+        .collect();
+    let conditions: Vec<String> = key_columns
+        .iter()
+        .enumerate()
+        .map(|(position, column)| format!("{} = ${}", column, values.len() + position + 1))
+        .collect();
+    let sql = format!(
+        "UPDATE {} SET {}, row_version = row_version + 1 WHERE {} AND row_version = ${}",
+        table,
+        assignments.join(", "),
+        conditions.join(" AND "),
+        values.len() + key_columns.len() + 1,
+    );
+    let mut query = sqlx::query(&sql);
+    for (_, value) in values {
+        query = query.bind(value.as_str());
+    }
+    for value in row.key.iter() {
+        query = match value {
+            KeyValue::Integer(value) => query.bind(*value),
+            KeyValue::Text(value) => query.bind(value.as_str()),
+        };
+    }
+    let result = query.bind(row.row_version).execute(executor).await.map_err(internal_error)?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Owner key, nonce, version, plan, account id and sealed xpub of a `users` row.
+type UserRow = (String, i64, i64, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>);
+const USER_COLUMNS: &str = "xpub, nonce, version, plan, account_id, sealed_xpub, user_id, label";
+
+fn parse_xpub(value: &str) -> Result<XpubWrapper, HttpResponse> {
+    bip32::Xpub::from_str(value).map(XpubWrapper::from).map_err(internal_error)
+}
+
+fn to_json(value: &impl Serialize) -> Result<String, HttpResponse> {
+    serde_json::to_string(value).map_err(internal_error)
+}
+
+fn from_json<T: DeserializeOwned>(value: &str) -> Result<T, HttpResponse> {
+    serde_json::from_str(value).map_err(internal_error)
+}
+
+fn script_type_key(script_type: ScriptType) -> String {
+    format!("{:?}", script_type)
+}
+
+/// Conditions of `filter` on top of the xpub, numbered from `$2` on as `bind_filter` binds them.
+fn filter_clause(filter: &DerivedKeyFilter) -> String {
+    let columns = [
+        filter.get_chain().map(|_| "chain"),
+        filter.get_script_type().map(|_| "script_type"),
+        filter.get_label().map(|_| "label"),
+        filter.get_used().map(|_| "used"),
+    ];
+    let mut clause = String::from("xpub = $1 AND body IS NOT NULL");
+    for (position, column) in columns.iter().flatten().enumerate() {
+        clause.push_str(&format!(" AND {} = ${}", column, position + 2));
+    }
+    clause
+}
+
+fn bind_filter<'q, O>(
+    mut query: QueryAs<'q, Any, O, AnyArguments<'q>>,
+    owner: String,
+    filter: &DerivedKeyFilter,
+) -> QueryAs<'q, Any, O, AnyArguments<'q>> {
+    query = query.bind(owner);
+    if let Some(chain) = filter.get_chain() {
+        query = query.bind(chain as i64);
+    }
+    if let Some(script_type) = filter.get_script_type() {
+        query = query.bind(script_type_key(script_type));
+    }
+    if let Some(label) = filter.get_label() {
+        query = query.bind(label.clone());
+    }
+    if let Some(used) = filter.get_used() {
+        query = query.bind(used);
+    }
+    query
+}
+
+fn status_key(status: DeliveryStatus) -> &'static str {
+    match status {
+        DeliveryStatus::Pending => "pending",
+        DeliveryStatus::Delivered => "delivered",
+        DeliveryStatus::Failed => "failed",
+    }
+}
+
+/// Storage backed by SQLite or PostgreSQL, picked from the URL scheme. Records with a query
+/// pattern get their own columns; the full record is kept as JSON in `body`, with xpubs and
+/// addresses sealed by `vault`. Rows are keyed by the salted fingerprint of their owner's xpub.
+pub struct SqlStorage {
+    pool: AnyPool,
+    postgres: bool,
+    vault: Vault,
+}
+
+impl SqlStorage {
+    pub async fn connect(url: &str, max_connections: u32, vault: Vault) -> Result<Self, sqlx::Error> {
+        sqlx::any::install_default_drivers();
+        let postgres = url.starts_with("postgres");
+        let pool = AnyPoolOptions::new()
+            .max_connections(max_connections)
+            // SQLite only enforces the foreign keys of the migrations, and their ON DELETE
+            // CASCADE, on connections that turn them on.
+            .after_connect(move |connection, _| Box::pin(async move {
+                if !postgres {
+                    connection.execute("PRAGMA foreign_keys = ON").await?;
+                }
+                Ok(())
+            }))
+            .connect(url)
+            .await?;
+        Ok(SqlStorage {
+            pool,
+            postgres,
+            vault,
+        })
+    }
+
+    /// Column value keying the rows of `xpub`.
+    fn owner_key(&self, xpub: &XpubWrapper) -> String {
+        self.vault.fingerprint(xpub).as_str().to_string()
+    }
+
+    /// `xpub` sealed for the column `column` of a row keyed by `owner`.
+    fn seal_xpub(&self, xpub: &XpubWrapper, owner: &str, column: &str) -> Result<String, HttpResponse> {
+        to_json(&self.vault.seal_xpub(xpub, &SaltedFingerPrint::from_stored(owner.to_string()), column)?)
+    }
+
+    fn record_body(&self, value: &impl Serialize) -> Result<String, HttpResponse> {
+        let mut record = match serde_json::to_value(value).map_err(internal_error)? {
+            serde_json::Value::Object(record) => record,
+            _ => return Err(internal_error("Record is not an object")),
+        };
+        self.vault.seal_record(&mut record)?;
+        to_json(&record)
+    }
+
+    fn read_body<T: DeserializeOwned>(&self, body: &str) -> Result<T, HttpResponse> {
+        let mut record: serde_json::Map<String, serde_json::Value> = from_json(body)?;
+        self.vault.open_record(&mut record)?;
+        serde_json::from_value(record.into()).map_err(internal_error)
+    }
+
+    fn read_bodies<T: DeserializeOwned>(&self, rows: Vec<(String,)>) -> Result<Vec<T>, HttpResponse> {
+        rows.iter().map(|(body,)| self.read_body(body)).collect()
+    }
+
+    /// `body` with its values re-wrapped by the active key and bound, `None` when all already were.
+    fn rewrap_body(&self, body: &str) -> Result<Option<String>, HttpResponse> {
+        let mut record: serde_json::Map<String, serde_json::Value> = from_json(body)?;
+        match self.vault.rewrap_record(&mut record)? {
+            true => to_json(&record).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    async fn store_deposit(&self, deposit: &Deposit) -> Result<(), HttpResponse> {
+        sqlx::query("UPDATE deposits SET block_height = $1, block_hash = $2, confirmations = $3, body = $4, row_version = row_version + 1 \
+            WHERE txid = $5 AND vout = $6")
+            .bind(deposit.get_block_height().map(i64::from))
+            .bind(deposit.get_block_hash().map(|hash| hash.to_string()))
+            .bind(deposit.get_confirmations() as i64)
+            .bind(self.record_body(deposit)?)
+            .bind(deposit.get_txid().to_string())
+            .bind(deposit.get_vout() as i64)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn load_address(&self, (xpub, nonce, version, plan, account_id, sealed_xpub, user_id, label): UserRow) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let xpub = match sealed_xpub {
+            Some(sealed) => {
+                let owner = SaltedFingerPrint::from_stored(xpub);
+                let opened = self.vault.open_xpub(&from_json(&sealed)?, &owner, "sealed_xpub")?;
+                // An xpub moved to another row before values were bound.
+                if self.vault.fingerprint(&opened) != owner {
+                    return Err(internal_error(format!("Sealed xpub of user {} belongs to another user", owner.as_str())))
+                }
+                opened
+            },
+            // Not sealed yet, keyed by its base58 encoding.
+            None => parse_xpub(&xpub)?,
+        };
+        Ok(UserAddress {
+            xpub,
+            nonce: Nonce(nonce as u32),
+            version: version as u64,
+            plan,
+            account_id: account_id.map(|id| ObjectId::parse_str(id).map_err(internal_error)).transpose()?,
+            user_id: user_id.map(SaltedFingerPrint::from_stored),
+            label,
+            schema_version: SCHEMA_VERSION,
+        })
+    }
+
+    /// Derived keys stored before their path was, in derivation order per xpub. Keys left
+    /// without a path when their user was sealed are not recovered anymore.
+    async fn legacy_derived_keys(&self) -> Result<Vec<(String, i64, String)>, sqlx::Error> {
+        sqlx::query_as("SELECT derived_keys.xpub, seq, derived_xpub FROM derived_keys JOIN users ON users.xpub = derived_keys.xpub \
+            WHERE body IS NULL AND sealed_xpub IS NULL ORDER BY derived_keys.xpub, seq")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn migrate_derived_keys(&self, step: &mut MigrationStep) -> Result<(), HttpResponse> {
+        let rows = self.legacy_derived_keys().await.map_err(internal_error)?;
+        for rows in rows.chunk_by(|first, second| first.0 == second.0) {
+            let parent = parse_xpub(&rows[0].0)?;
+            let user_id = self.owner_key(&parent);
+            let parent = parent.to_xpub();
+            let derived: Vec<XpubWrapper> = rows.iter().map(|(_, _, derived)| parse_xpub(derived)).collect::<Result<_, _>>()?;
+            for ((xpub, seq, _), path) in rows.iter().zip(derived::legacy_paths(&parent, &derived)) {
+                let Some(path) = path else {
+                    tracing::warn!("Derived key {} of user {} has no recoverable path", seq, user_id);
+                    continue
+                };
+                let key = DerivedKey::new(&parent, path, None);
+                sqlx::query("UPDATE derived_keys SET chain = $1, idx = $2, script_type = $3, body = $4 WHERE xpub = $5 AND seq = $6")
+                    .bind(path[0] as i64)
+                    .bind(path[1] as i64)
+                    .bind(script_type_key(key.get_script_type()))
+                    .bind(to_json(&key)?)
+                    .bind(xpub)
+                    .bind(seq)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(internal_error)?;
+                step.record_progress();
+            }
+        }
+        Ok(())
+    }
+
+    /// `body` as stored by this version, with its xpubs and addresses sealed.
+    fn seal_body(&self, body: &str) -> Result<String, HttpResponse> {
+        let mut record: serde_json::Map<String, serde_json::Value> = from_json(body)?;
+        self.vault.seal_record(&mut record)?;
+        record.insert("schema_version".to_string(), SCHEMA_VERSION.into());
+        to_json(&record)
+    }
+
+    /// Moves the rows of each user still keyed by its base58 xpub to its fingerprint, sealing
+    /// the xpubs and addresses they hold. The user row is inserted again under the new key,
+    /// as the derived keys reference it.
+    async fn seal_users(&self, step: &mut MigrationStep) -> Result<(), HttpResponse> {
+        let users: Vec<(String,)> = sqlx::query_as("SELECT xpub FROM users WHERE sealed_xpub IS NULL")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        for (key,) in users {
+            let xpub = parse_xpub(&key)?;
+            let owner = self.owner_key(&xpub);
+            let mut transaction = self.pool.begin().await.map_err(internal_error)?;
+            sqlx::query("INSERT INTO users (xpub, nonce, version, plan, account_id, sealed_xpub, user_id, label) \
+                SELECT $1, nonce, version, plan, account_id, $2, user_id, label FROM users WHERE xpub = $3")
+                .bind(&owner)
+                .bind(self.seal_xpub(&xpub, &owner, "sealed_xpub")?)
+                .bind(&key)
+                .execute(&mut *transaction)
+                .await
+                .map_err(internal_error)?;
+            let derived: Vec<(i64, String, Option<String>)> = sqlx::query_as("SELECT seq, derived_xpub, body FROM derived_keys WHERE xpub = $1")
+                .bind(&key)
+                .fetch_all(&mut *transaction)
+                .await
+                .map_err(internal_error)?;
+            for (seq, derived_xpub, body) in derived {
+                sqlx::query("UPDATE derived_keys SET xpub = $1, derived_xpub = $2, body = $3 WHERE xpub = $4 AND seq = $5")
+                    .bind(&owner)
+                    .bind(self.seal_xpub(&parse_xpub(&derived_xpub)?, &owner, "derived_xpub")?)
+                    .bind(body.map(|body| self.seal_body(&body)).transpose()?)
+                    .bind(&key)
+                    .bind(seq)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(internal_error)?;
+            }
+            for (table, key_columns) in RECORD_TABLES {
+                for row in record_rows(&mut *transaction, table, key_columns, Some(&key)).await? {
+                    let values = [("xpub", owner.clone()), ("body", self.seal_body(&row.body)?)];
+                    // Rolled back, to be sealed again by the next migration run.
+                    if !update_record_row(&mut *transaction, table, key_columns, &row, &values).await? {
+                        return Err(internal_error(format!("A row of {} changed while being sealed", table)))
+                    }
+                }
+            }
+            sqlx::query("DELETE FROM users WHERE xpub = $1")
+                .bind(&key)
+                .execute(&mut *transaction)
+                .await
+                .map_err(internal_error)?;
+            transaction.commit().await.map_err(internal_error)?;
+            step.record_progress();
+        }
+        Ok(())
+    }
+
+    /// Deliveries whose event still holds the owner's xpub, with their owner key. Rows still
+    /// keyed by a base58 xpub, whose user was not sealed, are left out.
+    async fn unnamed_deliveries(&self) -> Result<Vec<(String, String, String)>, HttpResponse> {
+        let rows: Vec<(String, String, String)> = sqlx::query_as("SELECT id, xpub, body FROM deliveries")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(rows
+            .into_iter()
+            .filter(|(_, owner, _)| parse_xpub(owner).is_err())
+            .filter(|(_, _, body)| serde_json::from_str::<serde_json::Value>(body).is_ok_and(|body| body["event"].get("user_id").is_none()))
+            .collect())
+    }
+
+    /// Replaces the xpub in the event of each delivery with the user id of its owner, which is
+    /// the key of the row once sealed.
+    async fn name_event_owners(&self, rows: Vec<(String, String, String)>, step: &mut MigrationStep) -> Result<(), HttpResponse> {
+        for (id, owner, body) in rows {
+            let mut record: serde_json::Map<String, serde_json::Value> = from_json(&body)?;
+            let event = record.remove("event").unwrap_or_default();
+            let payload = EventPayload::redact(event, SaltedFingerPrint::from_stored(owner)).map_err(internal_error)?;
+            record.insert("event".to_string(), serde_json::to_value(payload).map_err(internal_error)?);
+            record.insert("schema_version".to_string(), SCHEMA_VERSION.into());
+            sqlx::query("UPDATE deliveries SET body = $1, row_version = row_version + 1 WHERE id = $2")
+                .bind(to_json(&record)?)
+                .bind(id)
+                .execute(&self.pool)
+                .await
+                .map_err(internal_error)?;
+            step.record_progress();
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for SqlStorage {
+    async fn init(&self) -> Result<(), HttpResponse> {
+        let migrator = if self.postgres { &POSTGRES_MIGRATIONS } else { &SQLITE_MIGRATIONS };
+        migrator.run(&self.pool).await.map_err(internal_error)
+    }
+
+    async fn migrate(&self, dry_run: bool) -> Result<MigrationReport, HttpResponse> {
+        // Records are stored in their current shape; what changes are the tables, which the
+        // sqlx migrations also applied by `init` take care of.
+        let migrator = if self.postgres { &POSTGRES_MIGRATIONS } else { &SQLITE_MIGRATIONS };
+        let applied: Vec<(i64,)> = match sqlx::query_as("SELECT version FROM _sqlx_migrations WHERE success = TRUE")
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(applied) => applied,
+            // No migration ran yet: every one is pending.
+            Err(sqlx::Error::Database(err)) if is_missing_table(err.as_ref()) => Vec::new(),
+            Err(err) => return Err(internal_error(err)),
+        };
+        let mut steps: Vec<MigrationStep> = migrator
+            .iter()
+            .filter(|migration| !applied.iter().any(|(version,)| *version == migration.version))
+            .map(|migration| MigrationStep::new("tables", migration.version as u32 - 1, migration.version as u32, &migration.description, 1))
+            .collect();
+        if !dry_run && !steps.is_empty() {
+            migrator.run(&self.pool).await.map_err(internal_error)?;
+            steps.iter_mut().for_each(MigrationStep::record_progress);
+        }
+        let mut report = MigrationReport::new(dry_run);
+        steps.into_iter().for_each(|step| report.push(step));
+        // Before the tables gained the path columns, every derived key is pending.
+        let pending = match self.legacy_derived_keys().await {
+            Ok(rows) => rows.len() as u64,
+            Err(_) => sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM derived_keys")
+                .fetch_one(&self.pool)
+                .await
+                .map_or(0, |(count,)| count as u64),
+        };
+        let mut step = MigrationStep::new("derived_keys", 1, 2, "Record derivation paths", pending);
+        if !dry_run && pending > 0 {
+            self.migrate_derived_keys(&mut step).await?;
+        }
+        report.push(step);
+        // Before the sealed column, every user is pending.
+        let pending = match sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM users WHERE sealed_xpub IS NULL").fetch_one(&self.pool).await {
+            Ok((count,)) => count as u64,
+            Err(_) => sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM users")
+                .fetch_one(&self.pool)
+                .await
+                .map_or(0, |(count,)| count as u64),
+        };
+        let mut step = MigrationStep::new("users", 2, 3, "Seal xpubs and key rows by salted fingerprint", pending);
+        if !dry_run && pending > 0 {
+            self.seal_users(&mut step).await?;
+        }
+        report.push(step);
+        // In a dry run, deliveries of users not sealed yet are not counted.
+        let rows = self.unnamed_deliveries().await?;
+        let mut step = MigrationStep::new("deliveries", 3, 4, "Name webhook event owners by user id", rows.len() as u64);
+        if !dry_run && !rows.is_empty() {
+            self.name_event_owners(rows, &mut step).await?;
+        }
+        report.push(step);
+        Ok(report)
+    }
+
+    async fn rotate_keys(&self, dry_run: bool) -> Result<RotationReport, HttpResponse> {
+        let mut report = RotationReport::new(dry_run, Some(self.vault.get_active_key_id()));
+        let users: Vec<(String, String)> = sqlx::query_as("SELECT xpub, sealed_xpub FROM users WHERE sealed_xpub IS NOT NULL")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        for (owner, sealed) in users {
+            let mut sealed: Sealed = from_json(&sealed)?;
+            let owner_id = SaltedFingerPrint::from_stored(owner.clone());
+            // Checked as on load before being bound to its row.
+            if !sealed.is_bound() && self.vault.fingerprint(&self.vault.open_xpub(&sealed, &owner_id, "sealed_xpub")?) != owner_id {
+                return Err(internal_error(format!("Sealed xpub of user {} belongs to another user", owner)))
+            }
+            if !self.vault.rewrap_xpub(&mut sealed, &owner_id, "sealed_xpub")? {
+                continue
+            }
+            if !dry_run {
+                sqlx::query("UPDATE users SET sealed_xpub = $1 WHERE xpub = $2")
+                    .bind(to_json(&sealed)?)
+                    .bind(owner)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(internal_error)?;
+            }
+            report.record();
+        }
+        let derived: Vec<(String, i64, String, Option<String>)> = sqlx::query_as("SELECT xpub, seq, derived_xpub, body FROM derived_keys")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        for (owner, seq, derived_xpub, body) in derived {
+            // Keys of users not sealed yet keep their base58 encoding.
+            let Ok(mut sealed) = serde_json::from_str::<Sealed>(&derived_xpub) else {
+                continue
+            };
+            let rewrapped_body = body.as_deref().map(|body| self.rewrap_body(body)).transpose()?.flatten();
+            let owner_id = SaltedFingerPrint::from_stored(owner.clone());
+            if !self.vault.rewrap_xpub(&mut sealed, &owner_id, "derived_xpub")? && rewrapped_body.is_none() {
+                continue
+            }
+            if !dry_run {
+                sqlx::query("UPDATE derived_keys SET derived_xpub = $1, body = COALESCE($2, body) WHERE xpub = $3 AND seq = $4")
+                    .bind(to_json(&sealed)?)
+                    .bind(rewrapped_body)
+                    .bind(owner)
+                    .bind(seq)
+                    .execute(&self.pool)
+                    .await
+                    .map_err(internal_error)?;
+            }
+            report.record();
+        }
+        for (table, key_columns) in RECORD_TABLES {
+            for row in record_rows(&self.pool, table, key_columns, None).await? {
+                let Some(rewrapped) = self.rewrap_body(&row.body)? else {
+                    continue
+                };
+                // A row updated meanwhile was sealed under the active key already.
+                if !dry_run && !update_record_row(&self.pool, table, key_columns, &row, &[("body", rewrapped)]).await? {
+                    continue
+                }
+                report.record();
+            }
+        }
+        Ok(report)
+    }
+
+    fn wallet_id(&self, xpub: &XpubWrapper) -> SaltedFingerPrint {
+        self.vault.fingerprint(xpub)
+    }
+
+    async fn insert_address(&self, address: UserAddress<XpubWrapper>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        sqlx::query("INSERT INTO users (xpub, nonce, version, plan, account_id, sealed_xpub, user_id, label) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+            .bind(self.owner_key(&address.xpub))
+            .bind(address.nonce.0 as i64)
+            .bind(address.version as i64)
+            .bind(address.plan.clone())
+            .bind(address.account_id.map(|id| id.to_hex()))
+            .bind(self.seal_xpub(&address.xpub, &self.owner_key(&address.xpub), "sealed_xpub")?)
+            .bind(address.user_id.as_ref().map(|id| id.as_str().to_string()))
+            .bind(address.label.clone())
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(address)
+    }
+
+    async fn address_lookup(&self, xpub: XpubWrapper) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let user = sqlx::query_as(&format!("SELECT {} FROM users WHERE xpub = $1", USER_COLUMNS))
+            .bind(self.owner_key(&xpub))
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.load_address(user.ok_or_else(not_found)?).await
+    }
+
+    async fn push_derived(&self, xpub: XpubWrapper, version: u64, derived: Vec<DerivedKey>) -> Result<Option<UserAddress<XpubWrapper>>, HttpResponse> {
+        let key = self.owner_key(&xpub);
+        let mut transaction = self.pool.begin().await.map_err(internal_error)?;
+        let bumped = sqlx::query("UPDATE users SET version = version + 1 WHERE xpub = $1 AND version = $2")
+            .bind(&key)
+            .bind(version as i64)
+            .execute(&mut *transaction)
+            .await
+            .map_err(internal_error)?;
+        if bumped.rows_affected() == 0 {
+            return Ok(None)
+        }
+        let (next_seq,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM derived_keys WHERE xpub = $1")
+            .bind(&key)
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(internal_error)?;
+        for (offset, derived) in derived.iter().enumerate() {
+            let path = derived.get_derivation_path();
+            sqlx::query("INSERT INTO derived_keys (xpub, seq, derived_xpub, chain, idx, script_type, label, body) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
+                .bind(&key)
+                .bind(next_seq + offset as i64)
+                .bind(self.seal_xpub(derived.get_derived_xpub(), &key, "derived_xpub")?)
+                .bind(path[0] as i64)
+                .bind(path[1] as i64)
+                .bind(script_type_key(derived.get_script_type()))
+                .bind(derived.get_label().cloned())
+                .bind(self.record_body(derived)?)
+                .execute(&mut *transaction)
+                .await
+                .map_err(internal_error)?;
+        }
+        transaction.commit().await.map_err(internal_error)?;
+        self.address_lookup(xpub).await.map(Some)
+    }
+
+    async fn all_addresses(&self) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse> {
+        let users: Vec<UserRow> = sqlx::query_as(&format!("SELECT {} FROM users", USER_COLUMNS))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let mut addresses = Vec::with_capacity(users.len());
+        for user in users {
+            addresses.push(self.load_address(user).await?);
+        }
+        Ok(addresses)
+    }
+
+    async fn advance_nonce(&self, xpub: XpubWrapper, nonce: Nonce) -> Result<Option<Nonce>, HttpResponse> {
+        let next = nonce.next()?;
+        let result = sqlx::query("UPDATE users SET nonce = nonce + 1 WHERE xpub = $1 AND nonce = $2")
+            .bind(self.owner_key(&xpub))
+            .bind(nonce.0 as i64)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok((result.rows_affected() > 0).then_some(next))
+    }
+
+    async fn delete_account(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse> {
+        let key = self.owner_key(&xpub);
+        let mut transaction = self.pool.begin().await.map_err(internal_error)?;
+        let mut deleted = 0;
+        // The user row goes last, as deleting it first would cascade to the derived keys
+        // without counting them.
+        for table in ["derived_keys"].into_iter().chain(RECORD_TABLES.map(|(table, _)| table)).chain(["users"]) {
+            let rows = sqlx::query(&format!("DELETE FROM {} WHERE xpub = $1", table))
+                .bind(&key)
+                .execute(&mut *transaction)
+                .await
+                .map_err(internal_error)?
+                .rows_affected();
+            if table == "users" && rows == 0 {
+                return Err(not_found())
+            }
+            deleted += rows;
+        }
+        transaction.commit().await.map_err(internal_error)?;
+        Ok(deleted)
+    }
+
+    async fn set_plan(&self, xpub: XpubWrapper, plan: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let result = sqlx::query("UPDATE users SET plan = $1 WHERE xpub = $2")
+            .bind(plan)
+            .bind(self.owner_key(&xpub))
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        if result.rows_affected() == 0 {
+            return Err(not_found())
+        }
+        self.address_lookup(xpub).await
+    }
+
+    async fn wallets_lookup(&self, user_id: &SaltedFingerPrint) -> Result<Vec<UserAddress<XpubWrapper>>, HttpResponse> {
+        let users: Vec<UserRow> = sqlx::query_as(&format!("SELECT {} FROM users WHERE user_id = $1 OR (xpub = $1 AND user_id IS NULL) ORDER BY xpub", USER_COLUMNS))
+            .bind(user_id.as_str())
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let mut addresses = Vec::with_capacity(users.len());
+        for user in users {
+            addresses.push(self.load_address(user).await?);
+        }
+        Ok(addresses)
+    }
+
+    async fn link_wallet(&self, xpub: XpubWrapper, user_id: Option<SaltedFingerPrint>, label: Option<String>) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+        let result = sqlx::query("UPDATE users SET user_id = $1, label = $2 WHERE xpub = $3")
+            .bind(user_id.map(|id| id.as_str().to_string()))
+            .bind(label)
+            .bind(self.owner_key(&xpub))
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        if result.rows_affected() == 0 {
+            return Err(not_found())
+        }
+        self.address_lookup(xpub).await
+    }
+
+    async fn derived_key_lookup(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<Option<DerivedKey>, HttpResponse> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT body FROM derived_keys WHERE xpub = $1 AND chain = $2 AND idx = $3 AND body IS NOT NULL")
+            .bind(self.owner_key(&xpub))
+            .bind(path[0] as i64)
+            .bind(path[1] as i64)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        row.map(|(body,)| self.read_body(&body)).transpose()
+    }
+
+    async fn derived_keys_count(&self, xpub: XpubWrapper) -> Result<u64, HttpResponse> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM derived_keys WHERE xpub = $1 AND body IS NOT NULL")
+            .bind(self.owner_key(&xpub))
+            .fetch_one(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(count as u64)
+    }
+
+    async fn all_derived_keys(&self, xpub: XpubWrapper) -> Result<Vec<DerivedKey>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM derived_keys WHERE xpub = $1 AND body IS NOT NULL ORDER BY chain, idx")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn last_derived_index(&self, xpub: XpubWrapper, chain: u32) -> Result<Option<u32>, HttpResponse> {
+        let (index,): (Option<i64>,) = sqlx::query_as("SELECT MAX(idx) FROM derived_keys WHERE xpub = $1 AND chain = $2")
+            .bind(self.owner_key(&xpub))
+            .bind(chain as i64)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(index.map(|index| index as u32))
+    }
+
+    async fn last_derived_indexes(&self, xpub: XpubWrapper) -> Result<Vec<(u32, u32)>, HttpResponse> {
+        let rows: Vec<(i64, i64)> = sqlx::query_as("SELECT chain, MAX(idx) FROM derived_keys WHERE xpub = $1 GROUP BY chain ORDER BY chain")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(rows.into_iter().map(|(chain, index)| (chain as u32, index as u32)).collect())
+    }
+
+    async fn derived_keys_lookup(&self, xpub: XpubWrapper, filter: &DerivedKeyFilter) -> Result<DerivedKeyPage, HttpResponse> {
+        let clause = filter_clause(filter);
+        let count_sql = format!("SELECT COUNT(*) FROM derived_keys WHERE {}", clause);
+        let (total,): (i64,) = bind_filter(sqlx::query_as(&count_sql), self.owner_key(&xpub), filter)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let page_sql = format!(
+            "SELECT body FROM derived_keys WHERE {} ORDER BY chain, idx LIMIT {} OFFSET {}",
+            clause, filter.get_per_page(), filter.get_offset(),
+        );
+        let rows = bind_filter(sqlx::query_as(&page_sql), self.owner_key(&xpub), filter)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(DerivedKeyPage::new(filter, total as u64, self.read_bodies(rows)?))
+    }
+
+    async fn mark_used(&self, xpub: XpubWrapper, path: [u32; 2]) -> Result<(), HttpResponse> {
+        let mut key = match self.derived_key_lookup(xpub.clone(), path).await? {
+            Some(key) if !key.is_used() => key,
+            _ => return Ok(()),
+        };
+        key.mark_used();
+        sqlx::query("UPDATE derived_keys SET used = $1, body = $2 WHERE xpub = $3 AND chain = $4 AND idx = $5")
+            .bind(true)
+            .bind(self.record_body(&key)?)
+            .bind(self.owner_key(&xpub))
+            .bind(path[0] as i64)
+            .bind(path[1] as i64)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn upsert_psbt(&self, record: PsbtRecord) -> Result<PsbtRecord, HttpResponse> {
+        sqlx::query("INSERT INTO psbts (xpub, txid, updated_at, body) VALUES ($1, $2, $3, $4) \
+            ON CONFLICT (xpub, txid) DO UPDATE SET updated_at = excluded.updated_at, body = excluded.body, row_version = psbts.row_version + 1")
+            .bind(self.owner_key(record.get_xpub()))
+            .bind(record.get_txid().to_string())
+            .bind(record.get_updated_at() as i64)
+            .bind(self.record_body(&record)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(record)
+    }
+
+    async fn psbts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<PsbtRecord>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM psbts WHERE xpub = $1 ORDER BY updated_at DESC")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn insert_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, HttpResponse> {
+        sqlx::query("INSERT INTO audit_events (xpub, recorded_at, body) VALUES ($1, $2, $3)")
+            .bind(self.owner_key(event.get_xpub()))
+            .bind(event.get_at() as i64)
+            .bind(self.record_body(&event)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(event)
+    }
+
+    async fn audit_events_lookup(&self, xpub: XpubWrapper, limit: i64) -> Result<Vec<AuditEvent>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM audit_events WHERE xpub = $1 ORDER BY recorded_at DESC, id DESC LIMIT $2")
+            .bind(self.owner_key(&xpub))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn insert_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse> {
+        sqlx::query("INSERT INTO invoices (id, xpub, body) VALUES ($1, $2, $3)")
+            .bind(invoice.get_id().to_hex())
+            .bind(self.owner_key(invoice.get_xpub()))
+            .bind(self.record_body(&invoice)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(invoice)
+    }
+
+    async fn invoice_lookup(&self, xpub: XpubWrapper, id: ObjectId) -> Result<Invoice, HttpResponse> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT body FROM invoices WHERE id = $1 AND xpub = $2")
+            .bind(id.to_hex())
+            .bind(self.owner_key(&xpub))
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_body(&row.ok_or_else(not_found)?.0)
+    }
+
+    async fn invoices_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Invoice>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM invoices WHERE xpub = $1")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn open_invoices(&self, now: u64) -> Result<Vec<Invoice>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM invoices")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let invoices: Vec<Invoice> = self.read_bodies(rows)?;
+        Ok(invoices.into_iter().filter(|invoice| invoice.is_open(now)).collect())
+    }
+
+    async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, HttpResponse> {
+        sqlx::query("UPDATE invoices SET body = $1, row_version = row_version + 1 WHERE id = $2")
+            .bind(self.record_body(&invoice)?)
+            .bind(invoice.get_id().to_hex())
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(invoice)
+    }
+
+    async fn insert_broadcast(&self, record: BroadcastRecord) -> Result<BroadcastRecord, HttpResponse> {
+        sqlx::query("INSERT INTO broadcasts (xpub, txid, body) VALUES ($1, $2, $3)")
+            .bind(self.owner_key(record.get_xpub()))
+            .bind(record.get_txid().to_string())
+            .bind(self.record_body(&record)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(record)
+    }
+
+    async fn broadcast_lookup(&self, xpub: XpubWrapper, txid: Txid) -> Result<BroadcastRecord, HttpResponse> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT body FROM broadcasts WHERE xpub = $1 AND txid = $2 ORDER BY id LIMIT 1")
+            .bind(self.owner_key(&xpub))
+            .bind(txid.to_string())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_body(&row.ok_or_else(not_found)?.0)
+    }
+
+    async fn broadcasts_lookup(&self, xpub: XpubWrapper) -> Result<Vec<BroadcastRecord>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM broadcasts WHERE xpub = $1 ORDER BY id")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn upsert_deposit(&self, deposit: Deposit, confirmed: bool) -> Result<Option<Deposit>, HttpResponse> {
+        let inserted = sqlx::query("INSERT INTO deposits (txid, vout, xpub, block_height, block_hash, confirmations, first_seen, body) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (txid, vout) DO NOTHING")
+            .bind(deposit.get_txid().to_string())
+            .bind(deposit.get_vout() as i64)
+            .bind(self.owner_key(deposit.get_xpub()))
+            .bind(deposit.get_block_height().map(i64::from))
+            .bind(deposit.get_block_hash().map(|hash| hash.to_string()))
+            .bind(deposit.get_confirmations() as i64)
+            .bind(deposit.get_first_seen() as i64)
+            .bind(self.record_body(&deposit)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        if inserted.rows_affected() > 0 {
+            return Ok(Some(deposit))
+        }
+        if confirmed {
+            let row: (String,) = sqlx::query_as("SELECT body FROM deposits WHERE txid = $1 AND vout = $2")
+                .bind(deposit.get_txid().to_string())
+                .bind(deposit.get_vout() as i64)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(internal_error)?;
+            let mut stored: Deposit = self.read_body(&row.0)?;
+            stored.confirm_as(&deposit);
+            self.store_deposit(&stored).await?;
+        }
+        Ok(None)
+    }
+
+    async fn revert_deposits(&self, block_hash: BlockHash) -> Result<u64, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM deposits WHERE block_hash = $1")
+            .bind(block_hash.to_string())
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let deposits: Vec<Deposit> = self.read_bodies(rows)?;
+        for mut deposit in deposits.iter().cloned() {
+            deposit.revert();
+            self.store_deposit(&deposit).await?;
+        }
+        Ok(deposits.len() as u64)
+    }
+
+    async fn update_deposit_confirmations(&self, tip_height: u32, limit: u32) -> Result<u64, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM deposits WHERE block_height IS NOT NULL AND confirmations < $1")
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let deposits: Vec<Deposit> = self.read_bodies(rows)?;
+        let mut updated = 0;
+        for mut deposit in deposits {
+            let confirmations = deposit.get_confirmations();
+            deposit.set_tip_height(tip_height);
+            if deposit.get_confirmations() != confirmations {
+                self.store_deposit(&deposit).await?;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
+    async fn deposits_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Deposit>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM deposits WHERE xpub = $1 ORDER BY first_seen DESC")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn confirmed_deposits(&self, xpub: XpubWrapper, min_height: u32, max_height: u32) -> Result<Vec<Deposit>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM deposits WHERE xpub = $1 AND block_height >= $2 AND block_height <= $3")
+            .bind(self.owner_key(&xpub))
+            .bind(min_height as i64)
+            .bind(max_height as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn watcher_cursor(&self) -> Result<Option<WatcherCursor>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM watcher_cursor WHERE id = 1")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(self.read_bodies(rows)?.pop())
+    }
+
+    async fn set_watcher_cursor(&self, cursor: WatcherCursor) -> Result<(), HttpResponse> {
+        sqlx::query("INSERT INTO watcher_cursor (id, body) VALUES (1, $1) ON CONFLICT (id) DO UPDATE SET body = excluded.body")
+            .bind(self.record_body(&cursor)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(())
+    }
+
+    async fn insert_webhook(&self, webhook: Webhook) -> Result<Webhook, HttpResponse> {
+        sqlx::query("INSERT INTO webhooks (id, xpub, body) VALUES ($1, $2, $3)")
+            .bind(webhook.get_id().to_hex())
+            .bind(self.owner_key(webhook.get_xpub()))
+            .bind(self.record_body(&webhook)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(webhook)
+    }
+
+    async fn webhooks_lookup(&self, xpub: XpubWrapper) -> Result<Vec<Webhook>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM webhooks WHERE xpub = $1")
+            .bind(self.owner_key(&xpub))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn webhooks_for_event(&self, kind: EventKind) -> Result<Vec<Webhook>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM webhooks")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        let webhooks: Vec<Webhook> = self.read_bodies(rows)?;
+        Ok(webhooks.into_iter().filter(|webhook| webhook.subscribes(kind)).collect())
+    }
+
+    async fn webhook_by_id(&self, id: ObjectId) -> Result<Option<Webhook>, HttpResponse> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT body FROM webhooks WHERE id = $1")
+            .bind(id.to_hex())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        row.map(|(body,)| self.read_body(&body)).transpose()
+    }
+
+    async fn delete_webhook(&self, xpub: XpubWrapper, id: ObjectId) -> Result<(), HttpResponse> {
+        let result = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND xpub = $2")
+            .bind(id.to_hex())
+            .bind(self.owner_key(&xpub))
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        if result.rows_affected() == 0 {
+            return Err(not_found())
+        }
+        Ok(())
+    }
+
+    async fn insert_delivery(&self, delivery: Delivery) -> Result<Option<Delivery>, HttpResponse> {
+        let result = sqlx::query("INSERT INTO deliveries (id, webhook_id, xpub, event_key, status, next_attempt_at, created_at, body) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (webhook_id, event_key) DO NOTHING")
+            .bind(delivery.get_id().to_hex())
+            .bind(delivery.get_webhook_id().to_hex())
+            .bind(self.owner_key(delivery.get_xpub()))
+            .bind(delivery.get_event_key().to_string())
+            .bind(status_key(delivery.get_status()))
+            .bind(delivery.get_next_attempt_at() as i64)
+            .bind(delivery.get_created_at() as i64)
+            .bind(self.record_body(&delivery)?)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok((result.rows_affected() > 0).then_some(delivery))
+    }
+
+    async fn due_deliveries(&self, now: u64, limit: i64) -> Result<Vec<Delivery>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM deliveries WHERE status = $1 AND next_attempt_at <= $2 ORDER BY next_attempt_at LIMIT $3")
+            .bind(status_key(DeliveryStatus::Pending))
+            .bind(now as i64)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+
+    async fn update_delivery(&self, delivery: Delivery) -> Result<Delivery, HttpResponse> {
+        sqlx::query("UPDATE deliveries SET status = $1, next_attempt_at = $2, body = $3, row_version = row_version + 1 WHERE id = $4")
+            .bind(status_key(delivery.get_status()))
+            .bind(delivery.get_next_attempt_at() as i64)
+            .bind(self.record_body(&delivery)?)
+            .bind(delivery.get_id().to_hex())
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(delivery)
+    }
+
+    async fn deliveries_lookup(&self, xpub: XpubWrapper, webhook_id: ObjectId, limit: i64) -> Result<Vec<Delivery>, HttpResponse> {
+        let rows = sqlx::query_as("SELECT body FROM deliveries WHERE xpub = $1 AND webhook_id = $2 ORDER BY created_at DESC LIMIT $3")
+            .bind(self.owner_key(&xpub))
+            .bind(webhook_id.to_hex())
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        self.read_bodies(rows)
+    }
+}
@@ -1,12 +1,127 @@
-use bitcoin::{
-    Address,
-    Psbt,
+use actix_web::HttpResponse;
+use bitcoin::bip32;
+use serde::{
+    Serialize,
+    Deserialize,
 };
-use crate::model;
+use crate::model::{
+    storage::{
+        self,
+        Storage,
+    },
+    Credentials,
+    SaltedFingerPrint,
+    UserAddress,
+    XpubWrapper,
+};
+
+/// Id of the user owning `address`: the user it was linked to, else the id of its own wallet.
+pub fn owner_id(storage: &dyn Storage, address: &UserAddress<XpubWrapper>) -> SaltedFingerPrint {
+    match address.get_user_id() {
+        Some(user_id) => user_id.clone(),
+        None => storage.wallet_id(&address.xpub),
+    }
+}
+
+/// Id of the user owning the wallet of `xpub`.
+pub async fn owner_of(storage: &dyn Storage, xpub: &XpubWrapper) -> Result<SaltedFingerPrint, HttpResponse> {
+    Ok(owner_id(storage, &storage.address_lookup(xpub.clone()).await?))
+}
+
+/// A wallet of the logged-in user, as listed by `/wallets`.
+#[derive(Clone, Serialize)]
+pub struct Wallet {
+    wallet_id: SaltedFingerPrint,
+    xpub: bip32::Xpub,
+    label: Option<String>,
+    /// Whether the other endpoints act on this wallet.
+    selected: bool,
+}
+
+impl Wallet {
+    pub fn get_wallet_id(&self) -> &SaltedFingerPrint {
+        &self.wallet_id
+    }
+    pub fn get_xpubwrapper(&self) -> XpubWrapper {
+        self.xpub.into()
+    }
+}
 
+/// A user and its wallets, each linked after signing a login nonce with its key. A user is
+/// named after the wallet that registered it, which cannot be unlinked.
+#[derive(Serialize)]
 pub struct User {
-    _id: u32,
-    address: model::UserAddress<model::XpubWrapper>,
-    psbt_list: Vec<Psbt>,
-    salted_fingerprint: model::SaltedFingerPrint,
-}
\ No newline at end of file
+    user_id: SaltedFingerPrint,
+    wallets: Vec<Wallet>,
+}
+
+impl User {
+    /// The user `user_id`, its own wallet first, flagging the wallet of `selected`.
+    pub async fn load(storage: &dyn Storage, user_id: SaltedFingerPrint, selected: &XpubWrapper) -> Result<Self, HttpResponse> {
+        let mut wallets: Vec<Wallet> = storage.wallets_lookup(&user_id).await?
+            .into_iter()
+            .map(|address| Wallet {
+                wallet_id: storage.wallet_id(&address.xpub),
+                selected: address.xpub == *selected,
+                label: address.get_label().map(str::to_string),
+                xpub: address.get_xpub(),
+            })
+            .collect();
+        wallets.sort_by_key(|wallet| wallet.wallet_id != user_id);
+        Ok(User {
+            user_id,
+            wallets,
+        })
+    }
+    pub fn get_user_id(&self) -> &SaltedFingerPrint {
+        &self.user_id
+    }
+    pub fn wallet(&self, wallet_id: &str) -> Option<&Wallet> {
+        self.wallets.iter().find(|wallet| wallet.wallet_id.as_str() == wallet_id)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LinkRequest {
+    /// Login credentials of the wallet to link, signing its current nonce.
+    credentials: Credentials<XpubWrapper>,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+/// Links the wallet of `request` to the user `user_id`, registering it first when new. The
+/// signed nonce is used up as by a login. Wallets of another user, or with wallets of their
+/// own linked, are refused.
+pub async fn link(storage: &dyn Storage, user_id: &SaltedFingerPrint, request: LinkRequest) -> Result<UserAddress<XpubWrapper>, HttpResponse> {
+    let LinkRequest { credentials, label } = request;
+    if !UserAddress::authenticate(credentials.clone()).await? {
+        return Err(HttpResponse::Unauthorized().json("Unauthorized"))
+    }
+    let xpub = credentials.clone().get_xpub();
+    storage::register_address(storage, credentials.clone()).await?;
+    if storage.advance_nonce(xpub.clone(), credentials.get_nonce()).await?.is_none() {
+        return Err(HttpResponse::Unauthorized().json("Stale nonce"))
+    }
+    let address = storage.address_lookup(xpub.clone()).await?;
+    let wallet_id = storage.wallet_id(&xpub);
+    if wallet_id == *user_id {
+        return Err(HttpResponse::Conflict().json("The wallet the user is named after"))
+    }
+    match address.get_user_id() {
+        Some(owner) if owner != user_id => return Err(HttpResponse::Conflict().json("Wallet linked to another user")),
+        Some(_) => (),
+        None => if storage.wallets_lookup(&wallet_id).await?.len() > 1 {
+            return Err(HttpResponse::Conflict().json("Wallet with linked wallets"))
+        },
+    }
+    storage.link_wallet(xpub, Some(user_id.clone()), label).await
+}
+
+/// Refuses to delete the wallet a user is named after while other wallets are linked to it.
+pub async fn check_deletable(storage: &dyn Storage, address: &UserAddress<XpubWrapper>) -> Result<(), HttpResponse> {
+    let wallet_id = storage.wallet_id(&address.xpub);
+    if owner_id(storage, address) == wallet_id && storage.wallets_lookup(&wallet_id).await?.len() > 1 {
+        return Err(HttpResponse::Conflict().json("Unlink the other wallets first"))
+    }
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use actix_web::HttpResponse;
+use aes_gcm::{
+    aead::{
+        Aead,
+        KeyInit,
+        Payload,
+    },
+    Aes256Gcm,
+    Nonce,
+};
+use mongodb::bson::{
+    from_bson,
+    to_bson,
+    Document,
+};
+use rand::RngCore;
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    SaltedFingerPrint,
+    XpubWrapper,
+};
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+/// Shortest fingerprint salt accepted, in bytes.
+pub const MIN_SALT_LEN: usize = 16;
+/// Field of stored records keeping the fingerprint of their owner's xpub, which lookups match on.
+pub const FINGERPRINT_FIELD: &str = "salted_fingerprint";
+/// Fields of stored records holding an xpub or an address derived from one.
+pub const SEALED_FIELDS: [&str; 3] = ["xpub", "derived_xpub", "address"];
+
+/// A value encrypted with its own data key, which is stored wrapped by the application key
+/// `key_id`. Rotating application keys only re-wraps data keys.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    key_id: String,
+    /// Nonce followed by the encrypted data key.
+    #[serde(with = "serde_bytes")]
+    wrapped_key: Vec<u8>,
+    /// Nonce followed by the encrypted value.
+    #[serde(with = "serde_bytes")]
+    ciphertext: Vec<u8>,
+    /// Whether the value is authenticated along with its owner's fingerprint and its field name,
+    /// false for values sealed before records were bound.
+    #[serde(default)]
+    bound: bool,
+}
+
+impl Sealed {
+    pub fn get_key_id(&self) -> &str {
+        &self.key_id
+    }
+    pub fn is_bound(&self) -> bool {
+        self.bound
+    }
+}
+
+/// Plain forms of the sealed fields.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SealedValue {
+    Xpub(XpubWrapper),
+    Text(String),
+}
+
+/// A record serialized for storage, as a BSON document or a JSON object.
+pub trait StoredRecord {
+    /// The field as a `T`, `None` when missing or of another shape.
+    fn field<T: DeserializeOwned>(&self, name: &str) -> Option<T>;
+    fn set_field(&mut self, name: &str, value: &impl Serialize) -> Result<(), HttpResponse>;
+}
+
+impl StoredRecord for Document {
+    fn field<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        self.get(name).cloned().and_then(|value| from_bson(value).ok())
+    }
+    fn set_field(&mut self, name: &str, value: &impl Serialize) -> Result<(), HttpResponse> {
+        self.insert(name, to_bson(value).map_err(internal_error)?);
+        Ok(())
+    }
+}
+
+impl StoredRecord for serde_json::Map<String, serde_json::Value> {
+    fn field<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        self.get(name).cloned().and_then(|value| serde_json::from_value(value).ok())
+    }
+    fn set_field(&mut self, name: &str, value: &impl Serialize) -> Result<(), HttpResponse> {
+        self.insert(name.to_string(), serde_json::to_value(value).map_err(internal_error)?);
+        Ok(())
+    }
+}
+
+/// Associated data binding a value to the fingerprint of its owner, if any, and to the name of
+/// its field or column. A value copied into another record, row or field fails to open.
+fn aad(owner: Option<&SaltedFingerPrint>, name: &str) -> Vec<u8> {
+    format!("{}/{}", owner.map_or("", SaltedFingerPrint::as_str), name).into_bytes()
+}
+
+fn record_aad(record: &impl StoredRecord, name: &str) -> Vec<u8> {
+    aad(record.field::<SaltedFingerPrint>(FINGERPRINT_FIELD).as_ref(), name)
+}
+
+fn internal_error(err: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::InternalServerError().body(err.to_string())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn encrypt(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, HttpResponse> {
+    let nonce = random_bytes::<NONCE_LEN>();
+    let mut sealed = nonce.to_vec();
+    let payload = Payload { msg: plaintext, aad };
+    sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), payload).map_err(internal_error)?);
+    Ok(sealed)
+}
+
+fn decrypt(cipher: &Aes256Gcm, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, HttpResponse> {
+    if sealed.len() < NONCE_LEN {
+        return Err(internal_error("Truncated sealed value"))
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let payload = Payload { msg: ciphertext, aad };
+    cipher.decrypt(Nonce::from_slice(nonce), payload).map_err(internal_error)
+}
+
+fn parse_hex(value: &str, what: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value.trim()).map_err(|err| format!("Invalid {}: {}", what, err))
+}
+
+/// Application keys by id, the one sealing new values, and the salt of xpub fingerprints.
+/// Retired keys stay listed until `Storage::rotate_keys` re-wrapped everything sealed with them.
+pub struct Vault {
+    keys: HashMap<String, Aes256Gcm>,
+    active_key_id: String,
+    salt: Vec<u8>,
+}
+
+impl Vault {
+    pub fn new(keys: Vec<(String, [u8; KEY_LEN])>, active_key_id: &str, salt: Vec<u8>) -> Result<Self, String> {
+        if salt.len() < MIN_SALT_LEN {
+            return Err(format!("Fingerprint salt shorter than {} bytes", MIN_SALT_LEN))
+        }
+        let keys: HashMap<String, Aes256Gcm> = keys
+            .into_iter()
+            .map(|(id, key)| (id, Aes256Gcm::new(&key.into())))
+            .collect();
+        if !keys.contains_key(active_key_id) {
+            return Err(format!("Unknown active key {}", active_key_id))
+        }
+        Ok(Vault {
+            keys,
+            active_key_id: active_key_id.to_string(),
+            salt,
+        })
+    }
+    /// Reads keys from a `id=hex,...` list of 32 byte keys and a hex salt. Without an active
+    /// key id the last listed key is active.
+    pub fn parse(keys_spec: &str, active_key_id: Option<&str>, salt: &str) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        for entry in keys_spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (id, key) = entry.split_once('=').ok_or_else(|| format!("Invalid key entry {}, expected id=hex", entry))?;
+            let key: [u8; KEY_LEN] = parse_hex(key, "key")?
+                .try_into()
+                .map_err(|_| format!("Key {} is not {} bytes", id, KEY_LEN))?;
+            keys.push((id.trim().to_string(), key));
+        }
+        let active_key_id = match active_key_id {
+            Some(id) => id.to_string(),
+            None => keys.last().map(|(id, _)| id.clone()).ok_or("No encryption key")?,
+        };
+        Vault::new(keys, &active_key_id, parse_hex(salt, "salt")?)
+    }
+    pub fn get_active_key_id(&self) -> &str {
+        &self.active_key_id
+    }
+    /// Keyed hash of `xpub`, stable across key rotations.
+    pub fn fingerprint(&self, xpub: &XpubWrapper) -> SaltedFingerPrint {
+        SaltedFingerPrint::new(&self.salt, xpub)
+    }
+    fn key(&self, key_id: &str) -> Result<&Aes256Gcm, HttpResponse> {
+        self.keys.get(key_id).ok_or_else(|| internal_error(format!("Unknown encryption key {}", key_id)))
+    }
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Sealed, HttpResponse> {
+        self.seal_with(plaintext, None)
+    }
+    /// Seals `plaintext`, authenticating `aad` along with it when given.
+    fn seal_with(&self, plaintext: &[u8], aad: Option<&[u8]>) -> Result<Sealed, HttpResponse> {
+        let data_key = random_bytes::<KEY_LEN>();
+        Ok(Sealed {
+            key_id: self.active_key_id.clone(),
+            wrapped_key: encrypt(self.key(&self.active_key_id)?, &data_key, &[])?,
+            ciphertext: encrypt(&Aes256Gcm::new(&data_key.into()), plaintext, aad.unwrap_or_default())?,
+            bound: aad.is_some(),
+        })
+    }
+    fn data_key(&self, sealed: &Sealed) -> Result<Aes256Gcm, HttpResponse> {
+        let data_key = decrypt(self.key(&sealed.key_id)?, &sealed.wrapped_key, &[])?;
+        Aes256Gcm::new_from_slice(&data_key).map_err(internal_error)
+    }
+    pub fn open(&self, sealed: &Sealed) -> Result<Vec<u8>, HttpResponse> {
+        self.open_with(sealed, &[])
+    }
+    /// Opens `sealed`, checking `aad` if it was sealed bound to it.
+    fn open_with(&self, sealed: &Sealed, aad: &[u8]) -> Result<Vec<u8>, HttpResponse> {
+        let aad = if sealed.bound { aad } else { &[] };
+        decrypt(&self.data_key(sealed)?, &sealed.ciphertext, aad)
+    }
+    /// Wraps the data key of `sealed` with the active key, returning whether it was under another.
+    pub fn rewrap(&self, sealed: &mut Sealed) -> Result<bool, HttpResponse> {
+        if sealed.key_id == self.active_key_id {
+            return Ok(false)
+        }
+        let data_key = decrypt(self.key(&sealed.key_id)?, &sealed.wrapped_key, &[])?;
+        sealed.wrapped_key = encrypt(self.key(&self.active_key_id)?, &data_key, &[])?;
+        sealed.key_id = self.active_key_id.clone();
+        Ok(true)
+    }
+    /// Like `rewrap`, also sealing again bound to `aad` a value sealed before values were bound.
+    fn rebind(&self, sealed: &mut Sealed, aad: &[u8]) -> Result<bool, HttpResponse> {
+        if sealed.bound {
+            return self.rewrap(sealed)
+        }
+        *sealed = self.seal_with(&self.open(sealed)?, Some(aad))?;
+        Ok(true)
+    }
+    /// Seals `xpub` for the column `column` of a row owned by `owner`, bound to both.
+    pub fn seal_xpub(&self, xpub: &XpubWrapper, owner: &SaltedFingerPrint, column: &str) -> Result<Sealed, HttpResponse> {
+        self.seal_with(&xpub.clone().to_bytes(), Some(&aad(Some(owner), column)))
+    }
+    pub fn open_xpub(&self, sealed: &Sealed, owner: &SaltedFingerPrint, column: &str) -> Result<XpubWrapper, HttpResponse> {
+        let bytes: [u8; 78] = self.open_with(sealed, &aad(Some(owner), column))?
+            .try_into()
+            .map_err(|_| internal_error("Sealed xpub of unexpected length"))?;
+        XpubWrapper::try_from(bytes).map_err(internal_error)
+    }
+    /// Seals the plain xpub and address fields of `record`, keyed by the fingerprint of its xpub
+    /// and bound to it and to their field.
+    pub fn seal_record(&self, record: &mut impl StoredRecord) -> Result<(), HttpResponse> {
+        if let Some(xpub) = record.field::<XpubWrapper>("xpub") {
+            record.set_field(FINGERPRINT_FIELD, &self.fingerprint(&xpub))?;
+        }
+        for name in SEALED_FIELDS {
+            if let Some(value) = record.field::<SealedValue>(name) {
+                let plaintext = serde_json::to_vec(&value).map_err(internal_error)?;
+                let sealed = self.seal_with(&plaintext, Some(&record_aad(&*record, name)))?;
+                record.set_field(name, &sealed)?;
+            }
+        }
+        Ok(())
+    }
+    /// Opens the sealed fields of `record`. Fields still in plain are left as they are.
+    pub fn open_record(&self, record: &mut impl StoredRecord) -> Result<(), HttpResponse> {
+        for name in SEALED_FIELDS {
+            if let Some(sealed) = record.field::<Sealed>(name) {
+                let plaintext = self.open_with(&sealed, &record_aad(&*record, name))?;
+                let value: SealedValue = serde_json::from_slice(&plaintext).map_err(internal_error)?;
+                record.set_field(name, &value)?;
+            }
+        }
+        Ok(())
+    }
+    /// Re-wraps an xpub sealed by `seal_xpub` with the active key, returning whether it changed.
+    /// Values sealed before they were bound are sealed again, bound.
+    pub fn rewrap_xpub(&self, sealed: &mut Sealed, owner: &SaltedFingerPrint, column: &str) -> Result<bool, HttpResponse> {
+        self.rebind(sealed, &aad(Some(owner), column))
+    }
+    /// Re-wraps the sealed fields of `record` with the active key, returning whether any changed.
+    /// Values sealed before records were bound are sealed again, bound.
+    pub fn rewrap_record(&self, record: &mut impl StoredRecord) -> Result<bool, HttpResponse> {
+        let mut rewrapped = false;
+        for name in SEALED_FIELDS {
+            if let Some(mut sealed) = record.field::<Sealed>(name) {
+                if !self.rebind(&mut sealed, &record_aad(&*record, name))? {
+                    continue
+                }
+                record.set_field(name, &sealed)?;
+                rewrapped = true;
+            }
+        }
+        Ok(rewrapped)
+    }
+}
+
+/// Sealed values found under retired keys, and how many were re-wrapped.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RotationReport {
+    dry_run: bool,
+    /// Active key, none for backends keeping nothing sealed.
+    key_id: Option<String>,
+    pending: u64,
+    rewrapped: u64,
+}
+
+impl RotationReport {
+    pub fn new(dry_run: bool, key_id: Option<&str>) -> Self {
+        RotationReport {
+            dry_run,
+            key_id: key_id.map(str::to_string),
+            pending: 0,
+            rewrapped: 0,
+        }
+    }
+    /// Counts a record with values under a retired key, re-wrapped unless in a dry run.
+    pub fn record(&mut self) {
+        self.pending += 1;
+        if !self.dry_run {
+            self.rewrapped += 1;
+        }
+    }
+}
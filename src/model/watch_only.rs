@@ -0,0 +1,87 @@
+use actix_web::web;
+use bitcoin::bip32;
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    SaltedFingerPrint,
+    balance::SCAN_CHAINS,
+    chain::{
+        ChainBackend,
+        ChainError,
+        WatchDescriptor,
+    },
+};
+
+pub const DEFAULT_RANGE_END: u32 = 1000;
+
+/// Settings for mirroring registered xpubs into the node's watch-only wallet.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct WatchOnlyMirror {
+    enabled: bool,
+    range_end: u32,
+}
+
+impl Default for WatchOnlyMirror {
+    fn default() -> Self {
+        WatchOnlyMirror {
+            enabled: false,
+            range_end: DEFAULT_RANGE_END,
+        }
+    }
+}
+
+impl WatchOnlyMirror {
+    pub fn new(enabled: bool, range_end: u32) -> Self {
+        WatchOnlyMirror {
+            enabled,
+            range_end,
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    /// Receive and change `wpkh` descriptors of `xpub`, covering indexes up to `range_end`.
+    pub fn descriptors(&self, xpub: &bip32::Xpub, timestamp: Option<u64>) -> Vec<WatchDescriptor> {
+        SCAN_CHAINS
+            .iter()
+            .map(|chain| WatchDescriptor {
+                descriptor: format!("wpkh({}/{}/*)", xpub, chain),
+                range: (0, self.range_end),
+                internal: *chain == 1,
+                timestamp,
+            })
+            .collect()
+    }
+    /// Imports the descriptors of `xpub`, rescanning from `timestamp` when given.
+    pub fn mirror(&self, chain: &dyn ChainBackend, xpub: &bip32::Xpub, timestamp: Option<u64>) -> Result<(), ChainError> {
+        self.descriptors(xpub, timestamp)
+            .iter()
+            .try_for_each(|descriptor| chain.watch_descriptor(descriptor))
+    }
+    /// Imports the descriptors of `xpub` in the background, since a rescan from an old
+    /// `timestamp` can take hours. Failures are logged against `wallet_id`.
+    pub fn spawn_mirror(self, chain: web::Data<dyn ChainBackend>, xpub: bip32::Xpub, timestamp: Option<u64>, wallet_id: SaltedFingerPrint) {
+        actix_web::rt::spawn(async move {
+            match web::block(move || self.mirror(chain.get_ref(), &xpub, timestamp)).await {
+                Ok(Ok(())) => tracing::info!("Watch-only import done for wallet {}", wallet_id.as_str()),
+                Ok(Err(err)) => tracing::warn!("Watch-only import failed for wallet {}: {}", wallet_id.as_str(), err),
+                Err(err) => tracing::warn!("Watch-only import failed for wallet {}: {}", wallet_id.as_str(), err),
+            }
+        });
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ImportRequest {
+    /// Unix time to rescan from; the wallet birthday of the xpub.
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+impl ImportRequest {
+    pub fn get_timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{
+    HashMap,
+    VecDeque,
+};
+use std::time::Duration;
+use actix_web::{
+    http::StatusCode,
+    web,
+};
+use bitcoin::{
+    BlockHash,
+    ScriptBuf,
+    Transaction,
+    Txid,
+};
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    XpubWrapper,
+    balance::{
+        p2wpkh_script,
+        DEFAULT_GAP_LIMIT,
+    },
+    chain::{
+        ChainBackend,
+        ChainError,
+    },
+    invoice::{
+        self,
+        unix_now,
+    },
+    schema::SCHEMA_VERSION,
+    storage::{
+        self,
+        Storage,
+    },
+    user,
+    webhook::{
+        self,
+        EventKind,
+        WebhookEvent,
+    },
+};
+
+pub const DEFAULT_INTERVAL_SECS: u64 = 10;
+/// Block hashes kept to detect reorganizations.
+pub const REORG_WINDOW: usize = 100;
+/// Confirmation counts are refreshed until deposits reach this depth.
+pub const CONFIRMATION_LIMIT: u32 = 100;
+
+/// An output paying to an address derived from a registered xpub.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Deposit {
+    xpub: XpubWrapper,
+    txid: Txid,
+    vout: u32,
+    value_sat: u64,
+    derivation_path: [u32; 2],
+    block_height: Option<u32>,
+    block_hash: Option<BlockHash>,
+    confirmations: u32,
+    first_seen: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl Deposit {
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_txid(&self) -> Txid {
+        self.txid
+    }
+    pub fn get_vout(&self) -> u32 {
+        self.vout
+    }
+    pub fn get_derivation_path(&self) -> [u32; 2] {
+        self.derivation_path
+    }
+    pub fn get_block_height(&self) -> Option<u32> {
+        self.block_height
+    }
+    pub fn get_block_hash(&self) -> Option<BlockHash> {
+        self.block_hash
+    }
+    pub fn get_confirmations(&self) -> u32 {
+        self.confirmations
+    }
+    pub fn get_first_seen(&self) -> u64 {
+        self.first_seen
+    }
+    /// Takes the block data of `other`, a sighting of the same output.
+    pub fn confirm_as(&mut self, other: &Deposit) {
+        self.block_height = other.block_height;
+        self.block_hash = other.block_hash;
+        self.confirmations = other.confirmations;
+    }
+    /// Back to unconfirmed, after its block was disconnected.
+    pub fn revert(&mut self) {
+        self.block_height = None;
+        self.block_hash = None;
+        self.confirmations = 0;
+    }
+    pub fn set_tip_height(&mut self, tip_height: u32) {
+        if let Some(height) = self.block_height {
+            self.confirmations = (tip_height + 1).saturating_sub(height);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WatcherError {
+    Chain(ChainError),
+    Storage(StatusCode),
+}
+
+impl std::fmt::Display for WatcherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatcherError::Chain(err) => write!(f, "{}", err),
+            WatcherError::Storage(status) => write!(f, "Storage error: {}", status),
+        }
+    }
+}
+
+impl From<ChainError> for WatcherError {
+    fn from(value: ChainError) -> Self {
+        WatcherError::Chain(value)
+    }
+}
+
+impl From<actix_web::HttpResponse> for WatcherError {
+    fn from(value: actix_web::HttpResponse) -> Self {
+        WatcherError::Storage(value.status())
+    }
+}
+
+/// The last blocks processed by the watcher, newest last, persisted so that a restart resumes
+/// after them and still detects those disconnected meanwhile.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct WatcherCursor {
+    recent: VecDeque<(u32, BlockHash)>,
+}
+
+impl WatcherCursor {
+    pub fn get_recent(&self) -> &VecDeque<(u32, BlockHash)> {
+        &self.recent
+    }
+    /// Height of the block after the last processed one.
+    pub fn next_height(&self) -> Option<u32> {
+        self.recent.back().map(|(height, _)| height + 1)
+    }
+    fn push(&mut self, height: u32, hash: BlockHash) {
+        if self.recent.len() == REORG_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((height, hash));
+    }
+}
+
+type ScriptIndex = HashMap<ScriptBuf, (XpubWrapper, [u32; 2])>;
+
+fn match_deposits(
+    index: &ScriptIndex,
+    transaction: &Transaction,
+    block: Option<(u32, BlockHash)>,
+    tip_height: u32,
+) -> Vec<Deposit> {
+    let txid = transaction.compute_txid();
+    transaction.output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, txout)| {
+            index.get(&txout.script_pubkey).map(|(xpub, path)| Deposit {
+                xpub: xpub.clone(),
+                txid,
+                vout: vout as u32,
+                value_sat: txout.value.to_sat(),
+                derivation_path: *path,
+                block_height: block.map(|(height, _)| height),
+                block_hash: block.map(|(_, hash)| hash),
+                confirmations: block.map(|(height, _)| tip_height + 1 - height).unwrap_or(0),
+                first_seen: unix_now(),
+                schema_version: SCHEMA_VERSION,
+            })
+        })
+        .collect()
+}
+
+/// Background task polling the chain backend for new blocks and mempool transactions,
+/// recording deposits to every registered xpub and reverting them when blocks are disconnected,
+/// and matching open invoices against their payments.
+pub struct Watcher {
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    interval: Duration,
+    next_height: Option<u32>,
+    cursor: Option<WatcherCursor>,
+}
+
+impl Watcher {
+    /// Resumes after the cursor stored by a previous run, otherwise starts at `start_height`,
+    /// or at the current tip when `None`.
+    pub fn new(
+        storage: web::Data<dyn Storage>,
+        chain: web::Data<dyn ChainBackend>,
+        interval: Duration,
+        start_height: Option<u32>,
+    ) -> Self {
+        Watcher {
+            storage,
+            chain,
+            interval,
+            next_height: start_height,
+            cursor: None,
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            if let Err(err) = self.poll().await {
+                tracing::warn!("Watcher poll failed: {}", err);
+            }
+            actix_web::rt::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn on_chain<T, F>(&self, call: F) -> Result<T, WatcherError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn ChainBackend) -> Result<T, ChainError> + Send + 'static,
+    {
+        let chain = self.chain.clone();
+        match web::block(move || call(chain.get_ref())).await {
+            Ok(result) => Ok(result?),
+            Err(err) => Err(WatcherError::Chain(ChainError::Backend(err.to_string()))),
+        }
+    }
+
+    async fn script_index(&self) -> Result<ScriptIndex, WatcherError> {
+        let mut index = ScriptIndex::new();
+        for address in self.storage.all_addresses().await? {
+            let xpub = address.get_xpub();
+            for path in storage::scan_paths(self.storage.get_ref(), xpub.into(), DEFAULT_GAP_LIMIT).await? {
+                index.insert(p2wpkh_script(&xpub, &path), (XpubWrapper::from(xpub), path));
+            }
+        }
+        Ok(index)
+    }
+
+    /// The stored cursor, loaded on the first round.
+    async fn cursor(&mut self) -> Result<WatcherCursor, WatcherError> {
+        if let Some(cursor) = &self.cursor {
+            return Ok(cursor.clone())
+        }
+        let cursor = self.storage.watcher_cursor().await?.unwrap_or_default();
+        if let Some(height) = cursor.next_height() {
+            tracing::info!("Watcher resuming at height {}", height);
+            self.next_height = Some(height);
+        }
+        self.cursor = Some(cursor.clone());
+        Ok(cursor)
+    }
+
+    async fn save_cursor(&mut self, cursor: &WatcherCursor) -> Result<(), WatcherError> {
+        self.storage.set_watcher_cursor(cursor.clone()).await?;
+        self.cursor = Some(cursor.clone());
+        Ok(())
+    }
+
+    /// One polling round: reorg detection, new blocks, mempool and confirmation counts.
+    pub async fn poll(&mut self) -> Result<(), WatcherError> {
+        let tip_height = self.on_chain(|chain| chain.tip_height()).await?;
+        let mut cursor = self.cursor().await?;
+
+        // Walk back from the last processed block until it matches the active chain.
+        while let Some((height, hash)) = cursor.recent.back().copied() {
+            if self.on_chain(move |chain| chain.block_hash(height)).await? == Some(hash) {
+                break
+            }
+            tracing::info!("Block {} at height {} disconnected", hash, height);
+            self.storage.revert_deposits(hash).await?;
+            cursor.recent.pop_back();
+            self.save_cursor(&cursor).await?;
+            self.next_height = Some(height);
+        }
+
+        let index = self.script_index().await?;
+        let mut height = self.next_height.unwrap_or(tip_height);
+        while height <= tip_height {
+            let hash = match self.on_chain(move |chain| chain.block_hash(height)).await? {
+                Some(hash) => hash,
+                None => break,
+            };
+            let transactions = self.on_chain(move |chain| chain.block_transactions(&hash)).await?;
+            for transaction in transactions.iter() {
+                for deposit in match_deposits(&index, transaction, Some((height, hash)), tip_height) {
+                    self.record(deposit, true).await?;
+                }
+            }
+            cursor.push(height, hash);
+            self.save_cursor(&cursor).await?;
+            height += 1;
+            self.next_height = Some(height);
+        }
+
+        let mempool = self.on_chain(|chain| chain.mempool_transactions()).await?;
+        for transaction in mempool.iter() {
+            for deposit in match_deposits(&index, transaction, None, tip_height) {
+                self.record(deposit, false).await?;
+            }
+        }
+
+        self.storage.update_deposit_confirmations(tip_height, CONFIRMATION_LIMIT).await?;
+        self.notify_confirmed(tip_height).await?;
+        self.match_invoices().await?;
+        Ok(())
+    }
+
+    /// Matches the open invoices of every user against their received payments.
+    async fn match_invoices(&self) -> Result<(), WatcherError> {
+        for invoice in self.storage.open_invoices(unix_now()).await? {
+            let mut observed = invoice.clone();
+            let observed = self.on_chain(move |chain| {
+                invoice::observe_payments(chain, &mut observed, invoice::DEFAULT_MIN_CONF).map(|_| observed)
+            }).await?;
+            invoice::store_observed(self.storage.get_ref(), &invoice, observed).await?;
+        }
+        Ok(())
+    }
+
+    async fn record(&self, deposit: Deposit, confirmed: bool) -> Result<(), WatcherError> {
+        if let Some(deposit) = self.storage.upsert_deposit(deposit, confirmed).await? {
+            let xpub = deposit.get_xpub().clone();
+            self.storage.mark_used(xpub.clone(), deposit.get_derivation_path()).await?;
+            webhook::notify(self.storage.get_ref(), &xpub, WebhookEvent::DepositSeen(deposit)).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues `deposit_confirmed` for deposits reaching each webhook's confirmation target
+    /// within the reorg window; older ones were notified by earlier rounds.
+    async fn notify_confirmed(&self, tip_height: u32) -> Result<(), WatcherError> {
+        for webhook in self.storage.webhooks_for_event(EventKind::DepositConfirmed).await? {
+            let max_height = match (tip_height + 1).checked_sub(webhook.get_confirmations()) {
+                Some(height) => height,
+                None => continue,
+            };
+            let min_height = max_height.saturating_sub(REORG_WINDOW as u32);
+            let deposits = self.storage.confirmed_deposits(webhook.get_xpub().clone(), min_height, max_height).await?;
+            if deposits.is_empty() {
+                continue
+            }
+            let user_id = user::owner_of(self.storage.get_ref(), webhook.get_xpub()).await?;
+            for deposit in deposits {
+                let event = WebhookEvent::DepositConfirmed(deposit);
+                self.storage.insert_delivery(webhook::Delivery::new(&webhook, &event, user_id.clone())?).await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,604 @@
+use std::{
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+        ToSocketAddrs,
+    },
+    sync::Arc,
+    time::Duration,
+};
+use actix_web::web;
+use bitcoin_hashes::{
+    hmac,
+    sha256,
+    Hash,
+    HashEngine,
+};
+use mongodb::bson::oid::ObjectId;
+use rand::RngCore;
+use serde::{
+    Serialize,
+    Deserialize,
+};
+use crate::model::{
+    SaltedFingerPrint,
+    XpubWrapper,
+    broadcast::BroadcastRecord,
+    invoice::{
+        unix_now,
+        Invoice,
+    },
+    psbt::PsbtSignatures,
+    schema::SCHEMA_VERSION,
+    storage::Storage,
+    user,
+    watcher::Deposit,
+};
+
+pub const DEFAULT_DISPATCH_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_CONFIRMATIONS: u32 = 1;
+pub const MAX_ATTEMPTS: u32 = 8;
+pub const BASE_BACKOFF_SECS: u64 = 10;
+pub const MAX_BACKOFF_SECS: u64 = 3600;
+pub const REQUEST_TIMEOUT_SECS: u64 = 10;
+/// Deliveries sent per dispatch round.
+pub const DISPATCH_BATCH: i64 = 100;
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+#[derive(Debug)]
+pub enum WebhookError {
+    InvalidUrl(String),
+    PrivateUrl(String),
+    NoEvents,
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::InvalidUrl(url) => write!(f, "Invalid webhook url: {}", url),
+            WebhookError::PrivateUrl(url) => write!(f, "Webhook url does not resolve to public addresses only: {}", url),
+            WebhookError::NoEvents => write!(f, "At least one event is required"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+/// Whether `ip` is reachable on the public internet. Callbacks to anything else (loopback,
+/// private, link-local such as cloud metadata, shared, reserved...) would let users probe the
+/// network the server runs in.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space, benchmarking and reserved ranges.
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, link-local and site-local.
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation.
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // IPv4-translated, checked as the IPv4 address they carry.
+        || (segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+            && !is_public_ipv4(Ipv4Addr::from(((segments[6] as u32) << 16) | segments[7] as u32))))
+}
+
+/// Host of `url`, IPv6 addresses without their brackets.
+fn url_host(url: &reqwest::Url) -> Option<&str> {
+    url.host_str().map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// DNS resolver of the webhook client, refusing names resolving to addresses that are not
+/// public. Checked at connection time, so a name cannot be rebound after registration.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = actix_web::rt::task::spawn_blocking(move || {
+                (host.as_str(), 0).to_socket_addrs().map(|addrs| (host, addrs.collect::<Vec<SocketAddr>>()))
+            }).await??;
+            let (host, addrs) = addrs;
+            if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+                return Err(WebhookError::PrivateUrl(host).into())
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Which urls webhooks may target. Only public addresses unless `allow_private_urls`, meant for
+/// receivers on the local network during development.
+#[derive(Clone)]
+pub struct WebhookPolicy {
+    allow_private_urls: bool,
+}
+
+impl WebhookPolicy {
+    pub fn new(allow_private_urls: bool) -> Self {
+        WebhookPolicy { allow_private_urls }
+    }
+    /// Resolves the host of `url`, refusing it unless every address is public.
+    pub async fn check_url(&self, url: &reqwest::Url) -> Result<(), WebhookError> {
+        if self.allow_private_urls {
+            return Ok(())
+        }
+        let (host, port) = match (url_host(url), url.port_or_known_default()) {
+            (Some(host), Some(port)) => (host.to_string(), port),
+            _ => return Err(WebhookError::InvalidUrl(url.to_string())),
+        };
+        let addrs = web::block(move || (host.as_str(), port).to_socket_addrs().map(|addrs| addrs.collect::<Vec<SocketAddr>>()))
+            .await
+            .ok()
+            .and_then(|addrs| addrs.ok())
+            .ok_or_else(|| WebhookError::InvalidUrl(url.to_string()))?;
+        if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+            return Err(WebhookError::PrivateUrl(url.to_string()))
+        }
+        Ok(())
+    }
+    /// Whether a delivery may be sent to `url` when its host is an IP address; names are
+    /// checked by the client's resolver.
+    fn allows_literal(&self, url: &reqwest::Url) -> bool {
+        match url_host(url).and_then(|host| host.parse::<IpAddr>().ok()) {
+            Some(ip) => self.allow_private_urls || is_public_ip(ip),
+            None => true,
+        }
+    }
+    /// Webhook HTTP client: no redirects, and only public addresses unless allowed otherwise.
+    fn client(&self) -> reqwest::Client {
+        let builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .redirect(reqwest::redirect::Policy::none());
+        let builder = match self.allow_private_urls {
+            true => builder,
+            false => builder.dns_resolver(Arc::new(PublicResolver)),
+        };
+        builder.build().expect("Webhook HTTP client")
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    DepositSeen,
+    DepositConfirmed,
+    PsbtSigned,
+    TransactionBroadcast,
+    InvoicePaid,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    DepositSeen(Deposit),
+    DepositConfirmed(Deposit),
+    PsbtSigned(PsbtSignatures),
+    TransactionBroadcast(BroadcastRecord),
+    InvoicePaid(Invoice),
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            WebhookEvent::DepositSeen(_) => EventKind::DepositSeen,
+            WebhookEvent::DepositConfirmed(_) => EventKind::DepositConfirmed,
+            WebhookEvent::PsbtSigned(_) => EventKind::PsbtSigned,
+            WebhookEvent::TransactionBroadcast(_) => EventKind::TransactionBroadcast,
+            WebhookEvent::InvoicePaid(_) => EventKind::InvoicePaid,
+        }
+    }
+    /// Identifies the occurrence, so the same event is queued once per webhook.
+    /// Confirmations are keyed by block, notifying again when a reorg moves the deposit.
+    pub fn key(&self) -> String {
+        match self {
+            WebhookEvent::DepositSeen(deposit) => format!("deposit_seen:{}:{}", deposit.get_txid(), deposit.get_vout()),
+            WebhookEvent::DepositConfirmed(deposit) => format!(
+                "deposit_confirmed:{}:{}:{}",
+                deposit.get_txid(),
+                deposit.get_vout(),
+                deposit.get_block_hash().map(|hash| hash.to_string()).unwrap_or_default(),
+            ),
+            WebhookEvent::PsbtSigned(signatures) => format!(
+                "psbt_signed:{}:{}:{}",
+                signatures.get_txid(),
+                signatures.get_signatures(),
+                signatures.is_finalized(),
+            ),
+            WebhookEvent::TransactionBroadcast(record) => format!("transaction_broadcast:{}", record.get_txid()),
+            WebhookEvent::InvoicePaid(invoice) => format!("invoice_paid:{}", invoice.get_id()),
+        }
+    }
+}
+
+/// An event as sent to webhooks, its owner named by user id. Xpubs never leave the server.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EventPayload {
+    #[serde(rename = "type")]
+    kind: EventKind,
+    user_id: SaltedFingerPrint,
+    data: serde_json::Value,
+}
+
+impl EventPayload {
+    pub fn new(event: &WebhookEvent, user_id: SaltedFingerPrint) -> Result<Self, serde_json::Error> {
+        EventPayload::redact(serde_json::to_value(event)?, user_id)
+    }
+    /// Payload of a serialized `WebhookEvent`, or of a payload already redacted, without the
+    /// xpub of its data.
+    pub fn redact(event: serde_json::Value, user_id: SaltedFingerPrint) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct Serialized {
+            #[serde(rename = "type")]
+            kind: EventKind,
+            data: serde_json::Value,
+        }
+        let Serialized { kind, mut data } = serde_json::from_value(event)?;
+        if let Some(data) = data.as_object_mut() {
+            data.remove("xpub");
+        }
+        Ok(EventPayload {
+            kind,
+            user_id,
+            data,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebhookRequest {
+    url: String,
+    events: Vec<EventKind>,
+    /// Confirmations required before `deposit_confirmed` is sent.
+    #[serde(default)]
+    confirmations: Option<u32>,
+}
+
+impl WebhookRequest {
+    pub async fn to_webhook(self, xpub: XpubWrapper, policy: &WebhookPolicy) -> Result<Webhook, WebhookError> {
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => policy.check_url(&url).await?,
+            _ => return Err(WebhookError::InvalidUrl(self.url)),
+        }
+        if self.events.is_empty() {
+            return Err(WebhookError::NoEvents)
+        }
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Ok(Webhook {
+            id: ObjectId::new(),
+            xpub,
+            url: self.url,
+            secret: secret.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            events: self.events,
+            confirmations: self.confirmations.unwrap_or(DEFAULT_CONFIRMATIONS).max(1),
+            created_at: unix_now(),
+            schema_version: SCHEMA_VERSION,
+        })
+    }
+}
+
+/// A callback url registered by a user; payloads are signed with its secret.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    xpub: XpubWrapper,
+    url: String,
+    secret: String,
+    events: Vec<EventKind>,
+    confirmations: u32,
+    created_at: u64,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+impl Webhook {
+    pub fn get_id(&self) -> ObjectId {
+        self.id
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+    pub fn get_confirmations(&self) -> u32 {
+        self.confirmations
+    }
+    pub fn subscribes(&self, kind: EventKind) -> bool {
+        self.events.contains(&kind)
+    }
+    /// Hex HMAC-SHA256 of `{timestamp}.{body}` keyed with the webhook secret.
+    pub fn sign(&self, timestamp: u64, body: &[u8]) -> String {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(self.secret.as_bytes());
+        engine.input(timestamp.to_string().as_bytes());
+        engine.input(b".");
+        engine.input(body);
+        hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+    }
+}
+
+/// A webhook as listed to its owner, without the secret.
+#[derive(Serialize, Deserialize)]
+pub struct WebhookInfo {
+    id: String,
+    url: String,
+    events: Vec<EventKind>,
+    confirmations: u32,
+    created_at: u64,
+}
+
+impl From<&Webhook> for WebhookInfo {
+    fn from(value: &Webhook) -> Self {
+        WebhookInfo {
+            id: value.id.to_hex(),
+            url: value.url.clone(),
+            events: value.events.clone(),
+            confirmations: value.confirmations,
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    attempted_at: u64,
+    status_code: Option<u16>,
+    error: Option<String>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// One event queued for a webhook, with the outcome of every attempt so far.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    webhook_id: ObjectId,
+    xpub: XpubWrapper,
+    event_key: String,
+    event: EventPayload,
+    status: DeliveryStatus,
+    attempts: u32,
+    next_attempt_at: u64,
+    log: Vec<DeliveryAttempt>,
+    created_at: u64,
+    delivered_at: Option<u64>,
+    #[serde(default)]
+    schema_version: u32,
+}
+
+#[derive(Serialize)]
+struct DeliveryPayload<'a> {
+    delivery: String,
+    created_at: u64,
+    #[serde(flatten)]
+    event: &'a EventPayload,
+}
+
+impl Delivery {
+    pub fn new(webhook: &Webhook, event: &WebhookEvent, user_id: SaltedFingerPrint) -> Result<Self, actix_web::HttpResponse> {
+        let now = unix_now();
+        Ok(Delivery {
+            id: ObjectId::new(),
+            webhook_id: webhook.get_id(),
+            xpub: webhook.get_xpub().clone(),
+            event_key: event.key(),
+            event: EventPayload::new(event, user_id)
+                .map_err(|err| actix_web::HttpResponse::InternalServerError().body(err.to_string()))?,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            log: Vec::new(),
+            created_at: now,
+            delivered_at: None,
+            schema_version: SCHEMA_VERSION,
+        })
+    }
+    pub fn get_id(&self) -> ObjectId {
+        self.id
+    }
+    pub fn get_webhook_id(&self) -> ObjectId {
+        self.webhook_id
+    }
+    pub fn get_xpub(&self) -> &XpubWrapper {
+        &self.xpub
+    }
+    pub fn get_event_key(&self) -> &str {
+        &self.event_key
+    }
+    pub fn get_next_attempt_at(&self) -> u64 {
+        self.next_attempt_at
+    }
+    pub fn get_created_at(&self) -> u64 {
+        self.created_at
+    }
+    pub fn get_status(&self) -> DeliveryStatus {
+        self.status
+    }
+    pub fn body(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(&DeliveryPayload {
+            delivery: self.id.to_hex(),
+            created_at: self.created_at,
+            event: &self.event,
+        })
+    }
+    /// Records an attempt answered with `status_code`, or failed with `error` before any response.
+    /// Response bodies are never kept: they would show users what the receiver answered.
+    pub fn record_attempt(&mut self, status_code: Option<u16>, error: Option<String>, now: u64) {
+        self.attempts += 1;
+        self.log.push(DeliveryAttempt {
+            attempted_at: now,
+            status_code,
+            error,
+        });
+        if status_code.is_some_and(|code| (200..300).contains(&code)) {
+            self.status = DeliveryStatus::Delivered;
+            self.delivered_at = Some(now);
+        } else if self.attempts >= MAX_ATTEMPTS {
+            self.status = DeliveryStatus::Failed;
+        } else {
+            self.next_attempt_at = now + backoff_secs(self.attempts);
+        }
+    }
+    /// Gives up on a delivery whose webhook was deleted.
+    pub fn abandon(&mut self) {
+        self.status = DeliveryStatus::Failed;
+        self.log.push(DeliveryAttempt {
+            attempted_at: unix_now(),
+            status_code: None,
+            error: Some("Webhook deleted".to_string()),
+        });
+    }
+}
+
+/// Exponential backoff after the `attempts`-th failure: 10s, 20s, 40s... capped at an hour.
+pub fn backoff_secs(attempts: u32) -> u64 {
+    BASE_BACKOFF_SECS
+        .saturating_mul(1u64 << attempts.saturating_sub(1).min(32))
+        .min(MAX_BACKOFF_SECS)
+}
+
+/// Queues `event` for every webhook of `xpub` subscribed to it.
+pub async fn notify(
+    storage: &dyn Storage,
+    xpub: &XpubWrapper,
+    event: WebhookEvent,
+) -> Result<usize, actix_web::HttpResponse> {
+    let webhooks: Vec<Webhook> = storage.webhooks_lookup(xpub.clone()).await?
+        .into_iter()
+        .filter(|webhook| webhook.subscribes(event.kind()))
+        .collect();
+    if webhooks.is_empty() {
+        return Ok(0)
+    }
+    let user_id = user::owner_of(storage, xpub).await?;
+    let mut queued = 0;
+    for webhook in webhooks.iter() {
+        if storage.insert_delivery(Delivery::new(webhook, &event, user_id.clone())?).await?.is_some() {
+            queued += 1;
+        }
+    }
+    Ok(queued)
+}
+
+/// Like `notify`, logging instead of failing the caller: the event already happened.
+pub async fn notify_or_log(storage: &dyn Storage, xpub: &XpubWrapper, event: WebhookEvent) {
+    let kind = event.kind();
+    if let Err(err) = notify(storage, xpub, event).await {
+        tracing::warn!("Could not queue {:?} webhooks: {}", kind, err.status());
+    }
+}
+
+/// Background task posting queued deliveries, retrying failures with exponential backoff.
+pub struct Dispatcher {
+    storage: web::Data<dyn Storage>,
+    http: reqwest::Client,
+    policy: WebhookPolicy,
+    interval: Duration,
+}
+
+impl Dispatcher {
+    pub fn new(storage: web::Data<dyn Storage>, policy: WebhookPolicy, interval: Duration) -> Self {
+        Dispatcher {
+            storage,
+            http: policy.client(),
+            policy,
+            interval,
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            if let Err(err) = self.dispatch().await {
+                tracing::warn!("Webhook dispatch failed: {}", err.status());
+            }
+            actix_web::rt::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn send(&self, webhook: &Webhook, delivery: &Delivery) -> (Option<u16>, Option<String>) {
+        let body = match delivery.body() {
+            Ok(body) => body,
+            Err(err) => return (None, Some(err.to_string())),
+        };
+        let url = match reqwest::Url::parse(webhook.get_url()) {
+            Ok(url) if self.policy.allows_literal(&url) => url,
+            Ok(_) => return (None, Some("Destination address not allowed".to_string())),
+            Err(_) => return (None, Some("Invalid url".to_string())),
+        };
+        let timestamp = unix_now();
+        let response = self.http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, format!("sha256={}", webhook.sign(timestamp, &body)))
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(DELIVERY_HEADER, delivery.get_id().to_hex())
+            .body(body)
+            .send()
+            .await;
+        match response {
+            Ok(response) => (Some(response.status().as_u16()), None),
+            Err(err) if err.is_timeout() => (None, Some("Timed out".to_string())),
+            Err(err) if err.is_connect() => (None, Some("Connection failed".to_string())),
+            Err(_) => (None, Some("Request failed".to_string())),
+        }
+    }
+
+    /// Sends the deliveries that are due, returning how many were attempted.
+    pub async fn dispatch(&self) -> Result<usize, actix_web::HttpResponse> {
+        let deliveries = self.storage.due_deliveries(unix_now(), DISPATCH_BATCH).await?;
+        let attempted = deliveries.len();
+        for mut delivery in deliveries {
+            match self.storage.webhook_by_id(delivery.get_webhook_id()).await? {
+                Some(webhook) => {
+                    let (status_code, error) = self.send(&webhook, &delivery).await;
+                    delivery.record_attempt(status_code, error, unix_now());
+                    if delivery.get_status() == DeliveryStatus::Failed {
+                        tracing::warn!("Webhook delivery {} to {} failed permanently", delivery.get_id(), webhook.get_url());
+                    }
+                },
+                None => delivery.abandon(),
+            }
+            self.storage.update_delivery(delivery).await?;
+        }
+        Ok(attempted)
+    }
+}
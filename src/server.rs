@@ -1,41 +1,296 @@
-use actix_session::{storage::RedisSessionStore, SessionMiddleware};
+use actix_session::{config::BrowserSession, SessionMiddleware};
 use actix_web::{
-    cookie::{Key, SameSite},
+    cookie::{time, SameSite},
     middleware, web, App, HttpServer,
 };
-use tracing::level_filters::LevelFilter;
+use clap::Parser;
 use tracing_subscriber::EnvFilter;
 use mongodb::Client;
+use bitcoincore_rpc::Auth;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod handlers;
-pub mod model;
+use xpub_session_api::{
+    config::{
+        Cli,
+        Command,
+        Config,
+        CHAIN_BACKENDS,
+    },
+    admin,
+    handlers,
+    model::{
+        chain::{
+            ChainBackend,
+            core_rpc::CoreRpcBackend,
+            memory::MemoryChain,
+        },
+        fees::FeeOracle,
+        schema,
+        session::{
+            self,
+            MemorySessionStore,
+            SessionBackend,
+            SessionKeys,
+        },
+        vault::Vault,
+        storage::{
+            Storage,
+            memory::MemoryStorage,
+            mongo::MongoStorage,
+            sql::SqlStorage,
+        },
+        watch_only::WatchOnlyMirror,
+        watcher::Watcher,
+        webhook::{
+            Dispatcher,
+            WebhookPolicy,
+        },
+    },
+    tls::{
+        self,
+        ReloadingCert,
+    },
+};
+
+/// Vault of the backends persisting xpubs, from `XPUB_ENCRYPTION_KEYS`, `XPUB_ENCRYPTION_KEY_ID`
+/// and `XPUB_FINGERPRINT_SALT`.
+fn vault_from_env() -> std::io::Result<Vault> {
+    let required = |name: &str| std::env::var(name)
+        .map_err(|_| std::io::Error::other(format!("{} is required, see `cargo run --example generate_encryption_key`", name)));
+    let keys = required("XPUB_ENCRYPTION_KEYS")?;
+    let salt = required("XPUB_FINGERPRINT_SALT")?;
+    Vault::parse(&keys, std::env::var("XPUB_ENCRYPTION_KEY_ID").ok().as_deref(), &salt).map_err(std::io::Error::other)
+}
 
-const REDIS_URI: &str = "redis://127.0.0.1:6379";
-const MONGODB_URI: &str = "mongodb://localhost:27017";
+/// Session cookie keys from the configured keys file, else `SESSION_KEYS`, else a key lasting as
+/// long as the process.
+fn session_keys(config: &Config) -> std::io::Result<SessionKeys> {
+    let keys = match &config.session.keys_file {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|err| std::io::Error::other(format!("Session keys file {}: {}", path.display(), err)))?,
+        None => match std::env::var("SESSION_KEYS") {
+            Ok(keys) => keys,
+            Err(_) => {
+                tracing::warn!("No SESSION_KEYS: sessions will not survive a restart nor be shared between instances");
+                return Ok(SessionKeys::generate())
+            },
+        },
+    };
+    SessionKeys::parse(&keys).map_err(std::io::Error::other)
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    // `xpub-session-api generate-session-key` needs no configuration.
+    if let Some(Command::GenerateSessionKey) = cli.command {
+        println!("{}", SessionKeys::generate_hex());
+        return Ok(())
+    }
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("Invalid configuration: {}", error);
+            }
+            return Err(std::io::Error::other(format!("{} configuration errors", errors.len())))
+        },
+    };
+    // `xpub-session-api --check-config` prints the effective configuration and exits.
+    if cli.check_config {
+        print!("{}", config.to_redacted_toml());
+        return Ok(())
+    }
+
     tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
+        .with_env_filter(EnvFilter::builder().parse_lossy(&config.logging.filter))
         .init();
-    
-    let sessions_key = Key::generate();
 
-    let redis_uri = std::env::var("REDIS_URI").unwrap_or_else(|_| REDIS_URI.into());
-    let session_storage = RedisSessionStore::new(redis_uri).await.expect("Redis configuration");
+    let storage_config = &config.storage;
+    let storage: Arc<dyn Storage> = match storage_config.backend.as_str() {
+        "memory" => Arc::new(MemoryStorage::new()),
+        "sqlite" | "postgres" => {
+            let storage = SqlStorage::connect(&storage_config.database_url, storage_config.max_connections, vault_from_env()?).await
+                .map_err(|err| std::io::Error::other(format!("Database connection failed: {}", err)))?;
+            Arc::new(storage)
+        },
+        _ => {
+            let mongodb_client = Client::with_uri_str(&storage_config.mongodb_uri).await
+                .map_err(|err| std::io::Error::other(format!("MongoDB connection failed: {}", err)))?;
+            Arc::new(MongoStorage::new(
+                mongodb_client,
+                &storage_config.mongodb_database,
+                &storage_config.mongodb_addresses_collection,
+                vault_from_env()?,
+            ))
+        },
+    };
+    let storage: web::Data<dyn Storage> = web::Data::from(storage);
+
+    let quota_policy = config.limits.quota_policy().map_err(std::io::Error::other)?;
+
+    let dry_run = matches!(cli.command, Some(Command::Migrate { dry_run: true } | Command::RotateKeys { dry_run: true }));
+    // A dry run leaves the storage untouched, indexes and tables included.
+    if !dry_run {
+        tracing::info!("Initializing storage");
+        storage.init().await
+            .map_err(|err| std::io::Error::other(format!("Storage initialization failed: {}", err.status())))?;
+    }
+
+    match cli.command {
+        Some(Command::Migrate { dry_run }) => {
+            let report = storage.migrate(dry_run).await
+                .map_err(|err| std::io::Error::other(format!("Migration failed: {}", err.status())))?;
+            println!("{}", serde_json::to_string_pretty(&report).expect("Serializable report"));
+            return Ok(())
+        },
+        Some(Command::RotateKeys { dry_run }) => {
+            let report = storage.rotate_keys(dry_run).await
+                .map_err(|err| std::io::Error::other(format!("Key rotation failed: {}", err.status())))?;
+            println!("{}", serde_json::to_string_pretty(&report).expect("Serializable report"));
+            return Ok(())
+        },
+        Some(Command::SetPlan { xpub, plan }) => {
+            let xpub = bitcoin::bip32::Xpub::from_str(&xpub).map_err(std::io::Error::other)?;
+            if let Some(plan) = plan.as_deref().filter(|plan| !quota_policy.has_plan(plan)) {
+                return Err(std::io::Error::other(format!("Unknown plan {}", plan)))
+            }
+            let address = storage.set_plan(xpub.into(), plan).await
+                .map_err(|err| std::io::Error::other(format!("Plan update failed: {}", err.status())))?;
+            println!("{} on plan {}", xpub, quota_policy.effective_plan(address.get_plan()));
+            return Ok(())
+        },
+        Some(Command::GenerateSessionKey) | None => (),
+    }
+    if storage_config.migrate_on_startup {
+        let report = storage.migrate(false).await
+            .map_err(|err| std::io::Error::other(format!("Migration failed: {}", err.status())))?;
+        let migrated: u64 = report.get_steps().iter().map(|step| step.get_migrated()).sum();
+        tracing::info!("Storage at schema version {}, {} records upgraded", schema::SCHEMA_VERSION, migrated);
+    }
+
+    let session_keys = session_keys(&config)?;
+    if !session_keys.get_previous().is_empty() {
+        tracing::info!("Accepting session cookies sealed with {} previous keys", session_keys.get_previous().len());
+    }
+
+    let session_config = config.session.clone();
+    let cookie_secure = config.cookie_secure();
+    let session_storage = match session_config.store.as_str() {
+        "memory" => SessionBackend::Memory(MemorySessionStore::new()),
+        "cookie" => SessionBackend::Cookie,
+        _ => SessionBackend::redis(&session_config.redis_uri).await
+            .map_err(|err| std::io::Error::other(format!("{}, or set SESSION_STORE=memory to run without Redis", err)))?,
+    };
+    let same_site = match session_config.cookie_same_site.as_str() {
+        "lax" => SameSite::Lax,
+        "none" => SameSite::None,
+        _ => SameSite::Strict,
+    };
+
+    let network_config = &config.network;
+    let watch_only_mirror = WatchOnlyMirror::new(network_config.watch_only_mirror, network_config.watch_only_range);
+
+    let chain_backend: Arc<dyn ChainBackend> = match network_config.chain_backend.as_str() {
+        "memory" => Arc::new(MemoryChain::new()),
+        "core" => {
+            let mut rpc_url = network_config.rpc_url.clone();
+            let wallet = &network_config.watch_only_wallet;
+            if watch_only_mirror.is_enabled() {
+                rpc_url = format!("{}/wallet/{}", rpc_url, wallet);
+            }
+            let auth = Auth::UserPass(network_config.rpc_user.clone(), network_config.rpc_pass.clone());
+            let mut backend = CoreRpcBackend::new(&rpc_url, auth).expect("Bitcoin Core RPC configuration");
+            if watch_only_mirror.is_enabled() {
+                tracing::info!("Mirroring xpubs into watch-only wallet {}", wallet);
+                backend.ensure_watch_only_wallet(wallet).expect("Watch-only wallet");
+                backend = backend.with_mirror();
+            }
+            Arc::new(backend)
+        },
+        backend => return Err(std::io::Error::other(format!("Unknown chain backend {}, expected one of {:?}", backend, CHAIN_BACKENDS))),
+    };
+    let chain_backend: web::Data<dyn ChainBackend> = web::Data::from(chain_backend);
+    let network = web::Data::new(network_config.bitcoin);
+
+    let fee_oracle = web::Data::new(FeeOracle::new(
+        config.fees.floor_sat_vb,
+        config.fees.fallback_sat_vb,
+        Duration::from_secs(config.fees.cache_ttl_secs),
+    ));
+
+    if config.watcher.enabled {
+        let interval = config.watcher.interval_secs;
+        tracing::info!("Watching deposits every {}s", interval);
+        let deposit_watcher = Watcher::new(
+            storage.clone(),
+            chain_backend.clone(),
+            Duration::from_secs(interval),
+            config.watcher.start_height,
+        );
+        actix_web::rt::spawn(deposit_watcher.run());
+    }
+
+    let webhook_policy = WebhookPolicy::new(config.webhooks.allow_private_urls);
+    actix_web::rt::spawn(Dispatcher::new(
+        storage.clone(),
+        webhook_policy.clone(),
+        Duration::from_secs(config.webhooks.interval_secs),
+    ).run());
+
+    // Client certificates are only asked for on the admin listeners, browsers have none.
+    let (tls_config, admin_tls_config) = match config.tls.files() {
+        Some((cert_file, key_file)) => {
+            let cert = Arc::new(ReloadingCert::load(cert_file, key_file).map_err(std::io::Error::other)?);
+            actix_web::rt::spawn(cert.clone().watch(Duration::from_secs(config.tls.reload_interval_secs)));
+            if config.tls.client_ca_file.is_some() {
+                tracing::info!("Requiring client certificates on the admin listeners");
+            }
+            let tls_config = tls::server_config(cert.clone(), None).map_err(std::io::Error::other)?;
+            let admin_tls_config = tls::server_config(cert, config.tls.client_ca_file.as_deref()).map_err(std::io::Error::other)?;
+            (Some(tls_config), Some(admin_tls_config))
+        },
+        None => (None, None),
+    };
 
-    let mongodb_uri = std::env::var("MONGODB_URI").unwrap_or_else(|_| MONGODB_URI.into());
-    let mongodb_client = Client::with_uri_str(mongodb_uri).await.expect("failed to connect");
+    let admin_server = match config.listeners.admin_bind.is_empty() {
+        true => None,
+        false => {
+            let storage = storage.clone();
+            let chain_backend = chain_backend.clone();
+            let mut admin_server = HttpServer::new(move || {
+                App::new()
+                    .wrap(middleware::Logger::default())
+                    .app_data(storage.clone())
+                    .app_data(chain_backend.clone())
+                    .app_data(web::Data::new(watch_only_mirror))
+                    .service(admin::info)
+                    .service(admin::import_watch_only)
+                    .service(admin::watch_only_status)
+                    .service(admin::migrate)
+                    .service(admin::rotate_keys)
+            })
+            .workers(1);
+            for addr in &config.listeners.admin_bind {
+                match &admin_tls_config {
+                    Some(tls_config) => {
+                        tracing::info!("starting admin HTTPS server at https://{}", addr);
+                        admin_server = admin_server.bind_rustls_0_23(addr, tls_config.clone())?;
+                    },
+                    None => {
+                        tracing::info!("starting admin HTTP server at http://{}", addr);
+                        admin_server = admin_server.bind(addr)?;
+                    },
+                }
+            }
+            Some(admin_server.run())
+        },
+    };
 
-    tracing::info!("Indexing DB");
-    let _ = model::db::create_address_index(&mongodb_client).await;
-    
-    tracing::info!("starting HTTP server at http://localhost:8080");
-    HttpServer::new(move || {
+    let json_limit = config.limits.json_limit_bytes;
+    let mut server = HttpServer::new(move || {
         App::new()
             // Logger
             .wrap(middleware::Logger::default())
@@ -43,20 +298,85 @@ async fn main() -> std::io::Result<()> {
             .service(handlers::info)
             // cookie session
             .wrap(
-                SessionMiddleware::builder(session_storage.clone(), sessions_key.clone())
-                    // allow the cookie to be accessed from javascript
-                    .cookie_http_only(false)
-                    // allow the cookie only from the current domain
-                    .cookie_same_site(SameSite::Strict)
+                SessionMiddleware::builder(session_storage.clone(), session_keys.get_primary().clone())
+                    .cookie_name(session::COOKIE_NAME.into())
+                    // allow the cookie to be accessed from javascript unless configured otherwise
+                    .cookie_http_only(session_config.cookie_http_only)
+                    .cookie_secure(cookie_secure)
+                    // allow the cookie only from the current domain by default
+                    .cookie_same_site(same_site)
+                    .session_lifecycle(BrowserSession::default().state_ttl(time::Duration::seconds(session_config.ttl_secs)))
                     .build(),
             )
-            .app_data(web::Data::new(mongodb_client.clone()))
+            // accept cookies sealed with previous session keys
+            .wrap(middleware::from_fn({
+                let session_keys = session_keys.clone();
+                move |req, next| session::reseal_cookie(session_keys.clone(), req, next)
+            }))
+            .app_data(web::JsonConfig::default().limit(json_limit))
+            .app_data(storage.clone())
+            .app_data(network.clone())
+            .app_data(chain_backend.clone())
+            .app_data(web::Data::new(watch_only_mirror))
+            .app_data(fee_oracle.clone())
+            .app_data(web::Data::new(quota_policy.clone()))
+            .app_data(web::Data::new(webhook_policy.clone()))
             .service(handlers::login)
+            .service(handlers::get_nonce)
             .service(handlers::get_address)
             .service(handlers::derive_address)
+            .service(handlers::get_addresses)
+            .service(handlers::get_quota)
             .service(handlers::create_psbt)
+            .service(handlers::create_sweep_psbt)
+            .service(handlers::fees)
+            .service(handlers::payment_uri)
+            .service(handlers::parse_payment_uri)
+            .service(handlers::create_invoice)
+            .service(handlers::get_invoices)
+            .service(handlers::get_invoice)
+            .service(handlers::get_balance)
+            .service(handlers::get_utxos)
+            .service(handlers::get_transactions)
+            .service(handlers::broadcast)
+            .service(handlers::get_broadcast)
+            .service(handlers::get_deposits)
+            .service(handlers::psbt_signed)
+            .service(handlers::create_webhook)
+            .service(handlers::get_webhooks)
+            .service(handlers::delete_webhook)
+            .service(handlers::get_deliveries)
+            .service(handlers::get_psbts)
+            .service(handlers::get_audit)
+            .service(handlers::export_account)
+            .service(handlers::deletion_challenge)
+            .service(handlers::delete_account)
+            .service(handlers::get_wallets)
+            .service(handlers::link_wallet)
+            .service(handlers::select_wallet)
+            .service(handlers::unlink_wallet)
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .max_connections(config.limits.max_connections);
+    if let Some(workers) = config.listeners.workers {
+        server = server.workers(workers);
+    }
+    for addr in &config.listeners.bind {
+        match &tls_config {
+            Some(tls_config) => {
+                tracing::info!("starting HTTPS server at https://{}", addr);
+                server = server.bind_rustls_0_23(addr, tls_config.clone())?;
+            },
+            None => {
+                if addr.parse::<std::net::SocketAddr>().is_ok_and(|addr| !addr.ip().is_loopback()) {
+                    tracing::warn!("Serving plain HTTP on {}: session cookies travel unencrypted unless TLS is terminated in front", addr);
+                }
+                tracing::info!("starting HTTP server at http://{}", addr);
+                server = server.bind(addr)?;
+            },
+        }
+    }
+    match admin_server {
+        Some(admin_server) => futures_util::future::try_join(server.run(), admin_server).await.map(|_| ()),
+        None => server.run().await,
+    }
 }
\ No newline at end of file
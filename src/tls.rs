@@ -0,0 +1,149 @@
+use rustls::{
+    crypto::{
+        ring,
+        CryptoProvider,
+    },
+    pki_types::CertificateDer,
+    server::{
+        ClientHello,
+        ResolvesServerCert,
+        WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+    RootCertStore,
+    ServerConfig,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::{
+    Arc,
+    RwLock,
+};
+use std::time::{
+    Duration,
+    SystemTime,
+};
+
+/// Certificate files are checked for changes this often unless configured otherwise.
+pub const DEFAULT_RELOAD_INTERVAL_SECS: u64 = 30;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificate", path.display()))
+    }
+    Ok(certs)
+}
+
+/// The certificate chain of `cert_file` with the private key of `key_file`, which must match.
+pub fn load_certified_key(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey, String> {
+    let certs = read_certs(cert_file)?;
+    let file = File::open(key_file).map_err(|err| format!("{}: {}", key_file.display(), err))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("{}: {}", key_file.display(), err))?
+        .ok_or_else(|| format!("{}: no private key", key_file.display()))?;
+    let certified_key = CertifiedKey::from_der(certs, key, &provider())
+        .map_err(|err| format!("{}: {}", cert_file.display(), err))?;
+    Ok(certified_key)
+}
+
+/// Modification time of the newest of `paths`.
+fn modified(paths: &[&Path]) -> Option<SystemTime> {
+    paths.iter()
+        .filter_map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .max()
+}
+
+/// Server certificate reloaded from its files when they change, without restarting. A reload
+/// that fails keeps the current certificate.
+#[derive(Debug)]
+pub struct ReloadingCert {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert_file: &Path, key_file: &Path) -> Result<Self, String> {
+        let modified = modified(&[cert_file, key_file]);
+        let certified_key = load_certified_key(cert_file, key_file)?;
+        Ok(ReloadingCert {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            current: RwLock::new((Arc::new(certified_key), modified)),
+        })
+    }
+    /// Reloads the certificate if its files changed since the last load.
+    pub fn reload(&self) -> Result<bool, String> {
+        let modified = modified(&[&self.cert_file, &self.key_file]);
+        if modified == self.current.read().unwrap().1 {
+            return Ok(false)
+        }
+        let certified_key = load_certified_key(&self.cert_file, &self.key_file);
+        let mut current = self.current.write().unwrap();
+        // Not retried until the files change again.
+        current.1 = modified;
+        current.0 = Arc::new(certified_key?);
+        Ok(true)
+    }
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+            match self.reload() {
+                Ok(true) => tracing::info!("Reloaded TLS certificate {}", self.cert_file.display()),
+                Ok(false) => (),
+                Err(err) => tracing::warn!("TLS certificate reload failed, keeping the current one: {}", err),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+/// Clients must present a certificate issued by one of the CAs of `client_ca_file`.
+fn client_verifier(client_ca_file: &Path) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(client_ca_file)? {
+        roots.add(cert).map_err(|err| format!("{}: {}", client_ca_file.display(), err))?;
+    }
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+        .build()
+        .map_err(|err| format!("{}: {}", client_ca_file.display(), err))
+}
+
+/// Server configuration presenting `cert`, requiring client certificates when `client_ca_file`
+/// is given.
+pub fn server_config(cert: Arc<ReloadingCert>, client_ca_file: Option<&Path>) -> Result<ServerConfig, String> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let builder = match client_ca_file {
+        Some(client_ca_file) => builder.with_client_cert_verifier(client_verifier(client_ca_file)?),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_cert_resolver(cert))
+}
+
+/// Checks the files of a TLS configuration without serving them.
+pub fn check(cert_file: &Path, key_file: &Path, client_ca_file: Option<&Path>) -> Result<(), String> {
+    load_certified_key(cert_file, key_file)?;
+    if let Some(client_ca_file) = client_ca_file {
+        client_verifier(client_ca_file)?;
+    }
+    Ok(())
+}
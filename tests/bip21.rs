@@ -0,0 +1,102 @@
+use bitcoin::{
+    bip32,
+    Network,
+};
+use serde_json::json;
+use xpub_session_api::model::{
+    bip21::{
+        from_uri,
+        to_uri,
+        Bip21Error,
+        PaymentParams,
+    },
+    derivation,
+};
+
+fn address() -> bitcoin::Address {
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[3u8; 32]).unwrap();
+    derivation::derive_network_address(&bip32::Xpub::from_priv(&secp, &xpriv), &[0, 0])
+}
+
+fn params(value: serde_json::Value) -> PaymentParams {
+    serde_json::from_value(value).unwrap()
+}
+
+fn amount_in(uri: &str) -> Option<&str> {
+    uri.split(['?', '&']).find_map(|pair| pair.strip_prefix("amount="))
+}
+
+#[test]
+fn uris_round_trip() {
+    let address = address();
+    let sent = params(json!({
+        "amount_sat": 123_456,
+        "label": "Café & co",
+        "message": "order #42 100%",
+        "pj": "https://example.com/pj?v=1",
+    }));
+    let uri = to_uri(&address, &sent);
+    assert!(uri.starts_with(&format!("bitcoin:{}?", address)));
+    assert!(uri.contains("label=Caf%C3%A9%20%26%20co"));
+    let (parsed_address, parsed) = from_uri(&uri, Network::Testnet).unwrap();
+    assert_eq!(parsed_address, address);
+    assert_eq!(serde_json::to_value(parsed).unwrap(), serde_json::to_value(sent).unwrap());
+
+    assert_eq!(to_uri(&address, &PaymentParams::default()), format!("bitcoin:{}", address));
+}
+
+#[test]
+fn amounts_are_formatted_in_btc() {
+    let address = address();
+    for (sat, btc) in [
+        (1u64, "0.00000001"),
+        (10, "0.0000001"),
+        (100_000_000, "1"),
+        (123_456_789, "1.23456789"),
+        (2_100_000_000_000_000, "21000000"),
+    ] {
+        let uri = to_uri(&address, &params(json!({"amount_sat": sat})));
+        assert_eq!(amount_in(&uri), Some(btc), "{} sat", sat);
+        let (_, parsed) = from_uri(&uri, Network::Testnet).unwrap();
+        assert_eq!(serde_json::to_value(parsed).unwrap()["amount_sat"], json!(sat));
+    }
+    let uri = format!("bitcoin:{}?amount=0.000000001", address);
+    assert!(matches!(from_uri(&uri, Network::Testnet), Err(Bip21Error::Amount(_))));
+    let uri = format!("bitcoin:{}?amount=-1", address);
+    assert!(matches!(from_uri(&uri, Network::Testnet), Err(Bip21Error::Amount(_))));
+}
+
+#[test]
+fn parameters_are_percent_decoded() {
+    let address = address();
+    let uri = format!("BITCOIN:{}?label=Luke-Jr&message=Donation%20for%20project%20xyz", address);
+    let (_, parsed) = from_uri(&uri, Network::Testnet).unwrap();
+    let parsed = serde_json::to_value(parsed).unwrap();
+    assert_eq!(parsed["label"], json!("Luke-Jr"));
+    assert_eq!(parsed["message"], json!("Donation for project xyz"));
+
+    for value in ["%", "%2", "%zz", "%C3"] {
+        let uri = format!("bitcoin:{}?label={}", address, value);
+        assert!(matches!(from_uri(&uri, Network::Testnet), Err(Bip21Error::Encoding)), "{}", value);
+    }
+}
+
+#[test]
+fn unknown_required_parameters_are_rejected() {
+    let address = address();
+    let uri = format!("bitcoin:{}?req-somethingyoudontunderstand=50", address);
+    assert!(matches!(from_uri(&uri, Network::Testnet), Err(Bip21Error::RequiredParameter(name)) if name == "req-somethingyoudontunderstand"));
+    // Unknown optional parameters are ignored.
+    let uri = format!("bitcoin:{}?somethingyoudontunderstand=50", address);
+    assert!(from_uri(&uri, Network::Testnet).is_ok());
+}
+
+#[test]
+fn other_schemes_are_rejected() {
+    for uri in ["bitcoi€:abc", "bitcoin", "", "litecoin:abc", "€€€€€€€€"] {
+        assert!(matches!(from_uri(uri, Network::Testnet), Err(Bip21Error::Scheme)), "{}", uri);
+    }
+    let uri = format!("bitcoin:{}", address());
+    assert!(matches!(from_uri(&uri, Network::Bitcoin), Err(Bip21Error::Address(_))));
+}
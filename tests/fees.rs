@@ -0,0 +1,77 @@
+use std::time::Duration;
+use bitcoin::FeeRate;
+use serde_json::{
+    json,
+    Value,
+};
+use xpub_session_api::model::{
+    chain::memory::MemoryChain,
+    fees::{
+        FeeOracle,
+        FeeTarget,
+    },
+};
+
+fn sat_per_vb(sat_per_vb: u64) -> FeeRate {
+    FeeRate::from_sat_per_vb(sat_per_vb).unwrap()
+}
+
+/// The `(sat_per_vb, source)` estimated for `target`.
+fn estimate(oracle: &FeeOracle, chain: &MemoryChain, target: &str) -> (Value, Value) {
+    let estimates = serde_json::to_value(oracle.estimates(chain).unwrap()).unwrap();
+    let estimate = estimates["estimates"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|estimate| estimate["target"] == json!(target))
+        .unwrap()
+        .clone();
+    (estimate["sat_per_vb"].clone(), estimate["source"].clone())
+}
+
+#[test]
+fn without_estimates_the_fallback_is_used() {
+    let chain = MemoryChain::new();
+    let oracle = FeeOracle::new(1, 7, Duration::from_secs(60));
+    assert_eq!(oracle.fee_rate(&chain, FeeTarget::NextBlock).unwrap(), sat_per_vb(7));
+    assert_eq!(estimate(&oracle, &chain, "economy"), (json!(7), json!("fallback")));
+}
+
+#[test]
+fn node_estimates_are_used_for_their_target_and_shorter_ones() {
+    let chain = MemoryChain::new();
+    chain.set_fee_rate(6, sat_per_vb(12));
+    let oracle = FeeOracle::new(1, 7, Duration::from_secs(60));
+    assert_eq!(estimate(&oracle, &chain, "next_block"), (json!(12), json!("node")));
+    assert_eq!(estimate(&oracle, &chain, "hour"), (json!(12), json!("node")));
+    // Nothing for a longer horizon.
+    assert_eq!(estimate(&oracle, &chain, "economy"), (json!(7), json!("fallback")));
+}
+
+#[test]
+fn estimates_never_go_below_the_floor() {
+    let chain = MemoryChain::new();
+    chain.set_fee_rate(1, FeeRate::from_sat_per_kwu(100));
+    chain.set_fee_rate(144, sat_per_vb(2));
+    let oracle = FeeOracle::new(3, 1, Duration::from_secs(60));
+    assert_eq!(oracle.fee_rate(&chain, FeeTarget::NextBlock).unwrap(), sat_per_vb(3));
+    assert_eq!(oracle.fee_rate(&chain, FeeTarget::Economy).unwrap(), sat_per_vb(3));
+    let estimates = serde_json::to_value(oracle.estimates(&chain).unwrap()).unwrap();
+    assert_eq!(estimates["floor_sat_per_vb"], json!(3));
+
+    // The fallback too.
+    let oracle = FeeOracle::new(3, 1, Duration::from_secs(60));
+    assert_eq!(estimate(&oracle, &MemoryChain::new(), "economy"), (json!(3), json!("fallback")));
+}
+
+#[test]
+fn estimates_are_cached_until_the_ttl_expires() {
+    let chain = MemoryChain::new();
+    let oracle = FeeOracle::new(1, 7, Duration::from_millis(200));
+    assert_eq!(oracle.fee_rate(&chain, FeeTarget::NextBlock).unwrap(), sat_per_vb(7));
+
+    chain.set_fee_rate(1, sat_per_vb(25));
+    assert_eq!(oracle.fee_rate(&chain, FeeTarget::NextBlock).unwrap(), sat_per_vb(7));
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(oracle.fee_rate(&chain, FeeTarget::NextBlock).unwrap(), sat_per_vb(25));
+}
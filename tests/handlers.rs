@@ -0,0 +1,472 @@
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+use actix_session::{
+    storage::CookieSessionStore,
+    SessionMiddleware,
+};
+use actix_web::{
+    cookie::{
+        Cookie,
+        Key,
+    },
+    http::StatusCode,
+    test,
+    web,
+    App,
+};
+use bitcoin::{
+    absolute,
+    bip32,
+    hashes::Hash,
+    secp256k1::Secp256k1,
+    transaction,
+    Address,
+    Amount,
+    Network,
+    OutPoint,
+    ScriptBuf,
+    Sequence,
+    Transaction,
+    TxIn,
+    TxOut,
+    Txid,
+    Witness,
+};
+use mongodb::bson::oid::ObjectId;
+use serde_json::{
+    json,
+    Value,
+};
+use xpub_session_api::{
+    admin,
+    handlers,
+    model::{
+        XpubWrapper,
+        chain::{
+            memory::MemoryChain,
+            ChainBackend,
+        },
+        derivation,
+        fees::FeeOracle,
+        invoice::InvoiceStatus,
+        quota::QuotaPolicy,
+        storage::{
+            memory::MemoryStorage,
+            record_derivation,
+            DerivationTarget,
+            Storage,
+        },
+        watch_only::WatchOnlyMirror,
+        watcher::Watcher,
+        webhook::WebhookPolicy,
+    },
+};
+
+struct Wallet {
+    xpriv: bip32::Xpriv,
+    xpub: bip32::Xpub,
+}
+
+impl Wallet {
+    fn new(seed: u8) -> Self {
+        let secp = Secp256k1::new();
+        let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[seed; 32]).unwrap();
+        Wallet { xpriv, xpub: bip32::Xpub::from_priv(&secp, &xpriv) }
+    }
+    fn witness(&self, message: &str) -> Vec<u8> {
+        derivation::sign(&Secp256k1::new(), message, self.xpriv.private_key).serialize().to_vec()
+    }
+    /// Login credentials signing `nonce`.
+    fn credentials(&self, nonce: u32) -> Value {
+        json!({
+            "witness": self.witness(&format!("{}{}", self.xpub, nonce)),
+            "xpub": XpubWrapper::from(self.xpub),
+            "nonce": nonce,
+        })
+    }
+}
+
+struct Backends {
+    storage: web::Data<dyn Storage>,
+    chain: web::Data<dyn ChainBackend>,
+    memory_chain: Arc<MemoryChain>,
+}
+
+impl Backends {
+    fn new() -> Self {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let memory_chain = Arc::new(MemoryChain::new());
+        let chain: Arc<dyn ChainBackend> = memory_chain.clone();
+        Backends {
+            storage: web::Data::from(storage),
+            chain: web::Data::from(chain),
+            memory_chain,
+        }
+    }
+}
+
+/// The API as served, on memory storage and chain, with sessions kept in the cookie.
+macro_rules! app {
+    ($backends:expr, $quotas:expr) => {
+        test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::builder(CookieSessionStore::default(), Key::generate())
+                    .cookie_secure(false)
+                    .build())
+                .app_data($backends.storage.clone())
+                .app_data($backends.chain.clone())
+                .app_data(web::Data::new(Network::Testnet))
+                .app_data(web::Data::new(WatchOnlyMirror::new(false, 0)))
+                .app_data(web::Data::new($quotas))
+                .app_data(web::Data::new(WebhookPolicy::new(false)))
+                .app_data(web::Data::new(FeeOracle::default()))
+                .service(handlers::login)
+                .service(handlers::get_nonce)
+                .service(handlers::get_address)
+                .service(handlers::derive_address)
+                .service(handlers::payment_uri)
+                .service(handlers::get_quota)
+                .service(handlers::create_invoice)
+                .service(handlers::get_invoice)
+                .service(handlers::deletion_challenge)
+                .service(handlers::delete_account)
+                .service(handlers::get_wallets)
+                .service(handlers::link_wallet)
+                .service(handlers::select_wallet)
+                .service(handlers::unlink_wallet)
+                .service(handlers::create_sweep_psbt)
+        ).await
+    };
+}
+
+/// Sends `$request` with the cookies of `$jar`, keeping the cookies set by the response, and
+/// returns its status and JSON body (`Null` when not JSON).
+macro_rules! send {
+    ($app:expr, $jar:expr, $request:expr) => {{
+        let mut request = $request;
+        for cookie in $jar.iter() {
+            request = request.cookie(cookie.clone());
+        }
+        let response = test::call_service(&$app, request.to_request()).await;
+        for cookie in response.response().cookies() {
+            $jar.retain(|kept: &Cookie<'static>| kept.name() != cookie.name());
+            $jar.push(cookie.into_owned());
+        }
+        let status = response.status();
+        let body = test::read_body(response).await;
+        (status, serde_json::from_slice::<Value>(&body).unwrap_or(Value::Null))
+    }};
+}
+
+macro_rules! login {
+    ($app:expr, $wallet:expr, $nonce:expr) => {{
+        let mut jar: Vec<Cookie<'static>> = Vec::new();
+        let (status, _) = send!($app, jar, test::TestRequest::post().uri("/login").set_json($wallet.credentials($nonce)));
+        assert_eq!(status, StatusCode::OK);
+        jar
+    }};
+}
+
+fn payment(seed: u8, address: &Address, value: u64) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint { txid: Txid::from_byte_array([seed; 32]), vout: 0 },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut { value: Amount::from_sat(value), script_pubkey: address.script_pubkey() }],
+    }
+}
+
+fn address_of(invoice: &Value) -> Address {
+    invoice["address"].as_str().unwrap().parse::<Address<_>>().unwrap().assume_checked()
+}
+
+#[actix_web::test]
+async fn logins_use_each_nonce_once() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let wallet = Wallet::new(1);
+    let nonce_uri = format!("/nonce/{}", wallet.xpub);
+    let mut jar: Vec<Cookie<'static>> = Vec::new();
+
+    let (_, nonce) = send!(app, jar, test::TestRequest::get().uri(&nonce_uri));
+    assert_eq!(nonce, json!(0));
+    let (status, _) = send!(app, jar, test::TestRequest::get().uri("/get_address"));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let mut jar = login!(app, wallet, 0);
+    let (status, _) = send!(app, jar, test::TestRequest::get().uri("/get_address"));
+    assert_eq!(status, StatusCode::OK);
+    let (_, nonce) = send!(app, jar, test::TestRequest::get().uri(&nonce_uri));
+    assert_eq!(nonce, json!(1));
+
+    // Replayed, or signed by another key.
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/login").set_json(wallet.credentials(0)));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let mut forged = wallet.credentials(1);
+    forged["witness"] = json!(Wallet::new(2).witness(&format!("{}1", wallet.xpub)));
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/login").set_json(forged));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    login!(app, wallet, 1);
+    let (_, nonce) = send!(app, jar, test::TestRequest::get().uri(&nonce_uri));
+    assert_eq!(nonce, json!(2));
+}
+
+#[actix_web::test]
+async fn concurrent_derivations_stay_within_the_quota() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::parse("default=3", "default").unwrap());
+    let wallet = Wallet::new(3);
+    let mut jar = login!(app, wallet, 0);
+
+    let requests = (0..6).map(|index| {
+        let mut request = test::TestRequest::get().uri(&format!("/derive_address/0/{}", index));
+        for cookie in jar.iter() {
+            request = request.cookie(cookie.clone());
+        }
+        test::call_service(&app, request.to_request())
+    });
+    let statuses: Vec<StatusCode> = futures_util::future::join_all(requests).await
+        .iter()
+        .map(|response| response.status())
+        .collect();
+    assert_eq!(statuses.iter().filter(|status| **status == StatusCode::OK).count(), 3);
+    assert_eq!(statuses.iter().filter(|status| **status == StatusCode::INSUFFICIENT_STORAGE).count(), 3);
+
+    let (_, quota) = send!(app, jar, test::TestRequest::get().uri("/quota"));
+    assert_eq!(quota["derived"], json!(3));
+    assert_eq!(quota["quota"], json!(3));
+    // Keys already derived are returned again without counting against the quota.
+    let derived = statuses.iter().position(|status| *status == StatusCode::OK).unwrap();
+    let (status, key) = send!(app, jar, test::TestRequest::get().uri(&format!("/derive_address/0/{}", derived)));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(key["index"], json!(derived));
+}
+
+#[actix_web::test]
+async fn derivations_from_a_stale_version_are_retried_within_the_quota() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let wallet = Wallet::new(9);
+    login!(app, wallet, 0);
+    let quotas = QuotaPolicy::parse("default=2", "default").unwrap();
+    let stale = backends.storage.address_lookup(XpubWrapper::from(wallet.xpub)).await.unwrap();
+
+    // Each call starts from the version read before any of them, as racing requests would.
+    let storage = backends.storage.get_ref();
+    let (address, _) = record_derivation(storage, &quotas, stale.clone(), DerivationTarget::NextOnChain(0), None).await.unwrap();
+    assert_eq!(address.get_version(), stale.get_version() + 1);
+    let (address, key) = record_derivation(storage, &quotas, stale.clone(), DerivationTarget::NextOnChain(0), None).await.unwrap();
+    assert_eq!(address.get_version(), stale.get_version() + 2);
+    assert_eq!(key.get_derivation_path(), [0, 1]);
+    let err = record_derivation(storage, &quotas, stale, DerivationTarget::NextOnChain(0), None).await.err().unwrap();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::INSUFFICIENT_STORAGE);
+}
+
+#[actix_web::test]
+async fn hardened_indexes_are_rejected() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let wallet = Wallet::new(10);
+    let mut jar = login!(app, wallet, 0);
+
+    for uri in ["/derive_address/0/2147483648", "/derive_address/4294967295/0"] {
+        let (status, _) = send!(app, jar, test::TestRequest::get().uri(uri));
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/payment_uri/0/2147483648").set_json(json!({})));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/payment_uri/0/2147483647").set_json(json!({})));
+    assert_eq!(status, StatusCode::OK);
+
+    // The last normal index is taken: the chain has no next one.
+    let address = backends.storage.address_lookup(XpubWrapper::from(wallet.xpub)).await.unwrap();
+    let err = record_derivation(backends.storage.get_ref(), &QuotaPolicy::default(), address, DerivationTarget::NextOnChain(0), None).await.err().unwrap();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::BAD_REQUEST);
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/invoice").set_json(json!({"amount_sat": 1_000})));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn invoices_are_matched_on_request_and_by_the_watcher() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let wallet = Wallet::new(4);
+    let mut jar = login!(app, wallet, 0);
+    backends.memory_chain.set_tip_height(100);
+
+    let (status, first) = send!(app, jar, test::TestRequest::post().uri("/invoice").set_json(json!({"amount_sat": 20_000})));
+    assert_eq!(status, StatusCode::OK);
+    let (_, second) = send!(app, jar, test::TestRequest::post().uri("/invoice").set_json(json!({"amount_sat": 5_000})));
+    assert_ne!(first["address"], second["address"]);
+
+    backends.memory_chain.add_transaction(payment(1, &address_of(&first), 8_000), Some(100), Some(1_700_000_000));
+    let first_uri = format!("/invoice/{}", first["_id"]["$oid"].as_str().unwrap());
+    let (_, invoice) = send!(app, jar, test::TestRequest::get().uri(&first_uri));
+    assert_eq!(invoice["status"], json!("PartiallyPaid"));
+    assert_eq!(invoice["received_sat"], json!(8_000));
+    backends.memory_chain.add_transaction(payment(2, &address_of(&first), 12_000), Some(100), Some(1_700_000_000));
+    let (_, invoice) = send!(app, jar, test::TestRequest::get().uri(&first_uri));
+    assert_eq!(invoice["status"], json!("Paid"));
+
+    // Paid while nobody asks: the watcher matches it.
+    backends.memory_chain.add_transaction(payment(3, &address_of(&second), 6_000), Some(100), Some(1_700_000_000));
+    let mut watcher = Watcher::new(backends.storage.clone(), backends.chain.clone(), Duration::from_secs(1), None);
+    watcher.poll().await.unwrap();
+    let id = ObjectId::parse_str(second["_id"]["$oid"].as_str().unwrap()).unwrap();
+    let invoice = backends.storage.invoice_lookup(XpubWrapper::from(wallet.xpub), id).await.unwrap();
+    assert_eq!(invoice.get_status(), InvoiceStatus::Overpaid);
+}
+
+#[actix_web::test]
+async fn sweeps_consolidate_into_the_next_change_address() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let wallet = Wallet::new(9);
+    let mut jar = login!(app, wallet, 0);
+    backends.memory_chain.set_tip_height(120);
+
+    let (status, _) = send!(app, jar, test::TestRequest::get().uri("/derive_address/0/0"));
+    assert_eq!(status, StatusCode::OK);
+    let address = derivation::derive_network_address(&wallet.xpub, &[0, 0]);
+    let funding = payment(7, &address, 50_000);
+    let outpoint = OutPoint { txid: funding.compute_txid(), vout: 0 };
+    backends.memory_chain.add_transaction(funding, Some(110), Some(1_700_000_000));
+    let utxo = json!({"outpoint": outpoint, "value_u64": 50_000, "derivation_path": [0, 0]});
+    let sweep = |utxos: Vec<Value>| json!({"utxos": utxos, "destination": "Internal", "fee_rate_sat_vb": 2});
+
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/create_sweep_psbt").set_json(sweep(vec![utxo.clone(), utxo.clone()])));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let hardened = json!({"outpoint": outpoint, "value_u64": 50_000, "derivation_path": [0, 1u32 << 31]});
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/create_sweep_psbt").set_json(sweep(vec![hardened])));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    for index in 0..2 {
+        let (status, psbt) = send!(app, jar, test::TestRequest::post().uri("/create_sweep_psbt").set_json(sweep(vec![utxo.clone()])));
+        assert_eq!(status, StatusCode::OK);
+        let psbt: bitcoin::Psbt = serde_json::from_value(psbt).unwrap();
+        let change = derivation::derive_network_address(&wallet.xpub, &[1, index]);
+        assert_eq!(psbt.unsigned_tx.output[0].script_pubkey, change.script_pubkey());
+        assert_eq!(psbt.unsigned_tx.lock_time, absolute::LockTime::from_height(120).unwrap());
+        let derived = backends.storage.derived_key_lookup(XpubWrapper::from(wallet.xpub), [1, index]).await.unwrap();
+        assert!(derived.is_some());
+    }
+}
+
+#[actix_web::test]
+async fn account_deletion_takes_a_signed_challenge() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let wallet = Wallet::new(5);
+    let mut jar = login!(app, wallet, 0);
+    let (status, _) = send!(app, jar, test::TestRequest::get().uri("/derive_address/0/0"));
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/account/delete").set_json(json!({"witness": vec![0u8; 65]})));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // A challenge is good for one attempt.
+    let (_, challenge) = send!(app, jar, test::TestRequest::post().uri("/account/delete/challenge"));
+    let wrong = Wallet::new(6).witness(challenge["message"].as_str().unwrap());
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/account/delete").set_json(json!({"witness": wrong})));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let witness = wallet.witness(challenge["message"].as_str().unwrap());
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/account/delete").set_json(json!({"witness": witness})));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let stale_jar = jar.clone();
+    let (_, challenge) = send!(app, jar, test::TestRequest::post().uri("/account/delete/challenge"));
+    let witness = wallet.witness(challenge["message"].as_str().unwrap());
+    let (status, report) = send!(app, jar, test::TestRequest::post().uri("/account/delete").set_json(json!({"witness": witness})));
+    assert_eq!(status, StatusCode::OK);
+    assert!(report["deleted_records"].as_u64().unwrap() >= 2);
+
+    let xpub = XpubWrapper::from(wallet.xpub);
+    assert_eq!(backends.storage.address_lookup(xpub.clone()).await.err().map(|err| err.status()), Some(StatusCode::NOT_FOUND));
+    assert_eq!(backends.storage.derived_keys_count(xpub.clone()).await.unwrap(), 0);
+    let mut stale_jar = stale_jar;
+    let (status, _) = send!(app, stale_jar, test::TestRequest::get().uri("/get_address"));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Registering again starts a new account, which older sessions do not reach.
+    login!(app, wallet, 0);
+    let (status, _) = send!(app, stale_jar, test::TestRequest::get().uri("/get_address"));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn wallets_are_linked_with_their_signed_nonce() {
+    let backends = Backends::new();
+    let app = app!(backends, QuotaPolicy::default());
+    let owner = Wallet::new(7);
+    let other = Wallet::new(8);
+    let mut jar = login!(app, owner, 0);
+
+    let link = json!({"credentials": other.credentials(0), "label": "savings"});
+    let (status, user) = send!(app, jar, test::TestRequest::post().uri("/wallets/link").set_json(link.clone()));
+    assert_eq!(status, StatusCode::OK);
+    let wallets = user["wallets"].as_array().unwrap();
+    assert_eq!(wallets.len(), 2);
+    assert_eq!(wallets[0]["xpub"], json!(owner.xpub.to_string()));
+    assert_eq!(wallets[1]["label"], json!("savings"));
+    let other_id = wallets[1]["wallet_id"].as_str().unwrap().to_string();
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/wallets/link").set_json(link));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // The selected wallet is the one derived from.
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri(&format!("/wallets/{}/select", other_id)));
+    assert_eq!(status, StatusCode::OK);
+    let (_, key) = send!(app, jar, test::TestRequest::get().uri("/derive_address/0/0"));
+    assert_eq!(backends.storage.derived_keys_count(XpubWrapper::from(other.xpub)).await.unwrap(), 1);
+    assert_eq!(backends.storage.derived_keys_count(XpubWrapper::from(owner.xpub)).await.unwrap(), 0);
+    assert!(key["address"].is_string());
+
+    // The owner cannot be deleted while a wallet is linked to it.
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri(&format!("/wallets/{}/select", user["user_id"].as_str().unwrap())));
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/account/delete/challenge"));
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = send!(app, jar, test::TestRequest::delete().uri(&format!("/wallets/{}", other_id)));
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, user) = send!(app, jar, test::TestRequest::get().uri("/wallets"));
+    assert_eq!(user["wallets"].as_array().unwrap().len(), 1);
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri(&format!("/wallets/{}/select", other_id)));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn admin_endpoints_run_operator_tasks() {
+    let backends = Backends::new();
+    let app = test::init_service(
+        App::new()
+            .app_data(backends.storage.clone())
+            .app_data(backends.chain.clone())
+            .app_data(web::Data::new(WatchOnlyMirror::new(true, 10)))
+            .service(admin::import_watch_only)
+            .service(admin::migrate)
+            .service(admin::rotate_keys)
+    ).await;
+    let mut jar: Vec<Cookie<'static>> = Vec::new();
+    let (status, report) = send!(app, jar, test::TestRequest::post().uri("/migrate?dry_run=true"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["dry_run"], json!(true));
+    let (status, report) = send!(app, jar, test::TestRequest::post().uri("/rotate_keys"));
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["pending"], json!(0));
+
+    // Only registered xpubs are imported.
+    let unknown = Wallet::new(40);
+    let uri = format!("/watch_only/import/{}", unknown.xpub);
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri(&uri).set_json(json!({"timestamp": 0})));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send!(app, jar, test::TestRequest::post().uri("/watch_only/import/xpub").set_json(json!({})));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,232 @@
+use bitcoin::{
+    absolute,
+    bip32,
+    hashes::Hash,
+    transaction,
+    Amount,
+    FeeRate,
+    Network,
+    OutPoint,
+    ScriptBuf,
+    Sequence,
+    Transaction,
+    TxIn,
+    TxOut,
+    Txid,
+    Witness,
+};
+use xpub_session_api::model::{
+    balance::{
+        account_utxos,
+        p2wpkh_script,
+        Balance,
+    },
+    chain::{
+        memory::MemoryChain,
+        ChainBackend,
+        ChainError,
+        COINBASE_MATURITY,
+    },
+    derivation,
+};
+
+fn xpub() -> bip32::Xpub {
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[7u8; 32]).unwrap();
+    bip32::Xpub::from_priv(&secp, &xpriv)
+}
+
+/// A transaction spending `previous_output` into `value` sat locked to `script_pubkey`.
+fn payment(previous_output: OutPoint, script_pubkey: ScriptBuf, value: u64) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut { value: Amount::from_sat(value), script_pubkey }],
+    }
+}
+
+/// An outpoint unknown to the chain, so that every test transaction is unique.
+fn funding(seed: u8) -> OutPoint {
+    OutPoint { txid: Txid::from_byte_array([seed; 32]), vout: 0 }
+}
+
+#[test]
+fn utxos_follow_mempool_blocks_and_spends() {
+    let chain = MemoryChain::new();
+    let script = p2wpkh_script(&xpub(), &[0, 0]);
+    let other = p2wpkh_script(&xpub(), &[0, 1]);
+    chain.set_tip_height(10);
+
+    let deposit = payment(funding(1), script.clone(), 50_000);
+    let txid = deposit.compute_txid();
+    chain.add_transaction(deposit, None, None);
+    let utxos = chain.utxos_for_scripts(std::slice::from_ref(&script)).unwrap();
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos[0].outpoint, OutPoint { txid, vout: 0 });
+    assert!(!utxos[0].is_confirmed());
+
+    chain.mine(&txid, 1_700_000_000);
+    chain.set_tip_height(12);
+    let utxos = chain.utxos_for_scripts(std::slice::from_ref(&script)).unwrap();
+    assert_eq!(utxos[0].confirmations, 3);
+    assert!(chain.utxos_for_scripts(std::slice::from_ref(&other)).unwrap().is_empty());
+
+    chain.add_transaction(payment(OutPoint { txid, vout: 0 }, other.clone(), 49_000), None, None);
+    assert!(chain.utxos_for_scripts(&[script]).unwrap().is_empty());
+    assert_eq!(chain.utxos_for_scripts(&[other]).unwrap()[0].txout.value, Amount::from_sat(49_000));
+}
+
+#[test]
+fn disconnected_blocks_return_to_the_mempool() {
+    let chain = MemoryChain::new();
+    let script = p2wpkh_script(&xpub(), &[0, 0]);
+    chain.set_tip_height(5);
+    let deposit = payment(funding(2), script.clone(), 10_000);
+    let txid = deposit.compute_txid();
+    chain.add_transaction(deposit, Some(5), Some(1_700_000_000));
+    let hash = chain.block_hash(5).unwrap().unwrap();
+    assert_eq!(chain.block_transactions(&hash).unwrap().len(), 1);
+    assert!(chain.block_hash(6).unwrap().is_none());
+
+    chain.disconnect_blocks(5);
+    assert_eq!(chain.tip_height().unwrap(), 4);
+    assert!(chain.block_transactions(&hash).is_err());
+    assert_eq!(chain.mempool_transactions().unwrap()[0].compute_txid(), txid);
+    assert_eq!(chain.transaction(&txid).unwrap().unwrap().confirmations, 0);
+
+    chain.set_tip_height(5);
+    assert_ne!(chain.block_hash(5).unwrap(), Some(hash));
+}
+
+#[test]
+fn coinbase_outputs_mature_after_100_blocks() {
+    let chain = MemoryChain::new();
+    let script = p2wpkh_script(&xpub(), &[0, 0]);
+    chain.set_tip_height(1);
+    chain.add_transaction(payment(OutPoint::null(), script.clone(), 5_000_000_000), Some(1), Some(1_700_000_000));
+    assert!(chain.utxos_for_scripts(std::slice::from_ref(&script)).unwrap()[0].is_immature());
+
+    chain.set_tip_height(COINBASE_MATURITY);
+    assert!(!chain.utxos_for_scripts(&[script]).unwrap()[0].is_immature());
+}
+
+#[test]
+fn fee_estimates_fall_back_to_longer_targets() {
+    let chain = MemoryChain::new();
+    assert_eq!(chain.estimate_fee_rate(1).unwrap(), None);
+    chain.set_fee_rate(6, FeeRate::from_sat_per_vb_unchecked(5));
+    chain.set_fee_rate(144, FeeRate::from_sat_per_vb_unchecked(1));
+    assert_eq!(chain.estimate_fee_rate(1).unwrap(), Some(FeeRate::from_sat_per_vb_unchecked(5)));
+    assert_eq!(chain.estimate_fee_rate(7).unwrap(), Some(FeeRate::from_sat_per_vb_unchecked(1)));
+    assert_eq!(chain.estimate_fee_rate(145).unwrap(), None);
+}
+
+#[test]
+fn received_by_address_counts_watched_confirmed_payments() {
+    let chain = MemoryChain::new();
+    let address = derivation::derive_network_address(&xpub(), &[0, 3]);
+    chain.set_tip_height(3);
+    let first = payment(funding(3), address.script_pubkey(), 20_000);
+    let second = payment(funding(4), address.script_pubkey(), 5_000);
+    chain.add_transaction(first.clone(), Some(3), Some(1_700_000_000));
+    chain.add_transaction(second, None, None);
+    assert_eq!(chain.received_by_address(&address, 0).unwrap().0, Amount::ZERO);
+
+    chain.watch_address(&address, None).unwrap();
+    assert_eq!(chain.received_by_address(&address, 0).unwrap().0, Amount::from_sat(25_000));
+    let (received, txids) = chain.received_by_address(&address, 1).unwrap();
+    assert_eq!(received, Amount::from_sat(20_000));
+    assert_eq!(txids, vec![first.compute_txid()]);
+}
+
+#[test]
+fn broadcast_relays_accepted_transactions() {
+    let chain = MemoryChain::new();
+    let script = p2wpkh_script(&xpub(), &[0, 0]);
+    let transaction = payment(funding(5), script.clone(), 30_000);
+    let txid = transaction.compute_txid();
+
+    let acceptance = chain.test_mempool_accept(&transaction).unwrap();
+    assert!(acceptance.allowed);
+    assert_eq!(chain.broadcast(&transaction).unwrap(), txid);
+    assert_eq!(chain.mempool_transactions().unwrap().len(), 1);
+    assert_eq!(chain.utxos_for_scripts(&[script]).unwrap().len(), 1);
+
+    let acceptance = chain.test_mempool_accept(&transaction).unwrap();
+    assert!(!acceptance.allowed);
+    assert_eq!(acceptance.reject_reason.as_deref(), Some("txn-already-known"));
+}
+
+#[test]
+fn broadcast_fails_for_rejected_transactions() {
+    let chain = MemoryChain::new();
+    let transaction = payment(funding(6), p2wpkh_script(&xpub(), &[0, 0]), 30_000);
+    chain.reject(transaction.compute_txid(), "min relay fee not met");
+
+    let acceptance = chain.test_mempool_accept(&transaction).unwrap();
+    assert!(!acceptance.allowed);
+    assert_eq!(acceptance.reject_reason.as_deref(), Some("min relay fee not met"));
+    assert!(matches!(chain.broadcast(&transaction), Err(ChainError::Rejected(reason)) if reason == "min relay fee not met"));
+    assert!(chain.mempool_transactions().unwrap().is_empty());
+}
+
+#[test]
+fn account_utxos_and_balance() {
+    let chain = MemoryChain::new();
+    let xpub = xpub();
+    chain.set_tip_height(10);
+    chain.add_transaction(payment(funding(7), p2wpkh_script(&xpub, &[0, 0]), 100_000), Some(10), Some(1_700_000_000));
+    chain.add_transaction(payment(funding(8), p2wpkh_script(&xpub, &[1, 0]), 2_000), None, None);
+    chain.add_transaction(payment(OutPoint::null(), p2wpkh_script(&xpub, &[0, 1]), 625_000), Some(9), Some(1_700_000_000));
+    // Paying a path outside of the scanned ones.
+    chain.add_transaction(payment(funding(9), p2wpkh_script(&xpub, &[0, 50]), 7_000), Some(10), Some(1_700_000_000));
+
+    let mut utxos = account_utxos(&chain, &xpub, &[[0, 0], [0, 1], [1, 0]]).unwrap();
+    utxos.sort_by_key(|utxo| utxo.get_derivation_path());
+    let paths: Vec<[u32; 2]> = utxos.iter().map(|utxo| utxo.get_derivation_path()).collect();
+    assert_eq!(paths, vec![[0, 0], [0, 1], [1, 0]]);
+    assert_eq!(utxos[0].get_confirmations(), 1);
+    assert_eq!(utxos[2].get_confirmations(), 0);
+
+    let balance = serde_json::to_value(Balance::from_utxos(&utxos)).unwrap();
+    assert_eq!(balance, serde_json::json!({
+        "confirmed_sat": 100_000,
+        "unconfirmed_sat": 2_000,
+        "immature_sat": 625_000,
+    }));
+}
+
+#[test]
+fn transactions_are_paginated_newest_first() {
+    let chain = MemoryChain::new();
+    let script = p2wpkh_script(&xpub(), &[0, 0]);
+    chain.set_tip_height(3);
+    let txids: Vec<Txid> = (1..=3)
+        .map(|height| {
+            let transaction = payment(funding(10 + height as u8), script.clone(), 1_000);
+            let txid = transaction.compute_txid();
+            chain.add_transaction(transaction, Some(height), Some(1_700_000_000));
+            txid
+        })
+        .collect();
+    let unconfirmed = payment(funding(20), script.clone(), 1_000);
+    chain.add_transaction(unconfirmed.clone(), None, None);
+
+    let scripts = std::slice::from_ref(&script);
+    let page: Vec<Txid> = chain.transactions_for_scripts(scripts, 0, 2).unwrap()
+        .iter()
+        .map(|chain_tx| chain_tx.transaction.compute_txid())
+        .collect();
+    assert_eq!(page, vec![unconfirmed.compute_txid(), txids[2]]);
+    let page: Vec<Txid> = chain.transactions_for_scripts(scripts, 2, 5).unwrap()
+        .iter()
+        .map(|chain_tx| chain_tx.transaction.compute_txid())
+        .collect();
+    assert_eq!(page, vec![txids[1], txids[0]]);
+}
@@ -0,0 +1,3 @@
+#!/bin/bash
+# $1: serialized signature of the challenge message, e.g. [31,16,...]
+curl -b cookies.txt -c cookies.txt -X POST -H "Content-Type: application/json" -d "{\"witness\": $1}" http://localhost:8080/account/delete
@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -b cookies.txt -X POST http://localhost:8080/account/delete/challenge
@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -b cookies.txt -X GET http://localhost:8080/account/export
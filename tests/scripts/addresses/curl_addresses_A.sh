@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -b cookies.txt -X GET "http://localhost:8080/addresses?chain=0&used=false&per_page=50&page=0"
@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -X GET http://localhost:8080/fees
@@ -0,0 +1,4 @@
+#!/bin/bash
+curl -b cookies.txt -H 'Content-Type: application/json' -X POST \
+-d '{"amount_sat":150000,"expiry_secs":3600,"label":"Order 1"}' \
+http://localhost:8080/invoice
@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -b cookies.txt -H 'Content-Type: application/json' -X GET http://localhost:8080/invoices
@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -X GET http://localhost:8080/nonce/tpubDBehSLaWLmy4K2bPPwUHzCK4dtveXp83QdQFf1tyiFUqwyhGQ811X9QajfRcwE1MUHi2U1yhvBwttAHaS4JnwRvfXUFuYQPkzRHLqXzi2eG
@@ -0,0 +1,4 @@
+#!/bin/bash
+curl -b cookies.txt -H 'Content-Type: application/json' -X POST \
+-d '{"uri":"bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx?amount=0.001&label=Invoice%201"}' \
+http://localhost:8080/parse_payment_uri
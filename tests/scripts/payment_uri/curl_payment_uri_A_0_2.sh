@@ -0,0 +1,4 @@
+#!/bin/bash
+curl -b cookies.txt -H 'Content-Type: application/json' -X POST \
+-d '{"amount_sat":100000,"label":"Invoice 1"}' \
+http://localhost:8080/payment_uri/0/2
@@ -0,0 +1,6 @@
+#!/bin/bash
+curl -b cookies.txt -H 'Content-Type: application/json' -X POST \
+-d '{"utxos":[{"outpoint":"06980ca116f74c7845a897461dd0e1d15b114130176de5004957da516b4dee3a:0","value_u64":6588,"derivation_path":[0,0]}],
+"destination":"Internal",
+"fee_target":"economy"}' \
+http://localhost:8080/create_sweep_psbt
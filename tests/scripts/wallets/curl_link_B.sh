@@ -0,0 +1,8 @@
+#!/bin/bash
+# Links wallet B to the user logged in with A, signing B's nonce 0.
+curl -b cookies.txt -c cookies.txt -H 'Content-Type: application/json' -X POST \
+-d '{"credentials":{"witness":[31, 38, 156, 59, 94, 240, 163, 119, 218, 1, 45, 217, 200, 227, 75, 206, 147, 35, 27, 167, 39, 50, 101, 147, 240, 148, 106, 229, 114, 252, 42, 135, 56, 116, 114, 151, 208, 50, 116, 226, 250, 59, 29, 67, 18, 217, 231, 99, 40, 0, 145, 180, 11, 59, 142, 90, 134, 231, 99, 82, 178, 41, 105, 94, 163],
+"xpub":{"bytes":[4, 53, 135, 207, 2, 136, 248, 217, 191, 0, 0, 0, 0, 196, 135, 140, 42, 155, 225, 247, 55, 181, 230, 124, 102, 217, 125, 71, 238, 140, 227, 9, 47, 134, 240, 66, 115, 166, 31, 39, 105, 19, 190, 42, 126, 2, 212, 220, 93, 253, 25, 112, 33, 144, 170, 232, 27, 220, 164, 10, 248, 101, 61, 66, 248, 156, 174, 233, 173, 2, 236, 86, 80, 176, 0, 68, 40, 42]},
+"nonce":0},
+"label":"savings"}' \
+http://localhost:8080/wallets/link
@@ -0,0 +1,3 @@
+#!/bin/bash
+# $1: wallet_id listed by /wallets
+curl -b cookies.txt -c cookies.txt -X DELETE http://localhost:8080/wallets/$1
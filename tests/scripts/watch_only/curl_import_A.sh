@@ -0,0 +1,5 @@
+#!/bin/bash
+# Served on the admin listener, e.g. ADMIN_BIND=127.0.0.1:8081.
+curl -H 'Content-Type: application/json' -X POST \
+-d '{"timestamp":0}' \
+http://localhost:8081/watch_only/import/tpubDBehSLaWLmy4K2bPPwUHzCK4dtveXp83QdQFf1tyiFUqwyhGQ811X9QajfRcwE1MUHi2U1yhvBwttAHaS4JnwRvfXUFuYQPkzRHLqXzi2eG
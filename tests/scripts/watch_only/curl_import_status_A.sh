@@ -0,0 +1,2 @@
+#!/bin/bash
+curl -X GET http://localhost:8081/watch_only/import
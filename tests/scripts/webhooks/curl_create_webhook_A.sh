@@ -0,0 +1,5 @@
+#!/bin/bash
+# Pair with: cargo run --example webhook_receiver -- [SECRET]
+curl -b cookies.txt -H 'Content-Type: application/json' -X POST \
+-d '{"url":"http://localhost:8081/webhook","events":["deposit_seen","deposit_confirmed","psbt_signed","transaction_broadcast","invoice_paid"],"confirmations":3}' \
+http://localhost:8080/webhooks
@@ -0,0 +1,3 @@
+#!/bin/bash
+# Usage: curl_delete_webhook_A.sh [WEBHOOK_ID]
+curl -b cookies.txt -X DELETE http://localhost:8080/webhooks/$1
@@ -0,0 +1,3 @@
+#!/bin/bash
+# Usage: curl_deliveries_A.sh [WEBHOOK_ID]
+curl -b cookies.txt -X GET http://localhost:8080/webhooks/$1/deliveries
@@ -0,0 +1,5 @@
+#!/bin/bash
+# Usage: curl_psbt_signed_A.sh [SIGNED_PSBT_JSON_FILE]
+curl -b cookies.txt -H 'Content-Type: application/json' -X POST \
+-d "{\"psbt\":$(cat $1)}" \
+http://localhost:8080/psbt/signed
@@ -0,0 +1,242 @@
+use bitcoin::{
+    bip32,
+    Amount,
+    Network,
+};
+use rand::RngCore;
+use serde_json::{
+    json,
+    Value,
+};
+use xpub_session_api::model::{
+    Credentials,
+    Nonce,
+    UserAddress,
+    XpubWrapper,
+    derivation,
+    invoice::{
+        InvoiceRequest,
+        InvoiceStatus,
+    },
+    storage::{
+        sql::SqlStorage,
+        Storage,
+    },
+    vault::{
+        Sealed,
+        Vault,
+    },
+};
+
+fn xpub() -> XpubWrapper {
+    xpub_from(11)
+}
+
+fn xpub_from(seed: u8) -> XpubWrapper {
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[seed; 32]).unwrap();
+    XpubWrapper::from(bip32::Xpub::from_priv(&secp, &xpriv))
+}
+
+async fn register(storage: &SqlStorage, xpub: &XpubWrapper) {
+    let credentials: Credentials<XpubWrapper> = serde_json::from_value(json!({
+        "witness": vec![0u8; 65],
+        "xpub": xpub,
+        "nonce": 0,
+    })).unwrap();
+    storage.insert_address(UserAddress::from_credentials(credentials)).await.unwrap();
+}
+
+async fn sealed_xpub(pool: &sqlx::AnyPool, owner: &str) -> String {
+    let (sealed,): (String,) = sqlx::query_as("SELECT sealed_xpub FROM users WHERE xpub = $1")
+        .bind(owner)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    sealed
+}
+
+async fn set_sealed_xpub(pool: &sqlx::AnyPool, owner: &str, sealed: &str) {
+    sqlx::query("UPDATE users SET sealed_xpub = $1 WHERE xpub = $2")
+        .bind(sealed)
+        .bind(owner)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+fn vault(keys: &[(&str, u8)], active_key_id: &str) -> Vault {
+    let keys = keys.iter().map(|(id, byte)| (id.to_string(), [*byte; 32])).collect();
+    Vault::new(keys, active_key_id, vec![5u8; 32]).unwrap()
+}
+
+/// A SQLite database in a new file, shared by the storages connected to it and removed on drop.
+struct Database(std::path::PathBuf);
+
+impl Database {
+    fn new() -> Self {
+        let mut name = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut name);
+        Database(std::env::temp_dir().join(format!("xpub-session-api-{}.db", hex::encode(name))))
+    }
+    fn url(&self) -> String {
+        format!("sqlite://{}?mode=rwc", self.0.display())
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn table_steps(report: &Value) -> usize {
+    report["steps"].as_array().unwrap().iter().filter(|step| step["collection"] == json!("tables")).count()
+}
+
+#[actix_web::test]
+async fn applied_migrations_are_not_pending() {
+    let database = Database::new();
+    let storage = SqlStorage::connect(&database.url(), 1, vault(&[("a", 1)], "a")).await.unwrap();
+    storage.init().await.unwrap();
+    let report = serde_json::to_value(storage.migrate(true).await.unwrap()).unwrap();
+    assert_eq!(table_steps(&report), 0);
+    assert!(report["steps"].as_array().unwrap().iter().all(|step| step["pending"] == json!(0)));
+}
+
+#[actix_web::test]
+async fn rotation_rewraps_records_updated_since_they_were_written() {
+    let database = Database::new();
+    let url = database.url();
+    let xpub = xpub();
+    let storage = SqlStorage::connect(&url, 1, vault(&[("a", 1)], "a")).await.unwrap();
+    storage.init().await.unwrap();
+    let credentials: Credentials<XpubWrapper> = serde_json::from_value(json!({
+        "witness": vec![0u8; 65],
+        "xpub": xpub,
+        "nonce": 0,
+    })).unwrap();
+    storage.insert_address(UserAddress::from_credentials(credentials)).await.unwrap();
+    let address = derivation::derive_network_address(&xpub.clone().to_xpub(), &[0, 0]);
+    let request: InvoiceRequest = serde_json::from_value(json!({"amount_sat": 1_000})).unwrap();
+    let mut invoice = storage.insert_invoice(request.to_invoice(xpub.clone(), [0, 0], &address)).await.unwrap();
+    invoice.update_received(Amount::from_sat(1_000), Vec::new(), 0);
+    storage.update_invoice(invoice.clone()).await.unwrap();
+
+    let rotated = SqlStorage::connect(&url, 1, vault(&[("a", 1), ("b", 2)], "b")).await.unwrap();
+    let report = serde_json::to_value(rotated.rotate_keys(false).await.unwrap()).unwrap();
+    assert_eq!(report["key_id"], json!("b"));
+    // The user's sealed xpub and the invoice.
+    assert_eq!(report["rewrapped"], json!(2));
+    let report = serde_json::to_value(rotated.rotate_keys(true).await.unwrap()).unwrap();
+    assert_eq!(report["pending"], json!(0));
+
+    let stored = rotated.invoice_lookup(xpub.clone(), invoice.get_id()).await.unwrap();
+    assert_eq!(stored.get_status(), InvoiceStatus::Paid);
+    assert_eq!(stored.get_address(), invoice.get_address());
+}
+
+#[actix_web::test]
+async fn rows_of_users_from_before_sealing_are_sealed() {
+    let database = Database::new();
+    let xpub = xpub();
+    let storage = SqlStorage::connect(&database.url(), 1, vault(&[("a", 1)], "a")).await.unwrap();
+    storage.init().await.unwrap();
+    let address = derivation::derive_network_address(&xpub.clone().to_xpub(), &[0, 0]);
+    let request: InvoiceRequest = serde_json::from_value(json!({"amount_sat": 1_000})).unwrap();
+    let invoice = request.to_invoice(xpub.clone(), [0, 0], &address);
+
+    // Rows as written before sealing: keyed by the base58 xpub, with plain bodies.
+    sqlx::any::install_default_drivers();
+    let pool = sqlx::AnyPool::connect(&database.url()).await.unwrap();
+    let legacy_key = xpub.clone().to_xpub().to_string();
+    sqlx::query("INSERT INTO users (xpub, nonce, version) VALUES ($1, 0, 0)")
+        .bind(&legacy_key)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO invoices (id, xpub, body) VALUES ($1, $2, $3)")
+        .bind(invoice.get_id().to_hex())
+        .bind(&legacy_key)
+        .bind(serde_json::to_string(&invoice).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    storage.migrate(false).await.unwrap();
+    let stored = storage.invoice_lookup(xpub.clone(), invoice.get_id()).await.unwrap();
+    assert_eq!(stored.get_address(), invoice.get_address());
+    assert!(storage.address_lookup(xpub).await.is_ok());
+}
+
+#[actix_web::test]
+async fn sealed_xpubs_swapped_between_rows_fail_to_open() {
+    let database = Database::new();
+    let vault = vault(&[("a", 1)], "a");
+    let (first, second) = (xpub_from(11), xpub_from(12));
+    let (first_id, second_id) = (vault.fingerprint(&first), vault.fingerprint(&second));
+    let storage = SqlStorage::connect(&database.url(), 1, vault).await.unwrap();
+    storage.init().await.unwrap();
+    register(&storage, &first).await;
+    register(&storage, &second).await;
+
+    sqlx::any::install_default_drivers();
+    let pool = sqlx::AnyPool::connect(&database.url()).await.unwrap();
+    let sealed = sealed_xpub(&pool, second_id.as_str()).await;
+    set_sealed_xpub(&pool, first_id.as_str(), &sealed).await;
+    assert!(storage.address_lookup(first).await.is_err());
+    assert!(storage.address_lookup(second).await.is_ok());
+}
+
+#[actix_web::test]
+async fn unbound_sealed_xpubs_are_checked_and_bound_by_rotation() {
+    let database = Database::new();
+    let (first, second) = (xpub_from(11), xpub_from(12));
+    let vault = vault(&[("a", 1)], "a");
+    let (first_id, second_id) = (vault.fingerprint(&first), vault.fingerprint(&second));
+    // Sealed before values were bound to their row.
+    let unbound = |xpub: &XpubWrapper| serde_json::to_string(&vault.seal(&xpub.clone().to_bytes()).unwrap()).unwrap();
+    let (first_unbound, second_unbound) = (unbound(&first), unbound(&second));
+    let storage = SqlStorage::connect(&database.url(), 1, vault).await.unwrap();
+    storage.init().await.unwrap();
+    register(&storage, &first).await;
+    register(&storage, &second).await;
+
+    sqlx::any::install_default_drivers();
+    let pool = sqlx::AnyPool::connect(&database.url()).await.unwrap();
+    set_sealed_xpub(&pool, first_id.as_str(), &first_unbound).await;
+    assert!(storage.address_lookup(first.clone()).await.is_ok());
+    let report = serde_json::to_value(storage.rotate_keys(false).await.unwrap()).unwrap();
+    assert_eq!(report["rewrapped"], json!(1));
+    let sealed: Sealed = serde_json::from_str(&sealed_xpub(&pool, first_id.as_str()).await).unwrap();
+    assert!(sealed.is_bound());
+    assert!(storage.address_lookup(first).await.is_ok());
+
+    // An unbound xpub of another user is neither loaded nor bound to the row.
+    set_sealed_xpub(&pool, second_id.as_str(), &second_unbound).await;
+    set_sealed_xpub(&pool, first_id.as_str(), &second_unbound).await;
+    assert!(storage.address_lookup(xpub_from(11)).await.is_err());
+    assert!(storage.rotate_keys(false).await.is_err());
+}
+
+#[actix_web::test]
+async fn the_last_nonce_cannot_be_used() {
+    let database = Database::new();
+    let xpub = xpub();
+    let storage = SqlStorage::connect(&database.url(), 1, vault(&[("a", 1)], "a")).await.unwrap();
+    storage.init().await.unwrap();
+    let credentials: Credentials<XpubWrapper> = serde_json::from_value(json!({
+        "witness": vec![0u8; 65],
+        "xpub": xpub,
+        "nonce": u32::MAX - 1,
+    })).unwrap();
+    storage.insert_address(UserAddress::from_credentials(credentials)).await.unwrap();
+
+    let nonce: Nonce = serde_json::from_value(json!(u32::MAX - 1)).unwrap();
+    let next = storage.advance_nonce(xpub.clone(), nonce).await.unwrap().unwrap();
+    assert_eq!(next, serde_json::from_value(json!(u32::MAX)).unwrap());
+    let err = storage.advance_nonce(xpub.clone(), next).await.unwrap_err();
+    assert_eq!(err.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    assert_eq!(storage.address_lookup(xpub).await.unwrap().get_nonce(), serde_json::from_value::<Nonce>(json!(u32::MAX)).unwrap());
+}
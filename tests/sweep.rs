@@ -0,0 +1,101 @@
+use bitcoin::{
+    absolute,
+    bip32,
+    hashes::Hash,
+    secp256k1::Secp256k1,
+    transaction::{
+        self,
+        InputWeightPrediction,
+    },
+    Amount,
+    FeeRate,
+    Network,
+    OutPoint,
+    ScriptBuf,
+    Txid,
+};
+use serde_json::json;
+use xpub_session_api::model::psbt::{
+    create_sweep_psbt,
+    Timelocks,
+    UtxoSerialized,
+};
+
+fn xpub() -> bip32::Xpub {
+    let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[5; 32]).unwrap();
+    bip32::Xpub::from_priv(&Secp256k1::new(), &xpriv)
+}
+
+fn utxo(seed: u8, value: u64, path: [u32; 2]) -> UtxoSerialized {
+    let outpoint = OutPoint { txid: Txid::from_byte_array([seed; 32]), vout: 0 };
+    serde_json::from_value(json!({
+        "outpoint": outpoint,
+        "value_u64": value,
+        "derivation_path": path,
+    })).unwrap()
+}
+
+fn destination() -> ScriptBuf {
+    ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros())
+}
+
+fn fee(inputs: usize, fee_rate: FeeRate) -> Amount {
+    let weight = transaction::predict_weight(
+        vec![InputWeightPrediction::P2WPKH_MAX; inputs],
+        [destination().len()],
+    );
+    fee_rate.fee_wu(weight).unwrap()
+}
+
+#[test]
+fn every_utxo_is_swept_into_one_output_less_the_fee() {
+    let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+    let utxos = vec![utxo(1, 10_000, [0, 0]), utxo(2, 20_000, [0, 3]), utxo(3, 5_000, [1, 0])];
+    let psbt = create_sweep_psbt(&xpub(), utxos, destination(), fee_rate, &Timelocks::default()).unwrap();
+
+    let transaction = &psbt.unsigned_tx;
+    assert_eq!(transaction.input.len(), 3);
+    assert_eq!(transaction.output.len(), 1);
+    assert_eq!(transaction.output[0].script_pubkey, destination());
+    assert_eq!(transaction.output[0].value, Amount::from_sat(35_000) - fee(3, fee_rate));
+    // The witness UTXOs carry the swept values, so the signer sees the same fee.
+    let total: Amount = psbt.inputs.iter().map(|input| input.witness_utxo.as_ref().unwrap().value).sum();
+    assert_eq!(total, Amount::from_sat(35_000));
+    assert_eq!(psbt.fee().unwrap(), fee(3, fee_rate));
+    assert!(psbt.inputs.iter().all(|input| input.bip32_derivation.len() == 1));
+}
+
+#[test]
+fn the_timelocks_are_applied() {
+    let timelocks = Timelocks::default().with_tip_height(absolute::Height::from_consensus(800_000).unwrap());
+    let psbt = create_sweep_psbt(&xpub(), vec![utxo(1, 10_000, [0, 0])], destination(), FeeRate::from_sat_per_vb(1).unwrap(), &timelocks).unwrap();
+    assert_eq!(psbt.unsigned_tx.lock_time, absolute::LockTime::from_height(800_000).unwrap());
+}
+
+#[test]
+fn outputs_below_the_dust_limit_are_rejected() {
+    let fee_rate = FeeRate::from_sat_per_vb(1).unwrap();
+    let dust_limit = destination().minimal_non_dust();
+    let at_limit = (dust_limit + fee(1, fee_rate)).to_sat();
+    assert!(create_sweep_psbt(&xpub(), vec![utxo(1, at_limit, [0, 0])], destination(), fee_rate, &Timelocks::default()).is_ok());
+    let err = create_sweep_psbt(&xpub(), vec![utxo(1, at_limit - 1, [0, 0])], destination(), fee_rate, &Timelocks::default()).unwrap_err();
+    assert_eq!(err.to_string(), "Output below dust limit");
+    // Not even enough for the fee.
+    assert!(create_sweep_psbt(&xpub(), vec![utxo(1, 100, [0, 0])], destination(), fee_rate, &Timelocks::default()).is_err());
+}
+
+#[test]
+fn duplicate_inputs_are_rejected() {
+    let fee_rate = FeeRate::from_sat_per_vb(1).unwrap();
+    let utxos = vec![utxo(1, 10_000, [0, 0]), utxo(2, 10_000, [0, 1]), utxo(1, 10_000, [0, 0])];
+    assert!(create_sweep_psbt(&xpub(), utxos, destination(), fee_rate, &Timelocks::default()).is_err());
+}
+
+#[test]
+fn hardened_paths_are_rejected() {
+    let fee_rate = FeeRate::from_sat_per_vb(1).unwrap();
+    for path in [[1 << 31, 0], [0, u32::MAX]] {
+        assert!(create_sweep_psbt(&xpub(), vec![utxo(1, 10_000, path)], destination(), fee_rate, &Timelocks::default()).is_err());
+    }
+    assert!(create_sweep_psbt(&xpub(), Vec::new(), destination(), fee_rate, &Timelocks::default()).is_err());
+}
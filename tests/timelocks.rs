@@ -0,0 +1,154 @@
+use bitcoin::{
+    absolute,
+    transaction,
+    OutPoint,
+    ScriptBuf,
+    Sequence,
+    Transaction,
+    TxIn,
+    Witness,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use xpub_session_api::model::psbt::{
+    Timelocks,
+    TimelocksSerialized,
+};
+
+fn timelocks(value: Value, inputs: usize) -> Timelocks {
+    let serialized: TimelocksSerialized = serde_json::from_value(value).unwrap();
+    serialized.to_timelocks(inputs).unwrap()
+}
+
+/// A transaction spending `inputs` inputs, each with sequence `sequence`.
+fn transaction(inputs: usize, sequence: Sequence) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: (0..inputs)
+            .map(|_| TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::default(),
+            })
+            .collect(),
+        output: Vec::new(),
+    }
+}
+
+fn sequences(transaction: &Transaction) -> Vec<Sequence> {
+    transaction.input.iter().map(|input| input.sequence).collect()
+}
+
+#[test]
+fn relative_locks_are_bip68_encoded() {
+    let timelocks = timelocks(json!({
+        "relative_locks": [{"Blocks": 144}, {"Intervals": 10}, null],
+    }), 3);
+    let mut transaction = transaction(3, Sequence::MAX);
+    timelocks.apply(&mut transaction);
+    assert_eq!(sequences(&transaction), vec![
+        Sequence(144),
+        // Type flag (bit 22) set for 512 seconds intervals.
+        Sequence((1 << 22) | 10),
+        Sequence::MAX,
+    ]);
+    assert!(transaction.input[0].sequence.is_height_locked());
+    assert!(transaction.input[1].sequence.is_time_locked());
+    assert_eq!(transaction.lock_time, absolute::LockTime::ZERO);
+}
+
+#[test]
+fn more_relative_locks_than_inputs_are_rejected() {
+    let serialized: TimelocksSerialized = serde_json::from_value(json!({
+        "relative_locks": [{"Blocks": 1}, {"Blocks": 2}],
+    })).unwrap();
+    assert!(serialized.to_timelocks(1).is_err());
+}
+
+#[test]
+fn out_of_range_locktimes_are_rejected() {
+    for value in [
+        json!({"lock_time": {"Height": 500_000_000}}),
+        json!({"lock_time": {"Time": 499_999_999}}),
+        json!({"tip_height": 500_000_000}),
+    ] {
+        let serialized: TimelocksSerialized = serde_json::from_value(value).unwrap();
+        assert!(serialized.to_timelocks(1).is_err());
+    }
+}
+
+#[test]
+fn without_a_tip_the_locktime_is_zero() {
+    let timelocks = timelocks(json!({}), 2);
+    let mut transaction = transaction(2, Sequence::MAX);
+    timelocks.apply(&mut transaction);
+    assert_eq!(transaction.lock_time, absolute::LockTime::ZERO);
+    assert_eq!(sequences(&transaction), vec![Sequence::MAX; 2]);
+}
+
+#[test]
+fn the_tip_height_discourages_fee_sniping() {
+    let timelocks = timelocks(json!({"tip_height": 800_000}), 2);
+    let mut transaction = transaction(2, Sequence::MAX);
+    timelocks.apply(&mut transaction);
+    assert_eq!(transaction.lock_time, absolute::LockTime::from_height(800_000).unwrap());
+    // A final sequence on every input would disable the locktime.
+    assert_eq!(sequences(&transaction), vec![Sequence::ENABLE_LOCKTIME_NO_RBF; 2]);
+}
+
+#[test]
+fn a_requested_locktime_takes_precedence_over_the_tip() {
+    let timelocks = timelocks(json!({
+        "lock_time": {"Time": 1_700_000_000},
+        "tip_height": 800_000,
+    }), 1);
+    let mut transaction = transaction(1, Sequence::MAX);
+    timelocks.apply(&mut transaction);
+    assert_eq!(transaction.lock_time, absolute::LockTime::from_time(1_700_000_000).unwrap());
+    assert_eq!(sequences(&transaction), vec![Sequence::ENABLE_LOCKTIME_NO_RBF]);
+}
+
+#[test]
+fn rbf_is_signalled_and_keeps_the_locktime_enabled() {
+    let timelocks = timelocks(json!({"rbf": true, "tip_height": 800_000}), 2);
+    let mut transaction = transaction(2, Sequence::MAX);
+    timelocks.apply(&mut transaction);
+    assert_eq!(sequences(&transaction), vec![Sequence::ENABLE_RBF_NO_LOCKTIME; 2]);
+    assert!(transaction.input.iter().all(|input| input.sequence.is_rbf() && input.sequence.enables_absolute_lock_time()));
+}
+
+#[test]
+fn relative_and_absolute_locks_combine() {
+    // CSV on the first input, CLTV on the transaction: the BIP68 sequence must not be replaced,
+    // and the other input must still enable the locktime.
+    let timelocks = timelocks(json!({
+        "lock_time": {"Height": 800_100},
+        "relative_locks": [{"Blocks": 6}],
+    }), 2);
+    let mut transaction = transaction(2, Sequence::MAX);
+    timelocks.apply(&mut transaction);
+    assert_eq!(transaction.lock_time, absolute::LockTime::from_height(800_100).unwrap());
+    assert_eq!(sequences(&transaction), vec![Sequence(6), Sequence::ENABLE_LOCKTIME_NO_RBF]);
+    assert!(transaction.input[0].sequence.enables_absolute_lock_time());
+}
+
+#[test]
+fn relative_locks_override_client_sequences() {
+    let timelocks = timelocks(json!({
+        "rbf": true,
+        "relative_locks": [{"Intervals": 1}],
+    }), 2);
+    let mut transaction = transaction(2, Sequence(42));
+    timelocks.apply(&mut transaction);
+    assert_eq!(sequences(&transaction), vec![Sequence((1 << 22) | 1), Sequence::ENABLE_RBF_NO_LOCKTIME]);
+
+    // Without RBF or a locktime, the client's sequence is kept.
+    let kept = self::timelocks(json!({}), 1);
+    let mut unlocked = self::transaction(1, Sequence(42));
+    kept.apply(&mut unlocked);
+    assert_eq!(sequences(&unlocked), vec![Sequence(42)]);
+}
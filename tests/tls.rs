@@ -0,0 +1,184 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+use rand::RngCore;
+use rustls::{
+    crypto::ring,
+    pki_types::{
+        CertificateDer,
+        ServerName,
+    },
+    ClientConfig,
+    ClientConnection,
+    Connection,
+    RootCertStore,
+    ServerConfig,
+    ServerConnection,
+};
+use xpub_session_api::tls::{
+    self,
+    ReloadingCert,
+};
+
+/// Certificates issued by `ca.pem` for `localhost` (`server`, `renewed`) and for an operator
+/// (`client`), valid for a century.
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+}
+
+fn certs(name: &str) -> Vec<CertificateDer<'static>> {
+    let file = File::open(fixture(name)).unwrap();
+    rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>().unwrap()
+}
+
+/// A client trusting the fixture CA, presenting the `client` certificate when `authenticated`.
+fn client_config(authenticated: bool) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add(certs("ca.pem").remove(0)).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    if !authenticated {
+        return builder.with_no_client_auth()
+    }
+    let file = File::open(fixture("client.key.pem")).unwrap();
+    let key = rustls_pemfile::private_key(&mut BufReader::new(file)).unwrap().unwrap();
+    builder.with_client_auth_cert(certs("client.pem"), key).unwrap()
+}
+
+/// Moves the pending TLS records of `from` into `to`.
+fn transfer(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+    let mut records = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut records).unwrap();
+    }
+    let mut records = &records[..];
+    while !records.is_empty() {
+        to.read_tls(&mut records).unwrap();
+        to.process_new_packets()?;
+    }
+    Ok(())
+}
+
+/// Runs a handshake in memory, returning the certificate the server presented.
+fn handshake(server_config: ServerConfig, client_config: ClientConfig) -> Result<CertificateDer<'static>, rustls::Error> {
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut client = Connection::from(ClientConnection::new(Arc::new(client_config), name)?);
+    let mut server = Connection::from(ServerConnection::new(Arc::new(server_config))?);
+    for _ in 0..10 {
+        if !client.is_handshaking() && !server.is_handshaking() {
+            return Ok(client.peer_certificates().unwrap()[0].clone().into_owned())
+        }
+        transfer(&mut client, &mut server)?;
+        transfer(&mut server, &mut client)?;
+    }
+    panic!("Handshake did not complete")
+}
+
+#[test]
+fn only_the_admin_config_requires_client_certificates() {
+    let cert = Arc::new(ReloadingCert::load(&fixture("server.pem"), &fixture("server.key.pem")).unwrap());
+    let public = || tls::server_config(cert.clone(), None).unwrap();
+    let admin = || tls::server_config(cert.clone(), Some(&fixture("ca.pem"))).unwrap();
+
+    assert_eq!(handshake(public(), client_config(false)).unwrap(), certs("server.pem")[0]);
+    assert!(handshake(public(), client_config(true)).is_ok());
+    assert_eq!(handshake(admin(), client_config(true)).unwrap(), certs("server.pem")[0]);
+    assert!(matches!(handshake(admin(), client_config(false)), Err(rustls::Error::NoCertificatesPresented)));
+}
+
+#[test]
+fn client_certificates_from_other_cas_are_rejected() {
+    let cert = Arc::new(ReloadingCert::load(&fixture("server.pem"), &fixture("server.key.pem")).unwrap());
+    // A CA file holding a leaf certificate trusts no client.
+    let admin = tls::server_config(cert, Some(&fixture("renewed.pem"))).unwrap();
+    assert!(handshake(admin, client_config(true)).is_err());
+}
+
+#[test]
+fn configurations_with_mismatched_files_are_refused() {
+    assert!(tls::check(&fixture("server.pem"), &fixture("server.key.pem"), Some(&fixture("ca.pem"))).is_ok());
+    assert!(tls::check(&fixture("server.pem"), &fixture("renewed.key.pem"), None).is_err());
+    assert!(tls::check(&fixture("server.key.pem"), &fixture("server.key.pem"), None).is_err());
+    assert!(tls::check(&fixture("server.pem"), &fixture("server.key.pem"), Some(&fixture("client.key.pem"))).is_err());
+}
+
+/// Copies of fixture files that a test may overwrite, removed on drop.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Self {
+        let mut name = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut name);
+        let dir = std::env::temp_dir().join(format!("xpub-session-api-tls-{}", hex::encode(name)));
+        std::fs::create_dir(&dir).unwrap();
+        Scratch(dir)
+    }
+    /// Writes `contents` at `name`, dated `modified` so that the change is seen even within the
+    /// resolution of file times.
+    fn write(&self, name: &str, contents: &[u8], modified: SystemTime) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, contents).unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        path
+    }
+    fn install(&self, fixture_name: &str, modified: SystemTime) {
+        self.write("cert.pem", &std::fs::read(fixture(&format!("{}.pem", fixture_name))).unwrap(), modified);
+        self.write("key.pem", &std::fs::read(fixture(&format!("{}.key.pem", fixture_name))).unwrap(), modified);
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn renewed_certificates_are_served_without_a_restart() {
+    let scratch = Scratch::new();
+    let start = SystemTime::now() - Duration::from_secs(3_600);
+    scratch.install("server", start);
+    let cert = Arc::new(ReloadingCert::load(&scratch.0.join("cert.pem"), &scratch.0.join("key.pem")).unwrap());
+    let presented = || handshake(tls::server_config(cert.clone(), None).unwrap(), client_config(false)).unwrap();
+    assert_eq!(presented(), certs("server.pem")[0]);
+    assert!(!cert.reload().unwrap());
+
+    scratch.install("renewed", start + Duration::from_secs(60));
+    assert!(cert.reload().unwrap());
+    assert_eq!(presented(), certs("renewed.pem")[0]);
+    assert!(!cert.reload().unwrap());
+}
+
+#[test]
+fn failed_reloads_keep_the_current_certificate() {
+    let scratch = Scratch::new();
+    let start = SystemTime::now() - Duration::from_secs(3_600);
+    scratch.install("server", start);
+    let cert = Arc::new(ReloadingCert::load(&scratch.0.join("cert.pem"), &scratch.0.join("key.pem")).unwrap());
+    let presented = || handshake(tls::server_config(cert.clone(), None).unwrap(), client_config(false)).unwrap();
+
+    // Half way through a renewal: the new certificate with the old key.
+    let renewed = std::fs::read(fixture("renewed.pem")).unwrap();
+    scratch.write("cert.pem", &renewed, start + Duration::from_secs(60));
+    assert!(cert.reload().is_err());
+    assert_eq!(presented(), certs("server.pem")[0]);
+    // Not retried until the files change again.
+    assert!(!cert.reload().unwrap());
+
+    let key = std::fs::read(fixture("renewed.key.pem")).unwrap();
+    scratch.write("key.pem", &key, start + Duration::from_secs(120));
+    assert!(cert.reload().unwrap());
+    assert_eq!(presented(), certs("renewed.pem")[0]);
+}
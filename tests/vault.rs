@@ -0,0 +1,86 @@
+use bitcoin::{
+    bip32,
+    Network,
+};
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+use xpub_session_api::model::{
+    XpubWrapper,
+    vault::{
+        Sealed,
+        StoredRecord,
+        Vault,
+        FINGERPRINT_FIELD,
+    },
+};
+
+fn xpub(seed: u8) -> XpubWrapper {
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[seed; 32]).unwrap();
+    XpubWrapper::from(bip32::Xpub::from_priv(&secp, &xpriv))
+}
+
+fn vault() -> Vault {
+    Vault::new(vec![("a".to_string(), [1u8; 32])], "a", vec![5u8; 32]).unwrap()
+}
+
+fn record(xpub: &XpubWrapper, address: &str) -> Map<String, Value> {
+    let Value::Object(record) = json!({"xpub": xpub, "address": address}) else { unreachable!() };
+    record
+}
+
+#[test]
+fn sealed_values_only_open_in_their_record_and_field() {
+    let vault = vault();
+    let mut first = record(&xpub(1), "tb1qfirst");
+    let mut second = record(&xpub(2), "tb1qsecond");
+    vault.seal_record(&mut first).unwrap();
+    vault.seal_record(&mut second).unwrap();
+
+    // Another user's address copied into the second record.
+    let mut swapped = second.clone();
+    swapped.insert("address".to_string(), first["address"].clone());
+    assert!(vault.open_record(&mut swapped).is_err());
+    // The xpub copied into the address field of its own record.
+    let mut moved = first.clone();
+    moved.insert("address".to_string(), first["xpub"].clone());
+    assert!(vault.open_record(&mut moved).is_err());
+
+    vault.open_record(&mut first).unwrap();
+    assert_eq!(first["address"], json!("tb1qfirst"));
+}
+
+#[test]
+fn values_sealed_unbound_open_and_are_bound_by_rotation() {
+    let vault = vault();
+    let xpub = xpub(1);
+    let mut legacy = record(&xpub, "tb1qlegacy");
+    legacy.set_field(FINGERPRINT_FIELD, &vault.fingerprint(&xpub)).unwrap();
+    for name in ["xpub", "address"] {
+        let plaintext = serde_json::to_vec(&legacy[name]).unwrap();
+        legacy.set_field(name, &vault.seal(&plaintext).unwrap()).unwrap();
+    }
+
+    let mut opened = legacy.clone();
+    vault.open_record(&mut opened).unwrap();
+    assert_eq!(opened["address"], json!("tb1qlegacy"));
+
+    assert!(vault.rewrap_record(&mut legacy).unwrap());
+    assert!(legacy.field::<Sealed>("address").unwrap().is_bound());
+    assert!(!vault.rewrap_record(&mut legacy).unwrap());
+    vault.open_record(&mut legacy).unwrap();
+    assert!(legacy.field::<XpubWrapper>("xpub") == Some(xpub));
+}
+
+#[test]
+fn sealed_xpubs_only_open_for_their_owner_and_column() {
+    let vault = vault();
+    let (owner, other) = (vault.fingerprint(&xpub(1)), vault.fingerprint(&xpub(2)));
+    let sealed = vault.seal_xpub(&xpub(3), &owner, "derived_xpub").unwrap();
+    assert!(vault.open_xpub(&sealed, &other, "derived_xpub").is_err());
+    assert!(vault.open_xpub(&sealed, &owner, "sealed_xpub").is_err());
+    assert!(vault.open_xpub(&sealed, &owner, "derived_xpub").unwrap() == xpub(3));
+}
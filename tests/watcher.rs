@@ -0,0 +1,107 @@
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+use actix_web::web;
+use bitcoin::{
+    absolute,
+    bip32,
+    hashes::Hash,
+    transaction,
+    Amount,
+    Network,
+    OutPoint,
+    ScriptBuf,
+    Sequence,
+    Transaction,
+    TxIn,
+    TxOut,
+    Txid,
+    Witness,
+};
+use xpub_session_api::model::{
+    Credentials,
+    UserAddress,
+    XpubWrapper,
+    balance::p2wpkh_script,
+    chain::{
+        memory::MemoryChain,
+        ChainBackend,
+    },
+    storage::{
+        memory::MemoryStorage,
+        Storage,
+    },
+    watcher::Watcher,
+};
+
+fn xpub() -> bip32::Xpub {
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let xpriv = bip32::Xpriv::new_master(Network::Testnet, &[9u8; 32]).unwrap();
+    bip32::Xpub::from_priv(&secp, &xpriv)
+}
+
+fn payment(seed: u8, script_pubkey: ScriptBuf, value: u64) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint { txid: Txid::from_byte_array([seed; 32]), vout: 0 },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut { value: Amount::from_sat(value), script_pubkey }],
+    }
+}
+
+/// Storage holding the user of `xpub`, registered without checking a signature.
+async fn storage_with(xpub: &bip32::Xpub) -> web::Data<dyn Storage> {
+    let storage = MemoryStorage::new();
+    let credentials: Credentials<XpubWrapper> = serde_json::from_value(serde_json::json!({
+        "witness": vec![0u8; 65],
+        "xpub": XpubWrapper::from(*xpub),
+        "nonce": 0,
+    })).unwrap();
+    storage.insert_address(UserAddress::from_credentials(credentials)).await.unwrap();
+    let storage: Arc<dyn Storage> = Arc::new(storage);
+    web::Data::from(storage)
+}
+
+fn watcher(storage: &web::Data<dyn Storage>, chain: &web::Data<dyn ChainBackend>, start_height: Option<u32>) -> Watcher {
+    Watcher::new(storage.clone(), chain.clone(), Duration::from_secs(1), start_height)
+}
+
+#[actix_web::test]
+async fn restarts_resume_at_the_stored_cursor_and_revert_disconnected_blocks() {
+    let xpub = xpub();
+    let storage = storage_with(&xpub).await;
+    let memory_chain = Arc::new(MemoryChain::new());
+    let chain: Arc<dyn ChainBackend> = memory_chain.clone();
+    let chain = web::Data::from(chain);
+    memory_chain.set_tip_height(5);
+    let deposit = payment(1, p2wpkh_script(&xpub, &[0, 0]), 40_000);
+    memory_chain.add_transaction(deposit, Some(3), Some(1_700_000_000));
+
+    watcher(&storage, &chain, Some(1)).poll().await.unwrap();
+    let deposits = storage.deposits_lookup(XpubWrapper::from(xpub)).await.unwrap();
+    assert_eq!(deposits[0].get_block_height(), Some(3));
+    let cursor = storage.watcher_cursor().await.unwrap().unwrap();
+    assert_eq!(cursor.next_height(), Some(6));
+
+    // Blocks 3 and up are replaced while the watcher is down.
+    memory_chain.disconnect_blocks(3);
+    memory_chain.set_tip_height(7);
+    let later = payment(2, p2wpkh_script(&xpub, &[0, 1]), 10_000);
+    memory_chain.add_transaction(later, Some(7), Some(1_700_000_600));
+
+    watcher(&storage, &chain, None).poll().await.unwrap();
+    let mut deposits = storage.deposits_lookup(XpubWrapper::from(xpub)).await.unwrap();
+    deposits.sort_by_key(|deposit| deposit.get_derivation_path());
+    assert_eq!(deposits.len(), 2);
+    assert_eq!(deposits[0].get_block_height(), None);
+    assert_eq!(deposits[1].get_block_height(), Some(7));
+    let cursor = storage.watcher_cursor().await.unwrap().unwrap();
+    assert_eq!(cursor.next_height(), Some(8));
+    assert_eq!(cursor.get_recent().front().map(|(height, _)| *height), Some(1));
+}
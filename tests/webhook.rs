@@ -0,0 +1,43 @@
+use std::net::IpAddr;
+use xpub_session_api::model::webhook::{
+    is_public_ip,
+    WebhookError,
+    WebhookPolicy,
+};
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+#[test]
+fn only_public_addresses_are_allowed() {
+    for address in [
+        "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1",
+        "0.0.0.0", "255.255.255.255", "240.0.0.1", "::1", "::", "fd00::1", "fe80::1",
+        "::ffff:127.0.0.1", "::ffff:169.254.169.254", "64:ff9b::a9fe:a9fe", "2001:db8::1",
+    ] {
+        assert!(!is_public_ip(ip(address)), "{} is not public", address);
+    }
+    for address in ["1.1.1.1", "8.8.8.8", "2606:4700:4700::1111", "::ffff:1.1.1.1", "64:ff9b::101:101"] {
+        assert!(is_public_ip(ip(address)), "{} is public", address);
+    }
+}
+
+#[actix_web::test]
+async fn private_urls_are_refused_unless_allowed() {
+    let policy = WebhookPolicy::new(false);
+    for url in [
+        "http://169.254.169.254/latest/meta-data",
+        "http://127.0.0.1:8081/webhook",
+        "http://[::1]/webhook",
+        "https://10.0.0.5/",
+        "http://localhost:8081/webhook",
+    ] {
+        let result = policy.check_url(&reqwest::Url::parse(url).unwrap()).await;
+        assert!(matches!(result, Err(WebhookError::PrivateUrl(_))), "{} is refused", url);
+    }
+    assert!(policy.check_url(&reqwest::Url::parse("http://1.1.1.1/webhook").unwrap()).await.is_ok());
+
+    let allowing = WebhookPolicy::new(true);
+    assert!(allowing.check_url(&reqwest::Url::parse("http://localhost:8081/webhook").unwrap()).await.is_ok());
+}